@@ -0,0 +1,81 @@
+//! Times `acousticdi::vector` against the plain loops it falls back on, on a second of
+//! audio. Run with `cargo bench --bench vector`, and again with `--features simd`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use acousticdi::vector::{self, scalar};
+
+/// a second of audio at 44.1 kHz
+const LEN: usize = 44100;
+
+/// runs of every function, the fastest counts
+const RUNS: usize = 200;
+
+/// the fastest of `RUNS` runs of `f`
+fn fastest(mut f: impl FnMut()) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn report(name: &str, scalar: Duration, vector: Duration) {
+    println!(
+        "{:<12} scalar {:>8.1?} vector {:>8.1?} {:>5.2}x",
+        name,
+        scalar,
+        vector,
+        scalar.as_secs_f64() / vector.as_secs_f64()
+    );
+}
+
+fn main() {
+    let a = (0..LEN)
+        .map(|i| (i as f64 * 0.37).sin())
+        .collect::<Vec<f64>>();
+    let b = (0..LEN)
+        .map(|i| (i as f64 * 0.11).cos())
+        .collect::<Vec<f64>>();
+    println!(
+        "{} samples, simd {}",
+        LEN,
+        match cfg!(feature = "simd") {
+            true => "on",
+            false => "off",
+        }
+    );
+
+    let mut acc = a.clone();
+    report(
+        "add_assign",
+        fastest(|| scalar::add_assign(black_box(&mut acc), black_box(&b))),
+        fastest(|| vector::add_assign(black_box(&mut acc), black_box(&b))),
+    );
+    report(
+        "multiply",
+        fastest(|| {
+            let mut product = a.clone();
+            scalar::multiply_assign(black_box(&mut product), black_box(&b));
+            black_box(product);
+        }),
+        fastest(|| {
+            black_box(vector::multiply(black_box(&a), black_box(&b)));
+        }),
+    );
+    report(
+        "dot",
+        fastest(|| {
+            black_box(scalar::dot(black_box(&a), black_box(&b)));
+        }),
+        fastest(|| {
+            black_box(vector::dot(black_box(&a), black_box(&b)));
+        }),
+    );
+}
@@ -0,0 +1,192 @@
+//! # AFSK
+//!
+//! Bell 202 audio FSK, as spoken by `minimodem 1200` and packet radio: 1200 baud, a mark
+//! of 1200 Hz for a one and a space of 2200 Hz for a zero, keyed with a continuous phase.
+//! Bytes go out the way a UART sends them, a start bit of space, the eight data bits least
+//! significant first, and a stop bit of mark, which the line idles on between bytes. There
+//! are no packets, no preamble and no error correction, whatever bytes are heard are the
+//! message, so this is for talking to other modems rather than to ourselves.
+//!
+//! Bell 103, minimodem's `300`, is `Afsk { baud: 300.0, mark: 1270.0, space: 1070.0 }`.
+
+use std::f64::consts::PI;
+
+/// The tones and the speed of an AFSK line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Afsk {
+    /// bits per second
+    pub baud: f64,
+    /// tone of a one and of the idle line, in Hz
+    pub mark: f64,
+    /// tone of a zero, in Hz
+    pub space: f64,
+}
+
+/// 1200 baud on 1200 and 2200 Hz, `minimodem 1200`
+pub const BELL202: Afsk = Afsk {
+    baud: 1200.0,
+    mark: 1200.0,
+    space: 2200.0,
+};
+
+/// bits of mark before the first byte, for the other end to find the carrier in
+const LEADER_BITS: usize = 24;
+
+/// bits of mark after the last byte, for the last stop bit to be heard whole
+const TRAILER_BITS: usize = 4;
+
+/// how much of the loudest tone of a recording a start bit has to reach, to not take the
+/// noise between transmissions for bytes
+const CARRIER_FRACTION: f64 = 0.1;
+
+/// The start bit, the data bits least significant first and the stop bit of `byte`, `true`
+/// for mark.
+fn uart_frame(byte: u8) -> impl Iterator<Item = bool> {
+    std::iter::once(false)
+        .chain((0..8).map(move |i| byte >> i & 1 == 1))
+        .chain(std::iter::once(true))
+}
+
+impl Afsk {
+    /// samples one bit takes at `sample_rate`, not a whole number at most rates
+    fn bit_samples(&self, sample_rate: f64) -> f64 {
+        sample_rate / self.baud
+    }
+
+    /// `bytes` framed and keyed onto the tones, between a leader and a trailer of mark.
+    pub fn modulate(&self, sample_rate: f64, bytes: &[u8]) -> Vec<f64> {
+        let bits = std::iter::repeat_n(true, LEADER_BITS)
+            .chain(bytes.iter().flat_map(|byte| uart_frame(*byte)))
+            .chain(std::iter::repeat_n(true, TRAILER_BITS));
+        let per_bit = self.bit_samples(sample_rate);
+        let mut signal = Vec::with_capacity(
+            ((LEADER_BITS + 10 * bytes.len() + TRAILER_BITS) as f64 * per_bit).ceil() as usize,
+        );
+        let mut phase = 0.0_f64;
+        for (k, bit) in bits.enumerate() {
+            let freq = match bit {
+                true => self.mark,
+                false => self.space,
+            };
+            // bit boundaries on the nearest sample, the phase carries on across them
+            let end = ((k + 1) as f64 * per_bit).round() as usize;
+            while signal.len() < end {
+                signal.push(phase.sin());
+                phase = (phase + 2.0 * PI * freq / sample_rate) % (2.0 * PI);
+            }
+        }
+        signal
+    }
+
+    /// Every byte framed right in `signal`, in order. Bytes with a broken start or stop bit
+    /// are left out, the search for the next start bit goes on after them.
+    ///
+    /// Both tones are correlated over a bit around every sample. A start bit is where the
+    /// space gets louder than the mark, and every bit of its frame is decided in its middle,
+    /// one bit time after the one before.
+    pub fn demodulate(&self, sample_rate: f64, signal: &[f64]) -> Vec<u8> {
+        let per_bit = self.bit_samples(sample_rate);
+        let window = (per_bit.round() as usize).max(1);
+        let mark = ToneEnergy::new(signal, self.mark, sample_rate, window);
+        let space = ToneEnergy::new(signal, self.space, sample_rate, window);
+        let loudest = (0..signal.len())
+            .step_by(window.div_ceil(2))
+            .map(|t| mark.at(t).max(space.at(t)))
+            .fold(0.0, f64::max);
+        let threshold = CARRIER_FRACTION * loudest;
+        let is_mark = |t: usize| mark.at(t) >= space.at(t);
+
+        let frame_len = 10.0 * per_bit;
+        let mut bytes = Vec::new();
+        let mut t = 1;
+        while (t as f64 + frame_len) as usize <= signal.len() {
+            if !(is_mark(t - 1) && !is_mark(t) && space.at(t) > threshold) {
+                t += 1;
+                continue;
+            }
+            // the middle of bit `k` of the frame starting at `t`
+            let middle = |k: usize| (t as f64 + (k as f64 + 0.5) * per_bit) as usize;
+            if is_mark(middle(0)) || !is_mark(middle(9)) {
+                t += 1;
+                continue;
+            }
+            let byte = (0..8).fold(0_u8, |byte, i| byte | (is_mark(middle(1 + i)) as u8) << i);
+            bytes.push(byte);
+            // a start bit may follow the stop bit right away
+            t = middle(9);
+        }
+        bytes
+    }
+}
+
+/// How loud a tone is in a window around any sample of a signal, from running sums of the
+/// signal against the tone.
+struct ToneEnergy {
+    /// sums of `x * sin` and `x * cos` up to every sample
+    sums: Vec<(f64, f64)>,
+    window: usize,
+}
+
+impl ToneEnergy {
+    fn new(signal: &[f64], freq: f64, sample_rate: f64, window: usize) -> ToneEnergy {
+        let omega = 2.0 * PI * freq / sample_rate;
+        let mut sums = Vec::with_capacity(signal.len() + 1);
+        sums.push((0.0, 0.0));
+        let (mut i, mut q) = (0.0, 0.0);
+        for (t, x) in signal.iter().enumerate() {
+            let (sin, cos) = (omega * t as f64).sin_cos();
+            i += x * sin;
+            q += x * cos;
+            sums.push((i, q));
+        }
+        ToneEnergy { sums, window }
+    }
+
+    /// the energy of the tone in the window centered on sample `t`
+    fn at(&self, t: usize) -> f64 {
+        let len = self.sums.len() - 1;
+        let start = t.saturating_sub(self.window / 2).min(len);
+        let end = (start + self.window).min(len);
+        let (i, q) = (
+            self.sums[end].0 - self.sums[start].0,
+            self.sums[end].1 - self.sums[start].1,
+        );
+        i * i + q * q
+    }
+}
+
+#[test]
+fn test_afsk() {
+    use crate::simulator::awgn_seeded;
+
+    let message = (0..=255).collect::<Vec<u8>>();
+    for sample_rate in [44100.0, 48000.0, 8000.0] {
+        let signal = BELL202.modulate(sample_rate, &message);
+        let bits = LEADER_BITS + 10 * message.len() + TRAILER_BITS;
+        assert_eq!(
+            signal.len(),
+            (bits as f64 * sample_rate / 1200.0).round() as usize
+        );
+        assert_eq!(BELL202.demodulate(sample_rate, &signal), message);
+    }
+
+    // between silence, in noise, twice
+    let text = b"hello minimodem";
+    let signal = BELL202.modulate(48000.0, text);
+    let silence = vec![0.0; 12345];
+    let recording = [&silence[..], &signal, &silence, &signal, &silence].concat();
+    let noisy = awgn_seeded(&recording, 10.0, 1);
+    assert_eq!(
+        BELL202.demodulate(48000.0, &noisy),
+        [&text[..], &text[..]].concat()
+    );
+
+    // slower, on other tones
+    let bell103 = Afsk {
+        baud: 300.0,
+        mark: 1270.0,
+        space: 1070.0,
+    };
+    let signal = bell103.modulate(44100.0, text);
+    assert_eq!(bell103.demodulate(44100.0, &signal), text);
+}
@@ -0,0 +1,724 @@
+//! # Go-back-N ARQ
+//!
+//! Every symbol takes 100 ms, so waiting for an acknowledgment after each packet would leave
+//! the channel idle most of the time. The sender keeps up to `window` packets in flight and
+//! the receiver acknowledges cumulatively with the order of the next packet it expects. When
+//! an acknowledgment does not come in time, everything from the oldest unacknowledged packet
+//! on is sent again.
+//!
+//! Resending the whole window for one lost packet costs a second per packet at 100 ms
+//! symbols. In selective repeat, the receiver keeps packets that arrive after a gap, and its
+//! acknowledgment carries a bitmap of them, see `SelectiveAck`. The sender then resends only
+//! the packets missing. Its acknowledgments still start with the next packet expected, so
+//! that a go-back-N sender understands them too.
+//!
+//! A receiver that reads slowly, say one writing to disk as well, can tell the sender how
+//! many packets from the next one expected it has room for, see `SelectiveAck::window`.
+//! Senders then keep within that as well as their own window. Once it is zero they send
+//! nothing new, only the oldest packet again after a timeout, so that the acknowledgment of
+//! it says when there is room again.
+//!
+//! Both ends are plain state machines, how packets and acknowledgments travel is up to the
+//! caller.
+
+use std::collections::BTreeMap;
+
+use crate::{Packet, PacketKind};
+
+/// packets after the next one expected that an acknowledgment tells about
+pub const BITMAP_BITS: usize = 32;
+
+/// A cumulative acknowledgment, and which packets after the gap arrived already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelectiveAck {
+    /// every packet before this one arrived
+    pub next_expected: usize,
+    /// bit `i` is set when packet `next_expected + 1 + i` arrived too
+    pub received: u32,
+    /// packets from `next_expected` on the receiver has room for, `None` if it does not say
+    pub window: Option<usize>,
+}
+
+impl SelectiveAck {
+    /// just the cumulative part, as a go-back-N receiver acknowledges
+    pub fn cumulative(next_expected: usize) -> SelectiveAck {
+        SelectiveAck {
+            next_expected,
+            received: 0,
+            window: None,
+        }
+    }
+
+    /// the same acknowledgment, advertising room for `window` packets
+    pub fn with_window(self, window: usize) -> SelectiveAck {
+        SelectiveAck {
+            window: Some(window),
+            ..self
+        }
+    }
+
+    /// An ack packet, with the bitmap as a little endian `u32` for payload if any bit is set
+    /// or a window follows, then the window as a little endian `u16` if any.
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = Packet::ack(self.next_expected);
+        if self.received != 0 || self.window.is_some() {
+            packet.data = self.received.to_le_bytes().to_vec();
+        }
+        if let Some(window) = self.window {
+            let window = window.min(u16::MAX as usize) as u16;
+            packet.data.extend_from_slice(&window.to_le_bytes());
+        }
+        packet
+    }
+
+    /// The acknowledgment in an ack packet, `None` for other kinds.
+    pub fn from_packet(packet: &Packet) -> Option<SelectiveAck> {
+        if packet.kind != PacketKind::Ack {
+            return None;
+        }
+        let (received, window) = match packet.data[..] {
+            [a, b, c, d] => (u32::from_le_bytes([a, b, c, d]), None),
+            [a, b, c, d, low, high] => (
+                u32::from_le_bytes([a, b, c, d]),
+                Some(u16::from_le_bytes([low, high]) as usize),
+            ),
+            _ => (0, None),
+        };
+        Some(SelectiveAck {
+            next_expected: packet.order,
+            received,
+            window,
+        })
+    }
+
+    /// order of the first packet the receiver has no room for, if it says
+    pub fn limit(&self) -> Option<usize> {
+        self.window.map(|window| self.next_expected + window)
+    }
+
+    /// whether the packet with `order` arrived
+    pub fn has(&self, order: usize) -> bool {
+        match order.checked_sub(self.next_expected + 1) {
+            None => order < self.next_expected,
+            Some(i) => i < BITMAP_BITS && self.received & 1 << i > 0,
+        }
+    }
+}
+
+/// What `AcousticStream` needs of the sending end, whichever the scheme.
+pub trait ArqSender: Send {
+    /// Packets to send now.
+    fn poll_send(&mut self) -> Vec<Packet>;
+
+    fn on_ack(&mut self, ack: &SelectiveAck);
+
+    /// No acknowledgment in time.
+    fn timeout(&mut self);
+
+    /// packets sent but not acknowledged yet
+    fn in_flight(&self) -> usize;
+
+    fn is_done(&self) -> bool;
+
+    /// order right after the last packet, where the next message starts
+    fn end(&self) -> usize;
+
+    /// packets of the message the receiver has
+    fn acknowledged(&self) -> usize;
+
+    /// packets of the whole message
+    fn packet_count(&self) -> usize;
+}
+
+/// End of what may be in flight, `window` packets from `base` on, but no further than the
+/// receiver has room for.
+fn window_end(base: usize, window: usize, limit: Option<usize>, len: usize) -> usize {
+    let end = (base + window).min(len);
+    limit.map_or(end, |limit| end.min(limit.max(base)))
+}
+
+/// Where the receiver has no room from on, by index of the packets numbered from `first`.
+/// Acknowledgments from before `first` are about an earlier message.
+fn limit_after(ack: &SelectiveAck, first: usize, limit: Option<usize>) -> Option<usize> {
+    match ack.next_expected < first {
+        true => limit,
+        false => ack.limit().map(|limit| limit - first),
+    }
+}
+
+pub struct GoBackNSender {
+    packets: Vec<Packet>,
+    /// order of `packets[0]`
+    first: usize,
+    window: usize,
+    /// oldest packet not acknowledged yet
+    base: usize,
+    /// next packet to send
+    next: usize,
+    /// first packet the receiver has no room for, if it said
+    limit: Option<usize>,
+    /// the receiver had no room at the last timeout, send the oldest packet anyway
+    probe: bool,
+}
+
+impl GoBackNSender {
+    pub fn new(data: &[u8], window: usize) -> GoBackNSender {
+        Self::starting_at(data, window, 0)
+    }
+
+    /// Number the packets from `first` on, so that a stream of messages never reuses an order.
+    pub fn starting_at(data: &[u8], window: usize, first: usize) -> GoBackNSender {
+        Self::from_packets(Packet::new_packets(data), window, first)
+    }
+
+    /// Send the packets of a message split already, say to another size with
+    /// `Packet::new_packets_sized`, numbered from `first` on.
+    pub fn from_packets(mut packets: Vec<Packet>, window: usize, first: usize) -> GoBackNSender {
+        for packet in packets.iter_mut() {
+            packet.order += first;
+        }
+        GoBackNSender {
+            packets,
+            first,
+            window: window.max(1),
+            base: 0,
+            next: 0,
+            limit: None,
+            probe: false,
+        }
+    }
+
+    /// Packets that fit in the window now, each is handed out once until a `timeout`.
+    pub fn poll_send(&mut self) -> Vec<Packet> {
+        let end = window_end(self.base, self.window, self.limit, self.packets.len());
+        if std::mem::take(&mut self.probe) && end == self.base && !self.is_done() {
+            self.next = self.next.max(self.base + 1);
+            return vec![self.packets[self.base].clone()];
+        }
+        let ready = self.packets[self.next.min(end)..end].to_vec();
+        self.next = self.next.max(end);
+        ready
+    }
+
+    /// The receiver has every packet before `next_expected`.
+    pub fn acknowledge(&mut self, next_expected: usize) {
+        let Some(next_expected) = next_expected.checked_sub(self.first) else {
+            return;
+        };
+        if next_expected > self.base && next_expected <= self.next {
+            self.base = next_expected;
+        }
+    }
+
+    /// No acknowledgment in time, send the whole window again.
+    pub fn timeout(&mut self) {
+        self.next = self.base;
+        self.probe = true;
+    }
+
+    /// packets sent but not acknowledged yet
+    pub fn in_flight(&self) -> usize {
+        self.next - self.base
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.base == self.packets.len()
+    }
+
+    /// order right after the last packet, where the next message starts
+    pub fn end(&self) -> usize {
+        self.first + self.packets.len()
+    }
+}
+
+impl ArqSender for GoBackNSender {
+    fn poll_send(&mut self) -> Vec<Packet> {
+        GoBackNSender::poll_send(self)
+    }
+
+    /// the bitmap is no use, the whole window goes out again anyway
+    fn on_ack(&mut self, ack: &SelectiveAck) {
+        self.acknowledge(ack.next_expected);
+        self.limit = limit_after(ack, self.first, self.limit);
+    }
+
+    fn timeout(&mut self) {
+        GoBackNSender::timeout(self)
+    }
+
+    fn in_flight(&self) -> usize {
+        GoBackNSender::in_flight(self)
+    }
+
+    fn is_done(&self) -> bool {
+        GoBackNSender::is_done(self)
+    }
+
+    fn end(&self) -> usize {
+        GoBackNSender::end(self)
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.base
+    }
+
+    fn packet_count(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+pub struct SelectiveRepeatSender {
+    packets: Vec<Packet>,
+    /// order of `packets[0]`
+    first: usize,
+    window: usize,
+    /// which packets the receiver has
+    acked: Vec<bool>,
+    /// oldest packet not acknowledged yet
+    base: usize,
+    /// next packet never sent
+    next: usize,
+    /// packets to send again before new ones
+    resend: Vec<usize>,
+    /// first packet the receiver has no room for, if it said
+    limit: Option<usize>,
+    /// the receiver had no room at the last timeout, send the oldest packet anyway
+    probe: bool,
+}
+
+impl SelectiveRepeatSender {
+    pub fn new(data: &[u8], window: usize) -> SelectiveRepeatSender {
+        Self::starting_at(data, window, 0)
+    }
+
+    /// Number the packets from `first` on, see `GoBackNSender::starting_at`. The window is
+    /// at most what a bitmap tells about.
+    pub fn starting_at(data: &[u8], window: usize, first: usize) -> SelectiveRepeatSender {
+        Self::from_packets(Packet::new_packets(data), window, first)
+    }
+
+    /// Send the packets of a message split already, see `GoBackNSender::from_packets`.
+    pub fn from_packets(
+        mut packets: Vec<Packet>,
+        window: usize,
+        first: usize,
+    ) -> SelectiveRepeatSender {
+        for packet in packets.iter_mut() {
+            packet.order += first;
+        }
+        SelectiveRepeatSender {
+            acked: vec![false; packets.len()],
+            packets,
+            first,
+            window: window.clamp(1, BITMAP_BITS + 1),
+            base: 0,
+            next: 0,
+            resend: Vec::new(),
+            limit: None,
+            probe: false,
+        }
+    }
+}
+
+impl ArqSender for SelectiveRepeatSender {
+    /// Packets missing after a `timeout`, then new ones that fit in the window.
+    fn poll_send(&mut self) -> Vec<Packet> {
+        let end = window_end(self.base, self.window, self.limit, self.packets.len());
+        if std::mem::take(&mut self.probe) && end == self.base && !self.is_done() {
+            self.next = self.next.max(self.base + 1);
+            return vec![self.packets[self.base].clone()];
+        }
+        let mut ready = std::mem::take(&mut self.resend)
+            .into_iter()
+            .filter(|i| !self.acked[*i] && *i < end)
+            .map(|i| self.packets[i].clone())
+            .collect::<Vec<Packet>>();
+        ready.extend_from_slice(&self.packets[self.next.min(end)..end]);
+        self.next = self.next.max(end);
+        ready
+    }
+
+    fn on_ack(&mut self, ack: &SelectiveAck) {
+        for i in self.base..self.next {
+            if ack.has(self.first + i) {
+                self.acked[i] = true;
+            }
+        }
+        while self.base < self.next && self.acked[self.base] {
+            self.base += 1;
+        }
+        self.limit = limit_after(ack, self.first, self.limit);
+    }
+
+    /// Send again only what the receiver does not have.
+    fn timeout(&mut self) {
+        self.resend = (self.base..self.next).filter(|i| !self.acked[*i]).collect();
+        self.probe = true;
+    }
+
+    fn in_flight(&self) -> usize {
+        (self.base..self.next).filter(|i| !self.acked[*i]).count()
+    }
+
+    fn is_done(&self) -> bool {
+        self.base == self.packets.len()
+    }
+
+    fn end(&self) -> usize {
+        self.first + self.packets.len()
+    }
+
+    fn acknowledged(&self) -> usize {
+        self.acked.iter().filter(|acked| **acked).count()
+    }
+
+    fn packet_count(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+pub struct GoBackNReceiver {
+    packets: Vec<Packet>,
+    /// order of the first packet expected
+    first: usize,
+    /// payload bytes of every packet but the last
+    packet_size: usize,
+}
+
+impl Default for GoBackNReceiver {
+    fn default() -> Self {
+        GoBackNReceiver {
+            packets: Vec::new(),
+            first: 0,
+            packet_size: Packet::MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl GoBackNReceiver {
+    pub fn new() -> GoBackNReceiver {
+        GoBackNReceiver::default()
+    }
+
+    /// expect the first packet to have order `first`, see `GoBackNSender::starting_at`
+    pub fn starting_at(first: usize) -> GoBackNReceiver {
+        GoBackNReceiver {
+            first,
+            ..GoBackNReceiver::default()
+        }
+    }
+
+    /// for messages split to `packet_size` bytes a packet, see `Packet::new_packets_sized`
+    pub fn with_packet_size(mut self, packet_size: usize) -> GoBackNReceiver {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Take a packet and return the acknowledgment to send back. Packets out of order are
+    /// dropped, the sender will repeat them.
+    pub fn receive(&mut self, packet: Packet) -> usize {
+        if !self.is_done() && packet.order == self.end() {
+            self.packets.push(packet);
+        }
+        self.end()
+    }
+
+    /// order of the next packet expected
+    pub fn end(&self) -> usize {
+        self.first + self.packets.len()
+    }
+
+    pub fn is_done(&self) -> bool {
+        let size = self.packet_size;
+        self.packets
+            .last()
+            .is_some_and(|packet| packet.ends_message(size))
+    }
+
+    /// the whole message, once its last packet arrived
+    pub fn message(&self) -> Option<Vec<u8>> {
+        self.is_done().then(|| Packet::unpack(&self.packets))
+    }
+}
+
+/// The receiving end of selective repeat, which also serves a go-back-N sender.
+pub struct SelectiveRepeatReceiver {
+    packets: Vec<Packet>,
+    /// order of the first packet expected
+    first: usize,
+    /// packets that arrived after a gap, by order
+    ahead: BTreeMap<usize, Packet>,
+    /// payload bytes of every packet but the last
+    packet_size: usize,
+}
+
+impl Default for SelectiveRepeatReceiver {
+    fn default() -> Self {
+        SelectiveRepeatReceiver {
+            packets: Vec::new(),
+            first: 0,
+            ahead: BTreeMap::new(),
+            packet_size: Packet::MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl SelectiveRepeatReceiver {
+    pub fn new() -> SelectiveRepeatReceiver {
+        SelectiveRepeatReceiver::default()
+    }
+
+    /// expect the first packet to have order `first`, see `GoBackNSender::starting_at`
+    pub fn starting_at(first: usize) -> SelectiveRepeatReceiver {
+        SelectiveRepeatReceiver {
+            first,
+            ..SelectiveRepeatReceiver::default()
+        }
+    }
+
+    /// for messages split to `packet_size` bytes a packet, see `Packet::new_packets_sized`
+    pub fn with_packet_size(mut self, packet_size: usize) -> SelectiveRepeatReceiver {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Take a packet and return the acknowledgment to send back. Packets too far ahead for
+    /// the bitmap are dropped, the sender will repeat them.
+    pub fn receive(&mut self, packet: Packet) -> SelectiveAck {
+        let end = self.end();
+        if !self.is_done() && (end + 1..=end + BITMAP_BITS).contains(&packet.order) {
+            self.ahead.insert(packet.order, packet);
+        } else if !self.is_done() && packet.order == end {
+            self.packets.push(packet);
+            while !self.is_done() {
+                let Some(packet) = self.ahead.remove(&self.end()) else {
+                    break;
+                };
+                self.packets.push(packet);
+            }
+        }
+        self.ack()
+    }
+
+    /// what to acknowledge now
+    pub fn ack(&self) -> SelectiveAck {
+        let end = self.end();
+        let received = self
+            .ahead
+            .keys()
+            .map(|order| order - end - 1)
+            .filter(|i| *i < BITMAP_BITS)
+            .fold(0, |bits, i| bits | 1 << i);
+        SelectiveAck {
+            next_expected: end,
+            received,
+            window: None,
+        }
+    }
+
+    /// order of the next packet expected
+    pub fn end(&self) -> usize {
+        self.first + self.packets.len()
+    }
+
+    /// payload bytes held, in order or ahead
+    pub fn buffered(&self) -> usize {
+        self.packets
+            .iter()
+            .chain(self.ahead.values())
+            .map(|packet| packet.data.len())
+            .sum()
+    }
+
+    pub fn is_done(&self) -> bool {
+        let size = self.packet_size;
+        self.packets
+            .last()
+            .is_some_and(|packet| packet.ends_message(size))
+    }
+
+    /// the whole message, once every packet up to the last one arrived
+    pub fn message(&self) -> Option<Vec<u8>> {
+        self.is_done().then(|| Packet::unpack(&self.packets))
+    }
+}
+
+#[test]
+fn test_go_back_n_lossy() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let data = (0..1000).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut sender = GoBackNSender::new(&data, 4);
+    let mut receiver = GoBackNReceiver::new();
+    let mut rounds = 0;
+    while !sender.is_done() {
+        rounds += 1;
+        assert!(rounds < 100);
+        let sent = sender.poll_send();
+        assert!(sender.in_flight() <= 4);
+        for packet in sent {
+            // a quarter of the packets and of the acknowledgments are lost
+            if rng.gen_bool(0.75) {
+                let ack = receiver.receive(packet);
+                if rng.gen_bool(0.75) {
+                    sender.acknowledge(ack);
+                }
+            }
+        }
+        if sender.in_flight() > 0 {
+            sender.timeout();
+        }
+    }
+    assert_eq!(receiver.message().unwrap(), data);
+}
+
+#[test]
+fn test_go_back_n_window() {
+    let mut sender = GoBackNSender::new(&[0; 1000], 3);
+    assert_eq!(
+        sender
+            .poll_send()
+            .iter()
+            .map(|p| p.order)
+            .collect::<Vec<_>>(),
+        [0, 1, 2]
+    );
+    assert!(sender.poll_send().is_empty());
+    sender.acknowledge(2);
+    assert_eq!(
+        sender
+            .poll_send()
+            .iter()
+            .map(|p| p.order)
+            .collect::<Vec<_>>(),
+        [3, 4]
+    );
+    // a stale acknowledgment changes nothing
+    sender.acknowledge(1);
+    sender.timeout();
+    assert_eq!(
+        sender
+            .poll_send()
+            .iter()
+            .map(|p| p.order)
+            .collect::<Vec<_>>(),
+        [2, 3, 4]
+    );
+}
+
+#[test]
+fn test_selective_ack() {
+    let ack = SelectiveAck {
+        next_expected: 300,
+        received: 0b101,
+        window: None,
+    };
+    assert!(ack.has(299));
+    assert!(!ack.has(300));
+    assert!(ack.has(301));
+    assert!(!ack.has(302));
+    assert!(ack.has(303));
+    assert!(!ack.has(300 + BITMAP_BITS + 1));
+    assert_eq!(SelectiveAck::from_packet(&ack.to_packet()), Some(ack));
+    // a plain acknowledgment stays as short as before
+    let cumulative = SelectiveAck::cumulative(7);
+    assert_eq!(cumulative.to_packet().data, []);
+    assert_eq!(SelectiveAck::from_packet(&Packet::ack(7)), Some(cumulative));
+    assert_eq!(SelectiveAck::from_packet(&Packet::from((7, &[][..]))), None);
+    // a window goes after the bitmap, even an empty one
+    let ack = cumulative.with_window(3);
+    assert_eq!(ack.to_packet().data, [0, 0, 0, 0, 3, 0]);
+    assert_eq!(SelectiveAck::from_packet(&ack.to_packet()), Some(ack));
+    assert_eq!(ack.limit(), Some(10));
+}
+
+#[test]
+fn test_advertised_window() {
+    let orders = |packets: &[Packet]| packets.iter().map(|p| p.order).collect::<Vec<_>>();
+    let senders: [Box<dyn ArqSender>; 2] = [
+        Box::new(GoBackNSender::new(&[0; 1000], 6)),
+        Box::new(SelectiveRepeatSender::new(&[0; 1000], 6)),
+    ];
+    for mut sender in senders {
+        assert_eq!(orders(&sender.poll_send()), [0, 1, 2, 3, 4, 5]);
+        // room for one more after the two that arrived
+        sender.on_ack(&SelectiveAck::cumulative(2).with_window(1));
+        sender.timeout();
+        assert_eq!(orders(&sender.poll_send()), [2]);
+        // no room, nothing new, only the oldest to learn when there is
+        sender.on_ack(&SelectiveAck::cumulative(3).with_window(0));
+        assert!(sender.poll_send().is_empty());
+        sender.timeout();
+        assert_eq!(orders(&sender.poll_send()), [3]);
+        assert!(sender.poll_send().is_empty());
+        sender.on_ack(&SelectiveAck::cumulative(3).with_window(4));
+        sender.timeout();
+        assert_eq!(orders(&sender.poll_send()), [3, 4, 5, 6]);
+    }
+}
+
+#[test]
+fn test_selective_repeat_resends_gaps() {
+    let mut sender = SelectiveRepeatSender::new(&[0; 1000], 6);
+    let mut receiver = SelectiveRepeatReceiver::new();
+    let orders = |packets: &[Packet]| packets.iter().map(|p| p.order).collect::<Vec<_>>();
+    let sent = sender.poll_send();
+    assert_eq!(orders(&sent), [0, 1, 2, 3, 4, 5]);
+    let mut ack = SelectiveAck::default();
+    for packet in sent {
+        // 1 and 4 are lost
+        if packet.order != 1 && packet.order != 4 {
+            ack = receiver.receive(packet);
+        }
+    }
+    assert_eq!(ack.next_expected, 1);
+    sender.on_ack(&ack);
+    assert_eq!(sender.in_flight(), 2);
+    // the window moved past 0 only
+    assert_eq!(orders(&sender.poll_send()), [6]);
+    sender.timeout();
+    assert_eq!(orders(&sender.poll_send()), [1, 4, 6]);
+
+    // go-back-N resends from the first gap on
+    let mut go_back_n = GoBackNSender::new(&[0; 1000], 6);
+    go_back_n.poll_send();
+    ArqSender::on_ack(&mut go_back_n, &ack);
+    go_back_n.timeout();
+    assert_eq!(orders(&go_back_n.poll_send()), [1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn test_selective_repeat_lossy() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let data = (0..3000).map(|i| i as u8).collect::<Vec<u8>>();
+    let mut rng = StdRng::seed_from_u64(7);
+    let senders: [Box<dyn ArqSender>; 2] = [
+        Box::new(SelectiveRepeatSender::new(&data, 8)),
+        Box::new(GoBackNSender::new(&data, 8)),
+    ];
+    let sent = senders.map(|mut sender| {
+        let mut receiver = SelectiveRepeatReceiver::new();
+        let mut sent = 0;
+        while !sender.is_done() {
+            assert!(sent < 1000);
+            for packet in sender.poll_send() {
+                sent += 1;
+                // a quarter of the packets and of the acknowledgments are lost
+                if rng.gen_bool(0.75) {
+                    let ack = receiver.receive(packet);
+                    if rng.gen_bool(0.75) {
+                        sender.on_ack(&ack);
+                    }
+                }
+            }
+            if sender.in_flight() > 0 {
+                sender.timeout();
+            }
+        }
+        assert_eq!(receiver.message().unwrap(), data);
+        sent
+    });
+    assert!(sent[0] < sent[1], "{:?}", sent);
+}
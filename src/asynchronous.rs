@@ -0,0 +1,250 @@
+//! # Async API
+//!
+//! With the `tokio` feature, sending and receiving can be awaited instead of blocking a
+//! thread. The cpal callbacks feed tokio channels: recorded samples go to the
+//! `AsyncReceiver` as they arrive, and the end of playback completes `AsyncTransmitter::send`.
+//! Like the queue of the blocking `Recorder`, the channel of recorded samples is bounded: a
+//! block that does not fit is dropped and stands as silence in its place, and only the
+//! latest `DEFAULT_CAPACITY` samples are kept.
+//!
+//! The cpal streams have to be kept alive while in use and are not `Send`, so neither is
+//! the future of `send`. Run it on the task that owns the transmitter.
+
+use std::iter::repeat_n;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::{
+    config::AcousticConfig,
+    device::input_device,
+    error::{AcousticError, Result},
+    recorder::{record_with_device, DEFAULT_CAPACITY},
+    ring_buffer::RingBuffer,
+    transmission::{Event, Receiver, SampleReader},
+    transmitter::{modulate_message, Transmitter},
+};
+
+/// Blocks of recorded samples in flight, a few seconds of cpal callbacks.
+pub const CHANNEL_BLOCKS: usize = 512;
+
+/// Recorded samples, after `gap` samples dropped because the channel was full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub gap: usize,
+    pub samples: Vec<f32>,
+}
+
+/// The sending end of the channel of recorded samples, never waiting for room.
+pub struct BlockSender {
+    tx: mpsc::Sender<Block>,
+    /// samples dropped since the last block sent
+    gap: usize,
+}
+
+impl BlockSender {
+    pub fn new(tx: mpsc::Sender<Block>) -> BlockSender {
+        BlockSender { tx, gap: 0 }
+    }
+
+    /// Send `samples`, or drop them and flag the gap on the next block if the channel is full.
+    pub fn send(&mut self, samples: Vec<f32>) {
+        let len = samples.len();
+        let block = Block {
+            gap: self.gap,
+            samples,
+        };
+        match self.tx.try_send(block) {
+            Ok(()) => self.gap = 0,
+            Err(mpsc::error::TrySendError::Full(_)) => self.gap += len,
+            // nobody listens anymore once the receiver is dropped
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Record from `device`, the default one if `None`, into a channel for `AsyncReceiver`.
+pub fn record_to_channel(
+    config: &AcousticConfig,
+    device: Option<cpal::Device>,
+) -> Result<(cpal::Stream, mpsc::Receiver<Block>)> {
+    let device = match device {
+        Some(device) => device,
+        None => input_device(None)?,
+    };
+    let (tx, rx) = mpsc::channel(CHANNEL_BLOCKS);
+    let mut sender = BlockSender::new(tx);
+    let stream = record_with_device(config, device, move |samples| sender.send(samples))?;
+    Ok((stream, rx))
+}
+
+/// Samples received so far. Asking for more than that times out right away, the
+/// `AsyncReceiver` then awaits more and starts over.
+struct ChannelReader(Arc<Mutex<RingBuffer>>);
+
+impl SampleReader for ChannelReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        let samples = self.0.lock().map_err(|_| AcousticError::PoisonedBuffer)?;
+        if samples.end() < end {
+            return Err(AcousticError::Timeout);
+        }
+        samples.read(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.0
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)?
+            .consume(until);
+        Ok(())
+    }
+}
+
+pub struct AsyncReceiver {
+    receiver: Receiver,
+    buffer: Arc<Mutex<RingBuffer>>,
+    samples: mpsc::Receiver<Block>,
+    /// new samples to wait for before starting over, one symbol
+    batch: usize,
+}
+
+impl AsyncReceiver {
+    /// Decode the samples coming out of `samples`, see `record_to_channel`.
+    pub fn new(config: AcousticConfig, samples: mpsc::Receiver<Block>) -> Self {
+        let buffer = Arc::new(Mutex::new(RingBuffer::new(DEFAULT_CAPACITY)));
+        AsyncReceiver {
+            batch: config.sample_number(),
+            receiver: Receiver::with_config(Box::new(ChannelReader(buffer.clone())), config),
+            buffer,
+            samples,
+        }
+    }
+
+    /// the underlying receiver, to set its address and such
+    pub fn receiver(&mut self) -> &mut Receiver {
+        &mut self.receiver
+    }
+
+    /// Wait for the next well formed packet of any kind, `EndOfStream` once recording stopped.
+    pub async fn recv_frame(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.receiver.poll()? {
+                return Ok(event);
+            }
+            self.wait_for_samples().await?;
+        }
+    }
+
+    /// Starting over is not free, wait for a batch of new samples or the end of recording.
+    async fn wait_for_samples(&mut self) -> Result<()> {
+        let mut received = 0;
+        while received < self.batch {
+            let Some(Block { gap, samples }) = self.samples.recv().await else {
+                return match received {
+                    0 => Err(AcousticError::EndOfStream),
+                    _ => Ok(()),
+                };
+            };
+            if gap > 0 {
+                warn!("recording overran, {} samples lost", gap);
+            }
+            received += gap + samples.len();
+            self.buffer
+                .lock()
+                .map_err(|_| AcousticError::PoisonedBuffer)?
+                .extend(repeat_n(0.0, gap).chain(samples));
+        }
+        Ok(())
+    }
+}
+
+pub struct AsyncTransmitter {
+    transmitter: Transmitter,
+    config: AcousticConfig,
+}
+
+impl AsyncTransmitter {
+    pub fn new(transmitter: Transmitter, config: AcousticConfig) -> AsyncTransmitter {
+        AsyncTransmitter {
+            transmitter,
+            config,
+        }
+    }
+
+    /// Modulate `data` and play it, completing once playback finished.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let signal = modulate_message(&self.config, data)?;
+        let _stream = self.transmitter.start(&signal, move || {
+            let _ = tx.send(());
+        })?;
+        rx.await.map_err(|_| AcousticError::PlaybackInterrupted)
+    }
+}
+
+#[test]
+fn test_async_receiver() {
+    let config = AcousticConfig::default();
+    let signal = [
+        vec![0.0; 10000],
+        modulate_message(&config, b"hello world").unwrap(),
+        vec![0.0; 10000],
+    ]
+    .concat();
+    let (tx, rx) = mpsc::channel(CHANNEL_BLOCKS);
+    let mut receiver = AsyncReceiver::new(config, rx);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let feeder = tokio::spawn(async move {
+            // about what a cpal callback hands over at once
+            for block in signal.chunks(441) {
+                let samples = block.iter().map(|x| *x as f32).collect();
+                tx.send(Block { gap: 0, samples }).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        match receiver.recv_frame().await.unwrap() {
+            Event::Data(packet) => assert_eq!(packet.data, b"hello world"),
+            event => panic!("unexpected {:?}", event),
+        }
+        feeder.await.unwrap();
+        assert!(matches!(
+            receiver.recv_frame().await,
+            Err(AcousticError::EndOfStream)
+        ));
+    });
+}
+
+#[test]
+fn test_full_channel_gap() {
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut sender = BlockSender::new(tx);
+    sender.send(vec![1.0; 3]);
+    // no room, flagged on the next block through
+    sender.send(vec![2.0; 5]);
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        Block {
+            gap: 0,
+            samples: vec![1.0; 3]
+        }
+    );
+    sender.send(vec![3.0; 2]);
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        Block {
+            gap: 5,
+            samples: vec![3.0; 2]
+        }
+    );
+}
@@ -0,0 +1,112 @@
+//! # Backoff
+//!
+//! Devices that found the channel busy, or whose packets collided, would only collide again
+//! if each waited the same time. `Backoff` waits a random number of slots instead, from a
+//! range twice as long after every failure in a row, plus a random part of a slot on top,
+//! so that devices drawing the same number of slots still start apart. A fixed wait, or
+//! none at all, is there for links with a single sender, see `BackoffStrategy`.
+
+use std::time::Duration;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// the range of slots stops doubling after this many failures
+const MAX_EXPONENT: u32 = 6;
+
+/// how long a `Backoff` waits after a failure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackoffStrategy {
+    /// not at all
+    None,
+    /// the same every time
+    Fixed(Duration),
+    /// random slots of this length, from a range twice as long after every failure
+    Exponential(Duration),
+}
+
+#[derive(Clone)]
+pub struct Backoff {
+    strategy: BackoffStrategy,
+    /// failures in a row so far
+    failures: u32,
+    rng: StdRng,
+}
+
+impl Backoff {
+    /// exponential backoff with slots of `slot`
+    pub fn new(slot: Duration) -> Backoff {
+        Self::with_strategy(BackoffStrategy::Exponential(slot))
+    }
+
+    pub fn with_strategy(strategy: BackoffStrategy) -> Backoff {
+        Backoff {
+            strategy,
+            failures: 0,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// exponential backoff with slots of `slot` from now on
+    pub fn with_slot(mut self, slot: Duration) -> Backoff {
+        self.strategy = BackoffStrategy::Exponential(slot);
+        self
+    }
+
+    pub fn strategy(&self) -> BackoffStrategy {
+        self.strategy
+    }
+
+    /// draw the delays from `seed`, for tests
+    pub fn with_seed(mut self, seed: u64) -> Backoff {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// How long to wait after one more failure.
+    pub fn next_delay(&mut self) -> Duration {
+        let slot = match self.strategy {
+            BackoffStrategy::None => return Duration::ZERO,
+            BackoffStrategy::Fixed(delay) => return delay,
+            BackoffStrategy::Exponential(slot) => slot,
+        };
+        let slots = self
+            .rng
+            .gen_range(0..1_u32 << self.failures.min(MAX_EXPONENT));
+        let jitter = self.rng.gen::<f64>();
+        self.failures += 1;
+        slot.mul_f64(slots as f64 + jitter)
+    }
+
+    /// start over with the shortest range, after a success
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let slot = Duration::from_millis(100);
+    let mut backoff = Backoff::new(slot).with_seed(1);
+    let delays = (0..20)
+        .map(|_| backoff.next_delay())
+        .collect::<Vec<Duration>>();
+    assert!(delays[0] < slot);
+    for (failures, delay) in delays.iter().enumerate() {
+        assert!(*delay < slot * (1 << failures.min(MAX_EXPONENT as usize)) as u32);
+    }
+    // the range keeps growing, most of the later delays are long
+    assert!(
+        delays[10..]
+            .iter()
+            .filter(|delay| **delay > slot * 8)
+            .count()
+            > 5
+    );
+    backoff.reset();
+    assert!(backoff.next_delay() < slot);
+
+    let mut fixed = Backoff::with_strategy(BackoffStrategy::Fixed(slot));
+    assert_eq!([fixed.next_delay(), fixed.next_delay()], [slot, slot]);
+    let mut none = Backoff::with_strategy(BackoffStrategy::None);
+    assert_eq!(none.next_delay(), Duration::ZERO);
+}
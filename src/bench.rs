@@ -0,0 +1,179 @@
+//! # Benchmark
+//!
+//! Which profile is the fastest in a room is only found out by trying. `bench` sends the
+//! same payload a few times, each in rounds: the first round sends every packet, the ones
+//! after that only the packets that did not come through yet, much like selective repeat
+//! but without acknowledgments, the bench sees what was received anyway. How the signal
+//! gets to the receiver is up to the caller, `simulated` passes it through the
+//! `simulator`, the command line plays and records it as well.
+//!
+//! Airtime is the time the packets themselves take to send, retransmissions included but
+//! not the silence between rounds, so goodput is what the modulation manages, not what a
+//! protocol around it would.
+
+use std::time::Duration;
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    ber::Impairments,
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    transmission::{Event, MemoryReader, Receiver, SampleReader},
+    transmitter::modulate_packets,
+    Packet,
+};
+
+/// silence before and after every round, in symbols
+const PADDING_SYMBOLS: usize = 4;
+
+/// Bytes to send, the same every time for a given length but no pattern the scrambler or
+/// the error correction would treat better than real data.
+pub fn bench_payload(len: usize) -> Vec<u8> {
+    let mut payload = vec![0; len];
+    StdRng::seed_from_u64(0).fill_bytes(&mut payload);
+    payload
+}
+
+/// How the payloads came through, see `bench`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BenchResult {
+    /// payloads sent
+    pub payloads: usize,
+    /// of those, the ones received whole
+    pub delivered: usize,
+    /// payload bytes received whole
+    pub bytes: usize,
+    /// packets sent in first rounds
+    pub packets: usize,
+    /// packets sent again
+    pub retransmissions: usize,
+    /// spent sending packets, retransmissions included
+    pub airtime: Duration,
+}
+
+impl BenchResult {
+    /// payload bits delivered per second of airtime
+    pub fn goodput(&self) -> f64 {
+        match self.airtime.is_zero() {
+            true => 0.0,
+            false => 8.0 * self.bytes as f64 / self.airtime.as_secs_f64(),
+        }
+    }
+
+    /// airtime per payload byte delivered, `None` if nothing was
+    pub fn airtime_per_byte(&self) -> Option<Duration> {
+        (self.bytes > 0).then(|| self.airtime / self.bytes as u32)
+    }
+}
+
+/// A channel through `impairments`, with noise of its own for every round.
+pub fn simulated(
+    config: &AcousticConfig,
+    impairments: Impairments,
+) -> impl FnMut(&[f64]) -> Result<Box<dyn SampleReader>> {
+    let sample_rate = config.sample_rate;
+    let mut rounds = 0;
+    move |signal| {
+        let impairments = Impairments {
+            seed: impairments.seed.wrapping_add(rounds),
+            ..impairments.clone()
+        };
+        rounds += 1;
+        let recording = impairments.apply(signal, sample_rate);
+        Ok(Box::new(MemoryReader(recording)) as Box<dyn SampleReader>)
+    }
+}
+
+/// Send `payload` `repeat` times with `config`, each in at most `rounds` rounds, through
+/// `channel`, which turns a signal into what was recorded of it. The recording ends with
+/// `AcousticError::EndOfStream` or `AcousticError::Timeout`.
+pub fn bench(
+    config: &AcousticConfig,
+    payload: &[u8],
+    repeat: usize,
+    rounds: usize,
+    mut channel: impl FnMut(&[f64]) -> Result<Box<dyn SampleReader>>,
+) -> Result<BenchResult> {
+    let packets = Packet::new_packets_sized(payload, config.packet_size);
+    let silence = vec![0.0; PADDING_SYMBOLS * config.sample_number()];
+    let mut result = BenchResult {
+        payloads: repeat,
+        ..BenchResult::default()
+    };
+    for _ in 0..repeat {
+        let mut received = vec![false; packets.len()];
+        for round in 0..rounds {
+            let missing = packets
+                .iter()
+                .zip(&received)
+                .filter(|(_, received)| !**received)
+                .map(|(packet, _)| packet.clone())
+                .collect::<Vec<Packet>>();
+            if missing.is_empty() {
+                break;
+            }
+            match round {
+                0 => result.packets += missing.len(),
+                _ => result.retransmissions += missing.len(),
+            }
+            let signal = modulate_packets(config, &missing)?;
+            result.airtime += Duration::from_secs_f64(signal.len() as f64 / config.sample_rate);
+            let recording = channel(&[&silence[..], &signal, &silence].concat())?;
+            let mut receiver = Receiver::with_config(recording, config.clone());
+            loop {
+                match receiver.next_event() {
+                    Ok(Event::Data(packet)) => {
+                        let sent = packets.get(packet.order).map(|sent| &sent.data);
+                        if sent == Some(&packet.data) {
+                            received[packet.order] = true;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(AcousticError::EndOfStream | AcousticError::Timeout) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        if received.iter().all(|received| *received) {
+            result.delivered += 1;
+            result.bytes += payload.len();
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_bench() {
+    let config = AcousticConfig::default();
+    let payload = bench_payload(40);
+    assert_eq!(payload, bench_payload(40));
+
+    let clean = bench(
+        &config,
+        &payload,
+        2,
+        3,
+        simulated(&config, Impairments::default()),
+    )
+    .unwrap();
+    assert_eq!(clean.payloads, 2);
+    assert_eq!(clean.delivered, 2);
+    assert_eq!(clean.bytes, 80);
+    assert_eq!(clean.retransmissions, 0);
+    // two symbols a byte, plus preambles and headers
+    let per_byte = clean.airtime_per_byte().unwrap();
+    assert!(per_byte > Duration::from_secs_f64(2.0 * config.symbol_time));
+    assert!((clean.goodput() - 8.0 / per_byte.as_secs_f64()).abs() < 0.01);
+
+    // nothing gets through, every round after the first resends everything
+    let drowned = Impairments {
+        snr_db: Some(-30.0),
+        ..Impairments::default()
+    };
+    let lost = bench(&config, &payload, 1, 3, simulated(&config, drowned)).unwrap();
+    assert_eq!(lost.delivered, 0);
+    assert_eq!(lost.retransmissions, 2 * lost.packets);
+    assert_eq!(lost.goodput(), 0.0);
+    assert_eq!(lost.airtime_per_byte(), None);
+}
@@ -0,0 +1,190 @@
+//! # Bit error rate
+//!
+//! Which modulation, error correction or symbol time holds up better is a matter of
+//! numbers, not of listening. `measure_ber` modulates a payload, passes it through the
+//! `simulator` with the `Impairments` given, receives it again and compares what came out
+//! with what went in, packet by packet.
+//!
+//! A packet is lost if the receiver never took it, because its preamble or its header did
+//! not survive, and corrupted if it came out with any bit flipped. Bit errors are counted
+//! in the packets that came out only, a lost packet counts toward the packet error rate.
+
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::{
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    simulator::{awgn_with_rng, clock_drift, multipath, speaker_rolloff, Echo},
+    transmission::{Event, MemoryReader, Receiver},
+    transmitter::modulate_packets,
+    Packet,
+};
+
+/// silence before and after the packets, in symbols
+const PADDING_SYMBOLS: usize = 4;
+
+/// What happens to the signal on the way, in the order of the fields. Nothing by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Impairments {
+    /// cutoff of a small speaker in Hz, see `simulator::speaker_rolloff`
+    pub rolloff: Option<f64>,
+    /// reflections off the walls, see `simulator::multipath`
+    pub echoes: Vec<Echo>,
+    /// parts per million the recording clock runs slow, see `simulator::clock_drift`
+    pub drift_ppm: f64,
+    /// signal to noise ratio in decibels over the whole recording, gaps included
+    pub snr_db: Option<f64>,
+    /// of the noise, so that a measurement can be repeated
+    pub seed: u64,
+}
+
+impl Impairments {
+    /// The recording of `signal` through these impairments.
+    pub fn apply(&self, signal: &[f64], sample_rate: f64) -> Vec<f64> {
+        let mut signal = signal.to_vec();
+        if let Some(cutoff) = self.rolloff {
+            signal = speaker_rolloff(&signal, cutoff, sample_rate);
+        }
+        if !self.echoes.is_empty() {
+            signal = multipath(&signal, &self.echoes, sample_rate);
+        }
+        if self.drift_ppm != 0.0 {
+            signal = clock_drift(&signal, self.drift_ppm);
+        }
+        if let Some(snr_db) = self.snr_db {
+            signal = awgn_with_rng(&signal, snr_db, &mut StdRng::seed_from_u64(self.seed));
+        }
+        signal
+    }
+}
+
+/// How a payload came through, see `measure_ber`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorRates {
+    /// packets sent
+    pub packets: usize,
+    /// packets never received
+    pub lost: usize,
+    /// packets received with bits flipped
+    pub corrupted: usize,
+    /// payload bits of the packets received
+    pub bits: usize,
+    /// of those, the ones flipped, missing or in excess
+    pub bit_errors: usize,
+}
+
+impl ErrorRates {
+    /// flipped bits per bit received, 0 if nothing was
+    pub fn bit_error_rate(&self) -> f64 {
+        match self.bits {
+            0 => 0.0,
+            bits => self.bit_errors as f64 / bits as f64,
+        }
+    }
+
+    /// packets lost or corrupted per packet sent
+    pub fn packet_error_rate(&self) -> f64 {
+        match self.packets {
+            0 => 0.0,
+            packets => (self.lost + self.corrupted) as f64 / packets as f64,
+        }
+    }
+}
+
+/// Send `payload` with `config` through `impairments` and count what went wrong.
+pub fn measure_ber(
+    config: &AcousticConfig,
+    payload: &[u8],
+    impairments: &Impairments,
+) -> Result<ErrorRates> {
+    let packets = Packet::new_packets_sized(payload, config.packet_size);
+    let silence = vec![0.0; PADDING_SYMBOLS * config.sample_number()];
+    let signal = [&silence[..], &modulate_packets(config, &packets)?, &silence].concat();
+    let recording = impairments.apply(&signal, config.sample_rate);
+
+    let mut received = vec![None; packets.len()];
+    let mut receiver = Receiver::with_config(Box::new(MemoryReader(recording)), config.clone());
+    loop {
+        match receiver.next_event() {
+            Ok(Event::Data(packet)) => {
+                if let Some(slot) = received.get_mut(packet.order) {
+                    slot.get_or_insert(packet.data);
+                }
+            }
+            Ok(_) => {}
+            Err(AcousticError::EndOfStream) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut rates = ErrorRates {
+        packets: packets.len(),
+        ..ErrorRates::default()
+    };
+    for (sent, received) in packets.iter().zip(received) {
+        let Some(received) = received else {
+            rates.lost += 1;
+            continue;
+        };
+        let flipped = sent
+            .data
+            .iter()
+            .zip(&received)
+            .map(|(a, b)| (a ^ b).count_ones() as usize)
+            .sum::<usize>()
+            + 8 * sent.data.len().abs_diff(received.len());
+        rates.bits += 8 * sent.data.len();
+        rates.bit_errors += flipped;
+        if flipped > 0 {
+            rates.corrupted += 1;
+        }
+    }
+    Ok(rates)
+}
+
+#[test]
+fn test_measure_ber() {
+    use crate::config::Fec;
+
+    let payload = (0..200).collect::<Vec<u8>>();
+    let config = AcousticConfig::default();
+    let clean = measure_ber(&config, &payload, &Impairments::default()).unwrap();
+    assert_eq!(
+        clean,
+        ErrorRates {
+            packets: 2,
+            bits: 8 * 200,
+            ..ErrorRates::default()
+        }
+    );
+    assert_eq!(clean.bit_error_rate(), 0.0);
+    assert_eq!(clean.packet_error_rate(), 0.0);
+
+    // nothing gets through noise that loud
+    let drowned = Impairments {
+        snr_db: Some(-30.0),
+        seed: 1,
+        ..Impairments::default()
+    };
+    let lost = measure_ber(&config, &payload, &drowned).unwrap();
+    assert_eq!(lost.lost, 2);
+    assert_eq!(lost.packet_error_rate(), 1.0);
+
+    // a muffled speaker in an echoing room, with error correction
+    let room = Impairments {
+        rolloff: Some(6000.0),
+        echoes: vec![Echo {
+            delay: 0.002,
+            gain: 0.3,
+        }],
+        drift_ppm: 50.0,
+        ..Impairments::default()
+    };
+    let hamming = AcousticConfig::builder()
+        .fec(Fec::Hamming74)
+        .build()
+        .unwrap();
+    let rates = measure_ber(&hamming, &payload, &room).unwrap();
+    assert_eq!(rates.packets, 2);
+    assert!(rates.packet_error_rate() <= 0.5, "{:?}", rates);
+}
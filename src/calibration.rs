@@ -0,0 +1,118 @@
+//! # Frequency calibration
+//!
+//! Cheap sound cards do not quite play or record at the rate they claim, so every tone
+//! arrives shifted by the same factor, by tens of Hz on our carriers. Preamble detection
+//! does not mind, the Goertzel filters listening for the carriers do. To calibrate, one end
+//! plays a preamble and then every carrier at once, see `calibration_signal`, and the other
+//! measures where the carriers arrived, see `Receiver::calibrate`. The factor between the
+//! two is its `AcousticConfig::freq_correction` for that sender from then on.
+//!
+//! Both clocks are off by the same factor, so a calibrated receiver also knows the clock
+//! drift without estimating it from the preamble, which would not cover that much.
+
+use std::f64::consts::PI;
+
+use crate::{
+    config::AcousticConfig,
+    goertzel::GoertzelBank,
+    physics::{prepend_preamble, DETECTION_MARGIN},
+};
+
+/// symbols the reference tones last after the preamble
+pub const REFERENCE_SYMBOLS: usize = 4;
+
+/// Carriers are not looked for further off than this fraction of their frequency.
+pub const MAX_FREQ_OFFSET: f64 = 0.02;
+
+/// steps of the factor between the tried frequencies, well inside the main lobe of the
+/// reference tones at any carrier
+const SEARCH_STEP: f64 = 1e-4;
+
+/// A preamble, then every carrier at once for `REFERENCE_SYMBOLS` symbols, without the
+/// phase jumps of separate symbols.
+pub fn calibration_signal(config: &AcousticConfig) -> Vec<f64> {
+    let count = config.carrier_freqs.len() as f64;
+    let reference = (0..config.sample_number() * REFERENCE_SYMBOLS)
+        .map(|i| {
+            let t = i as f64 / config.sample_rate;
+            config
+                .carrier_freqs
+                .iter()
+                .map(|freq| (2.0 * PI * freq * t).sin())
+                .sum::<f64>()
+                / count
+        })
+        .collect::<Vec<f64>>();
+    prepend_preamble(config, &reference)
+}
+
+/// The factor by which the carriers in `reference`, the tones after the preamble of a
+/// `calibration_signal`, arrived higher than `config.carrier_freqs`. `None` if they were not
+/// heard.
+///
+/// Every factor up to `MAX_FREQ_OFFSET` is tried on all carriers at once, so that a carrier
+/// is never mistaken for its neighbour, and the loudest is refined between the steps.
+pub fn measure_freq_correction(config: &AcousticConfig, reference: &[f64]) -> Option<f64> {
+    let steps = (MAX_FREQ_OFFSET / SEARCH_STEP) as isize;
+    let factors = (-steps..=steps)
+        .map(|step| 1.0 + step as f64 * SEARCH_STEP)
+        .collect::<Vec<f64>>();
+    let freqs = factors
+        .iter()
+        .flat_map(|factor| config.carrier_freqs.iter().map(move |freq| freq * factor))
+        .collect::<Vec<f64>>();
+    let amplitudes = GoertzelBank::new(&freqs, config.sample_rate).amplitudes(reference);
+    let loudness = amplitudes
+        .chunks(config.carrier_freqs.len())
+        .map(|carriers| carriers.iter().sum())
+        .collect::<Vec<f64>>();
+    let (peak, loudest) = loudness
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+    let mut sorted = loudness.clone();
+    sorted.sort_by(f64::total_cmp);
+    if loudest <= sorted[sorted.len() / 2] * DETECTION_MARGIN {
+        return None;
+    }
+    // a parabola through the loudest step and its neighbours
+    let offset = match (peak.checked_sub(1), loudness.get(peak + 1)) {
+        (Some(before), Some(after)) => {
+            let (before, after) = (loudness[before], *after);
+            let curvature = before - 2.0 * loudest + after;
+            match curvature < 0.0 {
+                true => (before - after) / (2.0 * curvature),
+                false => 0.0,
+            }
+        }
+        _ => 0.0,
+    };
+    Some(factors[peak] + offset * SEARCH_STEP)
+}
+
+#[test]
+fn test_measure_freq_correction() {
+    use crate::simulator::{awgn_seeded, clock_drift};
+
+    let config = AcousticConfig::default();
+    let skip = 4 * config.sample_number();
+    for ppm in [-12000.0, 0.0, 7000.0] {
+        // a sender this much faster plays every tone as much higher
+        let signal = awgn_seeded(&clock_drift(&calibration_signal(&config), ppm), 10.0, 1);
+        let correction = measure_freq_correction(&config, &signal[skip..]).unwrap();
+        assert!(
+            (correction - 1.0 - ppm * 1e-6).abs() < 2e-4,
+            "{} ppm as {}",
+            ppm,
+            correction
+        );
+    }
+
+    let ultrasonic = AcousticConfig::ultrasonic();
+    let signal = clock_drift(&calibration_signal(&ultrasonic), 3000.0);
+    let correction = measure_freq_correction(&ultrasonic, &signal[skip..]).unwrap();
+    assert!((correction - 1.003).abs() < 1e-4, "{}", correction);
+
+    assert_eq!(measure_freq_correction(&config, &vec![0.0; skip]), None);
+}
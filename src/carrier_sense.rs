@@ -0,0 +1,180 @@
+//! # Carrier sense
+//!
+//! Several devices in one room share the air, and two packets on the same tones at once
+//! are both lost. Before a frame goes out, `CarrierSense` looks at what the microphone heard
+//! last and holds the frame back while someone else is sending, like Ethernet and Wi-Fi do.
+//!
+//! Busy means more energy in the band of our tones than the noise outside it explains, see
+//! `channel_busy`. Whatever the modulation, a transmission fills its band, while noise is
+//! spread over the whole spectrum. A busy channel is tried again after a random wait, see
+//! `backoff`, so that devices which waited together do not all start together.
+
+use std::{sync::Arc, thread::sleep, time::Duration};
+
+use tracing::info;
+
+use crate::{
+    backoff::Backoff,
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    filter::band_pass_taps,
+    recorder::SharedBuffer,
+};
+
+/// symbols of the recording that tell whether the channel is busy, also how long a slot is
+pub const SENSE_SYMBOLS: usize = 4;
+
+/// tries before giving up with `AcousticError::ChannelBusy`
+pub const MAX_ATTEMPTS: usize = 10;
+
+/// Whether someone is sending in the band of `config`. The power per Hz in the band has to
+/// be `detection_margin` times that outside of it.
+pub fn channel_busy(config: &AcousticConfig, samples: &[f64]) -> bool {
+    let (low, high) = config
+        .pass_band()
+        .unwrap_or_else(|| config.auto_pass_band());
+    let taps = band_pass_taps(low, high, config.sample_rate);
+    // only where the filter sees whole samples
+    let Some(len) = samples.len().checked_sub(taps.len() - 1) else {
+        return false;
+    };
+    let in_band = (0..len)
+        .map(|i| {
+            let y = taps
+                .iter()
+                .zip(&samples[i..])
+                .map(|(tap, x)| tap * x)
+                .sum::<f64>();
+            y * y
+        })
+        .sum::<f64>();
+    let total = samples[taps.len() / 2..][..len]
+        .iter()
+        .map(|x| x * x)
+        .sum::<f64>();
+    let out_of_band = (total - in_band).max(0.0);
+    let width = high - low;
+    let rest = config.sample_rate / 2.0 - width;
+    in_band > 0.0 && in_band / width > config.detection_margin * out_of_band / rest
+}
+
+/// Listens to a recording for other devices before we send.
+pub struct CarrierSense {
+    recorded: Arc<SharedBuffer>,
+    config: AcousticConfig,
+    backoff: Backoff,
+    max_attempts: usize,
+}
+
+impl CarrierSense {
+    /// Sense the band of `config` in what keeps being recorded into `recorded`.
+    pub fn new(recorded: Arc<SharedBuffer>, config: &AcousticConfig) -> CarrierSense {
+        CarrierSense {
+            recorded,
+            config: config.clone(),
+            backoff: Backoff::new(Duration::from_secs_f64(
+                SENSE_SYMBOLS as f64 * config.symbol_time,
+            )),
+            max_attempts: MAX_ATTEMPTS,
+        }
+    }
+
+    /// how long one backoff slot is, the time of `SENSE_SYMBOLS` symbols by default
+    pub fn with_slot(mut self, slot: Duration) -> CarrierSense {
+        self.backoff = self.backoff.with_slot(slot);
+        self
+    }
+
+    /// tries before giving up, `MAX_ATTEMPTS` by default
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> CarrierSense {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// draw the backoff from `seed`, for tests
+    pub fn with_seed(mut self, seed: u64) -> CarrierSense {
+        self.backoff = self.backoff.with_seed(seed);
+        self
+    }
+
+    /// whether someone is sending in the last `SENSE_SYMBOLS` symbols recorded
+    pub fn is_busy(&self) -> Result<bool> {
+        let samples = self
+            .recorded
+            .latest(SENSE_SYMBOLS * self.config.sample_number())?;
+        Ok(channel_busy(&self.config, &samples))
+    }
+
+    /// Block until the channel is free, backing off each time it is not.
+    pub fn wait_idle(&mut self) -> Result<()> {
+        self.backoff.reset();
+        for _ in 0..self.max_attempts {
+            if !self.is_busy()? {
+                return Ok(());
+            }
+            let delay = self.backoff.next_delay();
+            info!("channel busy, backing off for {:?}", delay);
+            sleep(delay);
+        }
+        Err(AcousticError::ChannelBusy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        recorder::Recorder,
+        simulator::awgn_seeded,
+        transmitter::{modulate_message, modulate_packets},
+        Packet,
+    };
+
+    #[test]
+    fn test_channel_busy() {
+        let config = AcousticConfig::default();
+        let window = SENSE_SYMBOLS * config.sample_number();
+        let signal = modulate_message(&config, b"hello world").unwrap();
+        // noise alone, however loud, is not a transmission
+        let noise = awgn_seeded(&vec![0.0; window], 0.0, 1);
+        assert!(!channel_busy(&config, &noise));
+        assert!(!channel_busy(&config, &vec![0.0; window]));
+
+        // the preamble, the data, and the last symbol of a packet, heard through noise
+        let heard = awgn_seeded(&signal, 0.0, 2);
+        for start in [0, signal.len() / 2, signal.len() - window] {
+            assert!(
+                channel_busy(&config, &heard[start..start + window]),
+                "{}",
+                start
+            );
+        }
+
+        // another device sending on the other band of a transceiver
+        let other = modulate_packets(&config.right_channel().unwrap(), &[Packet::ack(1)]).unwrap();
+        assert!(!channel_busy(&config, &other[..window]));
+    }
+
+    #[test]
+    fn test_wait_idle() {
+        let config = AcousticConfig::default();
+        let mut recorder = Recorder::new();
+        let recorded = recorder.clone_handle();
+        let mut sense = CarrierSense::new(recorded.clone(), &config)
+            .with_slot(Duration::from_millis(1))
+            .with_max_attempts(3)
+            .with_seed(1);
+        assert!(sense.wait_idle().is_ok());
+
+        let signal = modulate_message(&config, b"hello world").unwrap();
+        recorded.push(signal.iter().map(|x| *x as f32)).unwrap();
+        assert!(sense.is_busy().unwrap());
+        assert!(matches!(sense.wait_idle(), Err(AcousticError::ChannelBusy)));
+
+        // the other device is done
+        recorded
+            .push(vec![0.0; SENSE_SYMBOLS * config.sample_number()])
+            .unwrap();
+        assert!(sense.wait_idle().is_ok());
+    }
+}
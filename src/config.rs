@@ -0,0 +1,647 @@
+//! # Configuration
+//!
+//! Sender and receiver have to agree on the sample rate, the symbol duration and the
+//! frequencies in use. `AcousticConfig` carries these through modulation, demodulation and
+//! the `Receiver`; the defaults match the constants in `physics` and `transmission`.
+
+use std::ops::Range;
+
+use crate::{
+    crypto::{Key, MAC_SIZE, NONCE_SIZE},
+    error::{AcousticError, Result},
+    filter::BAND_MARGIN,
+    physics::{
+        fft_freqs, ofdm_freqs, ALIAS_GUARD, CARRIER_FREQS, CSS_SHIFTS, DETECTION_MARGIN, FFT_SIZE,
+        LOW_PASS_BAND, MAX_CARRIERS, PN_CHIPS, PREAMBLE_FREQS, ULTRASONIC_CARRIER_FREQS,
+        ULTRASONIC_PREAMBLE_FREQS,
+    },
+    transmission::{SAMPLE_RATE, SIGNAL_TIME},
+    Packet,
+};
+
+/// how bytes are put onto the carriers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Modulation {
+    /// one carrier per bit, as many bits per symbol as there are carriers
+    #[default]
+    Fsk,
+    /// `OFDM_SUBCARRIERS` bits per symbol, see `physics::ofdm_modulate`
+    Ofdm,
+    /// one bit per symbol on the lowest carrier, as a phase change, see `physics::dpsk_modulate`
+    Dpsk,
+    /// two bits per symbol on the lowest carrier, see `physics::qpsk_modulate`
+    Qpsk,
+    /// four bits per symbol on the lowest carrier, for quiet rooms, see
+    /// `physics::qam16_modulate`
+    Qam16,
+    /// one bit per symbol spread over `physics::PN_CHIPS` chips, see `physics::dsss_modulate`
+    Dsss,
+    /// a byte per chirp, for links far below the noise, see `physics::css_modulate`
+    Css,
+}
+
+impl Modulation {
+    pub const ALL: [Modulation; 7] = [
+        Modulation::Fsk,
+        Modulation::Ofdm,
+        Modulation::Dpsk,
+        Modulation::Qpsk,
+        Modulation::Qam16,
+        Modulation::Dsss,
+        Modulation::Css,
+    ];
+}
+
+/// the band-pass every recording goes through before preamble detection, see `filter`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum BandPass {
+    Off,
+    /// just wide enough for the tones in use
+    #[default]
+    Auto,
+    /// from and to, in Hz
+    Hz(f64, f64),
+}
+
+/// forward error correction applied to every sealed packet, see `fec`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fec {
+    #[default]
+    None,
+    /// every half byte becomes a 7 bit codeword, correcting one flipped bit in it
+    Hamming74,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcousticConfig {
+    /// samples per second, for both playing and recording
+    pub sample_rate: f64,
+    /// duration of one symbol, in seconds
+    pub symbol_time: f64,
+    /// fraction of every tone spent fading in and out with a raised cosine, 0 for square
+    /// pulses and at most 0.5
+    pub pulse_rolloff: f64,
+    /// seconds every FSK symbol ramps up and down in, so that changing carriers does not click
+    pub ramp_time: f64,
+    /// carrier of each bit of an FSK symbol, ascending, at most `MAX_CARRIERS`
+    pub carrier_freqs: Vec<f64>,
+    /// the two alternating tones of the preamble
+    pub preamble_freqs: [f64; 2],
+    /// how the data after the preamble is modulated
+    pub modulation: Modulation,
+    /// error correction of the modulated bytes
+    pub fec: Fec,
+    /// rows of the block interleaver after error correction, 1 turns it off
+    pub interleave_depth: usize,
+    /// filter applied to received samples, to keep speech and fans out of detection
+    pub band_pass: BandPass,
+    /// how many times louder than the noise floor a carrier has to be to count as heard
+    pub detection_margin: f64,
+    /// whiten the sealed bytes before error correction, see `scrambler`
+    pub scramble: bool,
+    /// send a pilot symbol after every FSK preamble, to even out how loud the carriers arrive
+    pub pilot: bool,
+    /// move the window of every FSK symbol towards where the symbol was heard, see
+    /// `timing_error`
+    pub timing_recovery: bool,
+    /// demodulate the symbols of a frame on every core, for recordings decoded offline,
+    /// see `physics::map_symbols`
+    pub parallel: bool,
+    /// how much higher than sent the carriers arrive, as a factor, see `calibration`
+    pub freq_correction: f64,
+    /// pre-shared key to encrypt payloads with, see `crypto`
+    pub key: Option<Key>,
+    /// pre-shared key to sign packets with, see `crypto`
+    pub mac_key: Option<Key>,
+    /// seconds of silence before every FSK symbol, for the echoes of the one before to die
+    /// down in, both ends have to agree on it
+    pub symbol_gap: f64,
+    /// seconds of silence between packets, see `symbol_gap`
+    pub packet_gap: f64,
+    /// scale of the signal played and written, from 0 to full scale at 1, see
+    /// `transmitter::output_level`
+    pub amplitude: f64,
+    /// payload bytes of every packet of a message but the last, from 1 to
+    /// `Packet::LARGEST_PACKET_SIZE`, both ends have to agree on it
+    pub packet_size: usize,
+}
+
+impl Default for AcousticConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+            symbol_time: SIGNAL_TIME,
+            pulse_rolloff: 0.0,
+            ramp_time: 0.0,
+            carrier_freqs: CARRIER_FREQS.to_vec(),
+            preamble_freqs: PREAMBLE_FREQS,
+            modulation: Modulation::default(),
+            fec: Fec::default(),
+            interleave_depth: 1,
+            band_pass: BandPass::default(),
+            detection_margin: DETECTION_MARGIN,
+            scramble: false,
+            pilot: false,
+            timing_recovery: true,
+            parallel: false,
+            freq_correction: 1.0,
+            key: None,
+            mac_key: None,
+            symbol_gap: 0.0,
+            packet_gap: 0.0,
+            amplitude: 1.0,
+            packet_size: Packet::MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl AcousticConfig {
+    pub fn builder() -> AcousticConfigBuilder {
+        AcousticConfigBuilder::default()
+    }
+
+    /// 18 to 20 kHz carriers, inaudible to most adults
+    pub fn ultrasonic() -> Self {
+        Self::builder()
+            .carrier_freqs(&ULTRASONIC_CARRIER_FREQS)
+            .preamble_freqs(ULTRASONIC_PREAMBLE_FREQS)
+            .build()
+            .expect("the ultrasonic preset is valid")
+    }
+
+    /// `AcousticError::InvalidConfig` unless symbols last at least a sample and there are 1
+    /// to `MAX_CARRIERS` carriers, each on a bin of its own.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(AcousticError::InvalidConfig(reason));
+        if !self.symbol_time.is_finite() || self.sample_number() == 0 {
+            return invalid(format!("symbol time of {} s", self.symbol_time));
+        }
+        let carriers = self.carrier_freqs.len();
+        if !(1..=MAX_CARRIERS).contains(&carriers) {
+            return invalid(format!("{carriers} carriers, not 1 to {MAX_CARRIERS}"));
+        }
+        if self.carrier_freqs.windows(2).any(|pair| pair[0] == pair[1]) {
+            return invalid("two carriers on one bin".to_string());
+        }
+        Ok(())
+    }
+
+    /// highest frequency we are willing to send
+    pub fn guard_freq(&self) -> f64 {
+        self.sample_rate / 2.0 * ALIAS_GUARD
+    }
+
+    /// `guard_freq` if a tone comes within `LOW_PASS_BAND` of the Nyquist frequency, like
+    /// those of `ultrasonic`, for the signal to be low-passed at. Lower tones need no filter.
+    pub fn low_pass_cutoff(&self) -> Option<f64> {
+        let highest = [&self.data_freqs()[..], &self.preamble_freqs]
+            .concat()
+            .into_iter()
+            .fold(0.0, f64::max);
+        (highest > self.sample_rate / 2.0 - LOW_PASS_BAND).then_some(self.guard_freq())
+    }
+
+    /// bytes in front of every payload: the packet header, and the nonce when encrypting
+    pub fn header_size(&self) -> usize {
+        match self.key {
+            Some(_) => Packet::HEADER_SIZE + NONCE_SIZE,
+            None => Packet::HEADER_SIZE,
+        }
+    }
+
+    /// bytes after every payload, the tag when signing
+    pub fn trailer_size(&self) -> usize {
+        match self.mac_key {
+            Some(_) => MAC_SIZE,
+            None => 0,
+        }
+    }
+
+    /// number of samples in one symbol
+    pub fn sample_number(&self) -> usize {
+        (self.sample_rate * self.symbol_time) as usize
+    }
+
+    /// Band the received samples are filtered to, if at all, see `auto_pass_band`.
+    pub fn pass_band(&self) -> Option<(f64, f64)> {
+        match self.band_pass {
+            BandPass::Off => None,
+            BandPass::Hz(low, high) => Some((low, high)),
+            BandPass::Auto => Some(self.auto_pass_band()),
+        }
+    }
+
+    /// The band our tones are in, reaching `BAND_MARGIN` past the lowest and highest one.
+    pub fn auto_pass_band(&self) -> (f64, f64) {
+        let freqs = [&self.data_freqs()[..], &self.preamble_freqs].concat();
+        let low = freqs.iter().copied().fold(f64::INFINITY, f64::min);
+        let high = freqs.iter().copied().fold(0.0, f64::max);
+        (
+            (low - BAND_MARGIN).max(0.0),
+            (high + BAND_MARGIN).min(self.guard_freq()),
+        )
+    }
+
+    /// The tones data goes out on, or the edges of the band it is spread over with DSSS and
+    /// CSS.
+    pub fn data_freqs(&self) -> Vec<f64> {
+        match self.modulation {
+            Modulation::Fsk => self.carrier_freqs.clone(),
+            Modulation::Ofdm => ofdm_freqs(self.sample_rate),
+            Modulation::Dpsk | Modulation::Qpsk | Modulation::Qam16 => {
+                self.carrier_freqs[..1].to_vec()
+            }
+            // the main lobe of the chips
+            Modulation::Dsss => {
+                let chip_rate = PN_CHIPS as f64 / self.symbol_time;
+                let carrier = self.carrier_freqs[0];
+                vec![carrier - chip_rate, carrier + chip_rate]
+            }
+            Modulation::Css => {
+                let bandwidth = CSS_SHIFTS as f64 / self.symbol_time;
+                let low = self.carrier_freqs[0].min(self.guard_freq() - bandwidth);
+                vec![low, low + bandwidth]
+            }
+        }
+    }
+
+    /// `carrier_freqs` where they arrive, see `freq_correction`
+    pub fn received_carrier_freqs(&self) -> Vec<f64> {
+        self.carrier_freqs
+            .iter()
+            .map(|freq| freq * self.freq_correction)
+            .collect()
+    }
+
+    /// The config of a second stream next to this one, for the right speaker, see `stereo`.
+    /// Every tone moves up by the width of the automatic pass band, or down if that does not
+    /// fit under the guard frequency. OFDM subcarriers cannot move.
+    pub fn right_channel(&self) -> Result<AcousticConfig> {
+        if self.modulation == Modulation::Ofdm {
+            return Err(AcousticError::NoRoomForChannel);
+        }
+        let (low, high) = self.auto_pass_band();
+        // whole bins, so that the tones stay on them
+        let spacing = self.sample_rate / FFT_SIZE as f64;
+        let width = ((high - low) / spacing).ceil() * spacing + spacing;
+        [width, -width]
+            .into_iter()
+            .map(|shift| AcousticConfig {
+                carrier_freqs: self.carrier_freqs.iter().map(|f| f + shift).collect(),
+                preamble_freqs: self.preamble_freqs.map(|f| f + shift),
+                band_pass: match self.band_pass {
+                    BandPass::Hz(low, high) => BandPass::Hz(low + shift, high + shift),
+                    band_pass => band_pass,
+                },
+                ..self.clone()
+            })
+            .find(|right| {
+                let (right_low, right_high) = right.auto_pass_band();
+                let tones = [&right.carrier_freqs[..], &right.preamble_freqs].concat();
+                tones
+                    .iter()
+                    .all(|f| (BAND_MARGIN..=self.guard_freq()).contains(f))
+                    && (right_low >= high || right_high <= low)
+            })
+            .ok_or(AcousticError::NoRoomForChannel)
+    }
+
+    /// samples of silence before every FSK symbol, see `symbol_gap`
+    pub fn symbol_gap_samples(&self) -> usize {
+        (self.sample_rate * self.symbol_gap) as usize
+    }
+
+    /// samples of silence between packets, see `packet_gap`
+    pub fn packet_gap_samples(&self) -> usize {
+        (self.sample_rate * self.packet_gap) as usize
+    }
+
+    /// samples at either end of a symbol spent ramping, see `ramp_time`
+    pub fn ramp_samples(&self) -> usize {
+        ((self.sample_rate * self.ramp_time) as usize).min(self.sample_number() / 2)
+    }
+
+    /// Samples of a symbol the receiver listens to: the middle half, without the fades and
+    /// ramps. At least a fifth of the symbol is kept however long they are.
+    pub fn symbol_window(&self) -> Range<usize> {
+        let n = self.sample_number();
+        let fade = (n as f64 * self.pulse_rolloff / 2.0) as usize;
+        let margin = fade.max(self.ramp_samples()).clamp(n / 4, n * 2 / 5);
+        margin..n - margin
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AcousticConfigBuilder {
+    config: AcousticConfig,
+    /// carriers to spread out from the lowest one at `build`, if set
+    carrier_count: Option<usize>,
+}
+
+impl AcousticConfigBuilder {
+    pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+
+    /// Shorter symbols are faster and need a quieter room. Below 10 ms the preamble detector
+    /// does not see enough of each tone.
+    pub fn symbol_time(mut self, symbol_time: f64) -> Self {
+        self.config.symbol_time = symbol_time;
+        self
+    }
+
+    pub fn pulse_rolloff(mut self, pulse_rolloff: f64) -> Self {
+        self.config.pulse_rolloff = pulse_rolloff.clamp(0.0, 0.5);
+        self
+    }
+
+    pub fn ramp_time(mut self, ramp_time: f64) -> Self {
+        self.config.ramp_time = ramp_time.max(0.0);
+        self
+    }
+
+    pub fn carrier_freqs(mut self, carrier_freqs: &[f64]) -> Self {
+        self.config.carrier_freqs = carrier_freqs.to_vec();
+        self
+    }
+
+    /// Replace the carriers with `count` of them, 1 to `MAX_CARRIERS`, starting from the
+    /// lowest one. More carriers put more bits into every symbol.
+    pub fn carrier_count(mut self, count: usize) -> Self {
+        self.carrier_count = Some(count);
+        self
+    }
+
+    pub fn preamble_freqs(mut self, preamble_freqs: [f64; 2]) -> Self {
+        self.config.preamble_freqs = preamble_freqs;
+        self
+    }
+
+    pub fn modulation(mut self, modulation: Modulation) -> Self {
+        self.config.modulation = modulation;
+        self
+    }
+
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.config.fec = fec;
+        self
+    }
+
+    pub fn interleave_depth(mut self, interleave_depth: usize) -> Self {
+        self.config.interleave_depth = interleave_depth;
+        self
+    }
+
+    pub fn band_pass(mut self, band_pass: BandPass) -> Self {
+        self.config.band_pass = band_pass;
+        self
+    }
+
+    pub fn detection_margin(mut self, detection_margin: f64) -> Self {
+        self.config.detection_margin = detection_margin;
+        self
+    }
+
+    pub fn scramble(mut self, scramble: bool) -> Self {
+        self.config.scramble = scramble;
+        self
+    }
+
+    pub fn pilot(mut self, pilot: bool) -> Self {
+        self.config.pilot = pilot;
+        self
+    }
+
+    pub fn timing_recovery(mut self, timing_recovery: bool) -> Self {
+        self.config.timing_recovery = timing_recovery;
+        self
+    }
+
+    pub fn parallel(mut self, parallel: bool) -> Self {
+        self.config.parallel = parallel;
+        self
+    }
+
+    pub fn freq_correction(mut self, freq_correction: f64) -> Self {
+        self.config.freq_correction = freq_correction;
+        self
+    }
+
+    pub fn key(mut self, key: Key) -> Self {
+        self.config.key = Some(key);
+        self
+    }
+
+    pub fn mac_key(mut self, mac_key: Key) -> Self {
+        self.config.mac_key = Some(mac_key);
+        self
+    }
+
+    /// Silence before every FSK symbol lets a reverberant room quiet down, at the cost of
+    /// speed. Other modulations ignore it.
+    pub fn symbol_gap(mut self, symbol_gap: f64) -> Self {
+        self.config.symbol_gap = symbol_gap.max(0.0);
+        self
+    }
+
+    pub fn packet_gap(mut self, packet_gap: f64) -> Self {
+        self.config.packet_gap = packet_gap.max(0.0);
+        self
+    }
+
+    /// Turn the volume down for speakers or DACs that distort at full scale.
+    pub fn amplitude(mut self, amplitude: f64) -> Self {
+        self.config.amplitude = amplitude.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Smaller packets for noisy links, where less has to be sent again, larger ones for
+    /// quiet and fast links, where headers and preambles cost more than losses. Taken into
+    /// range, see `Packet::check_packet_size`.
+    pub fn packet_size(mut self, packet_size: usize) -> Self {
+        self.config.packet_size = packet_size.clamp(1, Packet::LARGEST_PACKET_SIZE);
+        self
+    }
+
+    /// Frequencies are detected by STFT bin, so each one is moved onto the closest bin below
+    /// the guard frequency. `AcousticError::InvalidConfig` if the result does not validate,
+    /// see `AcousticConfig::validate`.
+    pub fn build(self) -> Result<AcousticConfig> {
+        let mut config = self.config;
+        let guard_freq = config.guard_freq();
+        let bins = fft_freqs(config.sample_rate)
+            .into_iter()
+            .filter(|f| *f <= guard_freq)
+            .collect::<Vec<f64>>();
+        let snap = |freq: f64| {
+            *bins
+                .iter()
+                .min_by(|a, b| (*a - freq).abs().total_cmp(&(*b - freq).abs()))
+                .unwrap()
+        };
+        config.carrier_freqs = config.carrier_freqs.iter().map(|f| snap(*f)).collect();
+        config.carrier_freqs.sort_by(f64::total_cmp);
+        config.preamble_freqs = config.preamble_freqs.map(snap);
+        if let Some(count) = self.carrier_count {
+            let lowest = config
+                .carrier_freqs
+                .first()
+                .copied()
+                .unwrap_or(CARRIER_FREQS[0]);
+            config.carrier_freqs =
+                spread_carriers(&bins, snap(lowest), &config.preamble_freqs, count);
+            if config.carrier_freqs.len() != count {
+                return Err(AcousticError::InvalidConfig(format!(
+                    "no room for {count} carriers"
+                )));
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// `count` carriers two bins apart, upwards from `lowest` and downwards once the guard
+/// frequency is in the way. The bins next to a preamble tone are left free, so that the
+/// preamble detector does not hear data as preamble.
+fn spread_carriers(bins: &[f64], lowest: f64, preamble_freqs: &[f64; 2], count: usize) -> Vec<f64> {
+    let bin = |freq: &f64| bins.iter().position(|f| f == freq).unwrap_or_default();
+    let start = bin(&lowest);
+    let preamble_bins = preamble_freqs.iter().map(bin).collect::<Vec<usize>>();
+    let free = |k: &usize| preamble_bins.iter().all(|p| k.abs_diff(*p) > 1);
+    let upwards = (start..bins.len()).step_by(2);
+    let downwards = (1..=start.saturating_sub(2)).rev().step_by(2);
+    let mut carriers = upwards
+        .chain(downwards)
+        .filter(free)
+        .take(count)
+        .map(|k| bins[k])
+        .collect::<Vec<f64>>();
+    carriers.sort_by(f64::total_cmp);
+    carriers
+}
+
+#[test]
+fn test_default_is_aligned() {
+    assert_eq!(
+        AcousticConfig::builder().build().unwrap(),
+        AcousticConfig::default()
+    );
+    assert_eq!(AcousticConfig::default().sample_number(), 4410);
+}
+
+#[test]
+fn test_snap_to_bins() {
+    let config = AcousticConfig::builder()
+        .carrier_freqs(&[4000.0, 2000.0, 2500.0, 3500.0])
+        .build()
+        .unwrap();
+    let bins = fft_freqs(config.sample_rate);
+    assert!(config.carrier_freqs.windows(2).all(|w| w[0] < w[1]));
+    assert!(config.carrier_freqs.iter().all(|f| bins.contains(f)));
+}
+
+#[test]
+fn test_ultrasonic_is_guarded() {
+    let config = AcousticConfig::ultrasonic();
+    let freqs = [config.carrier_freqs.as_slice(), &config.preamble_freqs].concat();
+    assert!(freqs
+        .iter()
+        .all(|f| (17900.0..=config.guard_freq()).contains(f)));
+
+    let config = AcousticConfig::builder()
+        .preamble_freqs([18000.0, 22000.0])
+        .build()
+        .unwrap();
+    assert!(config.preamble_freqs[1] <= config.guard_freq());
+}
+
+#[test]
+fn test_carrier_count() {
+    for config in [
+        AcousticConfig::builder().carrier_count(8).build().unwrap(),
+        AcousticConfig::builder()
+            .carrier_freqs(&ULTRASONIC_CARRIER_FREQS)
+            .preamble_freqs(ULTRASONIC_PREAMBLE_FREQS)
+            .carrier_count(8)
+            .build()
+            .unwrap(),
+    ] {
+        let bins = fft_freqs(config.sample_rate);
+        let spacing = bins[1];
+        let carriers = &config.carrier_freqs;
+        assert_eq!(carriers.len(), 8);
+        assert!(carriers.iter().all(|f| bins.contains(f)));
+        assert!(carriers.windows(2).all(|w| w[1] - w[0] > 1.5 * spacing));
+        assert!(carriers.iter().all(|f| config
+            .preamble_freqs
+            .iter()
+            .all(|p| (f - p).abs() > 1.5 * spacing)));
+        assert!(carriers.iter().all(|f| *f <= config.guard_freq()));
+    }
+    assert_eq!(
+        AcousticConfig::builder()
+            .carrier_count(6)
+            .build()
+            .unwrap()
+            .carrier_freqs[0],
+        CARRIER_FREQS[0]
+    );
+}
+
+#[test]
+fn test_invalid_carriers() {
+    let invalid = |builder: AcousticConfigBuilder| {
+        matches!(builder.build(), Err(AcousticError::InvalidConfig(_)))
+    };
+    assert!(invalid(AcousticConfig::builder().carrier_freqs(&[])));
+    assert!(invalid(AcousticConfig::builder().carrier_freqs(&[
+        1000.0, 1500.0, 2000.0, 2500.0, 3000.0, 3500.0, 4000.0, 4500.0, 5000.0
+    ])));
+    // both land on the same bin
+    assert!(invalid(
+        AcousticConfig::builder().carrier_freqs(&[2000.0, 2001.0])
+    ));
+    assert!(invalid(AcousticConfig::builder().carrier_count(0)));
+    assert!(invalid(
+        AcousticConfig::builder().carrier_count(MAX_CARRIERS + 1)
+    ));
+    assert!(AcousticConfig::builder().carrier_count(1).build().is_ok());
+}
+
+#[test]
+fn test_invalid_symbol_time() {
+    for symbol_time in [0.0, -0.1, 1e-9, f64::NAN, f64::INFINITY] {
+        assert!(matches!(
+            AcousticConfig::builder().symbol_time(symbol_time).build(),
+            Err(AcousticError::InvalidConfig(_))
+        ));
+    }
+    assert!(AcousticConfig::builder().symbol_time(0.01).build().is_ok());
+}
+
+#[test]
+fn test_right_channel() {
+    let bins = fft_freqs(SAMPLE_RATE);
+    for config in [AcousticConfig::default(), AcousticConfig::ultrasonic()] {
+        let right = config.right_channel().unwrap();
+        let (low, high) = config.pass_band().unwrap();
+        let (right_low, right_high) = right.pass_band().unwrap();
+        assert!(right_low >= high || right_high <= low, "{:?}", right);
+        let tones = [&right.carrier_freqs[..], &right.preamble_freqs].concat();
+        assert!(tones.iter().all(|f| bins.contains(f)));
+    }
+    // no room above the ultrasonic tones
+    let ultrasonic = AcousticConfig::ultrasonic();
+    assert!(ultrasonic.right_channel().unwrap().carrier_freqs[0] < ultrasonic.carrier_freqs[0]);
+
+    let ofdm = AcousticConfig::builder()
+        .modulation(Modulation::Ofdm)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        ofdm.right_channel(),
+        Err(AcousticError::NoRoomForChannel)
+    ));
+}
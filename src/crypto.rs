@@ -0,0 +1,199 @@
+//! # Encryption and authentication
+//!
+//! Sound is broadcast, anyone in the room can record it. With a pre-shared `Key` in the
+//! config, every payload is encrypted with AES-256-GCM before it is sealed. A fresh random
+//! nonce follows the header, and the header is authenticated along with the payload but
+//! stays readable, so the receiver still knows how much to demodulate.
+//!
+//! Independently of that, a packet can carry an HMAC-SHA256 tag after its payload, so that
+//! forged or corrupted packets are rejected with `AcousticError::AuthenticationFailed`
+//! rather than dropped as noise.
+
+use std::fmt;
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    error::{AcousticError, Result},
+    Packet,
+};
+
+/// bytes of nonce between the header and the payload
+pub const NONCE_SIZE: usize = 12;
+
+/// bytes the authentication tag adds to every payload
+pub const TAG_SIZE: usize = 16;
+
+/// bytes of HMAC-SHA256 kept after the payload, the first half of it
+pub const MAC_SIZE: usize = 16;
+
+/// a pre-shared key, for AES-256 or HMAC
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(key: [u8; 32]) -> Key {
+        Key(key)
+    }
+
+    /// parse 64 hex digits
+    pub fn from_hex(hex: &str) -> Option<Key> {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        Some(Key(bytes.try_into().ok()?))
+    }
+
+    /// 64 hex digits, as `from_hex` reads them
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.0.into())
+    }
+}
+
+/// never print the key itself
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Seal a packet with its payload encrypted: header, nonce, then ciphertext and tag.
+pub fn seal(key: &Key, packet: &Packet) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let header = packet.seal_header(packet.data.len() + TAG_SIZE)?;
+    let payload = Payload {
+        msg: &packet.data,
+        aad: &header,
+    };
+    let ciphertext = key
+        .cipher()
+        .encrypt(&nonce, payload)
+        .expect("a payload shorter than 64 GiB always encrypts");
+    Ok([header.as_slice(), nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Check and decrypt what `seal` made.
+pub fn unseal(key: &Key, sealed: &[u8]) -> Result<Packet> {
+    let header_size = Packet::HEADER_SIZE + NONCE_SIZE;
+    let len = Packet::payload_len(sealed)?;
+    if sealed.len() < header_size + len || len < TAG_SIZE {
+        return Err(AcousticError::MalformedPacket(format!(
+            "encrypted payload of {} bytes is cut short",
+            len
+        )));
+    }
+    let header = &sealed[..Packet::HEADER_SIZE];
+    let nonce = Nonce::from_slice(&sealed[Packet::HEADER_SIZE..header_size]);
+    let ciphertext = &sealed[header_size..header_size + len];
+    let data = key
+        .cipher()
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| AcousticError::AuthenticationFailed)?;
+    let mut packet = Packet::unseal_one(&[header, ciphertext].concat())?;
+    packet.data = data;
+    Ok(packet)
+}
+
+/// Append the HMAC tag of everything sealed so far.
+pub fn sign(key: &Key, sealed: &[u8]) -> Vec<u8> {
+    let tag = mac(key, sealed).finalize().into_bytes();
+    [sealed, &tag[..MAC_SIZE]].concat()
+}
+
+/// Check the tag `sign` appended, and return what it covers.
+pub fn verify<'a>(key: &Key, signed: &'a [u8]) -> Result<&'a [u8]> {
+    if signed.len() < MAC_SIZE {
+        return Err(AcousticError::MalformedPacket(
+            "no room for a tag".to_string(),
+        ));
+    }
+    let (sealed, tag) = signed.split_at(signed.len() - MAC_SIZE);
+    mac(key, sealed)
+        .verify_truncated_left(tag)
+        .map_err(|_| AcousticError::AuthenticationFailed)?;
+    Ok(sealed)
+}
+
+fn mac(key: &Key, data: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(&key.0).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac
+}
+
+#[test]
+fn test_sign_verify() {
+    let key = Key::new([1; 32]);
+    let sealed = Packet::seal(&[Packet::from((0, &b"hi"[..]))])
+        .unwrap()
+        .remove(0);
+    let signed = sign(&key, &sealed);
+    assert_eq!(signed.len(), sealed.len() + MAC_SIZE);
+    assert_eq!(verify(&key, &signed).unwrap(), sealed);
+
+    let mut forged = signed.clone();
+    forged[Packet::HEADER_SIZE] = b'H';
+    assert!(matches!(
+        verify(&key, &forged),
+        Err(AcousticError::AuthenticationFailed)
+    ));
+    assert!(matches!(
+        verify(&Key::new([2; 32]), &signed),
+        Err(AcousticError::AuthenticationFailed)
+    ));
+    assert!(matches!(
+        verify(&key, &signed[..4]),
+        Err(AcousticError::MalformedPacket(_))
+    ));
+}
+
+#[test]
+fn test_seal_unseal() {
+    let key = Key::from_hex(&"0123456789abcdef".repeat(4)).unwrap();
+    let packet = Packet::from((3, &b"hello world"[..])).addressed(1, 2);
+    let sealed = seal(&key, &packet).unwrap();
+    assert_eq!(
+        sealed.len(),
+        Packet::HEADER_SIZE + NONCE_SIZE + 11 + TAG_SIZE
+    );
+    assert!(!sealed.windows(11).any(|w| w == b"hello world"));
+
+    let unsealed = unseal(&key, &sealed).unwrap();
+    assert_eq!(
+        (unsealed.order, unsealed.destination, unsealed.data),
+        (3, 2, b"hello world".to_vec())
+    );
+
+    // a forged destination with a fitting checksum, a flipped payload bit, or the wrong key
+    let mut forged = sealed.clone();
+    forged[4] = 7;
+    let checksum = Packet::HEADER_SIZE - 1;
+    forged[checksum] = Packet::header_checksum(&forged[..checksum]);
+    let mut flipped = sealed.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    let wrong_key = Key::new([0; 32]);
+    for (key, sealed) in [(&key, forged), (&key, flipped), (&wrong_key, sealed)] {
+        assert!(matches!(
+            unseal(key, &sealed),
+            Err(AcousticError::AuthenticationFailed)
+        ));
+    }
+    assert!(Key::from_hex("0123").is_none());
+    assert_eq!(key.to_hex(), "0123456789abcdef".repeat(4));
+}
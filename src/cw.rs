@@ -0,0 +1,282 @@
+//! # CW
+//!
+//! Morse code keyed onto a single tone. At a few words a minute it is slow, but it is heard
+//! through noise nothing else survives, by ear as well, which makes it the way to send a
+//! beacon or an ID that anyone can read.
+//!
+//! Timing follows the usual convention: a dash is three dots, the elements of a letter are a
+//! dot apart, letters three dots and words seven. At `wpm` words a minute a dot lasts
+//! `1.2 / wpm` seconds, the length of `PARIS ` being fifty dots. Every element fades in and
+//! out over `RAMP_TIME`, hard keying clicks all over the band.
+//!
+//! `Cw::demodulate` follows the envelope of the tone in short blocks and keys it on where it
+//! is louder than halfway between the floor and the peak. The dot length starts out at `wpm`
+//! and then follows what is heard, so a sender a little faster or slower is read as well.
+
+use std::f64::consts::PI;
+
+use crate::error::{AcousticError, Result};
+
+/// A tone and a speed to key it at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cw {
+    /// words a minute, of fifty dots each
+    pub wpm: f64,
+    /// the tone, in Hz
+    pub freq: f64,
+}
+
+impl Default for Cw {
+    fn default() -> Self {
+        Cw {
+            wpm: 20.0,
+            freq: 700.0,
+        }
+    }
+}
+
+/// Letters, digits and the common punctuation.
+const MORSE: [(char, &str); 54] = [
+    ('A', ".-"),
+    ('B', "-..."),
+    ('C', "-.-."),
+    ('D', "-.."),
+    ('E', "."),
+    ('F', "..-."),
+    ('G', "--."),
+    ('H', "...."),
+    ('I', ".."),
+    ('J', ".---"),
+    ('K', "-.-"),
+    ('L', ".-.."),
+    ('M', "--"),
+    ('N', "-."),
+    ('O', "---"),
+    ('P', ".--."),
+    ('Q', "--.-"),
+    ('R', ".-."),
+    ('S', "..."),
+    ('T', "-"),
+    ('U', "..-"),
+    ('V', "...-"),
+    ('W', ".--"),
+    ('X', "-..-"),
+    ('Y', "-.--"),
+    ('Z', "--.."),
+    ('0', "-----"),
+    ('1', ".----"),
+    ('2', "..---"),
+    ('3', "...--"),
+    ('4', "....-"),
+    ('5', "....."),
+    ('6', "-...."),
+    ('7', "--..."),
+    ('8', "---.."),
+    ('9', "----."),
+    ('.', ".-.-.-"),
+    (',', "--..--"),
+    ('?', "..--.."),
+    ('\'', ".----."),
+    ('!', "-.-.--"),
+    ('/', "-..-."),
+    ('(', "-.--."),
+    (')', "-.--.-"),
+    ('&', ".-..."),
+    (':', "---..."),
+    (';', "-.-.-."),
+    ('=', "-...-"),
+    ('+', ".-.-."),
+    ('-', "-....-"),
+    ('_', "..--.-"),
+    ('"', ".-..-."),
+    ('$', "...-..-"),
+    ('@', ".--.-."),
+];
+
+/// seconds every element fades in and out over
+const RAMP_TIME: f64 = 0.005;
+
+/// seconds of every block the envelope is measured in, a fraction of a dot up to 40 wpm
+const BLOCK_TIME: f64 = 0.004;
+
+/// how much a dot heard pulls the dot length towards it
+const TIMING_GAIN: f64 = 0.2;
+
+/// the code of `c`, lower case letters as well
+fn code(c: char) -> Option<&'static str> {
+    let c = c.to_ascii_uppercase();
+    MORSE.iter().find(|(k, _)| *k == c).map(|(_, code)| *code)
+}
+
+/// the character of `code`, if it is one
+fn character(code: &str) -> Option<char> {
+    MORSE.iter().find(|(_, c)| *c == code).map(|(k, _)| *k)
+}
+
+impl Cw {
+    /// seconds a dot lasts
+    pub fn dot_time(&self) -> f64 {
+        1.2 / self.wpm
+    }
+
+    /// Key `text` onto the tone, after and before a word gap of silence. Runs of whitespace
+    /// are one word gap.
+    pub fn modulate(&self, sample_rate: f64, text: &str) -> Result<Vec<f64>> {
+        // the keying, on or off for so many dots
+        let mut keying = vec![(false, 7)];
+        for word in text.split_whitespace() {
+            for c in word.chars() {
+                let code = code(c).ok_or(AcousticError::NoMorseCode(c))?;
+                for element in code.chars() {
+                    let dots = match element {
+                        '.' => 1,
+                        _ => 3,
+                    };
+                    keying.extend([(true, dots), (false, 1)]);
+                }
+                // the gap after the last element grows to a letter gap
+                keying.push((false, 2));
+            }
+            keying.push((false, 4));
+        }
+        let dot = self.dot_time() * sample_rate;
+        let omega = 2.0 * PI * self.freq / sample_rate;
+        let ramp = (RAMP_TIME * sample_rate).max(1.0);
+        let mut signal = Vec::new();
+        let mut dots = 0;
+        for (on, len) in keying {
+            dots += len;
+            let start = signal.len();
+            let end = (dots as f64 * dot).round() as usize;
+            signal.extend((start..end).map(|t| match on {
+                true => {
+                    let from_edge = (t - start).min(end - 1 - t) as f64;
+                    let envelope = match from_edge < ramp {
+                        true => 0.5 - 0.5 * (PI * from_edge / ramp).cos(),
+                        false => 1.0,
+                    };
+                    envelope * (omega * t as f64).sin()
+                }
+                false => 0.0,
+            }));
+        }
+        Ok(signal)
+    }
+
+    /// The text keyed in `signal`, words a space apart, in upper case. Codes that are no
+    /// character are left out.
+    pub fn demodulate(&self, sample_rate: f64, signal: &[f64]) -> String {
+        let keying = self.keying(sample_rate, signal);
+        let mut dot = self.dot_time();
+        let mut text = String::new();
+        let mut letter = String::new();
+        let flush = |letter: &mut String, text: &mut String| {
+            text.extend(character(letter.as_str()));
+            letter.clear();
+        };
+        for (on, seconds) in keying {
+            match on {
+                true => {
+                    // a dot is one, a dash three, halfway is two
+                    let dots = match seconds < 2.0 * dot {
+                        true => {
+                            letter.push('.');
+                            1.0
+                        }
+                        false => {
+                            letter.push('-');
+                            3.0
+                        }
+                    };
+                    dot += TIMING_GAIN * (seconds / dots - dot);
+                }
+                // gaps of one, three and seven dots
+                false if seconds < 2.0 * dot => {}
+                false if seconds < 5.0 * dot => flush(&mut letter, &mut text),
+                false => {
+                    flush(&mut letter, &mut text);
+                    if !text.is_empty() && !text.ends_with(' ') {
+                        text.push(' ');
+                    }
+                }
+            }
+        }
+        flush(&mut letter, &mut text);
+        text.trim_end().to_string()
+    }
+
+    /// How long the tone was on and off, in turns, in seconds.
+    fn keying(&self, sample_rate: f64, signal: &[f64]) -> Vec<(bool, f64)> {
+        let block = ((BLOCK_TIME * sample_rate).round() as usize).max(1);
+        let omega = 2.0 * PI * self.freq / sample_rate;
+        let envelope = signal
+            .chunks(block)
+            .enumerate()
+            .map(|(k, chunk)| {
+                let (i, q) = chunk.iter().enumerate().fold((0.0, 0.0), |(i, q), (t, x)| {
+                    let phase = omega * (k * block + t) as f64;
+                    (i + x * phase.sin(), q + x * phase.cos())
+                });
+                2.0 * i.hypot(q) / chunk.len() as f64
+            })
+            .collect::<Vec<f64>>();
+        // three blocks at a time, for a block of noise not to break an element in two
+        let smoothed = (0..envelope.len())
+            .map(|k| {
+                let around = &envelope[k.saturating_sub(1)..(k + 2).min(envelope.len())];
+                around.iter().sum::<f64>() / around.len() as f64
+            })
+            .collect::<Vec<f64>>();
+        let peak = smoothed.iter().copied().fold(0.0, f64::max);
+        let mut sorted = smoothed.clone();
+        sorted.sort_by(f64::total_cmp);
+        // the tone is on for less than three quarters of the time, pauses between words
+        // included
+        let floor = sorted.get(sorted.len() / 4).copied().unwrap_or(0.0);
+        let threshold = (peak + floor) / 2.0;
+
+        let block_time = block as f64 / sample_rate;
+        let mut keying: Vec<(bool, f64)> = Vec::new();
+        for level in smoothed {
+            let on = level > threshold && peak > 0.0;
+            match keying.last_mut() {
+                Some((last, seconds)) if *last == on => *seconds += block_time,
+                _ => keying.push((on, block_time)),
+            }
+        }
+        keying
+    }
+}
+
+#[test]
+fn test_cw() {
+    use crate::simulator::awgn_seeded;
+
+    let cw = Cw::default();
+    let text = "CQ CQ DE ACOUSTICDI 73, K?";
+    let signal = cw.modulate(44100.0, text).unwrap();
+    // PARIS is fifty dots, with the word gap before it
+    let paris = cw.modulate(8000.0, "paris").unwrap();
+    assert_eq!(
+        paris.len(),
+        (57.0 * cw.dot_time() * 8000.0).round() as usize
+    );
+    assert_eq!(cw.demodulate(44100.0, &signal), text);
+    assert_eq!(cw.demodulate(8000.0, &paris), "PARIS");
+
+    // in noise, lower case and spread out
+    let signal = cw.modulate(44100.0, "beacon  de\tacousticdi").unwrap();
+    let noisy = awgn_seeded(&signal, 0.0, 5);
+    assert_eq!(cw.demodulate(44100.0, &noisy), "BEACON DE ACOUSTICDI");
+
+    // a sender faster than expected, the dot length catches up
+    let fast = Cw { wpm: 28.0, ..cw };
+    let signal = fast.modulate(44100.0, "SOS SOS 5NN").unwrap();
+    assert_eq!(cw.demodulate(44100.0, &signal), "SOS SOS 5NN");
+
+    assert!(matches!(
+        cw.modulate(44100.0, "A~"),
+        Err(AcousticError::NoMorseCode('~'))
+    ));
+    assert_eq!(cw.demodulate(44100.0, &[0.0; 10000]), "");
+}
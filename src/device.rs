@@ -0,0 +1,53 @@
+//! # Devices
+//!
+//! Looks up audio devices by name, falling back to the host's default one, and lists those
+//! to choose from. Input and output are chosen apart, say an external speaker to send with
+//! while recording through the laptop's microphone.
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use crate::error::{AcousticError, Result};
+
+/// the input device called `name`, or the default one
+pub fn input_device(name: Option<&str>) -> Result<cpal::Device> {
+    let host = cpal::default_host();
+    match name {
+        None => host
+            .default_input_device()
+            .ok_or(AcousticError::NoDevice("input")),
+        Some(name) => find(host.input_devices()?, name),
+    }
+}
+
+/// the output device called `name`, or the default one
+pub fn output_device(name: Option<&str>) -> Result<cpal::Device> {
+    let host = cpal::default_host();
+    match name {
+        None => host
+            .default_output_device()
+            .ok_or(AcousticError::NoDevice("output")),
+        Some(name) => find(host.output_devices()?, name),
+    }
+}
+
+/// names of the input devices, to pass to `input_device`
+pub fn input_device_names() -> Result<Vec<String>> {
+    names(cpal::default_host().input_devices()?)
+}
+
+/// names of the output devices, to pass to `output_device`
+pub fn output_device_names() -> Result<Vec<String>> {
+    names(cpal::default_host().output_devices()?)
+}
+
+fn names(devices: impl Iterator<Item = cpal::Device>) -> Result<Vec<String>> {
+    Ok(devices
+        .map(|device| device.name())
+        .collect::<std::result::Result<Vec<String>, _>>()?)
+}
+
+fn find(mut devices: impl Iterator<Item = cpal::Device>, name: &str) -> Result<cpal::Device> {
+    devices
+        .find(|device| device.name().is_ok_and(|n| n == name))
+        .ok_or_else(|| AcousticError::DeviceNotFound(name.to_string()))
+}
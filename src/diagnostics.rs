@@ -0,0 +1,175 @@
+//! # Diagnostics
+//!
+//! When a message does not decode, the first question is what the microphone heard.
+//! `write_spectrogram` draws the STFT of a signal, recorded or synthetic, to a PNG, time
+//! from left to right and frequency from the bottom up, the louder the brighter. Dashed
+//! lines mark where the data tones (green) and the preamble tones (magenta) of the config
+//! should be, so a shifted carrier, a missing preamble or a loud fan is plain to see.
+//!
+//! Each column of pixels is one STFT step, `FFT_STEP` samples, and long recordings are
+//! squeezed to at most `MAX_WIDTH` columns, each the loudest of the steps it stands for.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use crate::{
+    config::AcousticConfig,
+    error::Result,
+    physics::{Stft, FFT_SIZE, FFT_STEP},
+};
+
+/// widest spectrogram drawn, in pixels
+pub const MAX_WIDTH: usize = 4096;
+
+/// pixels per STFT bin
+const BIN_HEIGHT: usize = 2;
+
+/// decibels below the loudest bin still drawn brighter than black
+const DYNAMIC_RANGE: f64 = 80.0;
+
+const DATA_COLOR: [u8; 3] = [0, 255, 0];
+const PREAMBLE_COLOR: [u8; 3] = [255, 0, 255];
+
+/// The level of every STFT bin in decibels, a column every `FFT_STEP` samples. Unlike
+/// detection, which counts anything below 1 as nothing, quiet bins keep their level.
+pub fn spectrogram(signal: &[f64]) -> Vec<Vec<f64>> {
+    let mut stft = Stft::new();
+    (0..)
+        .map(|i| i * FFT_STEP)
+        .take_while(|start| start + FFT_SIZE <= signal.len())
+        .map(|start| {
+            stft.magnitudes(&signal[start..])
+                .into_iter()
+                .map(|magnitude| 20.0 * magnitude.max(f64::MIN_POSITIVE).log10())
+                .collect()
+        })
+        .collect()
+}
+
+/// Draw the spectrogram of `signal` with the tones of `config` to a PNG at `path`.
+pub fn write_spectrogram(
+    config: &AcousticConfig,
+    signal: &[f64],
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    let (width, height, pixels) = render(config, signal);
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        width as u32,
+        height as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// width, height and RGB rows of the spectrogram, the highest frequency on top
+fn render(config: &AcousticConfig, signal: &[f64]) -> (usize, usize, Vec<u8>) {
+    let steps = spectrogram(signal);
+    let bins = FFT_SIZE / 2;
+    let squeeze = steps.len().div_ceil(MAX_WIDTH).max(1);
+    let columns = steps
+        .chunks(squeeze)
+        .map(|chunk| {
+            (0..bins)
+                .map(|bin| {
+                    chunk
+                        .iter()
+                        .map(|step| step.get(bin).copied().unwrap_or(f64::NEG_INFINITY))
+                        .fold(f64::NEG_INFINITY, f64::max)
+                })
+                .collect::<Vec<f64>>()
+        })
+        .collect::<Vec<Vec<f64>>>();
+    let loudest = columns
+        .iter()
+        .flatten()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max);
+
+    let bin_of = |freq: f64| (freq * FFT_SIZE as f64 / config.sample_rate).round() as usize;
+    let data_bins = config
+        .data_freqs()
+        .into_iter()
+        .map(bin_of)
+        .collect::<Vec<usize>>();
+    let preamble_bins = config.preamble_freqs.map(bin_of);
+
+    let (width, height) = (columns.len().max(1), bins * BIN_HEIGHT);
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for row in 0..height {
+        let bin = bins - 1 - row / BIN_HEIGHT;
+        for x in 0..width {
+            // dashed, so the tone under the line still shows
+            let dash = (x / 4) % 2 == 0;
+            let color = match columns.get(x) {
+                _ if dash && preamble_bins.contains(&bin) => PREAMBLE_COLOR,
+                _ if dash && data_bins.contains(&bin) => DATA_COLOR,
+                Some(column) => heat((column[bin] - loudest + DYNAMIC_RANGE) / DYNAMIC_RANGE),
+                None => [0; 3],
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+    (width, height, pixels)
+}
+
+/// black through blue, red and yellow to white as `level` goes from 0 to 1
+fn heat(level: f64) -> [u8; 3] {
+    const STOPS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 160.0],
+        [220.0, 0.0, 0.0],
+        [255.0, 220.0, 0.0],
+        [255.0, 255.0, 255.0],
+    ];
+    let position = level.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let low = (position.floor() as usize).min(STOPS.len() - 2);
+    let t = position - low as f64;
+    let [r, g, b] = [0, 1, 2].map(|i| STOPS[low][i] + t * (STOPS[low + 1][i] - STOPS[low][i]));
+    [r as u8, g as u8, b as u8]
+}
+
+#[test]
+fn test_spectrogram() {
+    use crate::{physics::FFT_STEP, transmitter::modulate_message};
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &modulate_message(&config, b"hello").unwrap(),
+        &silence,
+    ]
+    .concat();
+    let (width, height, pixels) = render(&config, &signal);
+    assert_eq!(height, FFT_SIZE);
+    assert!(width.abs_diff(signal.len() / FFT_STEP) <= 2);
+    assert_eq!(pixels.len(), width * height * 3);
+
+    // the dashes over the tones, black silence, and the first carrier bright between the
+    // dashes while the message plays
+    let pixel = |x: usize, freq: f64| {
+        let bin = (freq * FFT_SIZE as f64 / config.sample_rate).round() as usize;
+        let row = (FFT_SIZE / 2 - 1 - bin) * BIN_HEIGHT;
+        let i = (row * width + x) * 3;
+        [pixels[i], pixels[i + 1], pixels[i + 2]]
+    };
+    assert_eq!(pixel(0, config.preamble_freqs[0]), PREAMBLE_COLOR);
+    assert_eq!(pixel(0, config.carrier_freqs[0]), DATA_COLOR);
+    assert_eq!(pixel(5, 500.0), [0; 3]);
+    let loud = (0..width)
+        .filter(|x| (x / 4) % 2 == 1)
+        .map(|x| pixel(x, config.carrier_freqs[0]))
+        .filter(|[r, ..]| *r > 200)
+        .count();
+    assert!(loud > width / 20, "{} bright of {}", loud, width);
+
+    write_spectrogram(&config, &signal, "spectrogram.png").unwrap();
+    let decoder = png::Decoder::new(File::open("spectrogram.png").unwrap());
+    let info = decoder.read_info().unwrap().info().clone();
+    std::fs::remove_file("spectrogram.png").unwrap();
+    assert_eq!((info.width, info.height), (width as u32, height as u32));
+}
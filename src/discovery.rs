@@ -0,0 +1,272 @@
+//! # Discovery
+//!
+//! Before devices can address each other they have to know who is around. Every device
+//! plays a short `PacketKind::Beacon` now and then, an `Announcer` does so in the
+//! background, with its address as the source and its `Capabilities` as the payload.
+//! `discover` listens for a while and returns every peer heard.
+//!
+//! Announcements come at random intervals around the period, so that two devices started
+//! together do not keep announcing on top of each other.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::{error, info};
+
+use crate::{
+    config::Modulation,
+    error::{AcousticError, Result},
+    transmission::{Event, Receiver},
+    transmitter::Transmitter,
+    Packet, PacketKind,
+};
+
+/// first byte of a beacon with capabilities, see `PacketKind::Beacon`
+pub const ANNOUNCE: u8 = 1;
+
+/// what a device can receive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub modulations: Vec<Modulation>,
+    /// it decodes Hamming(7,4), see `fec`
+    pub fec: bool,
+    /// it listens with two microphones, see `stereo`
+    pub stereo: bool,
+    /// it listens while it sends, see `transceiver`
+    pub full_duplex: bool,
+    /// it has a key to decrypt with, see `crypto`
+    pub encryption: bool,
+}
+
+impl Default for Capabilities {
+    /// what every device built on this crate receives
+    fn default() -> Self {
+        Capabilities {
+            modulations: Modulation::ALL.to_vec(),
+            fec: true,
+            stereo: false,
+            full_duplex: false,
+            encryption: false,
+        }
+    }
+}
+
+impl Capabilities {
+    const FEC: u8 = 1;
+    const STEREO: u8 = 2;
+    const FULL_DUPLEX: u8 = 4;
+    const ENCRYPTION: u8 = 8;
+
+    /// A beacon: `ANNOUNCE`, a bit per modulation in the order of `Modulation::ALL`, then the
+    /// flags, 1 for error correction, 2 for stereo, 4 for full duplex and 8 for encryption.
+    pub fn to_packet(&self) -> Packet {
+        let modulations = Modulation::ALL
+            .iter()
+            .enumerate()
+            .filter(|(_, modulation)| self.modulations.contains(modulation))
+            .fold(0, |bits, (i, _)| bits | 1 << i);
+        let flags = [
+            (self.fec, Self::FEC),
+            (self.stereo, Self::STEREO),
+            (self.full_duplex, Self::FULL_DUPLEX),
+            (self.encryption, Self::ENCRYPTION),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        Packet::new(PacketKind::Beacon, 0, &[ANNOUNCE, modulations, flags])
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Capabilities> {
+        match (packet.kind, packet.data.as_slice()) {
+            (PacketKind::Beacon, [ANNOUNCE, modulations, flags, ..]) => Ok(Capabilities {
+                modulations: Modulation::ALL
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| modulations & 1 << i > 0)
+                    .map(|(_, modulation)| *modulation)
+                    .collect(),
+                fec: flags & Self::FEC > 0,
+                stereo: flags & Self::STEREO > 0,
+                full_duplex: flags & Self::FULL_DUPLEX > 0,
+                encryption: flags & Self::ENCRYPTION > 0,
+            }),
+            _ => Err(AcousticError::MalformedPacket(format!(
+                "bad announcement {:?}",
+                packet.data
+            ))),
+        }
+    }
+}
+
+/// a device heard announcing itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub address: u8,
+    pub capabilities: Capabilities,
+}
+
+/// Play an announcement from the address of `transmitter`, blocking until it is out.
+pub fn announce(transmitter: &mut Transmitter, capabilities: &Capabilities) -> Result<()> {
+    let beacon = capabilities
+        .to_packet()
+        .addressed(transmitter.address(), Packet::BROADCAST);
+    transmitter.send_packets(&[beacon])
+}
+
+/// Listen for announcements until `timeout` is over, and return every peer heard, by
+/// address. The receiver should read from a `Recorder` with a timeout shorter than that,
+/// or from a file, which ends discovery early.
+pub fn discover(receiver: &mut Receiver, timeout: Duration) -> Result<Vec<Peer>> {
+    let deadline = Instant::now() + timeout;
+    let mut peers = BTreeMap::new();
+    while Instant::now() < deadline {
+        match receiver.next_event() {
+            Ok(Event::Beacon(packet)) => match Capabilities::from_packet(&packet) {
+                Ok(capabilities) => {
+                    info!("{} announced {:?}", packet.source, capabilities);
+                    peers.insert(packet.source, capabilities);
+                }
+                Err(_) => info!("skipped beacon {:?}", packet.data),
+            },
+            Ok(event) => info!("skipped {:?}", event),
+            Err(AcousticError::Timeout) => receiver.rewind()?,
+            Err(AcousticError::EndOfStream) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(peers
+        .into_iter()
+        .map(|(address, capabilities)| Peer {
+            address,
+            capabilities,
+        })
+        .collect())
+}
+
+/// Announces a device in the background every `period` or so, until dropped.
+pub struct Announcer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Announcer {
+    /// Start announcing through `transmitter`, the first time right away.
+    pub fn start(
+        mut transmitter: Transmitter,
+        capabilities: Capabilities,
+        period: Duration,
+    ) -> Announcer {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut rng = StdRng::from_entropy();
+            while !stopped.load(Ordering::Relaxed) {
+                if let Err(err) = announce(&mut transmitter, &capabilities) {
+                    error!("announcing failed: {}", err);
+                }
+                // half to one and a half periods, in steps short enough to stop soon
+                let next = Instant::now() + period.mul_f64(rng.gen_range(0.5..1.5));
+                while !stopped.load(Ordering::Relaxed) && Instant::now() < next {
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        });
+        Announcer {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::AcousticConfig, tdma::Schedule, transmission::MemoryReader,
+        transmitter::modulate_packets,
+    };
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities {
+            modulations: vec![Modulation::Fsk, Modulation::Dsss],
+            fec: false,
+            stereo: true,
+            full_duplex: true,
+            encryption: false,
+        };
+        let packet = capabilities.to_packet();
+        assert_eq!(packet.data, [ANNOUNCE, 0b100001, 6]);
+        assert_eq!(Capabilities::from_packet(&packet).unwrap(), capabilities);
+        let default = Capabilities::default();
+        assert_eq!(
+            Capabilities::from_packet(&default.to_packet()).unwrap(),
+            default
+        );
+        // a schedule is a beacon too
+        let schedule = Schedule::new(Duration::from_secs(1), &[1, 2]).to_packet();
+        assert!(Capabilities::from_packet(&schedule).is_err());
+    }
+
+    #[test]
+    fn test_discover() {
+        let config = AcousticConfig::default();
+        let stereo = Capabilities {
+            stereo: true,
+            ..Capabilities::default()
+        };
+        let packets = [
+            Capabilities::default()
+                .to_packet()
+                .addressed(3, Packet::BROADCAST),
+            Packet::from((0, &b"hi"[..])).addressed(3, 1),
+            stereo.to_packet().addressed(1, Packet::BROADCAST),
+            Schedule::new(Duration::from_secs(1), &[1, 3])
+                .to_packet()
+                .addressed(1, Packet::BROADCAST),
+            // heard again, as it announces every now and then
+            Capabilities::default()
+                .to_packet()
+                .addressed(3, Packet::BROADCAST),
+        ];
+        let heard = [
+            vec![0.0; 10000],
+            modulate_packets(&config, &packets).unwrap(),
+            vec![0.0; 10000],
+        ]
+        .concat();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(heard)), config);
+        let peers = discover(&mut receiver, Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            peers,
+            [
+                Peer {
+                    address: 1,
+                    capabilities: stereo
+                },
+                Peer {
+                    address: 3,
+                    capabilities: Capabilities::default()
+                },
+            ]
+        );
+    }
+}
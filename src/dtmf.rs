@@ -0,0 +1,152 @@
+//! # DTMF
+//!
+//! The tones of a telephone keypad: every key is one tone of its row and one of its column,
+//! sixteen keys on eight tones. Next to talking to phone systems, any phone, app or scanner
+//! tells what was sent, which makes DTMF the first thing to try when a speaker and a
+//! microphone do not get along.
+//!
+//! `dtmf_modulate` sends every key for `TONE_TIME` with `GAP_TIME` of silence after it,
+//! longer than the 40 ms the standard asks for of both. `dtmf_demodulate` runs a
+//! `GoertzelBank` on the eight tones over overlapping blocks, and takes a key as pressed once
+//! two blocks in a row hear it, and as released once a block does not.
+
+use std::f64::consts::PI;
+
+use crate::{
+    error::{AcousticError, Result},
+    goertzel::GoertzelBank,
+};
+
+/// the tones of the rows, top to bottom, in Hz
+pub const ROW_FREQS: [f64; 4] = [697.0, 770.0, 852.0, 941.0];
+
+/// the tones of the columns, left to right, in Hz
+pub const COLUMN_FREQS: [f64; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+
+/// the keypad, row by row
+const KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// seconds every key sounds
+pub const TONE_TIME: f64 = 0.07;
+
+/// seconds of silence after every key
+pub const GAP_TIME: f64 = 0.07;
+
+/// seconds of every block the tones are measured in, long enough to tell neighbouring rows
+/// 73 Hz apart through the window
+const BLOCK_TIME: f64 = 0.04;
+
+/// both tones have to be at least this loud, about -40 dB of full scale
+const MIN_AMPLITUDE: f64 = 0.01;
+
+/// how many times louder than the others of its row or column a tone has to be
+const DOMINANCE: f64 = 2.0;
+
+/// how many times louder one tone of a key may be than the other, 12 dB
+const MAX_TWIST: f64 = 4.0;
+
+/// the row and the column of `key`, lower case letters as well
+fn position(key: char) -> Option<(usize, usize)> {
+    let key = key.to_ascii_uppercase();
+    KEYS.iter()
+        .enumerate()
+        .find_map(|(row, keys)| keys.iter().position(|k| *k == key).map(|col| (row, col)))
+}
+
+/// `keys` one after the other, each followed by silence, at half of full scale a tone.
+pub fn dtmf_modulate(sample_rate: f64, keys: &str) -> Result<Vec<f64>> {
+    let tone = (TONE_TIME * sample_rate).round() as usize;
+    let gap = (GAP_TIME * sample_rate).round() as usize;
+    let mut signal = Vec::with_capacity(keys.chars().count() * (tone + gap));
+    for key in keys.chars() {
+        let (row, col) = position(key).ok_or(AcousticError::InvalidDtmfKey(key))?;
+        let omegas = [ROW_FREQS[row], COLUMN_FREQS[col]].map(|f| 2.0 * PI * f / sample_rate);
+        signal.extend((0..tone).map(|t| {
+            omegas
+                .iter()
+                .map(|omega| 0.5 * (omega * t as f64).sin())
+                .sum::<f64>()
+        }));
+        signal.resize(signal.len() + gap, 0.0);
+    }
+    Ok(signal)
+}
+
+/// The key sounding in `amplitudes` of the row tones and then the column tones, if any.
+fn detect_key(amplitudes: &[f64]) -> Option<char> {
+    // the loudest tone of a group, if it stands out of it
+    let loudest = |group: &[f64]| {
+        let (i, top) = group.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+        let stands_out = group
+            .iter()
+            .enumerate()
+            .all(|(j, a)| j == i || a * DOMINANCE <= *top);
+        (*top >= MIN_AMPLITUDE && stands_out).then_some((i, *top))
+    };
+    let (row, row_amplitude) = loudest(&amplitudes[..4])?;
+    let (col, col_amplitude) = loudest(&amplitudes[4..])?;
+    let twist = row_amplitude.max(col_amplitude) / row_amplitude.min(col_amplitude);
+    (twist <= MAX_TWIST).then_some(KEYS[row][col])
+}
+
+/// Every key pressed in `signal`, in order. A key held across a gap too short to hear is
+/// taken for one.
+pub fn dtmf_demodulate(sample_rate: f64, signal: &[f64]) -> String {
+    let block = ((BLOCK_TIME * sample_rate).round() as usize).max(1);
+    let freqs = [ROW_FREQS, COLUMN_FREQS].concat();
+    let bank = GoertzelBank::new(&freqs, sample_rate).with_block_len(block);
+    let mut keys = String::new();
+    let mut previous = None;
+    let mut pressed = false;
+    // blocks overlap by half, so that one lies within every tone
+    for start in (0..signal.len().saturating_sub(block) + 1).step_by(block.div_ceil(2)) {
+        let end = (start + block).min(signal.len());
+        let heard = detect_key(&bank.amplitudes(&signal[start..end]));
+        match (heard, heard == previous) {
+            (Some(key), true) if !pressed => {
+                keys.push(key);
+                pressed = true;
+            }
+            (_, false) => pressed = false,
+            _ => {}
+        }
+        previous = heard;
+    }
+    keys
+}
+
+#[test]
+fn test_dtmf() {
+    use crate::simulator::awgn_seeded;
+
+    let keys = "123A456B789C*0#D";
+    for sample_rate in [8000.0, 44100.0, 48000.0] {
+        let signal = dtmf_modulate(sample_rate, keys).unwrap();
+        let per_key = ((TONE_TIME + GAP_TIME) * sample_rate).round() as usize;
+        assert_eq!(signal.len(), keys.len() * per_key);
+        assert_eq!(dtmf_demodulate(sample_rate, &signal), keys);
+    }
+
+    // the same key twice, in noise, lower case as well
+    let signal = dtmf_modulate(44100.0, "55abd#").unwrap();
+    let padded = [vec![0.0; 5000], signal, vec![0.0; 5000]].concat();
+    let noisy = awgn_seeded(&padded, 15.0, 3);
+    assert_eq!(dtmf_demodulate(44100.0, &noisy), "55ABD#");
+
+    // a row tone alone, or noise alone, is no key
+    let row = (0..4410)
+        .map(|t| (2.0 * PI * ROW_FREQS[0] * t as f64 / 44100.0).sin())
+        .collect::<Vec<f64>>();
+    assert_eq!(dtmf_demodulate(44100.0, &row), "");
+    assert_eq!(dtmf_demodulate(44100.0, &awgn_seeded(&row, -20.0, 4)), "");
+
+    assert!(matches!(
+        dtmf_modulate(44100.0, "12E"),
+        Err(AcousticError::InvalidDtmfKey('E'))
+    ));
+}
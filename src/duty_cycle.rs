@@ -0,0 +1,156 @@
+//! # Duty cycle
+//!
+//! Background telemetry shares the room with people, and a device that chirps all the time
+//! wears on them however short each packet is. A `DutyCycle` keeps a transmitter on the air
+//! for at most a share of any `window`, holding packets back until enough of what it sent
+//! earlier is out of the window, see `Transmitter::with_duty_cycle`.
+//!
+//! A packet starting at `t` for `airtime` goes out only if what was sent since
+//! `t + airtime - window` and the packet itself fit the budget. That keeps every window
+//! within the budget, including those that end while the packet plays, since they reach
+//! further back by at most as much as they cut of the packet.
+
+use std::{
+    collections::VecDeque,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::error::{AcousticError, Result};
+
+/// window of the limits the command line sets, see `DutyCycle::new`
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Keeps the airtime within a share of every window.
+pub struct DutyCycle {
+    /// the most airtime in any window
+    budget: Duration,
+    window: Duration,
+    /// start and airtime of whatever was sent in the last window
+    sent: VecDeque<(Instant, Duration)>,
+}
+
+impl DutyCycle {
+    /// On the air for at most `share` of every `window`, `share` from 0 to 1.
+    pub fn new(share: f64, window: Duration) -> DutyCycle {
+        DutyCycle {
+            budget: window.mul_f64(share.clamp(0.0, 1.0)),
+            window,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// the most airtime in any window
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// How long after `now` something of `airtime` can start,
+    /// `AcousticError::AirtimeExceeded` if it never fits the budget.
+    pub fn delay(&self, airtime: Duration, now: Instant) -> Result<Duration> {
+        if airtime > self.budget {
+            return Err(AcousticError::AirtimeExceeded {
+                airtime,
+                budget: self.budget,
+            });
+        }
+        // the window ending with the new transmission if it started now, nothing before
+        // the clock started counts
+        let since = (now + airtime).checked_sub(self.window);
+        let from = |start: Instant| since.map_or(start, |since| since.max(start));
+        let used = self
+            .sent
+            .iter()
+            .map(|(start, sent)| (*start + *sent).saturating_duration_since(from(*start)))
+            .sum::<Duration>();
+        let mut need = (used + airtime).saturating_sub(self.budget);
+        if need.is_zero() {
+            return Ok(Duration::ZERO);
+        }
+        // the window slides on until enough of what was sent first left it
+        for (start, sent) in &self.sent {
+            let left = (*start + *sent).saturating_duration_since(from(*start));
+            if left >= need {
+                let since = from(*start) + need;
+                return Ok((since + self.window).saturating_duration_since(now + airtime));
+            }
+            need -= left;
+        }
+        Ok(self.window)
+    }
+
+    /// Something of `airtime` started at `start`.
+    pub fn record(&mut self, start: Instant, airtime: Duration) {
+        while let Some((first, sent)) = self.sent.front() {
+            if *first + *sent + self.window > start {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        self.sent.push_back((start, airtime));
+    }
+
+    /// Block until something of `airtime` fits the budget. Once it starts, it has to be
+    /// `record`ed.
+    pub fn wait(&self, airtime: Duration) -> Result<()> {
+        let delay = self.delay(airtime, Instant::now())?;
+        if !delay.is_zero() {
+            info!("duty cycle, holding back for {:?}", delay);
+            sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// How many of the first transmissions of `airtimes`, `gap` apart, fit the budget
+    /// together, and how long they take. At least one, whether it fits or not.
+    pub fn fitting(&self, airtimes: &[Duration], gap: Duration) -> (usize, Duration) {
+        let mut total = airtimes[0];
+        let mut count = 1;
+        for airtime in &airtimes[1..] {
+            if total + gap + *airtime > self.budget {
+                break;
+            }
+            total += gap + *airtime;
+            count += 1;
+        }
+        (count, total)
+    }
+}
+
+#[test]
+fn test_duty_cycle() {
+    let second = Duration::from_secs(1);
+    let mut duty_cycle = DutyCycle::new(0.1, 10 * second);
+    assert_eq!(duty_cycle.budget(), second);
+    assert!(matches!(
+        duty_cycle.delay(2 * second, Instant::now()),
+        Err(AcousticError::AirtimeExceeded { .. })
+    ));
+
+    let start = Instant::now();
+    assert_eq!(duty_cycle.delay(second / 2, start).unwrap(), Duration::ZERO);
+    duty_cycle.record(start, second / 2);
+    // room for another half second right away, not for more
+    let now = start + second;
+    assert_eq!(duty_cycle.delay(second / 2, now).unwrap(), Duration::ZERO);
+    duty_cycle.record(now, second / 2);
+    // a quarter of a second once that much of the first left the window
+    let delay = duty_cycle.delay(second / 4, now + second).unwrap();
+    assert_eq!(now + second + delay, start + 10 * second);
+    // a whole second once the first and half of the second left
+    let delay = duty_cycle.delay(second, now + second).unwrap();
+    assert_eq!(now + second + delay, now + 10 * second - second / 2);
+
+    let airtimes = [second / 2, second / 4, second / 4];
+    assert_eq!(
+        duty_cycle.fitting(&airtimes, second / 10),
+        (2, second / 2 + second / 10 + second / 4)
+    );
+    assert_eq!(duty_cycle.fitting(&airtimes, Duration::ZERO), (3, second));
+}
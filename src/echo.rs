@@ -0,0 +1,234 @@
+//! # Echo suppression
+//!
+//! A transceiver that plays and records on one device hears its own speaker far louder than
+//! the peer. We know exactly what we played though, and about when: `PlayedSignals` keeps
+//! each signal with the recorded sample it started playing at. Output and input latency add
+//! a delay on top, which `estimate_echo` finds by correlating the recording with the signal,
+//! along with how loud it arrived. `EchoSuppressor` then subtracts the signal, so delayed
+//! and scaled, from every window the receiver asks for.
+//!
+//! Only the direct path goes, the room still echoes a little, see `simulator::multipath`.
+
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tracing::info;
+
+use crate::{
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    transmission::SampleReader,
+};
+
+/// Seconds the echo may arrive after we started playing, latency of both ends included.
+pub const MAX_ECHO_DELAY: f64 = 0.3;
+
+/// a signal we played and where its echo is
+#[derive(Debug)]
+struct Played {
+    /// recorded sample at which it started playing
+    start: usize,
+    signal: Vec<f64>,
+    /// samples it arrived later than `start`, and its amplitude, once estimated
+    echo: Option<(usize, f64)>,
+}
+
+/// Signals we played, shared between whoever plays them and the `EchoSuppressor`.
+#[derive(Debug, Default)]
+pub struct PlayedSignals(Mutex<Vec<Played>>);
+
+impl PlayedSignals {
+    fn lock(&self) -> Result<MutexGuard<'_, Vec<Played>>> {
+        self.0.lock().map_err(|_| AcousticError::PoisonedBuffer)
+    }
+
+    /// `signal` started playing at recorded sample `start`
+    pub fn push(&self, start: usize, signal: Vec<f64>) -> Result<()> {
+        self.lock()?.push(Played {
+            start,
+            signal,
+            echo: None,
+        });
+        Ok(())
+    }
+}
+
+/// The delay, up to `max_delay`, at which `recorded` matches `signal` best, and the amplitude
+/// it matches with. Both start at the same sample, only `window` of the signal is compared.
+pub fn estimate_echo(
+    signal: &[f64],
+    recorded: &[f64],
+    window: Range<usize>,
+    max_delay: usize,
+) -> (usize, f64) {
+    let window = window.start.min(signal.len())..window.end.min(signal.len());
+    let part = &signal[window.clone()];
+    let energy = part.iter().map(|x| x * x).sum::<f64>();
+    if energy == 0.0 {
+        return (0, 0.0);
+    }
+    (0..=max_delay)
+        .filter_map(|delay| {
+            let recorded = recorded.get(window.start + delay..window.end + delay)?;
+            let correlation = part.iter().zip(recorded).map(|(x, y)| x * y).sum::<f64>();
+            Some((delay, correlation / energy))
+        })
+        .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+        .unwrap_or((0, 0.0))
+}
+
+/// A `SampleReader` with the echo of what we played taken out.
+pub struct EchoSuppressor {
+    inner: Box<dyn SampleReader>,
+    played: Arc<PlayedSignals>,
+    max_delay: usize,
+    /// part of every signal the delay is estimated on, past the preamble, whose tones repeat
+    window: Range<usize>,
+}
+
+impl EchoSuppressor {
+    pub fn new(inner: Box<dyn SampleReader>, config: &AcousticConfig) -> EchoSuppressor {
+        let n = config.sample_number();
+        EchoSuppressor {
+            inner,
+            played: Arc::new(PlayedSignals::default()),
+            max_delay: (MAX_ECHO_DELAY * config.sample_rate) as usize,
+            window: 4 * n..6 * n,
+        }
+    }
+
+    /// where to tell the suppressor what was played
+    pub fn played(&self) -> Arc<PlayedSignals> {
+        self.played.clone()
+    }
+
+    /// Correlate the recording with the start of `played`. A recording forgotten already has
+    /// nothing left to suppress.
+    fn estimate(&mut self, played: &Played) -> Result<(usize, f64)> {
+        let window = match played.signal.len() < self.window.end {
+            true => 0..played.signal.len(),
+            false => self.window.clone(),
+        };
+        let end = played.start + window.end + self.max_delay;
+        let recorded = match self.inner.take_samples(played.start, end) {
+            Err(AcousticError::Evicted { .. }) => return Ok((0, 0.0)),
+            // the end of a file is silence
+            Err(AcousticError::EndOfStream) => return Ok((0, 0.0)),
+            recorded => recorded?,
+        };
+        let (delay, gain) = estimate_echo(&played.signal, &recorded, window, self.max_delay);
+        info!("echo after {} samples at {}", delay, gain);
+        Ok((delay, gain))
+    }
+}
+
+impl SampleReader for EchoSuppressor {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, samples: &mut Vec<f64>) -> Result<()> {
+        self.inner.read_samples(start, end, samples)?;
+        let played = self.played.clone();
+        let mut played = played.lock()?;
+        for signal in played.iter_mut() {
+            let heard = signal.start..signal.start + signal.signal.len() + self.max_delay;
+            if end <= heard.start || heard.end <= start {
+                continue;
+            }
+            let (delay, gain) = match signal.echo {
+                Some(echo) => echo,
+                None => {
+                    let echo = self.estimate(signal)?;
+                    signal.echo = Some(echo);
+                    echo
+                }
+            };
+            for (i, x) in samples.iter_mut().enumerate() {
+                let t = (start + i).checked_sub(signal.start + delay);
+                if let Some(y) = t.and_then(|t| signal.signal.get(t)) {
+                    *x -= gain * y;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// forget the signals heard in full before `until`
+    fn consume(&mut self, until: usize) -> Result<()> {
+        let max_delay = self.max_delay;
+        self.played
+            .lock()?
+            .retain(|played| played.start + played.signal.len() + max_delay > until);
+        self.inner.consume(until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        simulator::{awgn_seeded, power},
+        transmission::{Event, MemoryReader, Receiver},
+        transmitter::modulate_message,
+    };
+
+    /// `ours` played from `start` with an echo `delay` samples later and `gain` as loud, over
+    /// what the peer sends
+    fn heard(ours: &[f64], start: usize, delay: usize, gain: f64, peer: &[f64]) -> Vec<f64> {
+        (0..(start + delay + ours.len()).max(peer.len()) + 10000)
+            .map(|i| {
+                let echo = (i.checked_sub(start + delay))
+                    .and_then(|t| ours.get(t))
+                    .map_or(0.0, |x| gain * x);
+                echo + peer.get(i).copied().unwrap_or(0.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_estimate_echo() {
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let ours = modulate_message(&config, b"hello world").unwrap();
+        let recorded = awgn_seeded(&heard(&ours, 0, 1234, 0.6, &[]), 10.0, 1);
+        let (delay, gain) = estimate_echo(&ours, &recorded, 4 * n..6 * n, 5000);
+        assert_eq!(delay, 1234);
+        assert!((gain - 0.6).abs() < 0.02, "{}", gain);
+    }
+
+    #[test]
+    fn test_suppress_echo() {
+        // both ends on the same tones, the worst case
+        let config = AcousticConfig::default();
+        let ours = modulate_message(&config, &[7; 30]).unwrap();
+        let peer = [
+            vec![0.0; 60000],
+            modulate_message(&config, b"hello world").unwrap(),
+        ]
+        .concat();
+        let recorded = heard(&ours, 20000, 3000, 4.0, &peer);
+        let suppressed = || {
+            let suppressor = EchoSuppressor::new(Box::new(MemoryReader(recorded.clone())), &config);
+            suppressor.played().push(20000, ours.clone()).unwrap();
+            suppressor
+        };
+
+        let mut suppressor = suppressed();
+        let residual = suppressor.take_samples(30000, 50000).unwrap();
+        assert!(power(&residual) < 1e-6 * power(&recorded[30000..50000]));
+
+        // the peer's packet is lost under ours without suppression
+        let hello = |reader: Box<dyn SampleReader>| {
+            let mut receiver = Receiver::with_config(reader, config.clone());
+            std::iter::from_fn(|| receiver.next_event().ok())
+                .any(|event| matches!(event, Event::Data(p) if p.data == b"hello world"))
+        };
+        assert!(!hello(Box::new(MemoryReader(recorded.clone()))));
+        assert!(hello(Box::new(suppressed())));
+    }
+}
@@ -0,0 +1,233 @@
+//! # Errors
+//!
+//! Everything that can go wrong in the public API: audio devices that are missing or
+//! refuse our config, wav files we cannot read or write, and frames that arrive mangled.
+
+use std::ops::RangeInclusive;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AcousticError {
+    #[error("failed to find {0} device")]
+    NoDevice(&'static str),
+
+    #[error("no device called '{0}'")]
+    DeviceNotFound(String),
+
+    #[error(transparent)]
+    Devices(#[from] cpal::DevicesError),
+
+    /// none of the configs of `device` plays at `rate`, `supported` lists the ranges it does
+    #[error("device '{device}' does not support {rate} Hz, only {}", ranges(.supported))]
+    UnsupportedSampleRate {
+        device: String,
+        rate: u32,
+        supported: Vec<RangeInclusive<u32>>,
+    },
+
+    #[error("unsupported sample format '{0}'")]
+    UnsupportedSampleFormat(cpal::SampleFormat),
+
+    #[error(transparent)]
+    DeviceName(#[from] cpal::DeviceNameError),
+
+    #[error(transparent)]
+    DefaultStreamConfig(#[from] cpal::DefaultStreamConfigError),
+
+    #[error(transparent)]
+    SupportedStreamConfigs(#[from] cpal::SupportedStreamConfigsError),
+
+    #[error(transparent)]
+    BuildStream(#[from] cpal::BuildStreamError),
+
+    #[error(transparent)]
+    PlayStream(#[from] cpal::PlayStreamError),
+
+    #[error(transparent)]
+    Resampler(#[from] rubato::ResamplerConstructionError),
+
+    #[error("output stream stopped before playback finished")]
+    PlaybackInterrupted,
+
+    /// another device kept sending through every backoff, see `carrier_sense`
+    #[error("the channel stayed busy")]
+    ChannelBusy,
+
+    /// the TDMA schedule, if any, has no slot for us, see `tdma`
+    #[error("no slot to send in")]
+    NotScheduled,
+
+    /// a packet takes longer than a TDMA slot
+    #[error("packet of {0:?} does not fit a slot")]
+    SlotTooShort(std::time::Duration),
+
+    /// something takes more airtime than the duty cycle allows in a whole window, see
+    /// `duty_cycle`
+    #[error("{airtime:?} on the air exceeds the duty cycle budget of {budget:?}")]
+    AirtimeExceeded {
+        airtime: std::time::Duration,
+        budget: std::time::Duration,
+    },
+
+    /// the tones in use leave no band for a second stream, see `AcousticConfig::right_channel`
+    #[error("no room for the tones of a second channel")]
+    NoRoomForChannel,
+
+    #[error(transparent)]
+    Wav(#[from] hound::Error),
+
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+
+    /// reading or writing raw samples failed, see `pcm`
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed packet: {0}")]
+    MalformedPacket(String),
+
+    /// a value could not be serialized, or a message carries no value of the type asked
+    /// for, see `value`
+    #[error("bad value: {0}")]
+    Value(#[from] bincode::Error),
+
+    /// packets of this many bytes do not fit the header, see `Packet::check_packet_size`
+    #[error("packet size {0} is out of range")]
+    InvalidPacketSize(usize),
+
+    /// packets past this order do not fit the header, see `Packet::check_order`
+    #[error("packet order {0} is out of range")]
+    InvalidPacketOrder(usize),
+
+    /// a config the modem cannot work with, see `AcousticConfig::validate`
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+
+    #[error("packet header version {0} is not supported")]
+    UnsupportedVersion(u8),
+
+    /// the packet was not encrypted or signed with our key, or was changed on the way
+    #[error("packet failed authentication")]
+    AuthenticationFailed,
+
+    /// the receiver fell so far behind that the recorder dropped what it asked for
+    #[error("sample {start} was evicted, the oldest one kept is {oldest}")]
+    Evicted { start: usize, oldest: usize },
+
+    /// a finite source, like a wav file, has no more samples
+    #[error("no more samples")]
+    EndOfStream,
+
+    #[error("timed out waiting for samples")]
+    Timeout,
+
+    /// another thread asked the receiver to stop, see `Receiver::stop_handle`
+    #[error("stopped")]
+    Stopped,
+
+    /// the peer did not acknowledge everything before the retries ran out, see
+    /// `stream::RetransmitPolicy`
+    #[error("the peer acknowledged {acknowledged} of {packets} packets before retries ran out")]
+    DeliveryFailed { acknowledged: usize, packets: usize },
+
+    /// the peer went quiet, not even keepalives came, see `keepalive`
+    #[error("the link is down")]
+    LinkDown,
+
+    /// nothing is kept for messages to this port, see `ports::Dispatcher::bind`
+    #[error("port {0} is not bound")]
+    PortNotBound(u8),
+
+    /// DTMF has keys for the digits, `A` to `D`, `*` and `#` only, see `dtmf`
+    #[error("'{0}' is no DTMF key")]
+    InvalidDtmfKey(char),
+
+    /// no Morse code is known for the character, see `cw`
+    #[error("'{0}' has no Morse code")]
+    NoMorseCode(char),
+
+    /// a thread panicked while holding the sample buffer
+    #[error("sample buffer is poisoned")]
+    PoisonedBuffer,
+}
+
+fn ranges(supported: &[RangeInclusive<u32>]) -> String {
+    match supported {
+        [] => "none".to_string(),
+        _ => supported
+            .iter()
+            .map(|range| match range.start() == range.end() {
+                true => format!("{} Hz", range.start()),
+                false => format!("{} to {} Hz", range.start(), range.end()),
+            })
+            .collect::<Vec<String>>()
+            .join(", "),
+    }
+}
+
+pub type Result<T> = std::result::Result<T, AcousticError>;
+
+/// Why a frame is none of our packets, see `Packet::unseal_one`. Noise and collisions make
+/// frames of any length and content, none of them may take the receiver down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum FrameError {
+    /// fewer bytes than a header
+    #[error("header too short: {len} bytes")]
+    TruncatedHeader { len: usize },
+
+    #[error("bad magic byte {0:#04x}")]
+    BadMagic(u8),
+
+    #[error("packet header version {0} is not supported")]
+    UnsupportedVersion(u8),
+
+    /// the header was corrupted, its length cannot be trusted to find the next frame
+    #[error("header checksum {found:#04x} does not match {expected:#04x}")]
+    HeaderChecksum { expected: u8, found: u8 },
+
+    #[error("unknown packet kind {0}")]
+    UnknownKind(u8),
+
+    /// the header announces more payload than the frame carries
+    #[error("payload of {announced} bytes is cut short at {available}")]
+    LengthMismatch { announced: usize, available: usize },
+}
+
+/// Versions keep their own variant, everything else is a `MalformedPacket`.
+impl From<FrameError> for AcousticError {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::UnsupportedVersion(version) => AcousticError::UnsupportedVersion(version),
+            err => AcousticError::MalformedPacket(err.to_string()),
+        }
+    }
+}
+
+impl From<AcousticError> for std::io::Error {
+    fn from(err: AcousticError) -> Self {
+        let kind = match err {
+            AcousticError::Timeout | AcousticError::DeliveryFailed { .. } => {
+                std::io::ErrorKind::TimedOut
+            }
+            AcousticError::EndOfStream => std::io::ErrorKind::UnexpectedEof,
+            AcousticError::LinkDown => std::io::ErrorKind::NotConnected,
+            AcousticError::Io(err) => return err,
+            _ => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[test]
+fn test_unsupported_sample_rate() {
+    let err = AcousticError::UnsupportedSampleRate {
+        device: "speaker".to_string(),
+        rate: 48000,
+        supported: vec![44100..=44100, 8000..=22050],
+    };
+    assert_eq!(
+        err.to_string(),
+        "device 'speaker' does not support 48000 Hz, only 44100 Hz, 8000 to 22050 Hz"
+    );
+}
@@ -0,0 +1,477 @@
+//! # Export
+//!
+//! Writes what would be played to a file to embed in a podcast or a video, WAV, FLAC or
+//! OGG/Vorbis depending on its extension, see `export`. FLAC is lossless and smaller than
+//! WAV, so editors and video platforms that take no WAV still pass the payload on
+//! unchanged, as long as they do not transcode it to a lossy format.
+//!
+//! FLAC is written by a small encoder of its own: mono, 16 bits per sample, fixed blocks,
+//! and per block the fixed predictor of order 0 to 4 with Rice coded residuals that takes
+//! the fewest bits. The signal loses nothing but the rounding to 16 bits, well below the
+//! noise of any room. The MD5 of the samples is left out, which FLAC allows.
+//!
+//! OGG/Vorbis is lossy and smaller still, see `vorbis` for the encoder. Our tones survive
+//! it at 32 kbit/s and above, below that the payload is lost to the quantization noise.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    config::AcousticConfig,
+    error::Result,
+    transmitter::{write_stream, SampleSink},
+    vorbis::{VorbisSampleWriter, DEFAULT_BITRATE},
+    wav_writer::WavSampleWriter,
+};
+
+/// samples per FLAC frame
+const BLOCK_SIZE: usize = 4096;
+
+/// bits per FLAC sample
+const BITS_PER_SAMPLE: u32 = 16;
+
+/// byte offset of the sample rate, channels, bits per sample and total samples in a FLAC
+/// file, rewritten once the total is known
+const STREAM_INFO_TOTAL: u64 = 18;
+
+/// largest Rice parameter, 15 escapes to unencoded residuals
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// the format of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Wav,
+    Flac,
+    Ogg,
+}
+
+impl ExportFormat {
+    /// FLAC for `.flac` files, OGG/Vorbis for `.ogg` and `.oga` ones, WAV for any other
+    pub fn from_path(path: impl AsRef<Path>) -> ExportFormat {
+        match path.as_ref().extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("flac") => ExportFormat::Flac,
+            Some(extension)
+                if extension.eq_ignore_ascii_case("ogg")
+                    || extension.eq_ignore_ascii_case("oga") =>
+            {
+                ExportFormat::Ogg
+            }
+            _ => ExportFormat::Wav,
+        }
+    }
+}
+
+/// Write a sound wave to `path` as it is generated, in the format of its extension, see
+/// `ExportFormat::from_path`. OGG files are encoded at `DEFAULT_BITRATE`.
+pub fn export(
+    config: &AcousticConfig,
+    modulated: impl IntoIterator<Item = f64>,
+    path: impl AsRef<Path>,
+) -> Result<()> {
+    export_with_bitrate(config, modulated, path, DEFAULT_BITRATE)
+}
+
+/// like `export`, encoding OGG files at `bitrate` bits per second
+pub fn export_with_bitrate(
+    config: &AcousticConfig,
+    modulated: impl IntoIterator<Item = f64>,
+    path: impl AsRef<Path>,
+    bitrate: u32,
+) -> Result<()> {
+    match ExportFormat::from_path(&path) {
+        ExportFormat::Wav => {
+            let mut writer = WavSampleWriter::create(path, config)?;
+            write_stream(config, modulated, &mut writer)
+        }
+        ExportFormat::Flac => {
+            let mut writer = FlacSampleWriter::create(path, config)?;
+            write_stream(config, modulated, &mut writer)
+        }
+        ExportFormat::Ogg => {
+            let mut writer = VorbisSampleWriter::create(path, config, bitrate)?;
+            write_stream(config, modulated, &mut writer)
+        }
+    }
+}
+
+/// Encodes the signal to FLAC a block at a time.
+pub struct FlacSampleWriter<W: Write + Seek> {
+    output: W,
+    sample_rate: u64,
+    /// samples of the block not written yet
+    block: Vec<i64>,
+    /// frames written
+    frames: u64,
+    samples: u64,
+    finished: bool,
+}
+
+impl FlacSampleWriter<BufWriter<File>> {
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: &AcousticConfig,
+    ) -> Result<FlacSampleWriter<BufWriter<File>>> {
+        FlacSampleWriter::new(BufWriter::new(File::create(path)?), config)
+    }
+}
+
+impl<W: Write + Seek> FlacSampleWriter<W> {
+    /// Write the stream header to `output` right away.
+    pub fn new(mut output: W, config: &AcousticConfig) -> Result<FlacSampleWriter<W>> {
+        let sample_rate = config.sample_rate as u64;
+        let mut header = BitWriter::default();
+        header.write_bytes(b"fLaC");
+        // the last metadata block, a stream info of 34 bytes
+        header.write(1, 1);
+        header.write(0, 7);
+        header.write(34, 24);
+        header.write(BLOCK_SIZE as u64, 16);
+        header.write(BLOCK_SIZE as u64, 16);
+        // frame sizes unknown
+        header.write(0, 24);
+        header.write(0, 24);
+        write_format(&mut header, sample_rate, 0);
+        // no MD5
+        header.write_bytes(&[0; 16]);
+        output.write_all(header.bytes())?;
+        Ok(FlacSampleWriter {
+            output,
+            sample_rate,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            frames: 0,
+            samples: 0,
+            finished: false,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    fn write_frame(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let mut frame = BitWriter::default();
+        // sync code, fixed block size
+        frame.write(0xfff8, 16);
+        // block size in 16 bits at the end of the header, sample rate of the stream info,
+        // mono, 16 bits per sample
+        frame.write(0b0111, 4);
+        frame.write(0b0000, 4);
+        frame.write(0b0000, 4);
+        frame.write(0b100, 3);
+        frame.write(0, 1);
+        frame.write_bytes(&utf8_number(self.frames));
+        frame.write(self.block.len() as u64 - 1, 16);
+        let checksum = crc8(frame.bytes());
+        frame.write(checksum as u64, 8);
+        write_subframe(&mut frame, &self.block);
+        frame.align();
+        let checksum = crc16(frame.bytes());
+        frame.write(checksum as u64, 16);
+        self.output.write_all(frame.bytes())?;
+        self.frames += 1;
+        self.samples += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> SampleSink for FlacSampleWriter<W> {
+    /// Samples written after `finish` are dropped.
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        let full_scale = ((1 << (BITS_PER_SAMPLE - 1)) - 1) as f64;
+        for x in samples {
+            self.block
+                .push((x.clamp(-1.0, 1.0) * full_scale).round() as i64);
+            if self.block.len() == BLOCK_SIZE {
+                self.write_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last block and the number of samples into the stream info.
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_frame()?;
+        let mut format = BitWriter::default();
+        write_format(&mut format, self.sample_rate, self.samples);
+        self.output.seek(SeekFrom::Start(STREAM_INFO_TOTAL))?;
+        self.output.write_all(format.bytes())?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// the 64 bits of the stream info from the sample rate to the number of samples
+fn write_format(bits: &mut BitWriter, sample_rate: u64, samples: u64) {
+    bits.write(sample_rate, 20);
+    // mono
+    bits.write(0, 3);
+    bits.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    bits.write(samples, 36);
+}
+
+/// A subframe with the fixed predictor that takes the fewest bits for `block`.
+fn write_subframe(bits: &mut BitWriter, block: &[i64]) {
+    let (order, residuals, parameter) = (0..=4.min(block.len() - 1))
+        .map(|order| {
+            let residuals = fixed_residuals(block, order);
+            let (parameter, size) = rice_parameter(&residuals);
+            (
+                size + order as u64 * BITS_PER_SAMPLE as u64,
+                order,
+                residuals,
+                parameter,
+            )
+        })
+        .min_by_key(|(size, ..)| *size)
+        .map(|(_, order, residuals, parameter)| (order, residuals, parameter))
+        .expect("a block has samples");
+    // padding, a fixed predictor, no wasted bits
+    bits.write(0, 1);
+    bits.write(0b001000 | order as u64, 6);
+    bits.write(0, 1);
+    for sample in &block[..order] {
+        bits.write(*sample as u64, BITS_PER_SAMPLE);
+    }
+    // 4 bit Rice parameters, a single partition
+    bits.write(0, 2);
+    bits.write(0, 4);
+    bits.write(parameter as u64, 4);
+    for residual in residuals {
+        let folded = fold(residual);
+        let quotient = folded >> parameter;
+        for _ in 0..quotient {
+            bits.write(0, 1);
+        }
+        bits.write(1, 1);
+        bits.write(folded, parameter);
+    }
+}
+
+/// what the fixed predictor of `order` leaves of the samples after the first `order`
+fn fixed_residuals(block: &[i64], order: usize) -> Vec<i64> {
+    let mut residuals = block.to_vec();
+    for _ in 0..order {
+        residuals = residuals.windows(2).map(|w| w[1] - w[0]).collect();
+    }
+    residuals
+}
+
+/// zigzag, so that small magnitudes of either sign are small
+fn fold(residual: i64) -> u64 {
+    ((residual << 1) ^ (residual >> 63)) as u64
+}
+
+/// the Rice parameter that codes `residuals` in the fewest bits, and how many
+fn rice_parameter(residuals: &[i64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|parameter| {
+            let size = residuals
+                .iter()
+                .map(|residual| (fold(*residual) >> parameter) + 1 + parameter as u64)
+                .sum::<u64>();
+            (parameter, size)
+        })
+        .min_by_key(|(_, size)| *size)
+        .expect("parameters to choose from")
+}
+
+/// the frame number as FLAC codes it, like UTF-8 but up to 36 bits
+fn utf8_number(n: u64) -> Vec<u8> {
+    if n < 0x80 {
+        return vec![n as u8];
+    }
+    // continuation bytes carry 6 bits each, the first byte what is left
+    let mut continuation = 1;
+    while n >> (6 * continuation) >= 1 << (6 - continuation) {
+        continuation += 1;
+    }
+    let lead = (0xff00_u16 >> (continuation + 1)) as u8 | (n >> (6 * continuation)) as u8;
+    let mut bytes = vec![lead];
+    bytes.extend(
+        (0..continuation)
+            .rev()
+            .map(|i| 0x80 | ((n >> (6 * i)) & 0x3f) as u8),
+    );
+    bytes
+}
+
+/// CRC-8 of FLAC frame headers, polynomial 0x07
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x07,
+        })
+    })
+}
+
+/// CRC-16 of FLAC frames, polynomial 0x8005
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x8005,
+        })
+    })
+}
+
+/// Collects bits most significant first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// bits of the last byte in use, 0 if it is full
+    used: u32,
+}
+
+impl BitWriter {
+    /// the lowest `count` bits of `value`
+    fn write(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().expect("a byte was pushed") |= bit << (7 - self.used);
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+
+    /// pad the last byte with zeros
+    fn align(&mut self) {
+        self.used = 0;
+    }
+
+    /// whole bytes so far, the last one padded
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[test]
+fn test_flac() {
+    use std::io::Cursor;
+
+    use crate::{
+        transmission::{MemoryReader, Receiver},
+        transmitter::modulate_message,
+    };
+
+    assert_eq!(ExportFormat::from_path("talk.FLAC"), ExportFormat::Flac);
+    assert_eq!(ExportFormat::from_path("talk.wav"), ExportFormat::Wav);
+    assert_eq!(ExportFormat::from_path("talk.oga"), ExportFormat::Ogg);
+    assert_eq!(utf8_number(0x7f), [0x7f]);
+    assert_eq!(utf8_number(0x80), [0xc2, 0x80]);
+    assert_eq!(utf8_number(0x800), [0xe0, 0xa0, 0x80]);
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &modulate_message(&config, b"hello podcast").unwrap(),
+        &silence,
+    ]
+    .concat();
+    let mut captured = Vec::new();
+    write_stream(&config, signal.iter().copied(), &mut captured).unwrap();
+    let mut writer = FlacSampleWriter::new(Cursor::new(Vec::new()), &config).unwrap();
+    write_stream(&config, signal, &mut writer).unwrap();
+    let encoded = writer.into_inner().into_inner();
+    // smaller than 16 bit WAV
+    assert!(encoded.len() < 2 * captured.len());
+
+    let mut reader = claxon::FlacReader::new(Cursor::new(encoded)).unwrap();
+    let info = reader.streaminfo();
+    assert_eq!(info.sample_rate, config.sample_rate as u32);
+    assert_eq!(info.samples, Some(captured.len() as u64));
+    let decoded = reader
+        .samples()
+        .map(|x| x.unwrap() as f64 / i16::MAX as f64)
+        .collect::<Vec<f64>>();
+    assert_eq!(decoded.len(), captured.len());
+    assert!(decoded
+        .iter()
+        .zip(&captured)
+        .all(|(a, b)| (a - b).abs() <= 0.5 / i16::MAX as f64));
+
+    let mut receiver = Receiver::with_config(Box::new(MemoryReader(decoded)), config);
+    assert_eq!(receiver.run().unwrap(), b"hello podcast");
+}
+
+#[test]
+fn test_ogg() {
+    use std::io::Cursor;
+
+    use lewton::inside_ogg::OggStreamReader;
+
+    use crate::{
+        transmission::{MemoryReader, Receiver},
+        transmitter::modulate_message,
+    };
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &modulate_message(&config, b"hello podcast").unwrap(),
+        &silence,
+    ]
+    .concat();
+    let mut captured = Vec::new();
+    write_stream(&config, signal.iter().copied(), &mut captured).unwrap();
+    let seconds = captured.len() as f64 / config.sample_rate;
+
+    for bitrate in [64_000, 32_000] {
+        let mut writer =
+            VorbisSampleWriter::new(Cursor::new(Vec::new()), &config, bitrate).unwrap();
+        write_stream(&config, signal.iter().copied(), &mut writer).unwrap();
+        let encoded = writer.into_inner().into_inner();
+        // the headers take a few hundred bytes
+        assert!((8 * encoded.len()) as f64 <= bitrate as f64 * seconds + 8000.0);
+
+        let mut reader = OggStreamReader::new(Cursor::new(encoded)).unwrap();
+        assert_eq!(reader.ident_hdr.audio_channels, 1);
+        assert_eq!(
+            reader.ident_hdr.audio_sample_rate,
+            config.sample_rate as u32
+        );
+        assert_eq!(reader.ident_hdr.bitrate_nominal, bitrate as i32);
+        let mut decoded = Vec::new();
+        while let Some(samples) = reader.read_dec_packet_itl().unwrap() {
+            decoded.extend(samples.into_iter().map(|x| x as f64 / i16::MAX as f64));
+        }
+        // padded to whole blocks
+        assert!(decoded.len() >= captured.len());
+        decoded.truncate(captured.len());
+        let noise = decoded
+            .iter()
+            .zip(&captured)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f64>();
+        let power = captured.iter().map(|x| x * x).sum::<f64>();
+        assert!(10.0 * (power / noise).log10() > 20.0);
+
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(decoded)), config.clone());
+        assert_eq!(receiver.run().unwrap(), b"hello podcast");
+    }
+}
@@ -0,0 +1,183 @@
+//! # Receive filters
+//!
+//! Filters that run on recorded samples before the receiver looks at them. `HighPass` runs
+//! on the samples as they are recorded and keeps its state from one block to the next. `BandPassReader`
+//! filters whatever window the `Receiver` asks for, so it does not mind going back.
+
+use std::f64::consts::PI;
+
+use crate::{
+    error::{AcousticError, Result},
+    transmission::SampleReader,
+};
+
+/// First order high-pass, a DC blocker. Built-in microphones often record with an offset
+/// and pick up rumble from fans and footsteps, far below any of our tones.
+#[derive(Debug, Clone)]
+pub struct HighPass {
+    /// pole of the filter, closer to 1 for a lower cutoff
+    r: f64,
+    previous_input: f64,
+    previous_output: f64,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f64, sample_rate: f64) -> HighPass {
+        HighPass {
+            r: (-2.0 * PI * cutoff / sample_rate).exp(),
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        input
+            .iter()
+            .map(|x| {
+                let x = *x as f64;
+                let y = x - self.previous_input + self.r * self.previous_output;
+                self.previous_input = x;
+                self.previous_output = y;
+                y as f32
+            })
+            .collect()
+    }
+}
+
+/// Hz between the outermost tones and the edges of an automatic pass band, more than half
+/// the transition of the band-pass filter.
+pub const BAND_MARGIN: f64 = 500.0;
+
+/// taps of the band-pass filter, odd so that it has a center
+const BAND_PASS_TAPS: usize = 255;
+
+/// Windowed-sinc band-pass from `low` to `high` Hz, a low-pass at `high` minus one at `low`.
+pub fn band_pass_taps(low: f64, high: f64, sample_rate: f64) -> Vec<f64> {
+    let center = (BAND_PASS_TAPS / 2) as f64;
+    let low_pass = |cutoff: f64, n: f64| match n {
+        0.0 => 2.0 * cutoff / sample_rate,
+        n => (2.0 * PI * cutoff / sample_rate * n).sin() / (PI * n),
+    };
+    (0..BAND_PASS_TAPS)
+        .map(|i| {
+            let n = i as f64 - center;
+            let hamming = 0.54 - 0.46 * (2.0 * PI * i as f64 / (BAND_PASS_TAPS - 1) as f64).cos();
+            (low_pass(high, n) - low_pass(low, n)) * hamming
+        })
+        .collect()
+}
+
+/// A `SampleReader` whose samples are band-passed, not delayed.
+pub struct BandPassReader {
+    inner: Box<dyn SampleReader>,
+    taps: Vec<f64>,
+    /// what the filter is run over, kept between reads
+    input: Vec<f64>,
+}
+
+impl BandPassReader {
+    pub fn new(inner: Box<dyn SampleReader>, low: f64, high: f64, sample_rate: f64) -> Self {
+        BandPassReader {
+            inner,
+            taps: band_pass_taps(low, high, sample_rate),
+            input: Vec::new(),
+        }
+    }
+}
+
+impl SampleReader for BandPassReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        let half = self.taps.len() / 2;
+        let from = start.saturating_sub(half);
+        // past the end of a file, the filter sees silence
+        match self.inner.read_samples(from, end + half, &mut self.input) {
+            Err(AcousticError::EndOfStream) => {
+                self.inner.read_samples(from, end, &mut self.input)?
+            }
+            read => read?,
+        }
+        let input = &self.input;
+        buffer.clear();
+        buffer.extend((start..end).map(|i| {
+            self.taps
+                .iter()
+                .enumerate()
+                .filter_map(|(k, tap)| {
+                    let j = (i + half).checked_sub(k)?.checked_sub(from)?;
+                    input.get(j).map(|x| x * tap)
+                })
+                .sum::<f64>()
+        }));
+        Ok(())
+    }
+
+    /// keep what the filter needs before `until`
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.inner
+            .consume(until.saturating_sub(self.taps.len() / 2))
+    }
+}
+
+#[test]
+fn test_band_pass() {
+    use crate::{goertzel::GoertzelBank, transmission::MemoryReader};
+
+    let sample_rate = 44100.0;
+    let freqs = [300.0, 2000.0, 4000.0, 8000.0];
+    let signal = (0..8820)
+        .map(|i| {
+            let t = i as f64 / sample_rate;
+            freqs.iter().map(|f| (2.0 * PI * f * t).sin()).sum()
+        })
+        .collect::<Vec<f64>>();
+    let mut reader =
+        BandPassReader::new(Box::new(MemoryReader(signal)), 1000.0, 5000.0, sample_rate);
+    let bank = GoertzelBank::new(&freqs, sample_rate);
+    let amplitudes = bank.amplitudes(&reader.take_samples(2000, 6000).unwrap());
+    assert!(
+        amplitudes[0] < 0.01 && amplitudes[3] < 0.01,
+        "{:?}",
+        amplitudes
+    );
+    assert!(
+        amplitudes[1] > 0.45 && amplitudes[2] > 0.45,
+        "{:?}",
+        amplitudes
+    );
+    // up to the very end, and windows that overlap agree
+    assert_eq!(reader.take_samples(8000, 8820).unwrap().len(), 820);
+    assert_eq!(
+        reader.take_samples(3000, 3100).unwrap(),
+        reader.take_samples(2900, 3200).unwrap()[100..200]
+    );
+}
+
+#[test]
+fn test_high_pass() {
+    let sample_rate = 44100.0;
+    let tone = |freq: f64, offset: f64| {
+        (0..44100)
+            .map(|i| (offset + (2.0 * PI * freq * i as f64 / sample_rate).sin()) as f32)
+            .collect::<Vec<f32>>()
+    };
+    let peak = |signal: &[f32]| signal[22050..].iter().fold(0.0_f32, |m, x| m.max(x.abs()));
+
+    // in pieces, as the callbacks hand them over
+    let mut filter = HighPass::new(200.0, sample_rate);
+    let filtered = tone(2000.0, 0.3)
+        .chunks(441)
+        .flat_map(|chunk| filter.process(chunk))
+        .collect::<Vec<f32>>();
+    let mean = filtered[22050..].iter().sum::<f32>() / 22050.0;
+    assert!(mean.abs() < 1e-3, "offset {}", mean);
+    assert!(peak(&filtered) > 0.95);
+
+    let rumble = HighPass::new(200.0, sample_rate).process(&tone(20.0, 0.0));
+    assert!(peak(&rumble) < 0.15, "rumble {}", peak(&rumble));
+}
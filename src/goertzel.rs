@@ -0,0 +1,104 @@
+//! # Goertzel filter bank
+//!
+//! The receiver only ever looks for a handful of known tones, so instead of computing a
+//! whole spectrum we run one Goertzel filter per tone. That is a couple of multiplications
+//! per sample and tone, and it works on blocks of any length.
+
+use std::{borrow::Cow, f64::consts::PI};
+
+use crate::vector;
+
+#[derive(Debug, Clone)]
+pub struct GoertzelBank {
+    freqs: Vec<f64>,
+    coeffs: Vec<f64>,
+    /// the window of blocks of its length, computed once, see `with_block_len`
+    window: Vec<f64>,
+}
+
+impl GoertzelBank {
+    pub fn new(freqs: &[f64], sample_rate: f64) -> GoertzelBank {
+        GoertzelBank {
+            freqs: freqs.to_vec(),
+            coeffs: freqs
+                .iter()
+                .map(|f| 2.0 * (2.0 * PI * f / sample_rate).cos())
+                .collect(),
+            window: Vec::new(),
+        }
+    }
+
+    /// keep the window of blocks of `len` samples, for banks run on many blocks that long
+    pub fn with_block_len(mut self, len: usize) -> GoertzelBank {
+        self.window = hanning(len);
+        self
+    }
+
+    pub fn freqs(&self) -> &[f64] {
+        &self.freqs
+    }
+
+    /// Amplitude of every tone in `block`, a full-scale sine reads about 1. The block is
+    /// Hanning windowed, so tones a few bins apart do not leak into each other.
+    pub fn amplitudes(&self, block: &[f64]) -> Vec<f64> {
+        if block.is_empty() {
+            return vec![0.0; self.coeffs.len()];
+        }
+        let window = match block.len() == self.window.len() {
+            true => Cow::Borrowed(&self.window),
+            false => Cow::Owned(hanning(block.len())),
+        };
+        let windowed = vector::multiply(block, &window);
+        let gain = window.iter().sum::<f64>() / 2.0;
+        self.coeffs
+            .iter()
+            .map(|coeff| goertzel(*coeff, &windowed) / gain)
+            .collect()
+    }
+}
+
+/// magnitude of the DTFT of `samples` at the frequency `coeff` was made for
+fn goertzel(coeff: f64, samples: &[f64]) -> f64 {
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0).sqrt()
+}
+
+/// symmetric Hann window of `len` samples
+pub(crate) fn hanning(len: usize) -> Vec<f64> {
+    if len == 1 {
+        return vec![1.0];
+    }
+    (0..len)
+        .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f64 / (len - 1) as f64).cos())
+        .collect()
+}
+
+#[test]
+fn test_goertzel_bank() {
+    use dasp::{signal, Signal};
+
+    let freqs = [1378.125, 2067.1875, 2583.984375];
+    let bank = GoertzelBank::new(&freqs, 44100.0);
+    let tone = signal::rate(44100.0)
+        .const_hz(freqs[1])
+        .sine()
+        .take(256)
+        .map(|x| x * 0.5)
+        .collect::<Vec<f64>>();
+    let amplitudes = bank.amplitudes(&tone);
+    assert!((amplitudes[1] - 0.5).abs() < 0.05, "{:?}", amplitudes);
+    assert!(
+        amplitudes[0] < 0.01 && amplitudes[2] < 0.01,
+        "{:?}",
+        amplitudes
+    );
+    assert_eq!(bank.amplitudes(&[]), [0.0; 3]);
+    // the same with the window kept
+    let kept = GoertzelBank::new(&freqs, 44100.0).with_block_len(tone.len());
+    assert_eq!(kept.amplitudes(&tone), amplitudes);
+}
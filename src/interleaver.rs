@@ -0,0 +1,69 @@
+//! # Block interleaver
+//!
+//! A clap or a key press wipes out several symbols in a row, far more flipped bits than one
+//! Hamming codeword can correct. The interleaver writes the encoded bits row by row into a
+//! block of `depth` rows and sends them column by column, so bits that are neighbours on air
+//! sit a whole row apart in the codewords. A burst of up to `depth` bits then flips at most
+//! one bit per row.
+
+/// Reorder the bits of `data` for sending. A depth of 1 leaves them as they are.
+pub fn interleave(data: &[u8], depth: usize) -> Vec<u8> {
+    let mut out = vec![0; data.len()];
+    for (to, from) in order(data.len() * 8, depth).enumerate() {
+        set_bit(&mut out, to, get_bit(data, from));
+    }
+    out
+}
+
+/// Undo `interleave` with the same depth.
+pub fn deinterleave(data: &[u8], depth: usize) -> Vec<u8> {
+    let mut out = vec![0; data.len()];
+    for (from, to) in order(data.len() * 8, depth).enumerate() {
+        set_bit(&mut out, to, get_bit(data, from));
+    }
+    out
+}
+
+/// index of the input bit sent at each position, the last column may be short
+fn order(len: usize, depth: usize) -> impl Iterator<Item = usize> {
+    let depth = depth.clamp(1, len.max(1));
+    let columns = len.div_ceil(depth);
+    (0..columns).flat_map(move |column| {
+        (0..depth)
+            .map(move |row| row * columns + column)
+            .filter(move |i| *i < len)
+    })
+}
+
+fn get_bit(data: &[u8], i: usize) -> u8 {
+    (data[i / 8] >> (i % 8)) & 1
+}
+
+fn set_bit(data: &mut [u8], i: usize, bit: u8) {
+    data[i / 8] |= bit << (i % 8);
+}
+
+#[test]
+fn test_interleave_roundtrip() {
+    let data = (0..=200).collect::<Vec<u8>>();
+    for depth in [1, 2, 7, 16, 100, 5000] {
+        let interleaved = interleave(&data, depth);
+        assert_eq!(deinterleave(&interleaved, depth), data, "depth={depth}");
+    }
+    assert_eq!(interleave(&data, 1), data);
+    assert!(interleave(&[], 8).is_empty());
+}
+
+#[test]
+fn test_interleave_spreads_bursts() {
+    use crate::{config::Fec, fec};
+
+    let data = b"hello world".to_vec();
+    let mut interleaved = interleave(&fec::encode(Fec::Hamming74, &data), 16);
+    // four symbols worth of bits flipped in a row
+    for i in 40..56 {
+        interleaved[i / 8] ^= 1 << (i % 8);
+    }
+    let encoded = deinterleave(&interleaved, 16);
+    assert_eq!(fec::decode(Fec::Hamming74, &encoded, data.len()), data);
+}
@@ -0,0 +1,129 @@
+//! # Keepalive
+//!
+//! A link over sound breaks without a word: someone walks between the devices, or the
+//! other one is carried out of the room. While a session is idle nothing would tell, so
+//! each end plays a tiny `PacketKind::Control` packet every `interval` it has not sent
+//! anything else, see `Keepalive`. The other end counts every packet it hears as a sign of
+//! life, see `Liveness`, and once none came for a few intervals the `Receiver` reports
+//! `Event::LinkDown`, once, until the peer is heard again.
+
+use std::time::{Duration, Instant};
+
+use crate::{Packet, PacketKind};
+
+/// first byte of a keepalive, see `PacketKind::Control`
+pub const KEEPALIVE: u8 = 32;
+
+/// intervals without a packet before the link is down
+pub const MISSED_KEEPALIVES: u32 = 3;
+
+/// the packet to play when idle
+pub fn keepalive_packet() -> Packet {
+    Packet::new(PacketKind::Control, 0, &[KEEPALIVE])
+}
+
+pub fn is_keepalive(packet: &Packet) -> bool {
+    packet.kind == PacketKind::Control && packet.data.first() == Some(&KEEPALIVE)
+}
+
+/// When the sending end of an idle link is due to play a keepalive.
+pub struct Keepalive {
+    interval: Duration,
+    last_sent: Instant,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration) -> Keepalive {
+        Keepalive {
+            interval,
+            last_sent: Instant::now(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// whether nothing went out for an interval
+    pub fn due(&self) -> bool {
+        self.last_sent.elapsed() >= self.interval
+    }
+
+    /// anything sent counts, not only keepalives
+    pub fn sent(&mut self) {
+        self.last_sent = Instant::now();
+    }
+}
+
+/// Whether the peer was heard lately.
+pub struct Liveness {
+    timeout: Duration,
+    last_heard: Instant,
+    /// `LinkDown` was reported since the peer was last heard
+    reported: bool,
+}
+
+impl Liveness {
+    /// The link is down once nothing came for `timeout`, counting from now.
+    pub fn new(timeout: Duration) -> Liveness {
+        Liveness {
+            timeout,
+            last_heard: Instant::now(),
+            reported: false,
+        }
+    }
+
+    /// down after `MISSED_KEEPALIVES` keepalives of a peer sending every `interval`
+    pub fn for_interval(interval: Duration) -> Liveness {
+        Self::new(interval * MISSED_KEEPALIVES)
+    }
+
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+        self.reported = false;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.last_heard.elapsed() < self.timeout
+    }
+
+    /// Whether the link just went down, true once until the peer is heard again.
+    pub fn went_down(&mut self) -> bool {
+        if self.is_alive() || self.reported {
+            return false;
+        }
+        self.reported = true;
+        true
+    }
+}
+
+#[test]
+fn test_keepalive() {
+    let packet = keepalive_packet();
+    assert!(is_keepalive(&packet));
+    assert!(!is_keepalive(&Packet::ack(0)));
+    assert!(!is_keepalive(&crate::session::Handshake::Start.to_packet()));
+
+    let mut keepalive = Keepalive::new(Duration::from_millis(20));
+    assert!(!keepalive.due());
+    std::thread::sleep(Duration::from_millis(25));
+    assert!(keepalive.due());
+    keepalive.sent();
+    assert!(!keepalive.due());
+}
+
+#[test]
+fn test_liveness() {
+    let mut liveness = Liveness::for_interval(Duration::from_millis(10));
+    assert!(liveness.is_alive());
+    assert!(!liveness.went_down());
+    std::thread::sleep(Duration::from_millis(35));
+    assert!(!liveness.is_alive());
+    assert!(liveness.went_down());
+    // reported once
+    assert!(!liveness.went_down());
+    liveness.heard();
+    assert!(liveness.is_alive());
+    std::thread::sleep(Duration::from_millis(35));
+    assert!(liveness.went_down());
+}
@@ -1,5 +1,9 @@
 pub mod recorder;
+pub mod resampler;
 
+use error::{AcousticError, FrameError, Result};
+
+#[cfg(test)]
 const TEST_DATA: &str = "WHAT is truth? said jesting Pilate and would not stay for an answer. Certainly there be that delight";
 
 /// To transmit data, we need to encode them to a byte array first.
@@ -20,31 +24,197 @@ fn test_encode_decode() {
     assert_eq!(data, decoded);
 }
 
+/// what a packet carries, so that control traffic shares the physical layer with data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    /// a piece of a message
+    Data = 0,
+    /// `order` is the next packet the receiver expects, see `arq`
+    Ack = 1,
+    /// the first byte of the payload tells what for, see `session`, `pairing`, `keepalive`
+    /// and `message`
+    Control = 2,
+    /// the first byte of the payload tells what it announces, see `tdma` and `discovery`
+    Beacon = 3,
+}
+
+impl TryFrom<u8> for PacketKind {
+    type Error = AcousticError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(PacketKind::Data),
+            1 => Ok(PacketKind::Ack),
+            2 => Ok(PacketKind::Control),
+            3 => Ok(PacketKind::Beacon),
+            _ => Err(AcousticError::MalformedPacket(format!(
+                "unknown packet kind {}",
+                value
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Packet {
+    pub kind: PacketKind,
+    /// address of the sender
+    pub source: u8,
+    /// address of the receiver, or `BROADCAST`
+    pub destination: u8,
+    /// logical channel at the receiver, see `ports`
+    pub port: u8,
     pub order: usize,
     pub data: Vec<u8>,
 }
 
 impl From<(usize, &[u8])> for Packet {
     fn from((order, data): (usize, &[u8])) -> Self {
-        Self {
+        Self::new(PacketKind::Data, order, data)
+    }
+}
+
+impl Packet {
+    /// Longer data are splitted to multiple packets, here is the threshold(in bytes) unless
+    /// split to another size, see `new_packets_sized` and `AcousticConfig::packet_size`
+    pub const MAX_PACKET_SIZE: usize = 128;
+
+    /// The largest packet size the length field of the header holds, with the tag of an
+    /// encrypted payload, see `crypto`.
+    pub const LARGEST_PACKET_SIZE: usize = u16::MAX as usize - crypto::TAG_SIZE;
+
+    /// A sealed packet starts with `MAGIC`, `VERSION`, its kind, source, destination and
+    /// port, then its order and its length as little endian `u16`s, the same on every
+    /// platform, and a CRC-8 of all that, see `header_checksum`.
+    pub const HEADER_SIZE: usize = 11;
+
+    /// First byte of every sealed packet. Bits 2 and 3 are never both set, OFDM subcarriers
+    /// on both sides of a preamble tone would make the first symbol look like preamble.
+    pub const MAGIC: u8 = 0xa5;
+
+    /// layout of the header we send, bumped whenever it changes
+    pub const VERSION: u8 = 5;
+
+    /// Oldest layout of the header we still read. Peers agree on a version both read
+    /// during the handshake, see `session`. Version 3 headers had no port and version 4
+    /// headers no checksum, each a byte shorter, which the receiver cannot tell before it
+    /// demodulated them.
+    pub const MIN_VERSION: u8 = 5;
+
+    /// destination of packets every receiver takes
+    pub const BROADCAST: u8 = 0xff;
+
+    pub fn new(kind: PacketKind, order: usize, data: &[u8]) -> Packet {
+        Packet {
+            kind,
+            source: 0,
+            destination: Self::BROADCAST,
+            port: 0,
             order,
             data: data.to_vec(),
         }
     }
-}
 
-impl Packet {
-    /// Longer data are splitted to multiple packets, here is the threshold(in bytes)
-    const MAX_PACKET_SIZE: usize = 128;
+    /// the same packet, sent from `source` to `destination`
+    pub fn addressed(self, source: u8, destination: u8) -> Packet {
+        Packet {
+            source,
+            destination,
+            ..self
+        }
+    }
+
+    /// the same packet, to `port` instead of 0
+    pub fn on_port(self, port: u8) -> Packet {
+        Packet { port, ..self }
+    }
+
+    /// acknowledge every packet before `next_expected`
+    pub fn ack(next_expected: usize) -> Packet {
+        Self::new(PacketKind::Ack, next_expected, &[])
+    }
 
     /// split a long long data to packets
+    ///
+    /// The last packet is always shorter than `MAX_PACKET_SIZE`, so the receiver knows
+    /// where a message ends. If the data fills up every packet, an empty one is appended.
     pub fn new_packets(v: &[u8]) -> Vec<Packet> {
-        v.chunks(Self::MAX_PACKET_SIZE)
-            .enumerate()
-            .map(Packet::from)
-            .collect()
+        Self::new_packets_sized(v, Self::MAX_PACKET_SIZE)
+    }
+
+    /// Like `new_packets`, `size` bytes to a packet. Small packets cost less to send again
+    /// on a noisy link, large ones spend less on headers and preambles on a quiet one.
+    /// Sizes out of range are taken as the closest one in it, see `check_packet_size`.
+    pub fn new_packets_sized(v: &[u8], size: usize) -> Vec<Packet> {
+        let size = size.clamp(1, Self::LARGEST_PACKET_SIZE);
+        let mut packets: Vec<Packet> = v.chunks(size).enumerate().map(Packet::from).collect();
+        if v.len().is_multiple_of(size) {
+            packets.push(Packet::from((packets.len(), &[][..])));
+        }
+        packets
+    }
+
+    /// `AcousticError::InvalidPacketSize` unless packets of `size` bytes fit the header,
+    /// from 1 to `LARGEST_PACKET_SIZE`
+    pub fn check_packet_size(size: usize) -> Result<()> {
+        match (1..=Self::LARGEST_PACKET_SIZE).contains(&size) {
+            true => Ok(()),
+            false => Err(AcousticError::InvalidPacketSize(size)),
+        }
+    }
+
+    /// `AcousticError::InvalidPacketOrder` unless `order` fits the header, up to `u16::MAX`,
+    /// rather than repeat the order of an earlier packet
+    pub fn check_order(order: usize) -> Result<()> {
+        match u16::try_from(order) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(AcousticError::InvalidPacketOrder(order)),
+        }
+    }
+
+    /// whether this packet ends a message
+    pub fn is_last(&self) -> bool {
+        self.ends_message(Self::MAX_PACKET_SIZE)
+    }
+
+    /// whether this packet ends a message split to `size` bytes a packet
+    pub fn ends_message(&self, size: usize) -> bool {
+        self.data.len() < size.clamp(1, Self::LARGEST_PACKET_SIZE)
+    }
+
+    /// CRC-8 (polynomial 0x07) of the header fields. It catches every burst of up to 8 bit
+    /// errors, so a corrupted length never makes the receiver demodulate a payload that is
+    /// not there and miss the frames after it.
+    fn header_checksum(fields: &[u8]) -> u8 {
+        fields.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            })
+        })
+    }
+
+    /// Read the payload length out of a sealed header, checking that it is one of ours and
+    /// in one piece.
+    pub fn payload_len(header: &[u8]) -> std::result::Result<usize, FrameError> {
+        let Some(header) = header.first_chunk::<{ Self::HEADER_SIZE }>() else {
+            return Err(FrameError::TruncatedHeader { len: header.len() });
+        };
+        if header[0] != Self::MAGIC {
+            return Err(FrameError::BadMagic(header[0]));
+        }
+        if !(Self::MIN_VERSION..=Self::VERSION).contains(&header[1]) {
+            return Err(FrameError::UnsupportedVersion(header[1]));
+        }
+        let (fields, found) = header.split_at(Self::HEADER_SIZE - 1);
+        let expected = Self::header_checksum(fields);
+        if found[0] != expected {
+            return Err(FrameError::HeaderChecksum {
+                expected,
+                found: found[0],
+            });
+        }
+        Ok(u16::from_le_bytes([header[8], header[9]]) as usize)
     }
 
     pub fn unpack(vp: &[Packet]) -> Vec<u8> {
@@ -56,27 +226,57 @@ impl Packet {
             .collect::<Vec<u8>>()
     }
 
-    fn seal_one(&self) -> Vec<u8> {
-        let mut packet = Vec::new();
-        packet.extend_from_slice(&self.order.to_le_bytes());
-        packet.extend_from_slice(&self.data.len().to_le_bytes());
+    fn seal_one(&self) -> Result<Vec<u8>> {
+        let mut packet = self.seal_header(self.data.len())?;
         packet.extend_from_slice(&self.data);
-        packet
+        Ok(packet)
     }
 
-    pub fn seal(s: &[Packet]) -> Vec<Vec<u8>> {
+    /// The header for a payload of `len` bytes, which always fits. An order that does not is
+    /// an error, see `check_order`.
+    fn seal_header(&self, len: usize) -> Result<Vec<u8>> {
+        Self::check_order(self.order)?;
+        let mut header = vec![
+            Self::MAGIC,
+            Self::VERSION,
+            self.kind as u8,
+            self.source,
+            self.destination,
+            self.port,
+        ];
+        header.extend_from_slice(&(self.order as u16).to_le_bytes());
+        header.extend_from_slice(&(len as u16).to_le_bytes());
+        header.push(Self::header_checksum(&header));
+        Ok(header)
+    }
+
+    pub fn seal(s: &[Packet]) -> Result<Vec<Vec<u8>>> {
         s.iter().map(Self::seal_one).collect()
     }
 
-    fn unseal_one(v: &[u8]) -> Self {
-        let order = usize::from_le_bytes(v[0..8].try_into().unwrap());
-        let len = usize::from_le_bytes(v[8..16].try_into().unwrap());
-        let data = v[16..16 + len].to_vec();
-        Self { order, data }
+    /// The packet a sealed frame carries. Whatever follows the payload, like a MAC, is
+    /// left out.
+    pub fn unseal_one(v: &[u8]) -> std::result::Result<Self, FrameError> {
+        let len = Self::payload_len(v)?;
+        let (header, payload) = v.split_at(Self::HEADER_SIZE);
+        let kind =
+            PacketKind::try_from(header[2]).map_err(|_| FrameError::UnknownKind(header[2]))?;
+        let data = payload.get(..len).ok_or(FrameError::LengthMismatch {
+            announced: len,
+            available: payload.len(),
+        })?;
+        Ok(Self {
+            kind,
+            source: header[3],
+            destination: header[4],
+            port: header[5],
+            order: u16::from_le_bytes([header[6], header[7]]) as usize,
+            data: data.to_vec(),
+        })
     }
 
-    pub fn unseal(v: &[Vec<u8>]) -> Vec<Packet> {
-        v.iter().map(|x| Self::unseal_one(x)).collect()
+    pub fn unseal(v: &[Vec<u8>]) -> Result<Vec<Packet>> {
+        v.iter().map(|x| Ok(Self::unseal_one(x)?)).collect()
     }
 }
 
@@ -88,79 +288,445 @@ fn pack_unpack_test() {
     assert_eq!(data, decode(&unpacked));
 }
 
+#[test]
+fn pack_full_packets_test() {
+    let data = [7_u8; 256];
+    let packets = Packet::new_packets(&data);
+    assert_eq!(packets.len(), 3);
+    assert!(!packets[1].is_last());
+    assert!(packets[2].is_last());
+    assert_eq!(Packet::unpack(&packets), data);
+}
+
+#[test]
+fn pack_sized_test() {
+    let data = [7_u8; 100];
+    let packets = Packet::new_packets_sized(&data, 32);
+    assert_eq!(packets.len(), 4);
+    assert!(!packets[2].ends_message(32));
+    assert!(packets[3].ends_message(32));
+    assert_eq!(Packet::unpack(&packets), data);
+
+    assert!(Packet::check_packet_size(32).is_ok());
+    assert!(Packet::check_packet_size(Packet::LARGEST_PACKET_SIZE).is_ok());
+    for size in [0, Packet::LARGEST_PACKET_SIZE + 1] {
+        assert!(matches!(
+            Packet::check_packet_size(size),
+            Err(AcousticError::InvalidPacketSize(_))
+        ));
+    }
+    // the largest payload sealed, encrypted or not, still fits the length field
+    let largest = Packet::new_packets_sized(&[1; 70000], usize::MAX);
+    assert_eq!(largest[0].data.len(), Packet::LARGEST_PACKET_SIZE);
+    let key = crypto::Key::new([3; 32]);
+    let sealed = crypto::seal(&key, &largest[0]).unwrap();
+    assert_eq!(
+        Packet::payload_len(&sealed).unwrap(),
+        Packet::LARGEST_PACKET_SIZE + crypto::TAG_SIZE
+    );
+}
+
 #[test]
 fn pack_unseal_test() {
     let data = "hello world";
     let packets = Packet::new_packets(&encode(data));
-    let sealed = Packet::seal(&packets);
-    let unsealed = Packet::unseal(&sealed);
+    let sealed = Packet::seal(&packets).unwrap();
+    let unsealed = Packet::unseal(&sealed).unwrap();
     let unpacked = Packet::unpack(&unsealed);
     assert_eq!(data, decode(&unpacked));
 }
 
-use std::{fs::File, io::BufWriter};
+#[test]
+fn unseal_truncated_test() {
+    let mut sealed = Packet::seal(&Packet::new_packets(&encode("hello world"))).unwrap();
+    sealed[0].truncate(Packet::HEADER_SIZE + 5);
+    assert!(matches!(
+        Packet::unseal(&sealed),
+        Err(AcousticError::MalformedPacket(_))
+    ));
+    assert!(Packet::unseal(&[vec![0; 4]]).is_err());
+}
+
+#[test]
+fn seal_order_test() {
+    // the order would wrap around to that of the first packet
+    let last = Packet::from((u16::MAX as usize, &b"hi"[..]));
+    assert!(Packet::seal(std::slice::from_ref(&last)).is_ok());
+    let past = Packet::from((u16::MAX as usize + 1, &b"hi"[..]));
+    assert!(matches!(
+        Packet::seal(&[last, past.clone()]),
+        Err(AcousticError::InvalidPacketOrder(65536))
+    ));
+    assert!(
+        transmitter::Modulated::new(&AcousticConfig::default(), std::slice::from_ref(&past))
+            .is_err()
+    );
+    assert!(matches!(
+        transmitter::modulate_packets(&AcousticConfig::default(), &[past]),
+        Err(AcousticError::InvalidPacketOrder(65536))
+    ));
+}
+
+#[test]
+fn unseal_header_test() {
+    let sealed = Packet::seal(&[Packet::from((258, &b"hi"[..]))]).unwrap();
+    assert_eq!(
+        sealed[0],
+        [
+            Packet::MAGIC,
+            Packet::VERSION,
+            0,
+            0,
+            0xff,
+            0,
+            2,
+            1,
+            2,
+            0,
+            0x38,
+            b'h',
+            b'i'
+        ]
+    );
+    let ack = Packet::seal(&[Packet::ack(3).addressed(1, 2).on_port(7)]).unwrap();
+    assert_eq!(
+        ack[0],
+        [Packet::MAGIC, Packet::VERSION, 1, 1, 2, 7, 3, 0, 0, 0, 0x77]
+    );
+    let ack = &Packet::unseal(&ack).unwrap()[0];
+    assert_eq!(
+        (ack.kind, ack.source, ack.destination, ack.port),
+        (PacketKind::Ack, 1, 2, 7)
+    );
+
+    let mut bad_magic = sealed[0].clone();
+    bad_magic[0] = 0;
+    assert!(matches!(
+        Packet::unseal(&[bad_magic]),
+        Err(AcousticError::MalformedPacket(_))
+    ));
+    let mut future = sealed[0].clone();
+    future[1] = Packet::VERSION + 1;
+    assert!(matches!(
+        Packet::unseal(&[future]),
+        Err(AcousticError::UnsupportedVersion(_))
+    ));
+    let mut past = sealed[0].clone();
+    past[1] = Packet::MIN_VERSION - 1;
+    assert!(matches!(
+        Packet::unseal(&[past]),
+        Err(AcousticError::UnsupportedVersion(_))
+    ));
+    let mut unknown = sealed[0].clone();
+    unknown[2] = 9;
+    assert!(Packet::unseal(&[unknown]).is_err());
+}
+
+#[test]
+fn header_checksum_test() {
+    let sealed = Packet::seal(&[Packet::from((3, &b"hello"[..]))])
+        .unwrap()
+        .remove(0);
+    // a length grown by a flipped bit would swallow the next frame
+    let mut longer = sealed.clone();
+    longer[9] ^= 0x01;
+    assert!(matches!(
+        Packet::unseal_one(&longer),
+        Err(FrameError::HeaderChecksum { .. })
+    ));
+    assert!(matches!(
+        Packet::unseal(&[longer]),
+        Err(AcousticError::MalformedPacket(_))
+    ));
+    for bit in 0..8 * Packet::HEADER_SIZE {
+        let mut corrupted = sealed.clone();
+        corrupted[bit / 8] ^= 1 << (bit % 8);
+        assert!(Packet::unseal_one(&corrupted).is_err());
+    }
+    // the payload is left to the layers above
+    let mut payload = sealed.clone();
+    payload[Packet::HEADER_SIZE] ^= 0x01;
+    assert_eq!(Packet::unseal_one(&payload).unwrap().data, b"iello");
+}
+
+#[test]
+fn unseal_frame_error_test() {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    let sealed = Packet::seal(&[Packet::from((1, &b"hello"[..]))])
+        .unwrap()
+        .remove(0);
+    assert_eq!(
+        Packet::unseal_one(&sealed[..4]).unwrap_err(),
+        FrameError::TruncatedHeader { len: 4 }
+    );
+    assert_eq!(
+        Packet::unseal_one(&sealed[..Packet::HEADER_SIZE + 2]).unwrap_err(),
+        FrameError::LengthMismatch {
+            announced: 5,
+            available: 2
+        }
+    );
+    let mut bad_magic = sealed.clone();
+    bad_magic[0] = 0x5a;
+    assert_eq!(
+        Packet::unseal_one(&bad_magic).unwrap_err(),
+        FrameError::BadMagic(0x5a)
+    );
+    let mut unknown = sealed.clone();
+    unknown[2] = 9;
+    unknown[Packet::HEADER_SIZE - 1] = Packet::header_checksum(&unknown[..Packet::HEADER_SIZE - 1]);
+    assert_eq!(
+        Packet::unseal_one(&unknown).unwrap_err(),
+        FrameError::UnknownKind(9)
+    );
+
+    // every cut and any corruption is an error or a packet, never a panic
+    for len in 0..sealed.len() {
+        assert!(Packet::unseal_one(&sealed[..len]).is_err());
+    }
+    let mut rng = StdRng::seed_from_u64(5);
+    for _ in 0..1000 {
+        let mut frame = sealed.clone();
+        frame.truncate(rng.gen_range(0..=sealed.len()));
+        for byte in frame.iter_mut() {
+            if rng.gen_bool(0.2) {
+                *byte = rng.gen();
+            }
+        }
+        let _ = Packet::unseal_one(&frame);
+    }
+}
+
+use config::AcousticConfig;
+pub mod afsk;
+pub mod arq;
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod backoff;
+pub mod bench;
+pub mod ber;
+pub mod calibration;
+pub mod carrier_sense;
+pub mod config;
+pub mod crypto;
+pub mod cw;
+pub mod device;
+pub mod diagnostics;
+pub mod discovery;
+pub mod dtmf;
+pub mod duty_cycle;
+pub mod echo;
+pub mod error;
+pub mod export;
+pub mod fec;
+pub mod filter;
+pub mod goertzel;
+pub mod interleaver;
+pub mod keepalive;
+pub mod loopback;
+pub mod message;
+pub mod pairing;
+pub mod pcm;
 pub mod physics;
+pub mod ports;
+pub mod progress;
+pub mod queue;
+pub mod rate;
+pub mod ring_buffer;
+pub mod scrambler;
+pub mod session;
+pub mod simulator;
+pub mod spsc;
+pub mod stereo;
+pub mod stream;
+pub mod tdma;
+pub mod test_vectors;
+pub mod transceiver;
 pub mod transmission;
+pub mod transmitter;
+pub mod value;
+pub mod vector;
+pub mod vorbis;
+pub mod waterfall;
+pub mod wav_reader;
+pub mod wav_writer;
 
 /// Generate sound wave to carry the information.
-/// For first version, I will just use BPSK modulation.
-pub fn modulate(segments: Vec<Vec<u8>>) -> Vec<f64> {
-    segments.into_iter().flat_map(modulate_vector).collect()
+/// For first version, I will just use BPSK modulation, see `physics::bpsk_modulate`.
+pub fn modulate(config: &AcousticConfig, segments: Vec<Vec<u8>>) -> Vec<f64> {
+    physics::bpsk_modulate(config, &segments.concat())
 }
 
-fn modulate_vector(p: Vec<u8>) -> Vec<f64> {
-    p.into_iter().flat_map(modulate_byte).collect()
-}
-
-fn modulate_byte(b: u8) -> Vec<f64> {
-    (0..8)
-        .flat_map(|i| modulate_half_byte(b & (1 << i) >> i))
-        .collect()
+/// Recover the bytes of `modulate`, from a recording that starts where the sound does.
+pub fn demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    physics::bpsk_demodulate(config, signal)
 }
 
 #[test]
 fn test_modulate() {
     let data = "hello world";
-    let modulated = modulate(Packet::seal(&Packet::new_packets(&encode(data))));
-    assert_eq!(modulated.len(), 4400 * 8 * (16 + 11));
+    let modulated = modulate(
+        &AcousticConfig::default(),
+        Packet::seal(&Packet::new_packets(&encode(data))).unwrap(),
+    );
+    // one symbol per bit, after the phase reference symbol
+    assert_eq!(
+        modulated.len(),
+        AcousticConfig::default().sample_number() * (8 * (Packet::HEADER_SIZE + 11) + 1)
+    );
 }
 
 /// output the sound wave to a wav file
-pub fn output_wav(modulated: &[f64], filename: &str) {
+pub fn output_wav(config: &AcousticConfig, modulated: &[f64], filename: &str) -> Result<()> {
+    output_wav_stream(config, modulated.iter().copied(), filename)
+}
+
+/// Output a sound wave to a wav file as it is generated, see `transmitter::Modulated`.
+pub fn output_wav_stream(
+    config: &AcousticConfig,
+    modulated: impl IntoIterator<Item = f64>,
+    filename: &str,
+) -> Result<()> {
+    let mut writer = wav_writer::WavSampleWriter::create(filename, config)?;
+    transmitter::write_stream(config, modulated, &mut writer)
+}
+
+/// Output one sound wave per channel to a wav file, the shorter ones padded with silence,
+/// at the level they would be played at, see `transmitter::output_level`.
+pub fn output_wav_channels(
+    config: &AcousticConfig,
+    channels: &[&[f64]],
+    filename: &str,
+) -> Result<()> {
     let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 44100,
+        channels: channels.len() as u16,
+        sample_rate: config.sample_rate as u32,
         bits_per_sample: 32,
         sample_format: hound::SampleFormat::Float,
     };
-    let mut writer = hound::WavWriter::create(filename, spec).unwrap();
-    for sample in modulated {
-        writer.write_sample(*sample as f32).unwrap();
+    let mut writer = hound::WavWriter::create(filename, spec)?;
+    let channels = channels
+        .iter()
+        .map(|channel| transmitter::output_level(config, channel))
+        .collect::<Vec<Vec<f64>>>();
+    let frames = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+    for i in 0..frames {
+        for channel in &channels {
+            writer.write_sample(channel.get(i).copied().unwrap_or(0.0) as f32)?;
+        }
     }
-    writer.finalize().unwrap();
+    writer.finalize()?;
+    Ok(())
 }
 
 #[test]
 fn test_output_wav() {
     let data = TEST_DATA;
-    let modulated = modulate(Packet::seal(&Packet::new_packets(&encode(data))));
-    output_wav(&modulated, "test.wav");
+    let config = AcousticConfig::default();
+    let modulated = modulate(
+        &config,
+        Packet::seal(&Packet::new_packets(&encode(data))).unwrap(),
+    );
+    output_wav(&config, &modulated, "test.wav").unwrap();
+}
+
+#[test]
+fn test_output_wav_stream() {
+    let config = AcousticConfig::default();
+    let packets = Packet::new_packets(&[7; 300]);
+    let modulated = transmitter::modulate_packets(&config, &packets).unwrap();
+    let path = "test_stream.wav";
+    output_wav_stream(
+        &config,
+        transmitter::Modulated::new(&config, &packets).unwrap(),
+        path,
+    )
+    .unwrap();
+    let written = input_wav(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(written.len(), modulated.len());
+    assert!(written
+        .iter()
+        .zip(transmitter::output_level(&config, &modulated))
+        .all(|(a, b)| (a - b).abs() < 1e-6));
 }
 
 use hound::WavReader;
-use physics::modulate_half_byte;
-/// read the sound wave from a wav file
-pub fn input_wav(filename: &str) -> Vec<f64> {
-    let mut reader = WavReader::open(filename).unwrap();
-    let samples: Vec<f64> = reader.samples::<f32>().map(|x| x.unwrap() as f64).collect();
-    samples
+/// Read the sound wave from a wav file, floats or integers of any width, mixed down to mono
+/// and resampled to `transmission::SAMPLE_RATE` if recorded at another rate. For a file
+/// too long to keep in memory, see `wav_reader::WavSampleReader`.
+pub fn input_wav(filename: &str) -> Result<Vec<f64>> {
+    let mut reader = WavReader::open(filename)?;
+    let spec = reader.spec();
+    let len = reader.len() as usize;
+    let interleaved = wav_reader::read_normalized(&mut reader, len)?;
+    let output_rate = transmission::SAMPLE_RATE as u32;
+    let mut converter =
+        resampler::InputConverter::new(spec.channels as usize, spec.sample_rate, output_rate)?;
+    let mut samples = converter.process(&interleaved);
+    if spec.sample_rate != output_rate {
+        // push out what the resampler holds back, and cut its delay off the end
+        let frames = len / spec.channels.max(1) as usize;
+        let expected = (frames as f64 * output_rate as f64 / spec.sample_rate as f64).round();
+        while samples.len() < expected as usize {
+            samples.extend(converter.process(&vec![0.0; 1024 * spec.channels as usize]));
+        }
+        samples.truncate(expected as usize);
+    }
+    Ok(samples.into_iter().map(|x| x as f64).collect())
 }
 
 #[test]
 fn test_input_wav() {
     let data = "hello world";
-    let modulated = modulate(Packet::seal(&Packet::new_packets(&encode(data))));
-    output_wav(&modulated, "test.wav");
-    let input = input_wav("test.wav");
+    let config = AcousticConfig::default();
+    let modulated = modulate(
+        &config,
+        Packet::seal(&Packet::new_packets(&encode(data))).unwrap(),
+    );
+    output_wav(&config, &modulated, "test.wav").unwrap();
+    let input = input_wav("test.wav").unwrap();
     assert_eq!(modulated.len(), input.len());
 }
+
+#[test]
+fn test_input_wav_int() {
+    use transmission::{MemoryReader, Receiver, SAMPLE_RATE};
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &transmitter::modulate_message(&config, b"hello phone").unwrap(),
+        &silence,
+    ]
+    .concat();
+    // a phone recording at 48 kHz in 16 bits, and a 24 bit one at our rate
+    for (sample_rate, bits_per_sample) in [(48000, 16), (SAMPLE_RATE as u32, 24)] {
+        let path = format!("input_wav_{}.wav", bits_per_sample);
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let recorded = resampler::stretch(&signal, SAMPLE_RATE / sample_rate as f64);
+        let full_scale = ((1 << (bits_per_sample - 1)) - 1) as f64;
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for x in &recorded {
+            writer
+                .write_sample((0.5 * x * full_scale).round() as i32)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        let input = input_wav(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!((input.len() as f64 - signal.len() as f64).abs() <= 1.0);
+        let peak = input.iter().fold(0.0_f64, |peak, x| peak.max(x.abs()));
+        assert!((peak - 0.5).abs() < 0.05, "peak {}", peak);
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(input)), config.clone());
+        assert_eq!(receiver.run().unwrap(), b"hello phone");
+    }
+}
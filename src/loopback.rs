@@ -0,0 +1,225 @@
+//! # Loopback
+//!
+//! A speaker wired straight into a microphone, in memory. What a `LoopbackSink` is written,
+//! by `transmitter::send_to_sink` or anything else that writes a `SampleSink`, a
+//! `LoopbackReader` hands to its `Receiver`, so whole conversations run headless, without
+//! audio hardware or files in between. Both ends may run on threads of their own, the
+//! reader blocks until the samples it asks for were written.
+//!
+//! With `LoopbackChannel::with_impairments`, every transmission, whatever was written
+//! between two `finish`es, goes through the `simulator` before the reader gets any of it,
+//! since echoes and drift need the whole signal. The reader sees the end of the stream
+//! once the sink is dropped.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use crate::{
+    ber::Impairments,
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    ring_buffer::RingBuffer,
+    transmission::SampleReader,
+    transmitter::SampleSink,
+};
+
+/// Written samples, shared by both ends.
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// notified whenever samples are written or the sink is dropped
+    written: Condvar,
+}
+
+struct State {
+    buffer: RingBuffer,
+    /// the sink is gone, nothing will be written any more
+    closed: bool,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            buffer: RingBuffer::new(usize::MAX),
+            closed: false,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> Result<MutexGuard<'_, State>> {
+        self.state.lock().map_err(|_| AcousticError::PoisonedBuffer)
+    }
+
+    fn write(&self, samples: &[f64]) -> Result<()> {
+        self.lock()?
+            .buffer
+            .extend(samples.iter().map(|x| *x as f32));
+        self.written.notify_all();
+        Ok(())
+    }
+}
+
+/// Both ends of a loopback, see `split`.
+pub struct LoopbackChannel {
+    sample_rate: f64,
+    impairments: Option<Impairments>,
+}
+
+impl LoopbackChannel {
+    pub fn new(config: &AcousticConfig) -> LoopbackChannel {
+        LoopbackChannel {
+            sample_rate: config.sample_rate,
+            impairments: None,
+        }
+    }
+
+    /// pass every transmission through `impairments`, each with noise of its own
+    pub fn with_impairments(mut self, impairments: Impairments) -> LoopbackChannel {
+        self.impairments = Some(impairments);
+        self
+    }
+
+    /// the end to write to and the end to receive from
+    pub fn split(self) -> (LoopbackSink, LoopbackReader) {
+        let shared = Arc::new(Shared::default());
+        let sink = LoopbackSink {
+            shared: shared.clone(),
+            sample_rate: self.sample_rate,
+            impairments: self.impairments,
+            pending: Vec::new(),
+            transmissions: 0,
+        };
+        (sink, LoopbackReader { shared })
+    }
+}
+
+/// The speaker end of a loopback.
+pub struct LoopbackSink {
+    shared: Arc<Shared>,
+    sample_rate: f64,
+    impairments: Option<Impairments>,
+    /// what was written of the transmission at hand, kept until `finish` to impair it
+    pending: Vec<f64>,
+    /// transmissions finished, each gets noise of its own
+    transmissions: u64,
+}
+
+impl SampleSink for LoopbackSink {
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        match self.impairments {
+            Some(_) => {
+                self.pending.extend_from_slice(samples);
+                Ok(())
+            }
+            None => self.shared.write(samples),
+        }
+    }
+
+    /// Hand what was written to the reader, impaired if it is to be.
+    fn finish(&mut self) -> Result<()> {
+        let Some(impairments) = &self.impairments else {
+            return Ok(());
+        };
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let impairments = Impairments {
+            seed: impairments.seed.wrapping_add(self.transmissions),
+            ..impairments.clone()
+        };
+        let impaired = impairments.apply(&std::mem::take(&mut self.pending), self.sample_rate);
+        self.transmissions += 1;
+        self.shared.write(&impaired)
+    }
+}
+
+/// Whatever was written but not finished yet is handed over, then the reader sees the end.
+impl Drop for LoopbackSink {
+    fn drop(&mut self) {
+        let _ = self.finish();
+        if let Ok(mut state) = self.shared.lock() {
+            state.closed = true;
+        }
+        self.shared.written.notify_all();
+    }
+}
+
+/// The microphone end of a loopback.
+pub struct LoopbackReader {
+    shared: Arc<Shared>,
+}
+
+impl SampleReader for LoopbackReader {
+    /// Block until the samples up to `end` were written, `AcousticError::EndOfStream` if the
+    /// sink was dropped before.
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        let mut state = self.shared.lock()?;
+        while state.buffer.end() < end {
+            if state.closed {
+                return Err(AcousticError::EndOfStream);
+            }
+            state = self
+                .shared
+                .written
+                .wait(state)
+                .map_err(|_| AcousticError::PoisonedBuffer)?;
+        }
+        state.buffer.read(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.shared.lock()?.buffer.consume(until);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_loopback() {
+    use std::thread;
+
+    use crate::{
+        transmission::Receiver,
+        transmitter::{send_to_sink, write_stream},
+        Packet,
+    };
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let send = |sink: &mut LoopbackSink, message: &[u8]| {
+        write_stream(&config, silence.iter().copied(), sink).unwrap();
+        send_to_sink(&config, &Packet::new_packets(message), sink).unwrap();
+    };
+
+    // the sender on a thread of its own, the receiver waits for it
+    let (mut sink, reader) = LoopbackChannel::new(&config).split();
+    let mut receiver = Receiver::with_config(Box::new(reader), config.clone());
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            send(&mut sink, b"hello");
+            send(&mut sink, b"loopback");
+            write_stream(&config, silence.iter().copied(), &mut sink).unwrap();
+            drop(sink);
+        });
+        assert_eq!(receiver.run().unwrap(), b"hello");
+        assert_eq!(receiver.run().unwrap(), b"loopback");
+        assert!(matches!(receiver.run(), Err(AcousticError::EndOfStream)));
+    });
+
+    // through a noisy room, everything written before receiving
+    let room = Impairments {
+        snr_db: Some(20.0),
+        rolloff: Some(8000.0),
+        ..Impairments::default()
+    };
+    let (mut sink, reader) = LoopbackChannel::new(&config).with_impairments(room).split();
+    send(&mut sink, b"noisy");
+    drop(sink);
+    let mut receiver = Receiver::with_config(Box::new(reader), config.clone());
+    assert_eq!(receiver.run().unwrap(), b"noisy");
+}
@@ -1,15 +1,913 @@
+use std::{fs, io::Write, path::PathBuf, thread, time::Duration};
+
 use acousticdi::{
-    recorder::{run_record, Recorder},
-    transmission::Receiver,
+    afsk::BELL202,
+    bench::{bench, bench_payload, simulated},
+    ber::Impairments,
+    calibration::calibration_signal,
+    carrier_sense::{CarrierSense, SENSE_SYMBOLS},
+    config::{AcousticConfig, Fec, Modulation},
+    crypto::Key,
+    cw::Cw,
+    device::{input_device, input_device_names, output_device, output_device_names},
+    diagnostics::write_spectrogram,
+    discovery::{announce, discover, Announcer, Capabilities},
+    dtmf::{dtmf_demodulate, dtmf_modulate},
+    duty_cycle::{DutyCycle, DEFAULT_WINDOW},
+    export::{self, ExportFormat},
+    input_wav, output_wav, output_wav_channels,
+    pairing::{pair, Initiator, Responder, Role},
+    pcm::{PcmFormat, PcmSampleReader, PcmSink},
+    recorder::{record_with_device, run_record_with_device, Recorder},
+    stereo::{modulate_stereo, StereoReceiver},
+    test_vectors::write_test_vectors,
+    transceiver::{Band, Transceiver},
+    transmission::{Receiver, SampleReader, SAMPLE_RATE},
+    transmitter::{modulate_packets, send_to_sink, Modulated, Transmitter},
+    vorbis::DEFAULT_BITRATE,
+    waterfall::{Waterfall, DEFAULT_PERIOD},
+    wav_reader::WavSampleReader,
+    Packet,
 };
-use tracing::info;
+use anyhow::anyhow;
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing::{error, info, Level};
+
+#[derive(Parser)]
+#[command(version, about = "Send and receive data over sound")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// carriers and modulation to use, both ends have to agree on it
+    #[arg(long, value_enum, global = true, default_value_t = Profile::Default)]
+    profile: Profile,
+
+    /// protect every half byte with a Hamming(7,4) code, both ends have to agree on it
+    #[arg(long, global = true)]
+    hamming: bool,
+
+    /// spread burst errors over this many rows of an interleaver, both ends have to agree on it
+    #[arg(long, global = true, default_value_t = 1)]
+    interleave: usize,
+
+    /// seconds per symbol instead of the profile's, shorter is faster and needs a quieter
+    /// room, both ends have to agree on it
+    #[arg(long, global = true)]
+    symbol_time: Option<f64>,
+
+    /// whiten the bytes so that long runs of one value do not go out as one tone, both ends
+    /// have to agree on it
+    #[arg(long, global = true)]
+    scramble: bool,
+
+    /// send a pilot symbol with every packet, for speakers that are much quieter at some
+    /// carriers, both ends have to agree on it
+    #[arg(long, global = true)]
+    pilot: bool,
+
+    /// seconds of silence before every symbol, for echoey rooms, both ends have to agree on it
+    #[arg(long, global = true, default_value_t = 0.0)]
+    symbol_gap: f64,
+
+    /// seconds of silence between packets
+    #[arg(long, global = true, default_value_t = 0.0)]
+    packet_gap: f64,
+
+    /// payload bytes to a packet, fewer for noisy links and more for quiet ones, both ends
+    /// have to agree on it
+    #[arg(long, global = true, default_value_t = Packet::MAX_PACKET_SIZE)]
+    packet_size: usize,
+
+    /// how loud to send, from 0 to 1 of full scale, for speakers that distort when loud
+    #[arg(long, global = true, default_value_t = 1.0)]
+    amplitude: f64,
+
+    /// how much higher than sent the carriers arrive, as printed by `calibrate --listen`
+    #[arg(long, global = true, default_value_t = 1.0)]
+    freq_correction: f64,
+
+    /// pre-shared key as 64 hex digits, encrypts every payload
+    #[arg(long, global = true)]
+    key: Option<String>,
+
+    /// pre-shared key as 64 hex digits, signs every packet
+    #[arg(long, global = true)]
+    mac_key: Option<String>,
+
+    /// our own address, packets are sent from it and received for it
+    #[arg(long, global = true, default_value_t = 0)]
+    address: u8,
+
+    /// name of the audio device, the default one if not given, see `devices`
+    #[arg(long, global = true)]
+    device: Option<String>,
+
+    /// name of the device to play through, `--device` if not given
+    #[arg(long, global = true)]
+    output_device: Option<String>,
+
+    /// log more, repeat for even more
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+impl Cli {
+    /// The config of `profile` with the options given.
+    fn config(&self, profile: Profile) -> Result<AcousticConfig, anyhow::Error> {
+        let mut config = profile.config()?;
+        if self.hamming {
+            config.fec = Fec::Hamming74;
+        }
+        config.interleave_depth = self.interleave;
+        if let Some(symbol_time) = self.symbol_time {
+            config.symbol_time = symbol_time;
+        }
+        config.scramble = self.scramble;
+        config.pilot = self.pilot;
+        config.freq_correction = self.freq_correction;
+        config.symbol_gap = self.symbol_gap.max(0.0);
+        config.packet_gap = self.packet_gap.max(0.0);
+        config.amplitude = self.amplitude.clamp(0.0, 1.0);
+        Packet::check_packet_size(self.packet_size)?;
+        config.packet_size = self.packet_size;
+        if let Some(key) = &self.key {
+            config.key =
+                Some(Key::from_hex(key).ok_or_else(|| anyhow!("the key must be 64 hex digits"))?);
+        }
+        if let Some(mac_key) = &self.mac_key {
+            config.mac_key = Some(
+                Key::from_hex(mac_key)
+                    .ok_or_else(|| anyhow!("the MAC key must be 64 hex digits"))?,
+            );
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// modulate a message and play it, or write it to a wav, flac or ogg file
+    Send {
+        /// the text to send, or a file with `--file`
+        input: String,
+
+        /// send the contents of the file at `input`
+        #[arg(short, long)]
+        file: bool,
+
+        /// write the signal to this file instead of playing it, flac if it ends in
+        /// `.flac`, ogg/vorbis if in `.ogg`, wav otherwise, see `export`
+        #[arg(long, visible_alias = "wav")]
+        export: Option<PathBuf>,
+
+        /// kbit/s of an ogg file written with `--export`, 32 and above carry the payload
+        #[arg(long, default_value_t = DEFAULT_BITRATE / 1000)]
+        bitrate: u32,
+
+        /// write the signal to stdout as raw mono PCM instead of playing it, see `pcm`
+        #[arg(long, value_enum, conflicts_with_all = ["export", "stereo"])]
+        pcm: Option<Pcm>,
+
+        /// address of the receiver, every receiver if not given
+        #[arg(long)]
+        to: Option<u8>,
+
+        /// send every other packet on the right speaker, twice as fast, both ends have to
+        /// agree on it
+        #[arg(long)]
+        stereo: bool,
+
+        /// listen on the default input device first, and wait while someone else is sending
+        #[arg(long)]
+        carrier_sense: bool,
+
+        /// announce the length of the message first, see `message`
+        #[arg(long, conflicts_with = "stereo")]
+        header: bool,
+
+        /// percent of every minute at most to spend on the air, for background telemetry
+        /// in a room with people, see `duty_cycle`
+        #[arg(long, conflicts_with = "stereo")]
+        duty_cycle: Option<f64>,
+    },
+    /// record, or read a wav file, and decode one message
+    Receive {
+        /// write the message to this file instead of stdout
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+
+        /// decode this wav file instead of recording
+        #[arg(long)]
+        wav: Option<PathBuf>,
+
+        /// decode raw mono PCM from stdin instead of recording, see `pcm`
+        #[arg(long, value_enum, conflicts_with_all = ["wav", "stereo"])]
+        pcm: Option<Pcm>,
+
+        /// take packets to any address
+        #[arg(long)]
+        promiscuous: bool,
+
+        /// listen to two microphones, or both channels of the wav file, for what was sent
+        /// with `send --stereo`
+        #[arg(long)]
+        stereo: bool,
+
+        /// give up after this many seconds without a whole message, not with `--stereo`
+        #[arg(long)]
+        timeout: Option<f64>,
+
+        /// print what the microphone hears to stderr while receiving, to see whether the
+        /// tones reach it and how loud, see `waterfall`
+        #[arg(long, conflicts_with_all = ["wav", "pcm", "stereo"])]
+        waterfall: bool,
+    },
+    /// announce our address to whoever discovers, once or every few seconds
+    Announce {
+        /// seconds between announcements, until interrupted
+        #[arg(long)]
+        every: Option<f64>,
+    },
+    /// record, or read a wav file, and print every device that announced itself
+    Discover {
+        /// seconds to listen for
+        #[arg(long, default_value_t = 10.0)]
+        seconds: f64,
+
+        /// read this wav file instead of recording
+        #[arg(long)]
+        wav: Option<PathBuf>,
+    },
+    /// agree on a key with another device, to use with `--key` once both show the same code
+    Pair {
+        /// start pairing, the other device has to be waiting already
+        #[arg(long)]
+        initiate: bool,
+    },
+    /// play reference tones, or listen for them and print the frequency correction to
+    /// receive from that sender with
+    Calibrate {
+        /// listen instead of playing
+        #[arg(long)]
+        listen: bool,
+
+        /// write the tones to, or read them from, this wav file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+    },
+    /// send plain bytes as Bell 202 AFSK, or listen for them, to talk to `minimodem 1200`
+    /// and packet radio tools, see `afsk`
+    Afsk {
+        /// the text to send, or a file with `--file`
+        #[arg(required_unless_present = "listen")]
+        input: Option<String>,
+
+        /// send the contents of the file at `input`
+        #[arg(short, long)]
+        file: bool,
+
+        /// listen instead of sending, and write what was heard to stdout
+        #[arg(long, conflicts_with_all = ["input", "file"])]
+        listen: bool,
+
+        /// write the signal to, or read it from, this wav file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+
+        /// seconds to listen for
+        #[arg(long, default_value_t = 10.0)]
+        seconds: f64,
+    },
+    /// play keypad tones, or listen for them and print the keys, to check a speaker and a
+    /// microphone with any phone, see `dtmf`
+    Dtmf {
+        /// the keys to play, digits, `A` to `D`, `*` and `#`
+        #[arg(required_unless_present = "listen")]
+        keys: Option<String>,
+
+        /// listen instead of playing
+        #[arg(long, conflicts_with = "keys")]
+        listen: bool,
+
+        /// write the tones to, or read them from, this wav file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+
+        /// seconds to listen for
+        #[arg(long, default_value_t = 10.0)]
+        seconds: f64,
+    },
+    /// key text in Morse code, or listen for it and print what was keyed, for beacons and
+    /// IDs anyone can read, see `cw`
+    Cw {
+        /// the text to key
+        #[arg(required_unless_present = "listen")]
+        text: Option<String>,
+
+        /// listen instead of keying
+        #[arg(long, conflicts_with = "text")]
+        listen: bool,
+
+        /// write the signal to, or read it from, this wav file
+        #[arg(long)]
+        wav: Option<PathBuf>,
+
+        /// seconds to listen for
+        #[arg(long, default_value_t = 10.0)]
+        seconds: f64,
+
+        /// words a minute, to key at or to expect at first
+        #[arg(long, default_value_t = 20.0)]
+        wpm: f64,
+
+        /// the tone, in Hz
+        #[arg(long, default_value_t = 700.0)]
+        freq: f64,
+    },
+    /// list the audio devices to pass to `--device` and `--output-device`
+    Devices,
+    /// draw what a wav file holds to a PNG, with the tones of the profile marked, to see
+    /// why a message did not decode
+    Spectrogram {
+        /// the recording
+        wav: PathBuf,
+
+        /// the PNG to write
+        png: PathBuf,
+    },
+    /// write a wav file and the message it decodes to for every modulation, for other
+    /// implementations to test against
+    TestVectors {
+        /// the directory to write them to
+        dir: PathBuf,
+    },
+    /// send a known payload a few times with every profile given, resending lost packets,
+    /// and print how fast each got it through, see `bench`
+    Bench {
+        /// the profiles to compare, all of them if not given, the other options apply to
+        /// every one
+        #[arg(long, value_enum, value_delimiter = ',')]
+        profiles: Vec<Profile>,
+
+        /// payload bytes
+        #[arg(long, default_value_t = 64)]
+        size: usize,
+
+        /// times to send the payload
+        #[arg(long, default_value_t = 3)]
+        repeat: usize,
+
+        /// rounds to get a payload through, the first included
+        #[arg(long, default_value_t = 4)]
+        rounds: usize,
+
+        /// play through the speaker and record with the microphone instead of simulating
+        #[arg(long)]
+        air: bool,
+
+        /// signal to noise ratio of the simulation in decibels, no noise if not given
+        #[arg(long, conflicts_with = "air")]
+        snr: Option<f64>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Profile {
+    /// audible 2 to 4 kHz tones
+    Default,
+    /// 18 to 20 kHz tones
+    Ultrasonic,
+    /// audible OFDM, much faster
+    Ofdm,
+    /// audible DPSK on a single carrier, slower but indifferent to the speaker's phase
+    Dpsk,
+    /// audible QPSK on a single carrier, twice as fast as DPSK
+    Qpsk,
+    /// audible 16-QAM on a single carrier, for a quiet room and a short distance
+    Qam16,
+    /// audible DSSS, slow but unbothered by beeps and whistles
+    Dsss,
+    /// audible chirps, for links across a room
+    Css,
+}
+
+impl Profile {
+    fn config(self) -> Result<AcousticConfig, anyhow::Error> {
+        Ok(match self {
+            Profile::Default => AcousticConfig::default(),
+            Profile::Ultrasonic => AcousticConfig::ultrasonic(),
+            Profile::Ofdm => AcousticConfig::builder()
+                .modulation(Modulation::Ofdm)
+                .build()?,
+            Profile::Dpsk => AcousticConfig::builder()
+                .modulation(Modulation::Dpsk)
+                .build()?,
+            Profile::Qpsk => AcousticConfig::builder()
+                .modulation(Modulation::Qpsk)
+                .build()?,
+            Profile::Qam16 => AcousticConfig::builder()
+                .modulation(Modulation::Qam16)
+                .build()?,
+            Profile::Dsss => AcousticConfig::builder()
+                .modulation(Modulation::Dsss)
+                .build()?,
+            Profile::Css => AcousticConfig::builder()
+                .modulation(Modulation::Css)
+                .build()?,
+        })
+    }
+}
+
+/// sample format of `--pcm`, little endian
+#[derive(Clone, Copy, ValueEnum)]
+enum Pcm {
+    /// 32 bit floats
+    F32,
+    /// 16 bit signed integers
+    S16,
+}
+
+impl Pcm {
+    fn format(self) -> PcmFormat {
+        match self {
+            Pcm::F32 => PcmFormat::F32,
+            Pcm::S16 => PcmFormat::S16,
+        }
+    }
+}
+
+/// Read all of `wav` if given, record `seconds` from `device` otherwise. Returns the samples
+/// and their rate.
+fn listen(
+    wav: Option<PathBuf>,
+    config: &AcousticConfig,
+    device: Option<&str>,
+    seconds: f64,
+) -> Result<(Vec<f64>, f64), anyhow::Error> {
+    if let Some(path) = wav {
+        return Ok((input_wav(&path.to_string_lossy())?, SAMPLE_RATE));
+    }
+    let seconds = seconds.max(0.0);
+    let len = (seconds * config.sample_rate) as usize;
+    let mut recorder =
+        Recorder::with_capacity(len).with_timeout(Duration::from_secs_f64(seconds + 1.0));
+    let _stream = run_record_with_device(recorder.clone_handle(), config, input_device(device)?)?;
+    // waiting by reading, the recorder only drains what the device records as it is read
+    Ok((recorder.take_samples(0, len)?, config.sample_rate))
+}
+
+/// Read `wav` if given, record from `device` otherwise. The stream, if any, has to be kept
+/// while reading.
+fn open_reader(
+    wav: Option<PathBuf>,
+    config: &AcousticConfig,
+    device: Option<&str>,
+) -> Result<(Box<dyn SampleReader>, Option<cpal::Stream>), anyhow::Error> {
+    match wav {
+        Some(path) => Ok((Box::new(WavSampleReader::open(path, config)?), None)),
+        None => {
+            let mut recorder = Recorder::new();
+            let stream =
+                run_record_with_device(recorder.clone_handle(), config, input_device(device)?)?;
+            Ok((Box::new(recorder), Some(stream)))
+        }
+    }
+}
 
-fn main() {
-    let _ = tracing_subscriber::fmt::try_init();
-    info!("Hello, world!");
-    let mut recorder = Recorder::new();
-    let _stream = run_record(recorder.clone_handle()).unwrap();
+fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    let level = match cli.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let _ = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .try_init();
+    let config = cli.config(cli.profile)?;
 
-    let mut receiver = Receiver::new(Box::new(recorder));
-    receiver.run();
+    let output = cli.output_device.as_deref().or(cli.device.as_deref());
+    match cli.command {
+        Command::Send {
+            input,
+            file,
+            export,
+            bitrate,
+            pcm,
+            to,
+            stereo,
+            carrier_sense,
+            header,
+            duty_cycle,
+        } => {
+            let data = match file {
+                true => fs::read(&input)?,
+                false => input.into_bytes(),
+            };
+            let destination = to.unwrap_or(Packet::BROADCAST);
+            let packets = match header {
+                true => Packet::new_message_sized(&data, config.packet_size),
+                false => Packet::new_packets_sized(&data, config.packet_size),
+            };
+            let packets = packets
+                .into_iter()
+                .map(|packet| packet.addressed(cli.address, destination))
+                .collect::<Vec<Packet>>();
+            match (export, pcm, stereo) {
+                (_, Some(pcm), _) => {
+                    let mut sink = PcmSink::new(std::io::stdout().lock(), pcm.format());
+                    send_to_sink(&config, &packets, &mut sink)?
+                }
+                (Some(path), None, true) => {
+                    if ExportFormat::from_path(&path) != ExportFormat::Wav {
+                        return Err(anyhow!("stereo signals are written to wav files only"));
+                    }
+                    let [left, right] = modulate_stereo(&config, &packets)?;
+                    output_wav_channels(&config, &[&left, &right], &path.to_string_lossy())?
+                }
+                (None, None, true) => {
+                    let [left, right] = modulate_stereo(&config, &packets)?;
+                    let device = output_device(output)?;
+                    Transmitter::with_device_channels(config, device, 2)?
+                        .play_channels(&[&left, &right])?
+                }
+                (Some(path), None, false) => {
+                    let modulated = Modulated::new(&config, &packets)?;
+                    export::export_with_bitrate(&config, modulated, path, 1000 * bitrate)?
+                }
+                (None, None, false) => {
+                    let device = output_device(output)?;
+                    let transmitter = Transmitter::with_device(config.clone(), device)?
+                        .with_address(cli.address)
+                        .with_progress(|progress| {
+                            info!(
+                                "sent {} of {} bytes, {:.0?} left",
+                                progress.bytes,
+                                progress.total_bytes.unwrap_or(0),
+                                progress.remaining().unwrap_or_default()
+                            )
+                        });
+                    // recording until we are done sending
+                    let (mut transmitter, _input) = match carrier_sense {
+                        true => {
+                            let recorded = Recorder::new().clone_handle();
+                            let input = run_record_with_device(
+                                recorded.clone(),
+                                &config,
+                                input_device(None)?,
+                            )?;
+                            // a full window to sense in
+                            thread::sleep(Duration::from_secs_f64(
+                                SENSE_SYMBOLS as f64 * config.symbol_time,
+                            ));
+                            let carrier_sense = CarrierSense::new(recorded, &config);
+                            (transmitter.with_carrier_sense(carrier_sense), Some(input))
+                        }
+                        false => (transmitter, None),
+                    };
+                    if let Some(percent) = duty_cycle {
+                        let duty_cycle = DutyCycle::new(percent / 100.0, DEFAULT_WINDOW);
+                        transmitter = transmitter.with_duty_cycle(duty_cycle);
+                    }
+                    transmitter.send_packets(&packets)?
+                }
+            }
+            info!("sent {} bytes", data.len());
+        }
+        Command::Receive {
+            out,
+            wav,
+            pcm,
+            promiscuous,
+            stereo: false,
+            timeout,
+            waterfall,
+        } => {
+            // a recording is there whole, its symbols may as well be demodulated at once
+            let config = AcousticConfig {
+                parallel: wav.is_some(),
+                ..config
+            };
+            let (reader, _stream) = match (pcm, waterfall) {
+                (Some(pcm), _) => {
+                    let stdin = PcmSampleReader::new(std::io::stdin(), pcm.format());
+                    (Box::new(stdin) as Box<dyn SampleReader>, None)
+                }
+                (None, true) => {
+                    let mut recorder = Recorder::new();
+                    let recorded = recorder.clone_handle();
+                    // a second of its own, the receiver drops what it consumed
+                    let heard = Recorder::with_capacity(config.sample_rate as usize).clone_handle();
+                    let waterfall = Waterfall::new(heard.clone(), &config);
+                    thread::spawn(move || waterfall.run(DEFAULT_PERIOD, std::io::stderr()));
+                    let device = input_device(cli.device.as_deref())?;
+                    let stream = record_with_device(&config, device, move |samples| {
+                        heard.push(samples.iter().copied()).ok();
+                        if recorded.push(samples).is_err() {
+                            error!("sample buffer is poisoned, dropping samples");
+                        }
+                    })?;
+                    (Box::new(recorder) as Box<dyn SampleReader>, Some(stream))
+                }
+                (None, false) => open_reader(wav, &config, cli.device.as_deref())?,
+            };
+            let mut receiver = Receiver::with_config(reader, config)
+                .with_address(cli.address)
+                .promiscuous(promiscuous);
+            if let Some(timeout) = timeout {
+                receiver = receiver.with_timeout(Duration::from_secs_f64(timeout));
+            }
+            let data = receiver.run()?;
+            let stats = receiver.stats();
+            info!(
+                "{:?}, {:.1} dB on average, {:.1} bit/s",
+                stats,
+                stats.average_snr().unwrap_or(0.0),
+                stats.throughput()
+            );
+            match out {
+                Some(path) => fs::write(path, &data)?,
+                None => std::io::stdout().write_all(&data)?,
+            }
+        }
+        Command::Receive {
+            out,
+            wav,
+            pcm: _,
+            promiscuous,
+            stereo: true,
+            timeout: _,
+            waterfall: _,
+        } => {
+            let (receiver, _stream) = match wav {
+                Some(path) => (StereoReceiver::open_wav(path, &config)?, None),
+                None => {
+                    let device = input_device(cli.device.as_deref())?;
+                    let (receiver, stream) = StereoReceiver::record(&config, device)?;
+                    (receiver, Some(stream))
+                }
+            };
+            let data = receiver
+                .with_address(cli.address)
+                .promiscuous(promiscuous)
+                .run()?;
+            match out {
+                Some(path) => fs::write(path, &data)?,
+                None => std::io::stdout().write_all(&data)?,
+            }
+        }
+        Command::Announce { every } => {
+            let device = output_device(output)?;
+            let mut transmitter =
+                Transmitter::with_device(config, device)?.with_address(cli.address);
+            match every {
+                Some(every) => {
+                    let _announcer = Announcer::start(
+                        transmitter,
+                        Capabilities::default(),
+                        Duration::from_secs_f64(every),
+                    );
+                    loop {
+                        thread::park();
+                    }
+                }
+                None => announce(&mut transmitter, &Capabilities::default())?,
+            }
+        }
+        Command::Discover { seconds, wav } => {
+            let (reader, _stream): (Box<dyn SampleReader>, _) = match wav {
+                Some(path) => (Box::new(WavSampleReader::open(path, &config)?), None),
+                None => {
+                    // back to check the time every second
+                    let mut recorder = Recorder::new().with_timeout(Duration::from_secs(1));
+                    let device = input_device(cli.device.as_deref())?;
+                    let stream = run_record_with_device(recorder.clone_handle(), &config, device)?;
+                    (Box::new(recorder), Some(stream))
+                }
+            };
+            let mut receiver = Receiver::with_config(reader, config);
+            for peer in discover(&mut receiver, Duration::from_secs_f64(seconds))? {
+                println!("{} {:?}", peer.address, peer.capabilities);
+            }
+        }
+        Command::Pair { initiate } => {
+            let (band, role) = match initiate {
+                true => (Band::Primary, Role::Initiator(Initiator::new())),
+                false => (Band::Secondary, Role::Responder(Responder::new())),
+            };
+            // long enough for the answer, which is longer than an acknowledgment
+            let answer = modulate_packets(&config, &[Initiator::new().commit()])?;
+            let timeout = Duration::from_secs_f64(3.0 * answer.len() as f64 / config.sample_rate);
+            let mut transceiver = Transceiver::new(&config, band)?
+                .with_address(cli.address)
+                .with_timeout(timeout);
+            let paired = pair(&mut transceiver, role, 20)?;
+            println!("code: {:06}", paired.code);
+            print!("does the other device show the same code? [y/N] ");
+            std::io::stdout().flush()?;
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+            match answer.trim() {
+                "y" | "Y" => println!("key: {}", paired.key.to_hex()),
+                _ => return Err(anyhow!("the codes differ, someone may be in the middle")),
+            }
+        }
+        Command::Calibrate { listen: false, wav } => match wav {
+            Some(path) => output_wav(
+                &config,
+                &calibration_signal(&config),
+                &path.to_string_lossy(),
+            )?,
+            None => {
+                let device = output_device(output)?;
+                Transmitter::with_device(config.clone(), device)?
+                    .play(&calibration_signal(&config))?
+            }
+        },
+        Command::Calibrate { listen: true, wav } => {
+            let (reader, _stream) = open_reader(wav, &config, cli.device.as_deref())?;
+            let correction = Receiver::with_config(reader, config).calibrate()?;
+            println!("{}", correction);
+        }
+        Command::Afsk {
+            input: Some(input),
+            file,
+            listen: false,
+            wav,
+            seconds: _,
+        } => {
+            let data = match file {
+                true => fs::read(&input)?,
+                false => input.into_bytes(),
+            };
+            let signal = BELL202.modulate(config.sample_rate, &data);
+            match wav {
+                Some(path) => output_wav(&config, &signal, &path.to_string_lossy())?,
+                None => {
+                    let device = output_device(output)?;
+                    Transmitter::with_device(config, device)?.play(&signal)?
+                }
+            }
+        }
+        Command::Afsk {
+            listen: true,
+            wav,
+            seconds,
+            ..
+        } => {
+            let (signal, sample_rate) = listen(wav, &config, cli.device.as_deref(), seconds)?;
+            std::io::stdout().write_all(&BELL202.demodulate(sample_rate, &signal))?;
+        }
+        Command::Afsk { input: None, .. } => return Err(anyhow!("nothing to send")),
+        Command::Dtmf {
+            keys: Some(keys),
+            listen: false,
+            wav,
+            seconds: _,
+        } => {
+            let signal = dtmf_modulate(config.sample_rate, &keys)?;
+            match wav {
+                Some(path) => output_wav(&config, &signal, &path.to_string_lossy())?,
+                None => {
+                    let device = output_device(output)?;
+                    Transmitter::with_device(config, device)?.play(&signal)?
+                }
+            }
+        }
+        Command::Dtmf {
+            listen: true,
+            wav,
+            seconds,
+            ..
+        } => {
+            let (signal, sample_rate) = listen(wav, &config, cli.device.as_deref(), seconds)?;
+            println!("{}", dtmf_demodulate(sample_rate, &signal));
+        }
+        Command::Dtmf { keys: None, .. } => return Err(anyhow!("no keys to play")),
+        Command::Cw {
+            text: Some(text),
+            listen: false,
+            wav,
+            seconds: _,
+            wpm,
+            freq,
+        } => {
+            let signal = Cw { wpm, freq }.modulate(config.sample_rate, &text)?;
+            match wav {
+                Some(path) => output_wav(&config, &signal, &path.to_string_lossy())?,
+                None => {
+                    let device = output_device(output)?;
+                    Transmitter::with_device(config, device)?.play(&signal)?
+                }
+            }
+        }
+        Command::Cw {
+            listen: true,
+            wav,
+            seconds,
+            wpm,
+            freq,
+            ..
+        } => {
+            let (signal, sample_rate) = listen(wav, &config, cli.device.as_deref(), seconds)?;
+            println!("{}", Cw { wpm, freq }.demodulate(sample_rate, &signal));
+        }
+        Command::Cw { text: None, .. } => return Err(anyhow!("no text to key")),
+        Command::Devices => {
+            println!("input:");
+            for name in input_device_names()? {
+                println!("  {}", name);
+            }
+            println!("output:");
+            for name in output_device_names()? {
+                println!("  {}", name);
+            }
+        }
+        Command::Spectrogram { wav, png } => {
+            let signal = input_wav(&wav.to_string_lossy())?;
+            write_spectrogram(&config, &signal, png)?
+        }
+        Command::TestVectors { dir } => {
+            for path in write_test_vectors(dir)? {
+                println!("{}", path.display());
+            }
+        }
+        Command::Bench {
+            ref profiles,
+            size,
+            repeat,
+            rounds,
+            air,
+            snr,
+        } => {
+            let profiles = match profiles.is_empty() {
+                true => Profile::value_variants().to_vec(),
+                false => profiles.clone(),
+            };
+            let payload = bench_payload(size);
+            println!(
+                "{:<12} {:>12} {:>10} {:>16} {:>14}",
+                "profile", "goodput", "delivered", "retransmissions", "airtime/byte"
+            );
+            for profile in profiles {
+                let config = cli.config(profile)?;
+                let result = match air {
+                    true => {
+                        let device = cli.device.as_deref();
+                        bench(&config, &payload, repeat, rounds, |signal: &[f64]| {
+                            // room for the tail of the echo and the late start of the speaker
+                            let len = signal.len() + config.sample_rate as usize / 2;
+                            let seconds = len as f64 / config.sample_rate;
+                            let mut recorder =
+                                Recorder::with_capacity(signal.len() + config.sample_rate as usize)
+                                    .with_timeout(Duration::from_secs_f64(seconds + 1.0));
+                            let stream = run_record_with_device(
+                                recorder.clone_handle(),
+                                &config,
+                                input_device(device)?,
+                            )?;
+                            let mut transmitter =
+                                Transmitter::with_device(config.clone(), output_device(output)?)?;
+                            let _output = transmitter.start(signal, || {})?;
+                            // reading along, the recorder only drains as it is read
+                            recorder.take_samples(0, len)?;
+                            drop(stream);
+                            Ok(Box::new(recorder.with_timeout(Duration::from_secs(1)))
+                                as Box<dyn SampleReader>)
+                        })?
+                    }
+                    false => {
+                        let impairments = Impairments {
+                            snr_db: snr,
+                            ..Impairments::default()
+                        };
+                        let channel = simulated(&config, impairments);
+                        bench(&config, &payload, repeat, rounds, channel)?
+                    }
+                };
+                let name = profile
+                    .to_possible_value()
+                    .map(|value| value.get_name().to_owned());
+                println!(
+                    "{:<12} {:>6.1} bit/s {:>6}/{:<3} {:>16} {:>14}",
+                    name.unwrap_or_default(),
+                    result.goodput(),
+                    result.delivered,
+                    result.payloads,
+                    result.retransmissions,
+                    result
+                        .airtime_per_byte()
+                        .map_or("-".to_owned(), |airtime| format!(
+                            "{} ms",
+                            airtime.as_millis()
+                        ))
+                );
+            }
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,253 @@
+//! # Messages
+//!
+//! A message goes out as data packets in order, see `Packet::new_packets`. The last one is
+//! shorter than the packet size, `Packet::MAX_PACKET_SIZE` unless configured otherwise, an
+//! empty one if the data fills every packet, which marks the end of the message.
+//!
+//! A sender can announce the message first with a `MessageHeader`, a `PacketKind::Control`
+//! packet with the length of the whole message and how many packets carry it, see
+//! `Packet::new_message`. A receiver that heard it knows when it has every packet, in
+//! whatever order they came, and keeps waiting for those missing when the end marker
+//! arrives, until the sender repeats the message. The end marker still ends messages whose
+//! header was lost, and those of senders without one, holes and all.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use tracing::info;
+
+use crate::{
+    error::{AcousticError, Result},
+    progress::Progress,
+    Packet, PacketKind,
+};
+
+/// first byte of a message header, see `PacketKind::Control`
+pub const MESSAGE: u8 = 48;
+
+/// what a message header announces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    /// payload bytes of the whole message
+    pub len: usize,
+    /// data packets carrying it, the end marker included
+    pub packets: usize,
+}
+
+impl MessageHeader {
+    /// the header of `data`, sent as `Packet::new_packets` splits it
+    pub fn for_data(data: &[u8]) -> MessageHeader {
+        Self::for_data_sized(data, Packet::MAX_PACKET_SIZE)
+    }
+
+    /// the header of `data`, sent as `Packet::new_packets_sized` splits it
+    pub fn for_data_sized(data: &[u8], size: usize) -> MessageHeader {
+        MessageHeader {
+            len: data.len(),
+            packets: data.len() / size.clamp(1, Packet::LARGEST_PACKET_SIZE) + 1,
+        }
+    }
+
+    /// `MESSAGE`, then the length as a little endian `u32` and the packet count as a little
+    /// endian `u16`.
+    pub fn to_packet(&self) -> Packet {
+        let mut data = vec![MESSAGE];
+        data.extend_from_slice(&(self.len as u32).to_le_bytes());
+        data.extend_from_slice(&(self.packets as u16).to_le_bytes());
+        Packet::new(PacketKind::Control, 0, &data)
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<MessageHeader> {
+        match (packet.kind, packet.data.as_slice()) {
+            (PacketKind::Control, [MESSAGE, len @ .., low, high]) if len.len() == 4 => {
+                Ok(MessageHeader {
+                    len: u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize,
+                    packets: u16::from_le_bytes([*low, *high]) as usize,
+                })
+            }
+            _ => Err(AcousticError::MalformedPacket(format!(
+                "bad message header {:?}",
+                packet.data
+            ))),
+        }
+    }
+}
+
+impl Packet {
+    /// Split `data` to packets like `new_packets`, announced by a `MessageHeader`.
+    pub fn new_message(data: &[u8]) -> Vec<Packet> {
+        Self::new_message_sized(data, Self::MAX_PACKET_SIZE)
+    }
+
+    /// Like `new_message`, `size` bytes to a packet, see `new_packets_sized`.
+    pub fn new_message_sized(data: &[u8], size: usize) -> Vec<Packet> {
+        let mut packets = vec![MessageHeader::for_data_sized(data, size).to_packet()];
+        packets.extend(Self::new_packets_sized(data, size));
+        packets
+    }
+}
+
+/// Puts the data packets of one message back together.
+pub struct MessageAssembler {
+    header: Option<MessageHeader>,
+    packets: BTreeMap<usize, Packet>,
+    /// the end marker arrived
+    ended: bool,
+    /// payload bytes of every packet but the last
+    packet_size: usize,
+}
+
+impl Default for MessageAssembler {
+    fn default() -> Self {
+        Self::with_packet_size(Packet::MAX_PACKET_SIZE)
+    }
+}
+
+impl MessageAssembler {
+    pub fn new() -> MessageAssembler {
+        Self::default()
+    }
+
+    /// for messages split to `packet_size` bytes a packet, see `Packet::new_packets_sized`
+    pub fn with_packet_size(packet_size: usize) -> MessageAssembler {
+        MessageAssembler {
+            header: None,
+            packets: BTreeMap::new(),
+            ended: false,
+            packet_size,
+        }
+    }
+
+    /// A header starts a new message, whatever was collected so far is dropped, unless the
+    /// same message is sent again.
+    pub fn start(&mut self, header: MessageHeader) {
+        if self.header == Some(header) {
+            return;
+        }
+        *self = MessageAssembler {
+            header: Some(header),
+            ..Self::with_packet_size(self.packet_size)
+        };
+    }
+
+    pub fn header(&self) -> Option<MessageHeader> {
+        self.header
+    }
+
+    /// Add a data packet, and tell whether the message is complete: every packet the
+    /// header announced arrived, or without a header, the end marker did.
+    pub fn add(&mut self, packet: Packet) -> bool {
+        self.ended |= packet.ends_message(self.packet_size);
+        self.packets.insert(packet.order, packet);
+        self.is_complete()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match self.header {
+            Some(header) => (0..header.packets).all(|order| self.packets.contains_key(&order)),
+            None => self.ended,
+        }
+    }
+
+    /// orders of the packets that should have come before the last one heard but did not
+    pub fn missing(&self) -> Vec<usize> {
+        let count = match (self.header, self.packets.keys().last()) {
+            (Some(header), _) => header.packets,
+            (None, Some(last)) => last + 1,
+            (None, None) => 0,
+        };
+        (0..count)
+            .filter(|order| !self.packets.contains_key(order))
+            .collect()
+    }
+
+    /// How much of the message arrived, `elapsed` after it started to.
+    pub fn progress(&self, elapsed: Duration) -> Progress {
+        Progress {
+            bytes: self.packets.values().map(|packet| packet.data.len()).sum(),
+            total_bytes: self.header.map(|header| header.len),
+            packets: self.packets.len(),
+            total_packets: self.header.map(|header| header.packets),
+            elapsed,
+        }
+    }
+
+    /// The payload collected, in order, and start over. Short of what the header announced
+    /// if packets were lost.
+    pub fn take_message(&mut self) -> Vec<u8> {
+        let message = self
+            .packets
+            .values()
+            .flat_map(|packet| packet.data.clone())
+            .collect::<Vec<u8>>();
+        if let Some(header) = self.header.filter(|header| header.len != message.len()) {
+            info!(
+                "message of {} bytes, {} announced",
+                message.len(),
+                header.len
+            );
+        }
+        *self = Self::with_packet_size(self.packet_size);
+        message
+    }
+}
+
+#[test]
+fn test_message_header() {
+    let data = [7; 300];
+    let packets = Packet::new_message(&data);
+    assert_eq!(packets.len(), 4);
+    let header = MessageHeader::from_packet(&packets[0]).unwrap();
+    assert_eq!(
+        header,
+        MessageHeader {
+            len: 300,
+            packets: 3
+        }
+    );
+    assert_eq!(packets[0].data, [MESSAGE, 44, 1, 0, 0, 3, 0]);
+    // the empty end marker is counted too
+    assert_eq!(MessageHeader::for_data(&[0; 256]).packets, 3);
+    assert!(MessageHeader::from_packet(&packets[1]).is_err());
+    assert!(MessageHeader::from_packet(&crate::keepalive::keepalive_packet()).is_err());
+}
+
+#[test]
+fn test_message_assembler() {
+    let data = (0..300).map(|i| i as u8).collect::<Vec<u8>>();
+    let packets = Packet::new_message(&data);
+    let header = MessageHeader::from_packet(&packets[0]).unwrap();
+
+    // the end marker came but the second packet was lost, the repeat fills the hole
+    let mut assembler = MessageAssembler::new();
+    assembler.start(header);
+    assert!(!assembler.add(packets[1].clone()));
+    assert!(!assembler.add(packets[3].clone()));
+    assert_eq!(assembler.missing(), [1]);
+    assembler.start(header);
+    let progress = assembler.progress(Duration::ZERO);
+    assert_eq!((progress.bytes, progress.packets), (172, 2));
+    assert_eq!(progress.total_bytes, Some(300));
+    assert!(assembler.add(packets[2].clone()));
+    assert_eq!(assembler.take_message(), data);
+    assert_eq!(assembler.header(), None);
+
+    // without a header, the end marker ends the message, holes and all
+    assert!(!assembler.add(packets[1].clone()));
+    assert!(assembler.add(packets[3].clone()));
+    assert_eq!(assembler.missing(), [1]);
+    assert_eq!(
+        assembler.take_message(),
+        [&data[..128], &data[256..]].concat()
+    );
+
+    // small packets, a short one ends the message
+    let packets = Packet::new_message_sized(&data, 100);
+    let header = MessageHeader::from_packet(&packets[0]).unwrap();
+    assert_eq!(header.packets, 4);
+    let mut assembler = MessageAssembler::with_packet_size(100);
+    for packet in &packets[1..4] {
+        assert!(!assembler.add(packet.clone()));
+    }
+    assert!(assembler.add(packets[4].clone()));
+    assert_eq!(assembler.take_message(), data);
+}
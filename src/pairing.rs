@@ -0,0 +1,493 @@
+//! # Pairing
+//!
+//! Two devices that never shared a secret agree on a `Key` over the air, with an X25519 key
+//! exchange. Anyone in the room hears it, which is fine, but a device in the middle could
+//! exchange keys with both ends instead. So both display a six digit code derived from
+//! everything exchanged, and the user checks they are the same, as Bluetooth does.
+//!
+//! A device in the middle would try public keys until both codes match, so the initiator
+//! commits to its public key and a nonce first, and reveals them only once the responder
+//! sent its own:
+//!
+//! 1. COMMIT, the SHA-256 of the initiator's public key and nonce;
+//! 2. KEY, the responder's public key and nonce;
+//! 3. REVEAL, the initiator's public key and nonce, checked against the commitment.
+//!
+//! All three travel as `PacketKind::Control` packets. Like the session handshake, both ends
+//! are state machines, `pair` runs one over a `Link`.
+
+use std::fmt;
+
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use tracing::info;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    crypto::Key,
+    error::{AcousticError, Result},
+    stream::Link,
+    transmission::Event,
+    Packet, PacketKind,
+};
+
+/// bytes of the nonce each end adds to the exchange
+pub const NONCE_SIZE: usize = 16;
+
+/// timeouts without another KEY after which the initiator takes its REVEAL as heard
+pub const QUIET_TIMEOUTS: usize = 3;
+
+/// digits of the code the user compares
+pub const CODE_DIGITS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingMessage {
+    Commit([u8; 32]),
+    Key {
+        public: [u8; 32],
+        nonce: [u8; NONCE_SIZE],
+    },
+    Reveal {
+        public: [u8; 32],
+        nonce: [u8; NONCE_SIZE],
+    },
+}
+
+impl PairingMessage {
+    // after those of `session::Handshake`
+    const COMMIT: u8 = 16;
+    const KEY: u8 = 17;
+    const REVEAL: u8 = 18;
+
+    /// A control packet: the message type, then the commitment, or the public key and the
+    /// nonce.
+    pub fn to_packet(&self) -> Packet {
+        let data = match self {
+            PairingMessage::Commit(commitment) => [&[Self::COMMIT][..], commitment].concat(),
+            PairingMessage::Key { public, nonce } => [&[Self::KEY][..], public, nonce].concat(),
+            PairingMessage::Reveal { public, nonce } => {
+                [&[Self::REVEAL][..], public, nonce].concat()
+            }
+        };
+        Packet::new(PacketKind::Control, 0, &data)
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<PairingMessage> {
+        let malformed =
+            || AcousticError::MalformedPacket(format!("bad pairing message {:?}", packet.data));
+        if packet.kind != PacketKind::Control {
+            return Err(malformed());
+        }
+        let keyed = || -> Option<([u8; 32], [u8; NONCE_SIZE])> {
+            let field = packet.data.get(1..1 + 32 + NONCE_SIZE)?;
+            Some((field[..32].try_into().ok()?, field[32..].try_into().ok()?))
+        };
+        match packet.data.first() {
+            Some(&Self::COMMIT) => packet
+                .data
+                .get(1..33)
+                .and_then(|commitment| commitment.try_into().ok())
+                .map(PairingMessage::Commit)
+                .ok_or_else(malformed),
+            Some(&Self::KEY) => keyed()
+                .map(|(public, nonce)| PairingMessage::Key { public, nonce })
+                .ok_or_else(malformed),
+            Some(&Self::REVEAL) => keyed()
+                .map(|(public, nonce)| PairingMessage::Reveal { public, nonce })
+                .ok_or_else(malformed),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// what both ends agreed on
+#[derive(Clone, PartialEq, Eq)]
+pub struct Paired {
+    /// for `AcousticConfig::key`, once the user confirmed the code
+    pub key: Key,
+    /// the user compares this with the one the other device shows
+    pub code: u32,
+}
+
+impl Paired {
+    /// Hash the shared secret into the key, and everything exchanged into the code, the
+    /// initiator's part first.
+    fn derive(
+        shared: &[u8; 32],
+        initiator: ([u8; 32], [u8; NONCE_SIZE]),
+        responder: ([u8; 32], [u8; NONCE_SIZE]),
+    ) -> Paired {
+        let exchanged = [&initiator.0[..], &initiator.1, &responder.0, &responder.1].concat();
+        let key = Sha256::new()
+            .chain_update(b"acousticdi pairing key")
+            .chain_update(shared)
+            .chain_update(&exchanged)
+            .finalize();
+        let code = Sha256::new()
+            .chain_update(b"acousticdi pairing code")
+            .chain_update(&exchanged)
+            .finalize();
+        let code = u32::from_le_bytes([code[0], code[1], code[2], code[3]]);
+        Paired {
+            key: Key::new(key.into()),
+            code: code % 10_u32.pow(CODE_DIGITS),
+        }
+    }
+}
+
+/// show the code, never the key
+impl fmt::Debug for Paired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Paired({:06})", self.code)
+    }
+}
+
+/// our half of the exchange, until the other half comes
+struct Exchange {
+    secret: Option<EphemeralSecret>,
+    public: [u8; 32],
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Exchange {
+    fn new<R: RngCore + CryptoRng>(rng: &mut R) -> Exchange {
+        let secret = EphemeralSecret::random_from_rng(&mut *rng);
+        let public = PublicKey::from(&secret).to_bytes();
+        let mut nonce = [0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        Exchange {
+            secret: Some(secret),
+            public,
+            nonce,
+        }
+    }
+
+    /// The shared secret with `public`, once. A public key of low order would make it
+    /// known to anyone.
+    fn agree(&mut self, public: [u8; 32]) -> Option<[u8; 32]> {
+        let shared = self.secret.take()?.diffie_hellman(&PublicKey::from(public));
+        shared.was_contributory().then(|| shared.to_bytes())
+    }
+}
+
+fn commitment(public: &[u8; 32], nonce: &[u8; NONCE_SIZE]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(public)
+        .chain_update(nonce)
+        .finalize()
+        .into()
+}
+
+/// the end that starts pairing
+pub struct Initiator {
+    exchange: Exchange,
+    paired: Option<Paired>,
+}
+
+impl Initiator {
+    pub fn new() -> Initiator {
+        Self::with_rng(&mut StdRng::from_entropy())
+    }
+
+    /// draw the secret and the nonce from `rng`, for tests
+    pub fn with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Initiator {
+        Initiator {
+            exchange: Exchange::new(rng),
+            paired: None,
+        }
+    }
+
+    /// the packet opening the exchange, send it again if no answer comes
+    pub fn commit(&self) -> Packet {
+        PairingMessage::Commit(commitment(&self.exchange.public, &self.exchange.nonce)).to_packet()
+    }
+
+    fn reveal(&self) -> Packet {
+        PairingMessage::Reveal {
+            public: self.exchange.public,
+            nonce: self.exchange.nonce,
+        }
+        .to_packet()
+    }
+
+    /// Take what the responder sent, and return the REVEAL to send, again for every KEY.
+    pub fn on_packet(&mut self, packet: &Packet) -> Option<Packet> {
+        match PairingMessage::from_packet(packet) {
+            Ok(PairingMessage::Key { public, nonce }) => {
+                if self.paired.is_none() {
+                    let shared = self.exchange.agree(public)?;
+                    let ours = (self.exchange.public, self.exchange.nonce);
+                    self.paired = Some(Paired::derive(&shared, ours, (public, nonce)));
+                }
+                Some(self.reveal())
+            }
+            other => {
+                info!("unexpected during pairing: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// the key and the code, once the responder sent its key
+    pub fn paired(&self) -> Option<&Paired> {
+        self.paired.as_ref()
+    }
+}
+
+impl Default for Initiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the end that waits to be paired with
+pub struct Responder {
+    exchange: Exchange,
+    /// the initiator's commitment, once it came
+    commitment: Option<[u8; 32]>,
+    paired: Option<Paired>,
+}
+
+impl Responder {
+    pub fn new() -> Responder {
+        Self::with_rng(&mut StdRng::from_entropy())
+    }
+
+    /// draw the secret and the nonce from `rng`, for tests
+    pub fn with_rng<R: RngCore + CryptoRng>(rng: &mut R) -> Responder {
+        Responder {
+            exchange: Exchange::new(rng),
+            commitment: None,
+            paired: None,
+        }
+    }
+
+    fn key(&self) -> Packet {
+        PairingMessage::Key {
+            public: self.exchange.public,
+            nonce: self.exchange.nonce,
+        }
+        .to_packet()
+    }
+
+    /// Take what the initiator sent, and return the KEY to send, again for the same
+    /// commitment. A REVEAL that does not match the commitment is ignored.
+    pub fn on_packet(&mut self, packet: &Packet) -> Option<Packet> {
+        match PairingMessage::from_packet(packet) {
+            Ok(PairingMessage::Commit(commitment)) if self.paired.is_none() => {
+                if self.commitment.is_some_and(|ours| ours != commitment) {
+                    info!("another initiator, ignored");
+                    return None;
+                }
+                self.commitment = Some(commitment);
+                Some(self.key())
+            }
+            Ok(PairingMessage::Reveal { public, nonce }) if self.paired.is_none() => {
+                if self.commitment != Some(commitment(&public, &nonce)) {
+                    info!("the reveal does not match the commitment");
+                    return None;
+                }
+                let shared = self.exchange.agree(public)?;
+                let ours = (self.exchange.public, self.exchange.nonce);
+                self.paired = Some(Paired::derive(&shared, (public, nonce), ours));
+                None
+            }
+            other => {
+                info!("unexpected during pairing: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// the key and the code, once the initiator revealed its key
+    pub fn paired(&self) -> Option<&Paired> {
+        self.paired.as_ref()
+    }
+}
+
+impl Default for Responder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Which end of the pairing to run with `pair`.
+pub enum Role {
+    Initiator(Initiator),
+    Responder(Responder),
+}
+
+/// Run a pairing over `link`, whose `recv` should time out, resending after each timeout
+/// up to `max_retries` times in a row. The initiator is done once it heard no KEY again for
+/// `QUIET_TIMEOUTS` timeouts after its REVEAL, the responder resends its KEY until it heard
+/// the REVEAL.
+pub fn pair(link: &mut impl Link, role: Role, max_retries: usize) -> Result<Paired> {
+    let mut role = role;
+    let mut retries = 0;
+    if let Role::Initiator(initiator) = &role {
+        link.send(&[initiator.commit()])?;
+    }
+    loop {
+        let event = match link.recv() {
+            Err(AcousticError::Timeout) => {
+                match &role {
+                    Role::Initiator(initiator) => match initiator.paired() {
+                        Some(paired) if retries + 1 >= QUIET_TIMEOUTS || retries >= max_retries => {
+                            return Ok(paired.clone())
+                        }
+                        Some(_) => {}
+                        None => link.send(&[initiator.commit()])?,
+                    },
+                    // the initiator may not have heard our key
+                    Role::Responder(responder) => {
+                        if responder.commitment.is_some() {
+                            link.send(&[responder.key()])?;
+                        }
+                    }
+                }
+                retries += 1;
+                if retries > max_retries {
+                    return Err(AcousticError::Timeout);
+                }
+                continue;
+            }
+            event => event?,
+        };
+        retries = 0;
+        let Event::Control(packet) = event else {
+            info!("skipped {:?}", event);
+            continue;
+        };
+        let answer = match &mut role {
+            Role::Initiator(initiator) => initiator.on_packet(&packet),
+            Role::Responder(responder) => responder.on_packet(&packet),
+        };
+        if let Some(answer) = answer {
+            link.send(&[answer])?;
+        }
+        if let Role::Responder(responder) = &role {
+            if let Some(paired) = responder.paired() {
+                return Ok(paired.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn rng(seed: u64) -> StdRng {
+        StdRng::seed_from_u64(seed)
+    }
+
+    #[test]
+    fn test_pairing() {
+        let mut initiator = Initiator::with_rng(&mut rng(1));
+        let mut responder = Responder::with_rng(&mut rng(2));
+        let commit = initiator.commit();
+        let key = responder.on_packet(&commit).unwrap();
+        // the initiator repeats itself until it hears the key
+        assert_eq!(responder.on_packet(&commit).unwrap().data, key.data);
+        assert!(responder.paired().is_none());
+        let reveal = initiator.on_packet(&key).unwrap();
+        assert!(responder.on_packet(&reveal).is_none());
+        let paired = initiator.paired().unwrap();
+        assert_eq!(Some(paired), responder.paired());
+        assert!(paired.code < 1_000_000);
+        assert_eq!(
+            format!("{:?}", paired),
+            format!("Paired({:06})", paired.code)
+        );
+
+        for message in [&commit, &key, &reveal] {
+            assert!(message.data.len() < Packet::MAX_PACKET_SIZE);
+            let parsed = PairingMessage::from_packet(message).unwrap();
+            assert_eq!(parsed.to_packet().data, message.data);
+        }
+        assert!(
+            PairingMessage::from_packet(&Packet::new(PacketKind::Control, 0, &[17, 1])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_pairing_in_the_middle() {
+        let mut initiator = Initiator::with_rng(&mut rng(1));
+        let mut responder = Responder::with_rng(&mut rng(2));
+        // someone pairs with each end in turn
+        let mut to_responder = Initiator::with_rng(&mut rng(3));
+        let mut to_initiator = Responder::with_rng(&mut rng(4));
+
+        let key = to_initiator.on_packet(&initiator.commit()).unwrap();
+        let reveal = initiator.on_packet(&key).unwrap();
+        to_initiator.on_packet(&reveal);
+        let key = responder.on_packet(&to_responder.commit()).unwrap();
+        let reveal = to_responder.on_packet(&key).unwrap();
+        responder.on_packet(&reveal);
+        let (ours, theirs) = (initiator.paired().unwrap(), responder.paired().unwrap());
+        assert_ne!(ours.key, theirs.key);
+        assert_ne!(ours.code, theirs.code);
+
+        // a reveal of another key than committed to
+        let mut responder = Responder::with_rng(&mut rng(2));
+        let key = responder.on_packet(&initiator.commit()).unwrap();
+        to_responder.on_packet(&key);
+        assert!(responder.on_packet(&to_responder.reveal()).is_none());
+        assert!(responder.paired().is_none());
+    }
+
+    /// drops every `drop_every`th packet it sends
+    struct ChannelLink {
+        tx: Sender<Packet>,
+        rx: Receiver<Packet>,
+        sent: usize,
+        drop_every: usize,
+    }
+
+    impl Link for ChannelLink {
+        fn send(&mut self, packets: &[Packet]) -> Result<()> {
+            for packet in packets {
+                self.sent += 1;
+                if !self.sent.is_multiple_of(self.drop_every) {
+                    let _ = self.tx.send(packet.clone());
+                }
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Event> {
+            match self.rx.recv_timeout(Duration::from_millis(50)) {
+                Ok(packet) => Ok(Event::from(packet)),
+                Err(RecvTimeoutError::Timeout) => Err(AcousticError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => Err(AcousticError::EndOfStream),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pair_over_lossy_link() {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let mut a = ChannelLink {
+            tx: a_tx,
+            rx: a_rx,
+            sent: 0,
+            drop_every: 2,
+        };
+        let mut b = ChannelLink {
+            tx: b_tx,
+            rx: b_rx,
+            sent: 0,
+            drop_every: 2,
+        };
+        let initiator = thread::spawn(move || pair(&mut a, Role::Initiator(Initiator::new()), 10));
+        let responder = pair(&mut b, Role::Responder(Responder::new()), 10).unwrap();
+        assert_eq!(initiator.join().unwrap().unwrap(), responder);
+    }
+}
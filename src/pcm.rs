@@ -0,0 +1,192 @@
+//! # Raw PCM
+//!
+//! Samples as bare little endian numbers, mono at the configured sample rate, with no
+//! header, the way sox, ffmpeg, PulseAudio and SDR tools pipe audio around. A `PcmSink`
+//! writes what would be played to any `Write`, like stdout, and a `PcmSampleReader` feeds
+//! the `Receiver` from any `Read`, like stdin, so that no audio device is involved.
+//!
+//! For instance, at the default sample rate,
+//! `acousticdi send --pcm s16 hello | sox -t raw -e signed -b 16 -c 1 -r 44100 - out.wav`.
+
+use std::io::{ErrorKind, Read, Write};
+
+use crate::{
+    error::{AcousticError, Result},
+    ring_buffer::RingBuffer,
+    transmission::SampleReader,
+    transmitter::SampleSink,
+};
+
+/// frames read at once
+const BLOCK_FRAMES: usize = 4096;
+
+/// how every sample is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmFormat {
+    /// 32 bit floats from -1 to 1
+    F32,
+    /// 16 bit signed integers, full scale at `i16::MAX`
+    S16,
+}
+
+impl PcmFormat {
+    /// bytes per sample
+    pub fn sample_size(self) -> usize {
+        match self {
+            PcmFormat::F32 => 4,
+            PcmFormat::S16 => 2,
+        }
+    }
+
+    fn encode(self, x: f64) -> Vec<u8> {
+        match self {
+            PcmFormat::F32 => (x as f32).to_le_bytes().to_vec(),
+            PcmFormat::S16 => ((x.clamp(-1.0, 1.0) * i16::MAX as f64).round() as i16)
+                .to_le_bytes()
+                .to_vec(),
+        }
+    }
+
+    /// `bytes` holds whole samples
+    fn decode(self, bytes: &[u8]) -> Vec<f32> {
+        bytes
+            .chunks_exact(self.sample_size())
+            .map(|sample| match self {
+                PcmFormat::F32 => f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]),
+                PcmFormat::S16 => {
+                    i16::from_le_bytes([sample[0], sample[1]]) as f32 / i16::MAX as f32
+                }
+            })
+            .collect()
+    }
+}
+
+/// Writes the signal to `output` as raw PCM.
+pub struct PcmSink<W: Write> {
+    output: W,
+    format: PcmFormat,
+}
+
+impl<W: Write> PcmSink<W> {
+    pub fn new(output: W, format: PcmFormat) -> PcmSink<W> {
+        PcmSink { output, format }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> SampleSink for PcmSink<W> {
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        let bytes = samples
+            .iter()
+            .flat_map(|x| self.format.encode(*x))
+            .collect::<Vec<u8>>();
+        self.output.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads raw PCM from `input` as it comes, keeping only what the receiver has not consumed.
+pub struct PcmSampleReader<R: Read + Send> {
+    input: R,
+    format: PcmFormat,
+    buffer: RingBuffer,
+    /// bytes of a sample the last read cut off
+    partial: Vec<u8>,
+}
+
+impl<R: Read + Send> PcmSampleReader<R> {
+    pub fn new(input: R, format: PcmFormat) -> PcmSampleReader<R> {
+        PcmSampleReader {
+            input,
+            format,
+            buffer: RingBuffer::new(usize::MAX),
+            partial: Vec::new(),
+        }
+    }
+
+    /// Read blocks until the samples up to `end` are buffered.
+    fn read_until(&mut self, end: usize) -> Result<()> {
+        while self.buffer.end() < end {
+            if !self.read_block()? {
+                return Err(AcousticError::EndOfStream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read whatever the next block brings. Returns `false` once the input ended.
+    fn read_block(&mut self) -> Result<bool> {
+        let mut bytes = std::mem::take(&mut self.partial);
+        let start = bytes.len();
+        bytes.resize(start + BLOCK_FRAMES * self.format.sample_size(), 0);
+        let read = loop {
+            match self.input.read(&mut bytes[start..]) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                read => break read?,
+            }
+        };
+        if read == 0 {
+            return Ok(false);
+        }
+        bytes.truncate(start + read);
+        let whole = bytes.len() - bytes.len() % self.format.sample_size();
+        self.partial = bytes.split_off(whole);
+        self.buffer.extend(self.format.decode(&bytes));
+        Ok(true)
+    }
+}
+
+impl<R: Read + Send> SampleReader for PcmSampleReader<R> {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        self.read_until(end)?;
+        self.buffer.get(start, end)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        self.read_until(end)?;
+        self.buffer.read(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.buffer.consume(until);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pcm() {
+    use crate::{
+        config::AcousticConfig,
+        transmission::Receiver,
+        transmitter::{modulate_message, write_stream},
+    };
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &modulate_message(&config, b"hello world").unwrap(),
+        &silence,
+    ]
+    .concat();
+    for format in [PcmFormat::F32, PcmFormat::S16] {
+        let mut sink = PcmSink::new(Vec::new(), format);
+        write_stream(&config, signal.iter().copied(), &mut sink).unwrap();
+        let bytes = sink.into_inner();
+        assert_eq!(bytes.len(), signal.len() * format.sample_size());
+
+        // half a sample at the end is dropped
+        let reader = PcmSampleReader::new(std::io::Cursor::new(bytes).chain(&[1_u8][..]), format);
+        let mut receiver = Receiver::with_config(Box::new(reader), config.clone());
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+        assert!(matches!(receiver.run(), Err(AcousticError::EndOfStream)));
+    }
+}
@@ -1,204 +1,914 @@
 //! # Physics Layer
 //!
-//! We use six frequencies to encode the data. One signal per six bits.
+//! Every FSK symbol sounds a subset of `config.carrier_freqs`, one carrier per bit, so a
+//! symbol carries as many bits as there are carriers: four by default, up to
+//! `MAX_CARRIERS`, see `AcousticConfigBuilder::carrier_count`.
+//!
+//! With `Modulation::Ofdm`, data bits go onto STFT-bin aligned subcarriers instead, see
+//! `ofdm_modulate`. With `Modulation::Dpsk`, one carrier carries a bit per symbol in its
+//! phase, see `dpsk_modulate`, with `Modulation::Qpsk` two bits, see `qpsk_modulate`, and
+//! with `Modulation::Qam16` four bits in its phase and amplitude, see `qam16_modulate`.
+//! `Modulation::Dsss` spreads every bit over a chip sequence instead, see `dsss_modulate`,
+//! and `Modulation::Css` sends a byte per chirp, see `css_modulate`.
 
 pub const FREQ_NUMBER: usize = 4;
 
+/// most carriers an FSK symbol can have, one bit each of a `u8`
+pub const MAX_CARRIERS: usize = 8;
+
 use crate::{
-    output_wav,
-    transmission::{SAMPLE_NUMBER, SAMPLE_RATE},
+    config::AcousticConfig,
+    goertzel::{hanning, GoertzelBank},
+    vector,
 };
 
-pub const CARRIER_FREQS: [f64; FREQ_NUMBER] = [
-    2083.464566929134,
-    2604.3307086614172,
-    3472.4409448818897,
-    4166.929133858268,
-];
+pub const CARRIER_FREQS: [f64; FREQ_NUMBER] = [2067.1875, 2583.984375, 3445.3125, 4134.375];
 
 pub const PREAMBLE_NUMBER: usize = 2;
 
-pub const PREAMBLE_FREQS: [f64; PREAMBLE_NUMBER] = [1388.976377952756, 2951.5748031496064];
+pub const PREAMBLE_FREQS: [f64; PREAMBLE_NUMBER] = [1378.125, 2928.515625];
+
+/// Near-inaudible carriers for quiet rooms, see `AcousticConfig::ultrasonic`.
+pub const ULTRASONIC_CARRIER_FREQS: [f64; FREQ_NUMBER] = [18000.0, 18500.0, 19000.0, 19500.0];
+
+pub const ULTRASONIC_PREAMBLE_FREQS: [f64; PREAMBLE_NUMBER] = [18250.0, 20000.0];
+
+/// A carrier is heard when it is this many times louder than the noise floor, about 12 dB.
+pub const DETECTION_MARGIN: f64 = 4.0;
+
+/// Nothing is sent above this fraction of the Nyquist frequency, so that whatever resamples
+/// our audio on the way to the speaker has room for its own anti-aliasing filter.
+pub const ALIAS_GUARD: f64 = 0.95;
 
-static PREAMBLE_SIGNALS: AudioSignalHandle =
-    Lazy::new(|| Mutex::new(generate_signals(&PREAMBLE_FREQS)));
+/// Tones closer than this to the Nyquist frequency splatter past the guard frequency at the
+/// symbol edges, so the signal is low-passed, see `AcousticConfig::low_pass_cutoff`.
+pub const LOW_PASS_BAND: f64 = 4000.0;
 
 pub const PREAMBLE_SEQUENCE: u8 = 0b01010101;
 
-use std::{iter::repeat, sync::Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    iter::{repeat, repeat_n},
+    sync::{Arc, PoisonError, RwLock},
+};
 
 use dasp::{signal, Signal};
 use once_cell::sync::Lazy;
-use ruststft::STFT;
+use rayon::prelude::*;
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
 use tracing::info;
 
-type AudioSignal = Vec<f64>;
-type AudioSignalHandle = Lazy<Mutex<Vec<AudioSignal>>>;
+/// a tone, shared by everyone modulating or listening for it
+type AudioSignal = Arc<[f64]>;
 
-static SIGNALS: AudioSignalHandle = Lazy::new(|| Mutex::new(generate_signals(&CARRIER_FREQS)));
+/// (sample rate, frequency, number of samples, pulse roll-off), floats by their bits
+type SignalKey = (u64, u64, usize, u64);
+type AudioSignalHandle = Lazy<RwLock<HashMap<SignalKey, AudioSignal>>>;
 
-static FFT_FREQS: Lazy<Mutex<Vec<f64>>> = Lazy::new(|| {
-    let stft: ruststft::STFT<f64> = STFT::new(ruststft::WindowType::Hanning, 256, 128);
-    Mutex::new(stft.freqs(SAMPLE_RATE))
-});
+/// Tones already generated for some config.
+static SIGNALS: AudioSignalHandle = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// window and step size of every STFT in this module
+pub const FFT_SIZE: usize = 256;
+pub const FFT_STEP: usize = 128;
+
+/// A Hann windowed FFT of `FFT_SIZE` samples, planned once and with the buffers it needs,
+/// so that a receiver keeps one and transforms block after block without allocating.
+pub struct Stft {
+    fft: Arc<dyn RealToComplex<f64>>,
+    window: Vec<f64>,
+    input: Vec<f64>,
+    spectrum: Vec<Complex<f64>>,
+    scratch: Vec<Complex<f64>>,
+}
+
+impl Default for Stft {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stft {
+    pub fn new() -> Stft {
+        let fft = RealFftPlanner::<f64>::new().plan_fft_forward(FFT_SIZE);
+        Stft {
+            window: hanning(FFT_SIZE),
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
+        }
+    }
+
+    /// The spectrum of the first `FFT_SIZE` samples of `block`, zero padded if it is
+    /// shorter, bin `k` at `k * sample_rate / FFT_SIZE` up to the Nyquist frequency.
+    pub fn spectrum(&mut self, block: &[f64]) -> &[Complex<f64>] {
+        let samples = block.iter().copied().chain(repeat(0.0));
+        for ((input, x), w) in self.input.iter_mut().zip(samples).zip(&self.window) {
+            *input = x * w;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.spectrum, &mut self.scratch)
+            .expect("buffers made by the plan fit it");
+        &self.spectrum
+    }
+
+    /// The magnitude of every bin of `fft_freqs` in `block`.
+    pub fn magnitudes(&mut self, block: &[f64]) -> Vec<f64> {
+        self.spectrum(block)[..FFT_SIZE / 2]
+            .iter()
+            .map(|bin| bin.norm())
+            .collect()
+    }
+}
+
+/// the center frequency of each STFT bin at `sample_rate`
+pub fn fft_freqs(sample_rate: f64) -> Vec<f64> {
+    (0..FFT_SIZE / 2)
+        .map(|k| k as f64 * sample_rate / FFT_SIZE as f64)
+        .collect()
+}
 
 #[test]
 fn test_freqs() {
     use crate::output_wav;
-    for i in 0..FREQ_NUMBER {
-        output_wav(&SIGNALS.lock().unwrap()[i], &format!("{}.wav", i))
+    let config = AcousticConfig::default();
+    for (i, signal) in generate_signals(&config, &config.carrier_freqs)
+        .iter()
+        .enumerate()
+    {
+        output_wav(&config, signal, &format!("{}.wav", i)).unwrap();
     }
 }
 
 #[test]
 fn test_add() {
     use crate::output_wav;
-    let a = SIGNALS.lock().unwrap()[0].clone();
-    let b = vector_add(&a, &SIGNALS.lock().unwrap()[3])
+    let config = AcousticConfig::default();
+    let signals = generate_signals(&config, &config.carrier_freqs);
+    let b = vector_add(&signals[0], &signals[3])
         .iter()
         .map(|x| x / 2.0)
         .collect::<Vec<f64>>();
 
-    output_wav(&b, "01.wav");
+    output_wav(&config, &b, "01.wav").unwrap();
 
-    let mut stft = ruststft::STFT::new(ruststft::WindowType::Hanning, 256, 128);
-    let result = stft_result(&mut stft, &b);
-    println!(
-        "{:?}, {}",
-        result[10]
-            .iter()
-            .map(|x| 10.0_f64.powf(*x))
-            .collect::<Vec<f64>>(),
-        result[10].len()
-    );
+    let result = stft_result(&b);
+    info!("{:?}, {}", result[10], result[10].len());
 }
 
-fn generate_signals(freqs: &[f64]) -> Vec<AudioSignal> {
+fn generate_signals(config: &AcousticConfig, freqs: &[f64]) -> Vec<AudioSignal> {
     freqs
         .iter()
-        .map(|freq| {
-            signal::rate(SAMPLE_RATE)
-                .const_hz(*freq)
-                .phase()
-                .sine()
-                .take(SAMPLE_NUMBER)
-                .collect()
+        .map(|freq| generate_signal(config, *freq))
+        .collect()
+}
+
+/// The symbol long tone at `freq`, generated once per config. Once it is there, taking it
+/// only takes the read lock, so threads modulating at once do not wait for each other.
+fn generate_signal(config: &AcousticConfig, freq: f64) -> AudioSignal {
+    let sample_number = config.sample_number();
+    let key = (
+        config.sample_rate.to_bits(),
+        freq.to_bits(),
+        sample_number,
+        config.pulse_rolloff.to_bits(),
+    );
+    // a tone is whole once it is in, whoever panicked while holding the lock
+    let signals = SIGNALS.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some(signal) = signals.get(&key) {
+        return signal.clone();
+    }
+    drop(signals);
+    let signal = signal::rate(config.sample_rate)
+        .const_hz(freq)
+        .phase()
+        .sine()
+        .take(sample_number)
+        .zip(tukey_window(sample_number, config.pulse_rolloff))
+        .map(|(x, w)| x * w)
+        .collect::<AudioSignal>();
+    SIGNALS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(key)
+        .or_insert(signal)
+        .clone()
+}
+
+#[test]
+fn test_signals_shared() {
+    let config = AcousticConfig::default();
+    let tone = generate_signal(&config, config.carrier_freqs[0]);
+    assert_eq!(tone.len(), config.sample_number());
+    // every thread gets the very same samples, not a copy
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let signals = generate_signals(&config, &config.carrier_freqs);
+                assert!(Arc::ptr_eq(&signals[0], &tone));
+            });
+        }
+    });
+}
+
+/// Raised cosine fades over `rolloff / 2` of the window at either end, flat in between.
+fn tukey_window(n: usize, rolloff: f64) -> impl Iterator<Item = f64> {
+    let fade = n as f64 * rolloff / 2.0;
+    (0..n).map(move |i| {
+        let from_edge = i.min(n - 1 - i) as f64;
+        match from_edge < fade {
+            true => 0.5 - 0.5 * (std::f64::consts::PI * from_edge / fade).cos(),
+            false => 1.0,
+        }
+    })
+}
+
+/// FSK symbols of `b`, each after `AcousticConfig::symbol_gap` of silence.
+pub fn modulate_bits(config: &AcousticConfig, b: Vec<u8>) -> Vec<f64> {
+    let mut signal = Vec::new();
+    modulate_bits_into(config, &b, &mut signal);
+    signal
+}
+
+/// `modulate_bits` appended to `signal`, which grows once to fit all of it.
+pub fn modulate_bits_into(config: &AcousticConfig, bytes: &[u8], signal: &mut Vec<f64>) {
+    let gap = config.symbol_gap_samples();
+    let symbols = pack_symbols(config, bytes);
+    signal.reserve(symbols.len() * (gap + config.sample_number()));
+    for symbol in symbols {
+        signal.resize(signal.len() + gap, 0.0);
+        modulate_symbol_into(config, symbol, signal);
+    }
+}
+
+/// FSK symbols that `len` bytes take
+pub fn fsk_symbols(config: &AcousticConfig, len: usize) -> usize {
+    (8 * len).div_ceil(config.carrier_freqs.len())
+}
+
+/// Cut `bytes` into symbols of one bit per carrier, most significant bit first. The last
+/// symbol is padded with zeros. With four carriers, that is the higher half of every byte
+/// and then the lower.
+pub fn pack_symbols(config: &AcousticConfig, bytes: &[u8]) -> Vec<u8> {
+    let width = config.carrier_freqs.len();
+    let bits = bytes
+        .iter()
+        .flat_map(|b| (0..8).rev().map(move |i| b >> i & 1))
+        .collect::<Vec<u8>>();
+    bits.chunks(width)
+        .map(|bits| {
+            bits.iter()
+                .enumerate()
+                .fold(0, |symbol, (i, bit)| symbol | bit << (width - 1 - i))
         })
         .collect()
 }
 
-pub fn modulate_bits(b: Vec<u8>) -> Vec<f64> {
-    b.iter()
-        .flat_map(|b| modulate_byte(*b))
-        .collect::<Vec<f64>>()
+/// Undo `pack_symbols`, keeping the first `len` bytes.
+pub fn unpack_symbols(config: &AcousticConfig, symbols: &[u8], len: usize) -> Vec<u8> {
+    let width = config.carrier_freqs.len();
+    let bits = symbols
+        .iter()
+        .flat_map(|symbol| (0..width).rev().map(move |i| symbol >> i & 1))
+        .collect::<Vec<u8>>();
+    bits.chunks_exact(8)
+        .take(len)
+        .map(|bits| bits.iter().fold(0, |b, bit| b << 1 | bit))
+        .collect()
 }
 
-pub fn modulate_byte(b: u8) -> Vec<f64> {
-    let higher_four = (b & 0b11110000) >> 4;
-    let lower_four = b & 0b00001111;
-    [
-        modulate_half_byte(higher_four),
-        modulate_half_byte(lower_four),
-    ]
-    .concat()
+/// Carrier `i` sounds when bit `i` of `b` is set. Missing a carrier or hearing one that was
+/// not sent then flips exactly one bit, which is what a Gray code would buy for symbols that
+/// are one tone each, so the bits are not remapped.
+pub fn modulate_symbol(config: &AcousticConfig, b: u8) -> Vec<f64> {
+    let mut symbol = Vec::with_capacity(config.sample_number());
+    modulate_symbol_into(config, b, &mut symbol);
+    symbol
 }
 
-pub fn modulate_half_byte(b: u8) -> Vec<f64> {
-    let mut modulate_result: Vec<f64> = repeat(0.0).take(SAMPLE_NUMBER).collect();
+/// `modulate_symbol` appended to `signal`, summed where it lands rather than in a `Vec` of
+/// its own.
+pub fn modulate_symbol_into(config: &AcousticConfig, b: u8, signal: &mut Vec<f64>) {
+    let signals = generate_signals(config, &config.carrier_freqs);
+    let start = signal.len();
+    signal.resize(start + config.sample_number(), 0.0);
+    let symbol = &mut signal[start..];
     let mut normalize_factor = 0;
-    for i in 0..FREQ_NUMBER {
+    for (i, tone) in signals.iter().enumerate() {
         if (b & (1_u8 << i)) > 0 {
             info!("add {}th bit", i);
-            modulate_result = vector_add(&modulate_result, &SIGNALS.lock().unwrap()[i]);
+            vector::add_assign(symbol, tone);
             normalize_factor += 1;
         }
     }
-    modulate_result
-        .into_iter()
-        .map(|x| {
-            x / (match normalize_factor {
-                0 => 1.0,
-                normalize => normalize as f64,
-            })
+    let n = symbol.len();
+    let ramp = config.ramp_samples();
+    let normalize = match normalize_factor {
+        0 => 1.0,
+        normalize => normalize as f64,
+    };
+    for (i, x) in symbol.iter_mut().enumerate() {
+        // linear ramps at both ends, up to the middle of the symbol if they are long
+        let from_edge = i.min(n - 1 - i);
+        let envelope = match from_edge < ramp {
+            true => from_edge as f64 / ramp as f64,
+            false => 1.0,
+        };
+        *x = *x * envelope / normalize;
+    }
+}
+
+/// taps of the low-pass filter, odd so that it has a center
+const LOW_PASS_TAPS: usize = 127;
+
+fn low_pass_taps(cutoff: f64, sample_rate: f64) -> Vec<f64> {
+    let fc = cutoff / sample_rate;
+    let center = (LOW_PASS_TAPS / 2) as isize;
+    (0..LOW_PASS_TAPS)
+        .map(|i| {
+            let n = i as isize - center;
+            let sinc = match n {
+                0 => 2.0 * fc,
+                n => {
+                    (2.0 * std::f64::consts::PI * fc * n as f64).sin()
+                        / (std::f64::consts::PI * n as f64)
+                }
+            };
+            let hamming = 0.54
+                - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (LOW_PASS_TAPS - 1) as f64).cos();
+            sinc * hamming
         })
         .collect()
 }
 
+/// Windowed-sinc low-pass filter. The output is as long as the input and not delayed.
+pub fn low_pass(signal: &[f64], cutoff: f64, sample_rate: f64) -> Vec<f64> {
+    let mut filter = LowPass::new(cutoff, sample_rate);
+    let mut filtered = signal
+        .iter()
+        .filter_map(|x| filter.push(*x))
+        .collect::<Vec<f64>>();
+    filtered.extend(std::iter::from_fn(|| filter.flush()));
+    filtered
+}
+
+/// `low_pass` a sample at a time, for signals too long to hold at once. Every output is
+/// given `LOW_PASS_TAPS / 2` inputs later, the last ones by `flush`.
+pub struct LowPass {
+    taps: Vec<f64>,
+    /// the last `LOW_PASS_TAPS` inputs, oldest first, silence before the first
+    window: VecDeque<f64>,
+    /// inputs pushed, and the silence after them
+    pushed: usize,
+    /// inputs pushed
+    len: usize,
+    /// outputs given
+    given: usize,
+}
+
+impl LowPass {
+    pub fn new(cutoff: f64, sample_rate: f64) -> LowPass {
+        LowPass {
+            taps: low_pass_taps(cutoff, sample_rate),
+            window: repeat_n(0.0, LOW_PASS_TAPS).collect(),
+            pushed: 0,
+            len: 0,
+            given: 0,
+        }
+    }
+
+    /// Take the next input, and give the output it completes, if any.
+    pub fn push(&mut self, x: f64) -> Option<f64> {
+        self.shift(x);
+        self.len += 1;
+        self.output()
+    }
+
+    /// After the last input, the next output held back, if any.
+    pub fn flush(&mut self) -> Option<f64> {
+        if self.given == self.len {
+            return None;
+        }
+        while !self.ready() {
+            self.shift(0.0);
+        }
+        self.output()
+    }
+
+    fn shift(&mut self, x: f64) {
+        self.window.pop_front();
+        self.window.push_back(x);
+        self.pushed += 1;
+    }
+
+    /// Output `given` needs the inputs up to `LOW_PASS_TAPS / 2` after it.
+    fn ready(&self) -> bool {
+        self.pushed > self.given + LOW_PASS_TAPS / 2
+    }
+
+    fn output(&mut self) -> Option<f64> {
+        if !self.ready() {
+            return None;
+        }
+        self.given += 1;
+        Some(
+            self.taps
+                .iter()
+                .zip(self.window.iter().rev())
+                .map(|(tap, x)| tap * x)
+                .sum(),
+        )
+    }
+}
+
+#[test]
+fn test_low_pass() {
+    let config = AcousticConfig::ultrasonic();
+    let tone = |freq: f64| {
+        signal::rate(config.sample_rate)
+            .const_hz(freq)
+            .sine()
+            .take(config.sample_number())
+            .collect::<Vec<f64>>()
+    };
+    let guard = config.guard_freq();
+    let kept = tone(19500.0);
+    let cut = tone(21800.0);
+    let energy = |s: &[f64]| {
+        s[LOW_PASS_TAPS..s.len() - LOW_PASS_TAPS]
+            .iter()
+            .map(|x| x * x)
+            .sum::<f64>()
+    };
+    assert!(energy(&low_pass(&kept, guard, config.sample_rate)) > 0.8 * energy(&kept));
+    assert!(energy(&low_pass(&cut, guard, config.sample_rate)) < 0.01 * energy(&cut));
+    // shorter than the filter, nothing is held back
+    assert_eq!(low_pass(&kept[..10], guard, config.sample_rate).len(), 10);
+}
+
+#[cfg(test)]
 fn vector_add(v1: &[f64], v2: &[f64]) -> Vec<f64> {
     assert!(v1.len() == v2.len());
-    v1.iter().zip(v2.iter()).map(|(x, y)| *x + *y).collect()
+    vector::add(v1, v2)
 }
 
-fn stft_result(stft: &mut STFT<f64>, input: &[f64]) -> Vec<Vec<f64>> {
-    let mut result = Vec::new();
-    stft.append_samples(input);
-    while stft.contains_enough_to_compute() {
-        let out_size = stft.output_size();
-        let mut segment_result = repeat(0.0).take(out_size).collect::<Vec<f64>>();
-        stft.compute_column(&mut segment_result);
-        result.push(segment_result.iter().map(|f| 10.0_f64.powf(*f)).collect());
-        stft.move_to_next_column();
-    }
-    result
+#[cfg(test)]
+fn stft_result(input: &[f64]) -> Vec<Vec<f64>> {
+    let mut stft = Stft::new();
+    (0..)
+        .map(|i| i * FFT_STEP)
+        .take_while(|start| start + FFT_SIZE <= input.len())
+        .map(|start| stft.magnitudes(&input[start..]))
+        .collect()
 }
 
 #[test]
 fn test_modulate_byte() {
     tracing_subscriber::fmt::init();
+    let config = AcousticConfig::default();
     let x = 0b00110111;
-    let modulated = modulate_byte(x);
-    let mut stft = ruststft::STFT::new(ruststft::WindowType::Hanning, 256, 128);
-    let result = stft_result(&mut stft, &modulated);
-    println!("{:?}, {}", result[5], result[5].len());
-    let b = demodulate_half_byte(&mut stft, modulated.clone());
-    let lower_b = demodulate_half_byte(&mut stft, modulated[modulated.len() / 2..].to_vec());
-    println!("{:#b}, {:#b}", b, lower_b);
+    let modulated = modulate_bits(&config, vec![x]);
+    let result = stft_result(&modulated);
+    info!("{:?}, {}", result[5], result[5].len());
+    let b = demodulate_symbol(&config, &modulated[..modulated.len() / 2]);
+    let lower_b = demodulate_symbol(&config, &modulated[modulated.len() / 2..]);
+    info!("{:#b}, {:#b}", b, lower_b);
     assert_eq!(b, 0b11);
     assert_eq!(lower_b, 0b111);
 }
 
-pub fn demodulate_half_byte(stft: &mut STFT<f64>, fs: Vec<f64>) -> u8 {
-    let result = stft_result(stft, &fs);
-    let freq_col = result[result.len() / 2].to_owned();
-    let freqs = detect_main_freqs(&freq_col);
-    decode_by_given_freq_pattern(&CARRIER_FREQS, &freqs)
+#[test]
+fn test_modulate_into() {
+    let config = AcousticConfig::builder()
+        .symbol_gap(0.01)
+        .ramp_time(0.005)
+        .build()
+        .unwrap();
+    let bytes = [0x3c, 0xa5, 0x00, 0xff];
+    // after what is in the buffer already, the same samples as modulated on their own
+    let mut signal = vec![1.0; 10];
+    modulate_bits_into(&config, &bytes, &mut signal);
+    assert_eq!(signal[..10], [1.0; 10]);
+    assert_eq!(signal[10..], modulate_bits(&config, bytes.to_vec()));
+    let per_symbol = config.symbol_gap_samples() + config.sample_number();
+    assert_eq!(
+        signal.len(),
+        10 + fsk_symbols(&config, bytes.len()) * per_symbol
+    );
+
+    let expected = [
+        vec![0.0; config.symbol_gap_samples()],
+        modulate_symbol(&config, 0b0011),
+    ];
+    assert_eq!(signal[10..10 + per_symbol], expected.concat());
+}
+
+#[test]
+fn test_modulate_custom_config() {
+    let config = AcousticConfig::builder()
+        .symbol_time(0.05)
+        .carrier_freqs(&[1000.0, 1500.0, 2000.0, 2500.0])
+        .build()
+        .unwrap();
+    let modulated = modulate_symbol(&config, 0b1010);
+    assert_eq!(modulated.len(), config.sample_number());
+    assert_eq!(demodulate_symbol(&config, &modulated), 0b1010);
+}
+
+#[test]
+fn test_pulse_shaping() {
+    let square = AcousticConfig::default();
+    let shaped = AcousticConfig::builder()
+        .pulse_rolloff(0.5)
+        .build()
+        .unwrap();
+    let symbol = |config: &AcousticConfig| modulate_symbol(config, 0b0101);
+    assert_eq!(demodulate_symbol(&shaped, &symbol(&shaped)), 0b0101);
+
+    // energy 300 Hz away from the carriers, in a gap between them
+    let splatter = |config: &AcousticConfig| {
+        let signal = [vec![0.0; 1000], symbol(config), vec![0.0; 1000]].concat();
+        GoertzelBank::new(&[2367.1875], config.sample_rate).amplitudes(&signal)[0]
+    };
+    assert!(
+        splatter(&shaped) < splatter(&square) / 4.0,
+        "{} {}",
+        splatter(&shaped),
+        splatter(&square)
+    );
+}
+
+#[test]
+fn test_symbol_ramps() {
+    let config = AcousticConfig::builder().ramp_time(0.03).build().unwrap();
+    let signal = modulate_bits(&config, vec![0x3c, 0xa5]);
+    let n = config.sample_number();
+    // no jumps where the carriers change
+    for boundary in (n..signal.len()).step_by(n) {
+        assert!(signal[boundary - 1].abs() < 0.01 && signal[boundary].abs() < 0.01);
+    }
+    let halves = signal
+        .chunks(n)
+        .map(|symbol| demodulate_symbol(&config, symbol))
+        .collect::<Vec<u8>>();
+    assert_eq!(halves, [0x3, 0xc, 0xa, 0x5]);
+    assert_eq!(config.symbol_window(), 1323..n - 1323);
+}
+
+#[test]
+fn test_detection_follows_noise_floor() {
+    use crate::simulator::awgn_seeded;
+
+    let config = AcousticConfig::default();
+    let symbol = modulate_symbol(&config, 0b1001);
+    // far below what used to count as silence
+    let quiet = symbol.iter().map(|x| x * 0.01).collect::<Vec<f64>>();
+    assert_eq!(demodulate_symbol(&config, &quiet), 0b1001);
+    // a loud microphone, with the noise amplified as much as the signal
+    let loud = awgn_seeded(&symbol, 0.0, 3)
+        .iter()
+        .map(|x| x * 20.0)
+        .collect::<Vec<f64>>();
+    assert_eq!(demodulate_symbol(&config, &loud), 0b1001);
+    // noise alone is not a symbol, however loud
+    let hiss = awgn_seeded(&vec![0.5; symbol.len()], 0.0, 3);
+    assert_eq!(demodulate_symbol(&config, &hiss), 0);
+    assert_eq!(demodulate_symbol(&config, &vec![0.0; symbol.len()]), 0);
 }
 
-fn detect_main_freqs(freq_col: &[f64]) -> Vec<f64> {
-    let mut freq_col_idx: Vec<(f64, usize)> = freq_col.to_owned().into_iter().zip(0..).collect();
-    freq_col_idx.sort_by(|(x, _), (a, _)| x.partial_cmp(a).unwrap());
-    freq_col_idx.reverse();
-    let mut prev_energy = freq_col_idx[0].0;
-    let mut freqs = vec![];
-    for (energy, idx) in freq_col_idx {
-        if (energy - prev_energy).abs() > 3.0 {
-            break;
+#[test]
+fn test_missed_carrier_flips_one_bit() {
+    let config = AcousticConfig::default();
+    let signals = generate_signals(&config, &config.carrier_freqs);
+    for b in 1..16_u8 {
+        for missed in (0..FREQ_NUMBER).filter(|i| b & (1 << i) > 0) {
+            let heard = (0..FREQ_NUMBER)
+                .filter(|i| b & (1 << i) > 0 && *i != missed)
+                .fold(vec![0.0; config.sample_number()], |sum, i| {
+                    vector_add(&sum, &signals[i])
+                });
+            let b_heard = demodulate_symbol(&config, &heard);
+            assert_eq!(
+                (b ^ b_heard).count_ones(),
+                1,
+                "{:#06b} as {:#06b}",
+                b,
+                b_heard
+            );
         }
-        prev_energy = energy;
-        freqs.push(FFT_FREQS.lock().unwrap()[idx]);
     }
-    freqs
 }
 
-fn decode_by_given_freq_pattern(freq_pattern: &[f64], freqs: &[f64]) -> u8 {
-    let mut byte_result = 0_u8;
-    for freq in freqs {
-        let idx = freq_pattern
-            .binary_search_by(|probe| probe.partial_cmp(freq).unwrap())
-            .unwrap();
-        byte_result |= 1 << idx;
+#[test]
+fn test_missed_carrier_flips_one_payload_bit() {
+    let config = AcousticConfig::default();
+    let data = [0x5a, 0xc3, 0xff, 0x81];
+    let symbols = pack_symbols(&config, &data);
+    for (s, symbol) in symbols.iter().enumerate() {
+        for missed in (0..FREQ_NUMBER).filter(|i| symbol & (1 << i) > 0) {
+            let signal = symbols
+                .iter()
+                .enumerate()
+                .flat_map(|(t, b)| match t == s {
+                    true => modulate_symbol(&config, b & !(1 << missed)),
+                    false => modulate_symbol(&config, *b),
+                })
+                .collect::<Vec<f64>>();
+            let heard = signal
+                .chunks(config.sample_number())
+                .map(|symbol| demodulate_symbol(&config, symbol))
+                .collect::<Vec<u8>>();
+            let received = unpack_symbols(&config, &heard, data.len());
+            let flipped = data
+                .iter()
+                .zip(&received)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum::<u32>();
+            assert_eq!(flipped, 1, "carrier {missed} of symbol {s}");
+        }
+    }
+}
+
+/// Demodulate one symbol, looking at its `config.symbol_window()`. Every carrier heard sets
+/// its own bit, see `modulate_symbol`.
+pub fn demodulate_symbol(config: &AcousticConfig, fs: &[f64]) -> u8 {
+    demodulate_symbol_with_gains(config, fs, &vec![1.0; config.carrier_freqs.len()])
+}
+
+/// Like `demodulate_symbol`, on a channel that attenuates the carriers by `gains`, see
+/// `estimate_gains`.
+pub fn demodulate_symbol_with_gains(config: &AcousticConfig, fs: &[f64], gains: &[f64]) -> u8 {
+    let amplitudes = carrier_amplitudes(config, fs);
+    let audible = noise_floor(config, fs) * config.detection_margin;
+    let heard = amplitudes
+        .iter()
+        .zip(gains)
+        .map(|(amplitude, gain)| match *amplitude > audible {
+            true => amplitude / gain,
+            false => 0.0,
+        })
+        .collect::<Vec<f64>>();
+    let strongest = heard.iter().copied().fold(0.0, f64::max);
+    // every carrier of a symbol is sent equally loud
+    heard
+        .iter()
+        .enumerate()
+        .filter(|(_, amplitude)| **amplitude > 0.0 && **amplitude > strongest / 2.0)
+        .fold(0, |b, (i, _)| b | (1 << i))
+}
+
+/// A symbol with every carrier on, sent right after the preamble when `config.pilot` is set.
+pub fn pilot_symbol(config: &AcousticConfig) -> Vec<f64> {
+    modulate_symbol(config, pilot_bits(config))
+}
+
+/// the bits of `pilot_symbol`, every carrier on
+pub fn pilot_bits(config: &AcousticConfig) -> u8 {
+    u8::MAX >> (MAX_CARRIERS - config.carrier_freqs.len())
+}
+
+/// How loud each carrier of a received `pilot_symbol` is, relative to the loudest.
+pub fn estimate_gains(config: &AcousticConfig, fs: &[f64]) -> Vec<f64> {
+    let amplitudes = carrier_amplitudes(config, fs);
+    let strongest = amplitudes.iter().copied().fold(0.0, f64::max);
+    if strongest <= noise_floor(config, fs) * config.detection_margin {
+        return vec![1.0; amplitudes.len()];
+    }
+    amplitudes
+        .iter()
+        .map(|amplitude| (amplitude / strongest).max(MIN_GAIN))
+        .collect()
+}
+
+/// A carrier quieter than this, relative to the loudest, is not boosted any further, it
+/// would only boost noise.
+const MIN_GAIN: f64 = 0.1;
+
+fn carrier_amplitudes(config: &AcousticConfig, fs: &[f64]) -> Vec<f64> {
+    let bank = GoertzelBank::new(&config.received_carrier_freqs(), config.sample_rate);
+    bank.amplitudes(&fs[config.symbol_window()])
+}
+
+/// Median amplitude halfway between the carriers, and half a spacing outside of them, where
+/// nothing is sent. Never below `MIN_NOISE_FLOOR`.
+fn noise_floor(config: &AcousticConfig, fs: &[f64]) -> f64 {
+    let mut carriers = config.received_carrier_freqs();
+    carriers.sort_by(f64::total_cmp);
+    let gaps = carriers
+        .windows(2)
+        .map(|w| w[1] - w[0])
+        .collect::<Vec<f64>>();
+    let (Some(first), Some(last)) = (gaps.first(), gaps.last()) else {
+        return MIN_NOISE_FLOOR;
+    };
+    let freqs = [
+        vec![carriers[0] - first / 2.0],
+        carriers.windows(2).map(|w| (w[0] + w[1]) / 2.0).collect(),
+        vec![carriers[carriers.len() - 1] + last / 2.0],
+    ]
+    .concat();
+    let mut amplitudes =
+        GoertzelBank::new(&freqs, config.sample_rate).amplitudes(&fs[config.symbol_window()]);
+    amplitudes.sort_by(f64::total_cmp);
+    amplitudes[amplitudes.len() / 2].max(MIN_NOISE_FLOOR)
+}
+
+/// Samples either side of the expected start of an FSK symbol the timing recovery listens
+/// to, see `timing_error`. About an eighth of a symbol, in steps of `FFT_STEP`.
+pub fn timing_gate(config: &AcousticConfig) -> usize {
+    let n = config.sample_number();
+    (n / 8 / FFT_STEP * FFT_STEP).max(FFT_STEP).min(n / 4)
+}
+
+/// How many samples later than expected the FSK symbol `b` arrived after `previous`, from
+/// `fs` which holds both, `b` expected to start at `config.sample_number()`.
+///
+/// Around the expected start, a carrier that `b` turns on sounds for as many samples as the
+/// symbol is early, and one that it turns off for as many as it is late, counted by how
+/// loud it is there compared to the middle of its symbol. The counts of every carrier that
+/// changed are averaged, carriers that did not change tell nothing.
+///
+/// Carriers sit on STFT bins, so over a multiple of `FFT_SIZE` samples the other carriers
+/// do not leak into the one counted, both windows are cut to that.
+pub fn timing_error(config: &AcousticConfig, fs: &[f64], previous: u8, b: u8) -> f64 {
+    let n = config.sample_number();
+    let gate = timing_gate(config);
+    if fs.len() < 2 * n {
+        return 0.0;
+    }
+    let window = config.symbol_window();
+    let trim = match window.len() < FFT_SIZE {
+        true => 0,
+        false => window.len() % FFT_SIZE,
+    };
+    let window = window.start + trim / 2..window.end - (trim - trim / 2);
+    let edge = &fs[n - gate..n + gate];
+    let errors = config
+        .received_carrier_freqs()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, freq)| {
+            let turned_on = b & (1 << i) > 0;
+            let middle = match (previous & (1 << i) > 0, turned_on) {
+                (false, true) => &fs[n + window.start..n + window.end],
+                (true, false) => &fs[window.clone()],
+                _ => return None,
+            };
+            let per_sample = tone_magnitude(config, *freq, middle) / middle.len() as f64;
+            if per_sample <= MIN_NOISE_FLOOR {
+                return None;
+            }
+            let sounding = tone_magnitude(config, *freq, edge) / per_sample;
+            if sounding > edge.len() as f64 {
+                return None;
+            }
+            Some(match turned_on {
+                true => gate as f64 - sounding,
+                false => sounding - gate as f64,
+            })
+        })
+        .collect::<Vec<f64>>();
+    match errors.is_empty() {
+        true => 0.0,
+        false => {
+            let error = errors.iter().sum::<f64>() / errors.len() as f64;
+            error.clamp(-(gate as f64), gate as f64)
+        }
     }
-    byte_result
 }
 
-pub fn prepend_preamble(signal: &[f64]) -> Vec<f64> {
-    let a = PREAMBLE_SIGNALS.lock().unwrap()[0].clone();
-    let b = PREAMBLE_SIGNALS.lock().unwrap()[1].clone();
-    let mut s = [a, b].concat().repeat(2);
+/// Magnitude of the correlation of `fs` with a tone at `freq`, without the taper of the
+/// `GoertzelBank`, so that it grows with every sample the tone sounds for.
+fn tone_magnitude(config: &AcousticConfig, freq: f64, fs: &[f64]) -> f64 {
+    let omega = 2.0 * std::f64::consts::PI * freq / config.sample_rate;
+    let (i, q) = fs.iter().enumerate().fold((0.0, 0.0), |(i, q), (t, x)| {
+        let phase = omega * t as f64;
+        (i + x * phase.sin(), q + x * phase.cos())
+    });
+    i.hypot(q)
+}
+
+#[test]
+fn test_timing_error() {
+    let config = AcousticConfig::default();
+    let n = config.sample_number();
+    let gate = timing_gate(&config);
+    let signal = [0b0000, 0b0101, 0b0110, 0b0000]
+        .iter()
+        .flat_map(|b| modulate_symbol(&config, *b))
+        .collect::<Vec<f64>>();
+    for late in [-(gate as isize) / 2, 0, gate as isize / 2] {
+        // the third symbol arrives `late` samples after the expected `2 * n`, one carrier
+        // stays on, one turns on and one off
+        let start = (n as isize - late) as usize;
+        let error = timing_error(&config, &signal[start..start + 2 * n], 0b0101, 0b0110);
+        assert!(
+            (error - late as f64).abs() < 10.0,
+            "{} late as {}",
+            late,
+            error
+        );
+    }
+    // nothing changed
+    assert_eq!(
+        timing_error(&config, &signal[n..3 * n], 0b0110, 0b0110),
+        0.0
+    );
+}
+
+/// Quieter than anything a sound card records, so that digital silence stays silent.
+const MIN_NOISE_FLOOR: f64 = 1e-6;
+
+/// Tones weaker than this, relative to full scale, are silence.
+const SILENCE_AMPLITUDE: f64 = 0.03;
+
+pub fn prepend_preamble(config: &AcousticConfig, signal: &[f64]) -> Vec<f64> {
+    let mut s = generate_signals(config, &config.preamble_freqs)
+        .concat()
+        .repeat(2);
     s.extend_from_slice(signal);
     s
 }
 
+/// Estimates of the clock drift beyond this are noise, no sound card is that far off.
+pub const MAX_CLOCK_DRIFT: f64 = 2e-3;
+
+/// How much faster the sender's sample clock runs than ours, as a fraction, from the four
+/// symbols of a received preamble, see `prepend_preamble`.
+///
+/// Both times the lower tone is sent it starts at the same phase. Cut out where it should be
+/// two symbols apart, the second one arrives early or late by as many samples as the clocks
+/// drifted apart in between, and its phase shows by how much. Up to half a period of the
+/// tone, which is 1800 ppm with the default preamble and only 130 ppm with the ultrasonic
+/// one.
+pub fn estimate_clock_drift(config: &AcousticConfig, preamble: &[f64]) -> f64 {
+    let n = config.sample_number();
+    if preamble.len() < 4 * n {
+        return 0.0;
+    }
+    let low = config.preamble_freqs[0].min(config.preamble_freqs[1]);
+    let first = match config.preamble_freqs[0] <= config.preamble_freqs[1] {
+        true => 0,
+        false => n,
+    };
+    let omega = 2.0 * std::f64::consts::PI * low / config.sample_rate;
+    let phase = |start: usize| {
+        let (i, q) = (n / 4..n * 3 / 4).fold((0.0, 0.0), |(i, q), t| {
+            let x = preamble[start + t];
+            let phase = omega * t as f64;
+            (i + x * phase.sin(), q + x * phase.cos())
+        });
+        q.atan2(i)
+    };
+    let turned = phase(first + 2 * n) - phase(first);
+    let turned = (turned + std::f64::consts::PI).rem_euclid(2.0 * std::f64::consts::PI)
+        - std::f64::consts::PI;
+    let drift = turned / (2.0 * n as f64 * omega);
+    match drift.abs() < MAX_CLOCK_DRIFT {
+        true => drift,
+        false => 0.0,
+    }
+}
+
+/// How loud the tone each symbol of a received preamble should not have is, relative to
+/// the one it should, see `prepend_preamble`. Close to 0 for a clean preamble, while a
+/// preamble of another sender overlapping it out of step sounds both tones at once.
+pub fn preamble_overlap(config: &AcousticConfig, preamble: &[f64]) -> f64 {
+    let n = config.sample_number();
+    if preamble.len() < 4 * n {
+        return 0.0;
+    }
+    let bank = GoertzelBank::new(&config.preamble_freqs, config.sample_rate);
+    let (wanted, unwanted) = (0..4).fold((0.0, 0.0), |(wanted, unwanted), i| {
+        let amplitudes = bank.amplitudes(&preamble[i * n..][config.symbol_window()]);
+        (wanted + amplitudes[i % 2], unwanted + amplitudes[1 - i % 2])
+    });
+    match wanted > 0.0 {
+        true => unwanted / wanted,
+        false => 0.0,
+    }
+}
+
+/// Signal to noise ratio of a received preamble in decibels: the tone each symbol should
+/// have, over the noise between the carriers, see `noise_floor`.
+pub fn preamble_snr(config: &AcousticConfig, preamble: &[f64]) -> Option<f64> {
+    let n = config.sample_number();
+    if preamble.len() < 4 * n {
+        return None;
+    }
+    let bank = GoertzelBank::new(&config.preamble_freqs, config.sample_rate);
+    let (signal, noise) = (0..4).fold((0.0, 0.0), |(signal, noise), i| {
+        let symbol = &preamble[i * n..][..n];
+        let amplitudes = bank.amplitudes(&symbol[config.symbol_window()]);
+        (
+            signal + amplitudes[i % 2],
+            noise + noise_floor(config, symbol),
+        )
+    });
+    Some(20.0 * (signal / noise).log10())
+}
+
 /// Preamble detection
 #[derive(Debug, Clone, Copy)]
 pub enum Preamble {
@@ -206,73 +916,961 @@ pub enum Preamble {
     Detected {
         ending_position: usize,
         signal_bit: u8,
-        votes: u8,
+        votes: u32,
     },
 }
 
-pub fn detect_preamble(signal: &[f64]) -> Preamble {
-    let mut stft = STFT::new(ruststft::WindowType::Hanning, 256, 128);
-    let mut ending_position = 0;
-    let mut zero_vote = 0;
-    let mut one_vote = 0;
-    let freq_cols = stft_result(&mut stft, signal);
-    'outer: for col in freq_cols {
-        let main_freqs = detect_main_freqs(&col);
-        info!("freq: {:?}", main_freqs[0]);
-        ending_position += stft.output_size();
-        for main_freq in main_freqs {
-            if (main_freq - PREAMBLE_FREQS[0]).abs() < 1e-1 {
-                if one_vote != 0 {
-                    ending_position -= stft.output_size();
-                    break 'outer;
-                }
-                zero_vote += 1;
-                break;
-            } else if (main_freq - PREAMBLE_FREQS[1]).abs() < 1e-1 {
-                if zero_vote != 0 {
-                    ending_position -= stft.output_size();
-                    break 'outer;
-                }
-                one_vote += 1;
-                break;
-            }
+/// Look for preamble tones in blocks of `FFT_SIZE` samples, every `FFT_STEP` samples, see
+/// `PreambleDetector`.
+pub fn detect_preamble(config: &AcousticConfig, signal: &[f64]) -> Preamble {
+    PreambleDetector::new(config).detect(signal)
+}
+
+/// The filters `detect_preamble` listens with, set up once for a config, so that a receiver
+/// probing block after block keeps one.
+///
+/// A block votes for a preamble tone if that tone is the strongest of the preamble and
+/// carrier tones. The bins right next to the preamble tones are listened to as well, so
+/// that a neighbour leaking into a preamble bin does not count as a preamble.
+#[derive(Debug, Clone)]
+pub struct PreambleDetector {
+    bank: GoertzelBank,
+}
+
+impl PreambleDetector {
+    pub fn new(config: &AcousticConfig) -> PreambleDetector {
+        let bin = config.sample_rate / FFT_SIZE as f64;
+        let neighbours = config
+            .preamble_freqs
+            .iter()
+            .flat_map(|f| [f - bin, f + bin])
+            .collect::<Vec<f64>>();
+        let bank = GoertzelBank::new(
+            &[
+                &config.preamble_freqs[..],
+                &config.carrier_freqs,
+                &neighbours,
+            ]
+            .concat(),
+            config.sample_rate,
+        );
+        PreambleDetector {
+            bank: bank.with_block_len(FFT_SIZE),
         }
     }
-    match (zero_vote, one_vote) {
-        (0, 0) => Preamble::NoPreamble,
-        (x, y) => Preamble::Detected {
-            ending_position,
-            signal_bit: if x > y { 0 } else { 1 },
-            votes: if x > y { zero_vote } else { one_vote },
-        },
+
+    pub fn detect(&self, signal: &[f64]) -> Preamble {
+        let bank = &self.bank;
+        let mut ending_position = 0;
+        let mut zero_vote = 0;
+        let mut one_vote = 0;
+        let blocks = (0..)
+            .map(|i| i * FFT_STEP)
+            .take_while(|start| start + FFT_SIZE <= signal.len());
+        for start in blocks {
+            let amplitudes = bank.amplitudes(&signal[start..start + FFT_SIZE]);
+            let (strongest, amplitude) = amplitudes
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .unwrap_or((0, 0.0));
+            info!("freq: {}, amplitude {}", bank.freqs()[strongest], amplitude);
+            if amplitude < SILENCE_AMPLITUDE {
+                ending_position += FFT_STEP;
+                continue;
+            }
+            match strongest {
+                0 if one_vote != 0 => break,
+                0 => zero_vote += 1,
+                1 if zero_vote != 0 => break,
+                1 => one_vote += 1,
+                _ => {}
+            }
+            ending_position += FFT_STEP;
+        }
+        match (zero_vote, one_vote) {
+            (0, 0) => Preamble::NoPreamble,
+            (x, y) => Preamble::Detected {
+                ending_position,
+                signal_bit: if x > y { 0 } else { 1 },
+                votes: if x > y { zero_vote } else { one_vote },
+            },
+        }
     }
 }
 
 #[test]
 fn test_preamble() {
+    use crate::output_wav;
+    let config = AcousticConfig::default();
     let mut v = Vec::new();
-    v = prepend_preamble(&v);
-    println!("{:?}", detect_preamble(&v));
-    output_wav(&v, "preamble.wav");
+    v = prepend_preamble(&config, &v);
+    info!("{:?}", detect_preamble(&config, &v));
+    output_wav(&config, &v, "preamble.wav").unwrap();
     let mut reader = hound::WavReader::open("preamble.wav").unwrap();
     let samples: Vec<f64> = reader.samples::<f32>().map(|f| f.unwrap() as f64).collect();
-    println!("{:?}", detect_preamble(&v));
+    info!("{:?}", detect_preamble(&config, &samples));
 }
 
 #[test]
 fn test_preamble_zero() {
+    use crate::output_wav;
+    let config = AcousticConfig::default();
     let mut v = Vec::new();
-    v.extend(PREAMBLE_SIGNALS.lock().unwrap()[0].repeat(100));
-    output_wav(&v, "always0.wav");
+    v.extend(generate_signals(&config, &config.preamble_freqs)[0].repeat(100));
+    output_wav(&config, &v, "always0.wav").unwrap();
 }
 
 #[test]
 fn test_output_freqs() {
-    use ruststft::STFT;
-    let test_signal = SIGNALS.lock().unwrap()[0].clone();
-    let mut sfft: STFT<f64> = STFT::new(ruststft::WindowType::Hanning, 256, 128);
-    sfft.append_samples(&test_signal);
-    let mut result = repeat(0.0).take(sfft.output_size()).collect::<Vec<f64>>();
-    sfft.compute_column(&mut result);
-    println!("{:?}, {}", sfft.freqs(44100.0), sfft.freqs(44100.0).len());
+    let config = AcousticConfig::default();
+    let test_signal = generate_signals(&config, &config.carrier_freqs)[0].clone();
+    let result = Stft::new().magnitudes(&test_signal);
+    let freqs = fft_freqs(config.sample_rate);
+    info!("{:?}, {}", freqs, freqs.len());
+    // the carrier sits on its bin
+    let loudest = (0..result.len())
+        .max_by(|a, b| result[*a].total_cmp(&result[*b]))
+        .unwrap();
+    assert_eq!(freqs[loudest], config.carrier_freqs[0]);
+}
+
+/// OFDM puts one bit on each subcarrier. Subcarriers sit on every other STFT bin: the
+/// Hanning window leaks a bin-aligned tone into its two neighbours only, so bins two
+/// apart stay orthogonal.
+pub const OFDM_SUBCARRIERS: usize = 32;
+pub const OFDM_FIRST_BIN: usize = 12;
+pub const OFDM_SYMBOL_BYTES: usize = OFDM_SUBCARRIERS / 8;
+
+/// A window starting anywhere inside the cyclic prefix still sees whole periods of every
+/// subcarrier. The receiver finds the end of a preamble to within an STFT step either
+/// way, so the prefix is two steps long.
+pub const OFDM_CYCLIC_PREFIX: usize = 2 * FFT_STEP;
+pub const OFDM_SYMBOL_SIZE: usize = OFDM_CYCLIC_PREFIX + FFT_SIZE;
+
+/// Subcarriers whose magnitude is below this are silence.
+const OFDM_SILENCE_MAGNITUDE: f64 = 0.5;
+
+fn ofdm_bin(subcarrier: usize) -> usize {
+    OFDM_FIRST_BIN + 2 * subcarrier
+}
+
+/// frequencies of the OFDM subcarriers
+pub fn ofdm_freqs(sample_rate: f64) -> Vec<f64> {
+    let bins = fft_freqs(sample_rate);
+    (0..OFDM_SUBCARRIERS).map(|i| bins[ofdm_bin(i)]).collect()
+}
+
+/// modulate bytes to OFDM symbols, the last symbol is padded with zeros
+pub fn ofdm_modulate(bytes: &[u8]) -> Vec<f64> {
+    bytes
+        .chunks(OFDM_SYMBOL_BYTES)
+        .flat_map(ofdm_modulate_symbol)
+        .collect()
+}
+
+pub fn ofdm_modulate_symbol(bytes: &[u8]) -> Vec<f64> {
+    let bins = (0..OFDM_SUBCARRIERS)
+        .filter(|i| bytes.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) > 0))
+        .map(ofdm_bin)
+        .collect::<Vec<usize>>();
+    let normalize_factor = bins.len().max(1) as f64;
+    let body = (0..FFT_SIZE)
+        .map(|n| {
+            bins.iter()
+                .map(|k| (2.0 * std::f64::consts::PI * (k * n) as f64 / FFT_SIZE as f64).sin())
+                .sum::<f64>()
+                / normalize_factor
+        })
+        .collect::<Vec<f64>>();
+    [&body[FFT_SIZE - OFDM_CYCLIC_PREFIX..], &body[..]].concat()
+}
+
+/// demodulate whole OFDM symbols, trailing samples that do not fill a symbol are ignored
+pub fn ofdm_demodulate(signal: &[f64]) -> Vec<u8> {
+    ofdm_demodulate_with(&mut Stft::new(), signal)
+}
+
+/// `ofdm_demodulate` on every core, each with an FFT of its own
+pub fn ofdm_demodulate_parallel(signal: &[f64]) -> Vec<u8> {
+    signal
+        .par_chunks_exact(OFDM_SYMBOL_SIZE)
+        .map_init(Stft::new, |stft, symbol| {
+            ofdm_demodulate_symbol(stft, symbol)
+        })
+        .collect::<Vec<Vec<u8>>>()
+        .concat()
+}
+
+/// `ofdm_demodulate` with an FFT planned already
+pub fn ofdm_demodulate_with(stft: &mut Stft, signal: &[f64]) -> Vec<u8> {
+    signal
+        .chunks_exact(OFDM_SYMBOL_SIZE)
+        .flat_map(|symbol| ofdm_demodulate_symbol(stft, symbol))
+        .collect()
+}
+
+pub fn ofdm_demodulate_symbol(stft: &mut Stft, symbol: &[f64]) -> Vec<u8> {
+    // start in the middle of the cyclic prefix, to tolerate being early or late
+    let start = OFDM_CYCLIC_PREFIX / 2;
+    let spectrum = stft.spectrum(&symbol[start..start + FFT_SIZE]);
+    let subcarriers = (0..OFDM_SUBCARRIERS)
+        .map(|i| spectrum[ofdm_bin(i)].norm())
+        .collect::<Vec<f64>>();
+    let strongest = subcarriers.iter().copied().fold(0.0, f64::max);
+    let mut bytes = vec![0_u8; OFDM_SYMBOL_BYTES];
+    if strongest < OFDM_SILENCE_MAGNITUDE {
+        return bytes;
+    }
+    for (i, magnitude) in subcarriers.iter().enumerate() {
+        if *magnitude > strongest / 2.0 {
+            bytes[i / 8] |= 1 << (i % 8);
+        }
+    }
+    bytes
+}
+
+#[test]
+fn test_ofdm() {
+    let data = (0..=255).collect::<Vec<u8>>();
+    let modulated = ofdm_modulate(&data);
+    assert_eq!(
+        modulated.len(),
+        OFDM_SYMBOL_SIZE * data.len() / OFDM_SYMBOL_BYTES
+    );
+    assert!(modulated.iter().all(|x| x.abs() <= 1.0));
+    assert_eq!(ofdm_demodulate(&modulated), data);
+}
+
+#[test]
+fn test_ofdm_padding_and_silence() {
+    let modulated = ofdm_modulate(b"hello");
+    assert_eq!(modulated.len(), 2 * OFDM_SYMBOL_SIZE);
+    assert_eq!(ofdm_demodulate(&modulated), b"hello\0\0\0");
+    assert_eq!(
+        ofdm_demodulate(&[0.0; OFDM_SYMBOL_SIZE]),
+        [0; OFDM_SYMBOL_BYTES]
+    );
+}
+
+/// Costas loop gain on the phase error of every symbol
+const COSTAS_PHASE_GAIN: f64 = 0.5;
+
+/// Costas loop gain on the frequency, the phase error per sample
+const COSTAS_FREQ_GAIN: f64 = 0.1;
+
+/// Single carrier BPSK on the lowest carrier, one bit per symbol, least significant first.
+///
+/// Bits are differentially encoded behind one reference symbol: a 1 flips the phase, a 0
+/// keeps it. A Costas loop locks on either of two opposite phases, this way it does not
+/// matter which.
+pub fn bpsk_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    let n = config.sample_number();
+    let omega = 2.0 * std::f64::consts::PI * config.carrier_freqs[0] / config.sample_rate;
+    let mut sign = 1.0;
+    let signs = std::iter::once(sign).chain(
+        bytes
+            .iter()
+            .flat_map(|b| (0..8).map(move |i| b >> i & 1))
+            .map(|bit| {
+                if bit == 1 {
+                    sign = -sign;
+                }
+                sign
+            }),
+    );
+    signs
+        .enumerate()
+        .flat_map(|(k, sign)| (k * n..(k + 1) * n).map(move |i| sign * (omega * i as f64).sin()))
+        .collect()
+}
+
+/// Undo `bpsk_modulate` on a signal starting at the reference symbol, with whatever carrier
+/// phase it arrives at. Every symbol is correlated against the in-phase and quadrature
+/// references of a local oscillator, and the arctangent of the two steers the oscillator
+/// onto the carrier.
+pub fn bpsk_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    let n = config.sample_number();
+    let omega = 2.0 * std::f64::consts::PI * config.carrier_freqs[0] / config.sample_rate;
+    // oscillator phase, and its correction of the nominal frequency
+    let mut theta = 0.0_f64;
+    let mut delta = 0.0;
+    let mut previous = None;
+    let mut bits = Vec::new();
+    for symbol in signal.chunks_exact(n) {
+        let (mut i, mut q) = (0.0, 0.0);
+        for x in symbol {
+            i += x * theta.sin();
+            q += x * theta.cos();
+            theta += omega + delta;
+        }
+        // the data sign cancels out, the loop only sees the phase error
+        let error = match i {
+            0.0 => 0.0,
+            i => (q / i).atan(),
+        };
+        theta += COSTAS_PHASE_GAIN * error;
+        delta += COSTAS_FREQ_GAIN * error / n as f64;
+        let sign = i > 0.0;
+        if let Some(previous) = previous {
+            bits.push((sign != previous) as u8);
+        }
+        previous = Some(sign);
+    }
+    bits.chunks_exact(8)
+        .map(|bits| bits.iter().enumerate().fold(0, |b, (i, bit)| b | bit << i))
+        .collect()
+}
+
+/// a point of a single carrier constellation, in-phase and quadrature
+type Point = (f64, f64);
+
+/// The middle of every symbol of `signal` correlated against the in-phase and quadrature
+/// references of an oscillator on the lowest carrier, that starts with the signal.
+fn carrier_phasors(config: &AcousticConfig, signal: &[f64]) -> Vec<Point> {
+    let n = config.sample_number();
+    let omega = 2.0 * std::f64::consts::PI * config.carrier_freqs[0] / config.sample_rate;
+    let window = config.symbol_window();
+    map_symbols(config, signal, n, |k, symbol| {
+        window.clone().fold((0.0, 0.0), |(i, q), j| {
+            let phase = omega * (k * n + j) as f64;
+            (i + symbol[j] * phase.sin(), q + symbol[j] * phase.cos())
+        })
+    })
+}
+
+/// `f` of every whole symbol of `n` samples in `signal` and of its index, in order. With
+/// `config.parallel` the symbols are spread over every core, which only pays off for long
+/// recordings, the threads take longer to wake up than a packet takes to demodulate.
+pub fn map_symbols<T: Send>(
+    config: &AcousticConfig,
+    signal: &[f64],
+    n: usize,
+    f: impl Fn(usize, &[f64]) -> T + Sync + Send,
+) -> Vec<T> {
+    match config.parallel {
+        true => signal
+            .par_chunks_exact(n)
+            .enumerate()
+            .map(|(k, symbol)| f(k, symbol))
+            .collect(),
+        false => signal
+            .chunks_exact(n)
+            .enumerate()
+            .map(|(k, symbol)| f(k, symbol))
+            .collect(),
+    }
+}
+
+/// Put `points` on the lowest carrier one symbol each, behind a reference symbol at (1, 0).
+fn single_carrier_modulate(config: &AcousticConfig, points: &[Point]) -> Vec<f64> {
+    let n = config.sample_number();
+    let omega = 2.0 * std::f64::consts::PI * config.carrier_freqs[0] / config.sample_rate;
+    std::iter::once(&(1.0, 0.0))
+        .chain(points)
+        .enumerate()
+        .flat_map(|(k, (i, q))| {
+            (k * n..(k + 1) * n).map(move |t| {
+                let phase = omega * t as f64;
+                i * phase.sin() + q * phase.cos()
+            })
+        })
+        .collect()
+}
+
+/// gain on the phase error of every decision, to follow a drifting carrier
+const DECISION_PHASE_GAIN: f64 = 0.3;
+
+/// Undo `single_carrier_modulate`, returning the index of the nearest point of
+/// `constellation` for every symbol, which for a grid are square decision regions. Dividing by the reference symbol takes out the phase
+/// and the gain of the channel, and every decision after that nudges the phase towards the
+/// point decided on.
+fn single_carrier_demodulate(
+    config: &AcousticConfig,
+    signal: &[f64],
+    constellation: &[Point],
+) -> Vec<usize> {
+    let phasors = carrier_phasors(config, signal);
+    let Some(((ri, rq), symbols)) = phasors.split_first() else {
+        return vec![];
+    };
+    let power = (ri * ri + rq * rq).max(f64::MIN_POSITIVE);
+    let mut drift = 0.0_f64;
+    symbols
+        .iter()
+        .map(|(i, q)| {
+            // divided by the reference, then turned back by the drift
+            let (i, q) = ((i * ri + q * rq) / power, (q * ri - i * rq) / power);
+            let (sin, cos) = drift.sin_cos();
+            let (i, q) = (i * cos + q * sin, q * cos - i * sin);
+            let nearest = (0..constellation.len())
+                .min_by(|a, b| {
+                    let distance = |k: &usize| {
+                        let (pi, pq) = constellation[*k];
+                        (i - pi).powi(2) + (q - pq).powi(2)
+                    };
+                    distance(a).total_cmp(&distance(b))
+                })
+                .unwrap_or_default();
+            let (pi, pq) = constellation[nearest];
+            drift += DECISION_PHASE_GAIN * (q * pi - i * pq).atan2(i * pi + q * pq);
+            nearest
+        })
+        .collect()
+}
+
+/// QPSK points, Gray coded: the low bit picks the sign of the in-phase part, the high bit
+/// the sign of the quadrature part
+const QPSK_POINTS: [Point; 4] = [
+    (
+        std::f64::consts::FRAC_1_SQRT_2,
+        std::f64::consts::FRAC_1_SQRT_2,
+    ),
+    (
+        -std::f64::consts::FRAC_1_SQRT_2,
+        std::f64::consts::FRAC_1_SQRT_2,
+    ),
+    (
+        std::f64::consts::FRAC_1_SQRT_2,
+        -std::f64::consts::FRAC_1_SQRT_2,
+    ),
+    (
+        -std::f64::consts::FRAC_1_SQRT_2,
+        -std::f64::consts::FRAC_1_SQRT_2,
+    ),
+];
+
+/// samples `qpsk_modulate` takes for `len` bytes, a reference symbol and one per two bits
+pub fn qpsk_len(config: &AcousticConfig, len: usize) -> usize {
+    (4 * len + 1) * config.sample_number()
+}
+
+/// Single carrier QPSK on the lowest carrier, two bits per symbol, least significant first.
+pub fn qpsk_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    let points = bytes
+        .iter()
+        .flat_map(|b| (0..4).map(move |k| QPSK_POINTS[(b >> (2 * k) & 0b11) as usize]))
+        .collect::<Vec<Point>>();
+    single_carrier_modulate(config, &points)
+}
+
+/// Undo `qpsk_modulate` on a signal starting at the reference symbol.
+pub fn qpsk_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    single_carrier_demodulate(config, signal, &QPSK_POINTS)
+        .chunks_exact(4)
+        .map(|pairs| {
+            pairs
+                .iter()
+                .enumerate()
+                .fold(0, |b, (k, pair)| b | (*pair as u8) << (2 * k))
+        })
+        .collect()
+}
+
+/// 16-QAM levels along one axis, Gray coded, scaled so that the corners are as loud as the
+/// reference symbol
+const QAM16_LEVELS: [f64; 4] = [
+    -3.0 / QAM16_CORNER,
+    -1.0 / QAM16_CORNER,
+    3.0 / QAM16_CORNER,
+    1.0 / QAM16_CORNER,
+];
+
+/// distance of the outer corners from the origin before scaling, 3√2
+const QAM16_CORNER: f64 = 3.0 * std::f64::consts::SQRT_2;
+
+/// 16-QAM points, the low two bits pick the in-phase level and the high two the quadrature
+static QAM16_POINTS: Lazy<Vec<Point>> = Lazy::new(|| {
+    (0..16)
+        .map(|k| (QAM16_LEVELS[k & 0b11], QAM16_LEVELS[k >> 2]))
+        .collect()
+});
+
+/// samples `qam16_modulate` takes for `len` bytes, a reference symbol and one per half byte
+pub fn qam16_len(config: &AcousticConfig, len: usize) -> usize {
+    (2 * len + 1) * config.sample_number()
+}
+
+/// Single carrier 16-QAM on the lowest carrier, a half byte per symbol, the lower first.
+/// Only for short, quiet links, the inner points are a third as loud as the corners.
+pub fn qam16_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    let points = bytes
+        .iter()
+        .flat_map(|b| [b & 0xf, b >> 4])
+        .map(|half| QAM16_POINTS[half as usize])
+        .collect::<Vec<Point>>();
+    single_carrier_modulate(config, &points)
+}
+
+/// Undo `qam16_modulate` on a signal starting at the reference symbol. The reference also
+/// sets the scale, so the volume of the speaker does not move the decision boundaries.
+pub fn qam16_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    single_carrier_demodulate(config, signal, &QAM16_POINTS)
+        .chunks_exact(2)
+        .map(|halves| (halves[0] | halves[1] << 4) as u8)
+        .collect()
+}
+
+/// samples `dpsk_modulate` takes for `len` bytes, a reference symbol and one per bit
+pub fn dpsk_len(config: &AcousticConfig, len: usize) -> usize {
+    (8 * len + 1) * config.sample_number()
+}
+
+/// DPSK puts the same differentially encoded phases on the carrier as `bpsk_modulate`.
+pub fn dpsk_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    bpsk_modulate(config, bytes)
+}
+
+/// Undo `dpsk_modulate` without recovering the carrier. The middle of every symbol is
+/// correlated against a free running oscillator, and a bit is 1 where the phase turned
+/// around from the symbol before. Whatever phase the speaker and microphone add, they add
+/// to both symbols.
+pub fn dpsk_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    let bits = carrier_phasors(config, signal)
+        .windows(2)
+        .map(|w| ((w[0].0 * w[1].0 + w[0].1 * w[1].1) < 0.0) as u8)
+        .collect::<Vec<u8>>();
+    bits.chunks_exact(8)
+        .map(|bits| bits.iter().enumerate().fold(0, |b, (i, bit)| b | bit << i))
+        .collect()
+}
+
+/// chips every DSSS bit is spread over, the period of `PN_SEQUENCE`
+pub const PN_CHIPS: usize = 31;
+
+/// Maximum length sequence of the LFSR x^5 + x^3 + 1, as signs. Shifted against itself it
+/// correlates to -1 out of 31.
+static PN_SEQUENCE: Lazy<[f64; PN_CHIPS]> = Lazy::new(|| {
+    let mut state = 0b11111_u8;
+    [0; PN_CHIPS].map(|_| {
+        let bit = ((state >> 4) ^ (state >> 2)) & 1;
+        state = ((state << 1) | bit) & 0b11111;
+        match bit {
+            1 => 1.0,
+            _ => -1.0,
+        }
+    })
+});
+
+/// The lowest carrier as sent by `dsss_modulate` for one bit, in phase and in quadrature.
+fn dsss_reference(config: &AcousticConfig) -> Vec<Point> {
+    let n = config.sample_number();
+    let omega = 2.0 * std::f64::consts::PI * config.carrier_freqs[0] / config.sample_rate;
+    (0..n)
+        .map(|t| {
+            let chip = PN_SEQUENCE[t * PN_CHIPS / n];
+            let phase = omega * t as f64;
+            (chip * phase.sin(), chip * phase.cos())
+        })
+        .collect()
+}
+
+/// samples `dsss_modulate` takes for `len` bytes, a reference symbol and one per bit
+pub fn dsss_len(config: &AcousticConfig, len: usize) -> usize {
+    dpsk_len(config, len)
+}
+
+/// samples on either side of where the receiver expects it that `dsss_demodulate` searches
+/// for the signal, one chip
+pub fn dsss_slack(config: &AcousticConfig) -> usize {
+    config.sample_number() / PN_CHIPS
+}
+
+/// Direct sequence spread spectrum on the lowest carrier, one bit per symbol, least
+/// significant first. Every symbol is the carrier flipped by `PN_SEQUENCE`, and the bits
+/// are differentially encoded behind a reference symbol like `bpsk_modulate` does.
+pub fn dsss_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    let chips = dsss_reference(config)
+        .into_iter()
+        .map(|(i, _)| i)
+        .collect::<Vec<f64>>();
+    let mut sign = 1.0;
+    let signs = std::iter::once(sign).chain(
+        bytes
+            .iter()
+            .flat_map(|b| (0..8).map(move |i| b >> i & 1))
+            .map(|bit| {
+                if bit == 1 {
+                    sign = -sign;
+                }
+                sign
+            }),
+    );
+    signs
+        .flat_map(|sign| chips.iter().map(move |x| sign * x))
+        .collect()
+}
+
+/// Undo `dsss_modulate` on a signal with `dsss_slack` extra samples on either side.
+///
+/// Correlating with the chip sequence despreads the bits and spreads everything else, a
+/// whistle or a beep on the carrier included, over the whole band. Where the reference
+/// symbol correlates best is where the symbols start, so timing comes with it.
+pub fn dsss_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    let n = config.sample_number();
+    let (sin, cos): (Vec<f64>, Vec<f64>) = dsss_reference(config).into_iter().unzip();
+    let correlate = |start: usize| {
+        let symbol = &signal[start..start + n];
+        (vector::dot(symbol, &sin), vector::dot(symbol, &cos))
+    };
+    let slack = dsss_slack(config);
+    let symbols = signal.len().saturating_sub(2 * slack) / n;
+    if symbols == 0 {
+        return vec![];
+    }
+    let start = (0..=2 * slack)
+        .max_by(|a, b| {
+            let power = |start: usize| {
+                let (i, q) = correlate(start);
+                i * i + q * q
+            };
+            power(*a).total_cmp(&power(*b))
+        })
+        .unwrap_or(slack);
+    let phasors = (0..symbols)
+        .map(|k| correlate(start + k * n))
+        .collect::<Vec<Point>>();
+    let bits = phasors
+        .windows(2)
+        .map(|w| ((w[0].0 * w[1].0 + w[0].1 * w[1].1) < 0.0) as u8)
+        .collect::<Vec<u8>>();
+    bits.chunks_exact(8)
+        .map(|bits| bits.iter().enumerate().fold(0, |b, (i, bit)| b | bit << i))
+        .collect()
+}
+
+/// bits every CSS chirp carries, a chirp has `1 << CSS_SPREADING_FACTOR` cyclic shifts
+pub const CSS_SPREADING_FACTOR: usize = 8;
+
+/// cyclic shifts of the up-chirp, and steps of its sweep
+pub const CSS_SHIFTS: usize = 1 << CSS_SPREADING_FACTOR;
+
+/// Phase at every sample of an up-chirp that starts `shift` steps into its sweep and wraps
+/// around to the bottom. The sweep takes one symbol and is `CSS_SHIFTS` FFT bins of that
+/// symbol wide, from the lowest carrier, or lower if it would reach the guard frequency.
+fn css_phases(config: &AcousticConfig, shift: usize) -> Vec<f64> {
+    let n = config.sample_number();
+    let bandwidth = CSS_SHIFTS as f64 * config.sample_rate / n as f64;
+    let low = config.carrier_freqs[0].min(config.guard_freq() - bandwidth);
+    let mut phase = 0.0;
+    (0..n)
+        .map(|t| {
+            let sweep = (t as f64 / n as f64 + shift as f64 / CSS_SHIFTS as f64).fract();
+            let current = phase;
+            phase += 2.0 * std::f64::consts::PI * (low + bandwidth * sweep) / config.sample_rate;
+            current
+        })
+        .collect()
+}
+
+/// samples `css_modulate` takes for `len` bytes, a reference chirp and one per byte
+pub fn css_len(config: &AcousticConfig, len: usize) -> usize {
+    (len + 1) * config.sample_number()
+}
+
+/// Chirp spread spectrum like LoRa: every byte is the up-chirp shifted by its Gray code,
+/// behind a reference chirp that is not shifted.
+pub fn css_modulate(config: &AcousticConfig, bytes: &[u8]) -> Vec<f64> {
+    std::iter::once(0)
+        .chain(bytes.iter().map(|b| b ^ (b >> 1)))
+        .flat_map(|shift| css_phases(config, shift as usize).into_iter().map(f64::sin))
+        .collect()
+}
+
+/// Undo `css_modulate` on a signal starting at the reference chirp.
+///
+/// Multiplying by the down-chirp turns a shifted chirp into a tone whose frequency is the
+/// shift. Summed over every step of the sweep, an FFT of the steps has its peak at the
+/// shift, with the energy of the whole chirp in it, which is why this survives so much
+/// noise. Wherever the reference peaks is shift zero, that takes out how late the symbols
+/// are cut, and the Gray code turns the neighbouring shift into a single bit error.
+pub fn css_demodulate(config: &AcousticConfig, signal: &[f64]) -> Vec<u8> {
+    let n = config.sample_number();
+    let down = css_phases(config, 0)
+        .into_iter()
+        .map(|phase| (phase.cos(), -phase.sin()))
+        .collect::<Vec<Point>>();
+    let twiddles = (0..CSS_SHIFTS)
+        .map(|k| {
+            let angle = -2.0 * std::f64::consts::PI * k as f64 / CSS_SHIFTS as f64;
+            (angle.cos(), angle.sin())
+        })
+        .collect::<Vec<Point>>();
+    // the spectrum of a chirp after the down-chirp
+    let spectrum = |symbol: &[f64]| {
+        let mut steps = vec![(0.0, 0.0); CSS_SHIFTS];
+        for (t, (x, (i, q))) in symbol.iter().zip(&down).enumerate() {
+            // centered on the step, where the tones before and after the wrap line up
+            let step = &mut steps[(t * CSS_SHIFTS + n / 2) / n % CSS_SHIFTS];
+            step.0 += x * i;
+            step.1 += x * q;
+        }
+        (0..CSS_SHIFTS)
+            .map(|bin| {
+                let (i, q) = steps
+                    .iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(i, q), (k, (si, sq))| {
+                        let (ti, tq) = twiddles[bin * k % CSS_SHIFTS];
+                        (i + si * ti - sq * tq, q + si * tq + sq * ti)
+                    });
+                (i * i + q * q).sqrt()
+            })
+            .collect::<Vec<f64>>()
+    };
+    let peak = |spectrum: &[f64]| {
+        (0..CSS_SHIFTS)
+            .max_by(|a, b| spectrum[*a].total_cmp(&spectrum[*b]))
+            .unwrap_or_default()
+    };
+    if signal.len() < n {
+        return vec![];
+    }
+    // Symbols cut part of a step late or early peak between two bins, and the part of the
+    // chirp after the wrap comes in out of phase with the part before. Where between the
+    // bins the reference peaks tells how much later to cut them to land on the next bin.
+    let reference = spectrum(&signal[..n]);
+    let p = peak(&reference);
+    let (a, b, c) = (
+        reference[(p + CSS_SHIFTS - 1) % CSS_SHIFTS],
+        reference[p],
+        reference[(p + 1) % CSS_SHIFTS],
+    );
+    let fraction = match a - 2.0 * b + c {
+        0.0 => 0.0,
+        curvature => 0.5 * (a - c) / curvature,
+    };
+    let delay = ((1.0 - fraction.rem_euclid(1.0)) * n as f64 / CSS_SHIFTS as f64).round() as usize;
+    let signal = [&signal[delay.min(signal.len())..], &vec![0.0; delay]].concat();
+    let shifts = map_symbols(config, &signal, n, |_, symbol| peak(&spectrum(symbol)));
+    let Some((reference, shifts)) = shifts.split_first() else {
+        return vec![];
+    };
+    shifts
+        .iter()
+        .map(|shift| {
+            let gray = ((shift + CSS_SHIFTS - reference) % CSS_SHIFTS) as u8;
+            (1..8).fold(gray, |b, i| b ^ (gray >> i))
+        })
+        .collect()
+}
+
+#[test]
+fn test_bpsk() {
+    use crate::simulator::awgn_seeded;
+
+    let config = AcousticConfig::default();
+    let data = b"hello world";
+    let signal = bpsk_modulate(&config, data);
+    assert_eq!(signal.len(), config.sample_number() * (8 * data.len() + 1));
+    assert_eq!(bpsk_demodulate(&config, &signal), data);
+
+    // recorded a few samples late, so at another carrier phase, and noisy
+    let late = [&signal[7..], &[0.0; 7]].concat();
+    assert_eq!(bpsk_demodulate(&config, &awgn_seeded(&late, 0.0, 5)), data);
+
+    // and with the sound card clock a little off
+    let detuned = AcousticConfig {
+        carrier_freqs: vec![config.carrier_freqs[0] + 0.5],
+        ..config.clone()
+    };
+    assert_eq!(bpsk_demodulate(&detuned, &awgn_seeded(&late, 0.0, 6)), data);
+}
+
+#[test]
+fn test_dpsk() {
+    use crate::simulator::awgn_seeded;
+
+    let config = AcousticConfig::default();
+    let data = b"hello world";
+    let signal = dpsk_modulate(&config, data);
+    assert_eq!(signal.len(), dpsk_len(&config, data.len()));
+    assert_eq!(dpsk_demodulate(&config, &signal), data);
+
+    // an unknown delay and an inverted speaker only turn every phase by the same angle
+    let late = [&signal[13..], &[0.0; 13]].concat();
+    let inverted = late.iter().map(|x| -0.3 * x).collect::<Vec<f64>>();
+    assert_eq!(
+        dpsk_demodulate(&config, &awgn_seeded(&inverted, 0.0, 7)),
+        data
+    );
+}
+
+#[test]
+fn test_qpsk() {
+    use crate::simulator::awgn_seeded;
+
+    let config = AcousticConfig::default();
+    let data = (0..=255).collect::<Vec<u8>>();
+    let signal = qpsk_modulate(&config, &data);
+    assert_eq!(signal.len(), qpsk_len(&config, data.len()));
+    assert_eq!(qpsk_demodulate(&config, &signal), data);
+
+    // late, quiet and noisy
+    let late = [&signal[13..], &[0.0; 13]]
+        .concat()
+        .iter()
+        .map(|x| 0.2 * x)
+        .collect::<Vec<f64>>();
+    assert_eq!(qpsk_demodulate(&config, &awgn_seeded(&late, 10.0, 8)), data);
+
+    // the sound card clock a little off turns the phase a bit further every symbol
+    let detuned = AcousticConfig {
+        carrier_freqs: vec![config.carrier_freqs[0] + 0.2],
+        ..config.clone()
+    };
+    assert_eq!(
+        qpsk_demodulate(&detuned, &awgn_seeded(&late, 10.0, 9)),
+        data
+    );
+}
+
+#[test]
+fn test_qam16() {
+    use crate::simulator::awgn_seeded;
+
+    // neighbours differ in one bit
+    for (k, (i, q)) in QAM16_POINTS.iter().enumerate() {
+        let step = 2.0 / QAM16_CORNER + 1e-9;
+        for (l, (j, p)) in QAM16_POINTS.iter().enumerate() {
+            if (i - j).hypot(q - p) < step {
+                assert!((k ^ l).count_ones() <= 1, "{k} next to {l}");
+            }
+        }
+    }
+
+    let config = AcousticConfig::default();
+    let data = (0..=255).collect::<Vec<u8>>();
+    let signal = qam16_modulate(&config, &data);
+    assert_eq!(signal.len(), qam16_len(&config, data.len()));
+    assert!(signal.iter().all(|x| x.abs() <= 1.0));
+    assert_eq!(qam16_demodulate(&config, &signal), data);
+
+    // as loud or as quiet as the speaker plays it
+    for volume in [0.05, 0.7] {
+        let late = [&signal[13..], &[0.0; 13]]
+            .concat()
+            .iter()
+            .map(|x| volume * x)
+            .collect::<Vec<f64>>();
+        assert_eq!(
+            qam16_demodulate(&config, &awgn_seeded(&late, 20.0, 10)),
+            data,
+            "volume {volume}"
+        );
+    }
+}
+
+#[test]
+fn test_carrier_count() {
+    let data = (0..=255).collect::<Vec<u8>>();
+    for count in [4, 6, 8] {
+        let config = AcousticConfig::builder()
+            .carrier_count(count)
+            .build()
+            .unwrap();
+        assert_eq!(config.carrier_freqs.len(), count);
+        let symbols = pack_symbols(&config, &data);
+        assert_eq!(symbols.len(), fsk_symbols(&config, data.len()));
+        assert!(symbols.iter().all(|s| (*s as usize) < 1 << count));
+        assert_eq!(unpack_symbols(&config, &symbols, data.len()), data);
+
+        let signal = modulate_bits(&config, data[..12].to_vec());
+        let heard = signal
+            .chunks(config.sample_number())
+            .map(|symbol| demodulate_symbol(&config, symbol))
+            .collect::<Vec<u8>>();
+        assert_eq!(unpack_symbols(&config, &heard, 12), data[..12], "{count}");
+    }
+}
+
+#[test]
+fn test_dsss() {
+    use crate::simulator::awgn_seeded;
+
+    assert_eq!(PN_SEQUENCE.iter().filter(|c| **c > 0.0).count(), 16);
+    for shift in 1..PN_CHIPS {
+        let correlation = (0..PN_CHIPS)
+            .map(|k| PN_SEQUENCE[k] * PN_SEQUENCE[(k + shift) % PN_CHIPS])
+            .sum::<f64>();
+        assert_eq!(correlation, -1.0);
+    }
+
+    let config = AcousticConfig::default();
+    let data = b"hello world";
+    let signal = dsss_modulate(&config, data);
+    assert_eq!(signal.len(), dsss_len(&config, data.len()));
+    let slack = vec![0.0; dsss_slack(&config)];
+    assert_eq!(
+        dsss_demodulate(&config, &[&slack[..], &signal, &slack].concat()),
+        data
+    );
+
+    // 50 samples early, with a beep on the carrier twice as loud as the signal
+    let beep = generate_signals(&config, &config.carrier_freqs[..1])[0].clone();
+    let beeping = |signal: &[f64]| {
+        let late = [&slack[50..], signal, &slack, &slack[..50]].concat();
+        let beeps = beep.iter().cycle().map(|x| 2.0 * x);
+        awgn_seeded(&late, 10.0, 11)
+            .iter()
+            .zip(beeps)
+            .map(|(x, b)| x + b)
+            .collect::<Vec<f64>>()
+    };
+    assert_eq!(dsss_demodulate(&config, &beeping(&signal)), data);
+
+    // the same beep drowns DPSK
+    let dpsk = beeping(&dpsk_modulate(&config, data));
+    assert_ne!(dpsk_demodulate(&config, &dpsk[slack.len() - 50..]), data);
+}
+
+#[test]
+fn test_css() {
+    use crate::simulator::awgn_seeded;
+
+    let config = AcousticConfig::default();
+    let data = (0..=255).collect::<Vec<u8>>();
+    let signal = css_modulate(&config, &data);
+    assert_eq!(signal.len(), css_len(&config, data.len()));
+    assert_eq!(css_demodulate(&config, &signal), data);
+
+    // cut late, and under noise sixteen times as strong as the chirps
+    let late = [&signal[9..], &[0.0; 9]].concat();
+    assert_eq!(css_demodulate(&config, &late), data);
+    assert_eq!(
+        css_demodulate(&config, &awgn_seeded(&late, -12.0, 12)),
+        data
+    );
+
+    // the sweep stays below the guard frequency
+    let ultrasonic = AcousticConfig {
+        modulation: crate::config::Modulation::Css,
+        ..AcousticConfig::ultrasonic()
+    };
+    let signal = css_modulate(&ultrasonic, &data);
+    assert_eq!(css_demodulate(&ultrasonic, &signal), data);
+    let highest = GoertzelBank::new(&[ultrasonic.guard_freq() + 200.0], ultrasonic.sample_rate)
+        .amplitudes(&signal)[0];
+    assert!(highest < 0.01, "{highest}");
+}
+
+#[test]
+fn test_estimate_clock_drift() {
+    use crate::{resampler::stretch, simulator::awgn_seeded};
+
+    let config = AcousticConfig::default();
+    let preamble = prepend_preamble(&config, &[]);
+    assert!(estimate_clock_drift(&config, &preamble).abs() < 1e-6);
+    for ppm in [-800.0, -300.0, -50.0, 50.0, 300.0, 800.0] {
+        let drifted = stretch(&preamble, 1.0 + ppm * 1e-6);
+        // recorded a few samples off and noisy, as the preamble detector leaves it
+        let heard = awgn_seeded(&[&[0.0; 40], &drifted[..]].concat(), 10.0, 13);
+        let drift = estimate_clock_drift(&config, &heard[..4 * config.sample_number()]);
+        assert!(
+            (drift * 1e6 - ppm).abs() < 10.0,
+            "{ppm} ppm as {}",
+            drift * 1e6
+        );
+    }
 }
@@ -0,0 +1,169 @@
+//! # Ports
+//!
+//! One link can carry several conversations at once, say commands next to a file transfer.
+//! Like UDP, every packet names a port at its destination, see `Packet::port`, and a
+//! `Dispatcher` puts the messages arriving on each port in a queue of its own, to be taken
+//! in whatever order the application likes. Messages to ports nobody bound are dropped.
+//!
+//! Every sender numbers the packets of each port on its own, so messages to different
+//! ports can be interleaved on the air, see `Transmitter::send_to_port`.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use tracing::info;
+
+use crate::{
+    error::{AcousticError, Result},
+    message::{MessageAssembler, MessageHeader},
+    transmission::{Event, Receiver},
+};
+
+/// port of everything sent without one
+pub const DEFAULT_PORT: u8 = 0;
+
+/// Routes the messages a `Receiver` hears to the ports they were sent to.
+pub struct Dispatcher {
+    receiver: Receiver,
+    /// messages not taken yet, with their sender, by bound port
+    queues: BTreeMap<u8, VecDeque<(u8, Vec<u8>)>>,
+    /// messages being put together, by sender and port
+    partial: BTreeMap<(u8, u8), MessageAssembler>,
+}
+
+impl Dispatcher {
+    pub fn new(receiver: Receiver) -> Dispatcher {
+        Dispatcher {
+            receiver,
+            queues: BTreeMap::new(),
+            partial: BTreeMap::new(),
+        }
+    }
+
+    /// Keep the messages arriving on `port` from now on.
+    pub fn bind(&mut self, port: u8) {
+        self.queues.entry(port).or_default();
+    }
+
+    /// Drop the messages arriving on `port` from now on, and those not taken yet.
+    pub fn unbind(&mut self, port: u8) {
+        self.queues.remove(&port);
+        self.partial.retain(|(_, to), _| *to != port);
+    }
+
+    pub fn receiver(&mut self) -> &mut Receiver {
+        &mut self.receiver
+    }
+
+    /// Wait for the next packet and route it. Malformed and unauthenticated packets are
+    /// dropped, only failures of the sample source are returned.
+    pub fn dispatch(&mut self) -> Result<()> {
+        let packet_size = self.receiver.config().packet_size;
+        let assembler = || MessageAssembler::with_packet_size(packet_size);
+        let event = match self.receiver.next_event() {
+            Err(AcousticError::AuthenticationFailed) => {
+                info!("unauthenticated packet, dropped");
+                return Ok(());
+            }
+            event => event?,
+        };
+        match event {
+            Event::Data(packet) if self.queues.contains_key(&packet.port) => {
+                let (source, port) = (packet.source, packet.port);
+                let message = self.partial.entry((source, port)).or_insert_with(assembler);
+                if message.add(packet) {
+                    let message = message.take_message();
+                    info!("message of {} bytes on port {}", message.len(), port);
+                    self.receiver.forget_seen_on(source, port);
+                    self.queues
+                        .entry(port)
+                        .or_default()
+                        .push_back((source, message));
+                }
+            }
+            Event::Data(packet) => info!("nobody on port {}, dropped", packet.port),
+            Event::Control(packet) => match MessageHeader::from_packet(&packet) {
+                Ok(header) if self.queues.contains_key(&packet.port) => self
+                    .partial
+                    .entry((packet.source, packet.port))
+                    .or_insert_with(assembler)
+                    .start(header),
+                _ => info!("skipped control {:?}", packet.data),
+            },
+            event => info!("skipped {:?}", event),
+        }
+        Ok(())
+    }
+
+    /// The oldest message on `port` not taken yet, and who sent it, without waiting.
+    pub fn try_recv(&mut self, port: u8) -> Option<(u8, Vec<u8>)> {
+        self.queues.get_mut(&port)?.pop_front()
+    }
+
+    /// Wait for a message on `port`, routing whatever arrives for other ports meanwhile.
+    /// Returns it with its sender. `port` has to be bound.
+    pub fn recv_from(&mut self, port: u8) -> Result<(u8, Vec<u8>)> {
+        if !self.queues.contains_key(&port) {
+            return Err(AcousticError::PortNotBound(port));
+        }
+        loop {
+            if let Some(message) = self.try_recv(port) {
+                return Ok(message);
+            }
+            self.dispatch()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::AcousticConfig, transmission::MemoryReader, transmitter::modulate_packets, Packet,
+    };
+
+    #[test]
+    fn test_dispatch() {
+        let config = AcousticConfig::default();
+        let on = |port: u8, data: &[u8]| {
+            Packet::new_packets(data)
+                .into_iter()
+                .map(|packet| packet.addressed(1, 0).on_port(port))
+                .collect::<Vec<Packet>>()
+        };
+        let bulk = (0..200).map(|i| i as u8).collect::<Vec<u8>>();
+        let (data, control, other) = (on(5, &bulk), on(1, b"stop"), on(9, b"nobody"));
+        // a command between the two packets of the bulk message, orders overlap
+        let packets = [
+            data[0].clone(),
+            control[0].clone(),
+            other[0].clone(),
+            data[1].clone(),
+        ];
+        let heard = [
+            vec![0.0; 10000],
+            modulate_packets(&config, &packets).unwrap(),
+            vec![0.0; 10000],
+        ]
+        .concat();
+        let receiver = Receiver::with_config(Box::new(MemoryReader(heard)), config);
+        let mut dispatcher = Dispatcher::new(receiver);
+        dispatcher.bind(1);
+        dispatcher.bind(5);
+        assert!(matches!(
+            dispatcher.recv_from(2),
+            Err(AcousticError::PortNotBound(2))
+        ));
+        assert_eq!(dispatcher.recv_from(5).unwrap(), (1, bulk));
+        // it came first, and waited in its queue
+        assert_eq!(dispatcher.try_recv(1), Some((1, b"stop".to_vec())));
+        assert_eq!(dispatcher.try_recv(1), None);
+        assert!(matches!(
+            dispatcher.recv_from(9),
+            Err(AcousticError::PortNotBound(9))
+        ));
+        assert!(matches!(
+            dispatcher.recv_from(1),
+            Err(AcousticError::EndOfStream)
+        ));
+    }
+}
@@ -0,0 +1,102 @@
+//! # Progress
+//!
+//! A message of a few kilobytes takes minutes over sound. A `Progress` says how far along
+//! sending or receiving one is, for a frontend to draw a bar and guess the time left.
+//!
+//! A `Transmitter` reports every packet once it was played, see
+//! `Transmitter::with_progress`, an `AcousticStream` every packet once its peer
+//! acknowledged it, see `AcousticStream::with_progress`, and a `Receiver` every packet of
+//! the message it collects, see `ReceiverEvents::on_progress`. A `Sender` of a channel
+//! does as a callback for all three, see `to_channel`.
+
+use std::{sync::mpsc::Sender, time::Duration};
+
+use crate::{transmission::ReceiverEvents, Packet, PacketKind};
+
+/// how far a transfer got
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// payload bytes sent, acknowledged or received so far
+    pub bytes: usize,
+    /// payload bytes of the whole message, if known
+    pub total_bytes: Option<usize>,
+    /// packets sent, acknowledged or received so far
+    pub packets: usize,
+    /// packets of the whole message, if known
+    pub total_packets: Option<usize>,
+    /// since the transfer started
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Progress of sending `packets`, the first `sent` of them out already.
+    pub fn of_packets(packets: &[Packet], sent: usize, elapsed: Duration) -> Progress {
+        let bytes = |packets: &[Packet]| {
+            packets
+                .iter()
+                .filter(|packet| packet.kind == PacketKind::Data)
+                .map(|packet| packet.data.len())
+                .sum()
+        };
+        Progress {
+            bytes: bytes(&packets[..sent]),
+            total_bytes: Some(bytes(packets)),
+            packets: sent,
+            total_packets: Some(packets.len()),
+            elapsed,
+        }
+    }
+
+    /// share of the message done, from 0 to 1, if its length is known
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total_bytes? {
+            0 => Some(1.0),
+            total => Some(self.bytes as f64 / total as f64),
+        }
+    }
+
+    /// Time left at the rate so far, if the length of the message is known and anything
+    /// got through yet.
+    pub fn remaining(&self) -> Option<Duration> {
+        let total = self.total_bytes?;
+        if self.bytes == 0 {
+            return None;
+        }
+        let left = total.saturating_sub(self.bytes) as f64;
+        Some(self.elapsed.mul_f64(left / self.bytes as f64))
+    }
+}
+
+/// what to call with the progress of a transfer
+pub type OnProgress = Box<dyn FnMut(&Progress) + Send>;
+
+/// A callback that sends every `Progress` down `sender`, for a frontend on another thread.
+/// Nothing is reported once the other end is gone.
+pub fn to_channel(sender: Sender<Progress>) -> OnProgress {
+    Box::new(move |progress| {
+        let _ = sender.send(*progress);
+    })
+}
+
+/// only progress, sent down the channel
+impl ReceiverEvents for Sender<Progress> {
+    fn on_progress(&mut self, progress: &Progress) {
+        let _ = self.send(*progress);
+    }
+}
+
+#[test]
+fn test_progress() {
+    let packets = Packet::new_message(&[1; 300]);
+    let progress = Progress::of_packets(&packets, 2, Duration::from_secs(10));
+    // the message header carries no payload
+    assert_eq!(progress.bytes, 128);
+    assert_eq!(progress.total_bytes, Some(300));
+    assert_eq!(progress.total_packets, Some(4));
+    assert_eq!(progress.fraction(), Some(128.0 / 300.0));
+    let remaining = progress.remaining().unwrap().as_secs_f64();
+    assert!((remaining - 10.0 * 172.0 / 128.0).abs() < 1e-6);
+
+    let progress = Progress::of_packets(&packets, 0, Duration::from_secs(1));
+    assert_eq!(progress.remaining(), None);
+}
@@ -0,0 +1,142 @@
+//! # Transmit queue
+//!
+//! Packets wait in a `TransmitQueue` until the transmitter gets to them, the most urgent
+//! first. Acknowledgments, beacons and control packets go before any data queued earlier,
+//! so that an acknowledgment never waits behind a long message while the peer's window
+//! stalls. Packets of one priority keep their order.
+//!
+//! The transmitter takes one packet at a time, see `Transmitter::send_queued`, so whatever
+//! is queued while a long message goes out is played right after the packet on the air.
+//! Packets of one message are numbered from 0, so two messages at different priorities
+//! have to go to different ports, or the receiver takes the packets of one for duplicates
+//! of the other's.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use crate::{ports::DEFAULT_PORT, Packet, PacketKind};
+
+/// how urgent a packet is, the higher the sooner it is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// bulk data, whenever nothing else waits
+    Low,
+    Normal,
+    /// acknowledgments, beacons and control packets
+    Control,
+}
+
+impl Priority {
+    /// `Control` for everything but data, which is `Normal`
+    pub fn of(packet: &Packet) -> Priority {
+        match packet.kind {
+            PacketKind::Data => Priority::Normal,
+            PacketKind::Ack | PacketKind::Control | PacketKind::Beacon => Priority::Control,
+        }
+    }
+}
+
+/// Packets waiting to be sent, by priority. Clones share the queue, so that a thread
+/// receiving can queue acknowledgments for the one sending.
+#[derive(Clone)]
+pub struct TransmitQueue {
+    queues: Arc<Mutex<BTreeMap<Priority, VecDeque<Packet>>>>,
+    /// payload bytes of every packet of a message but the last
+    packet_size: usize,
+}
+
+impl Default for TransmitQueue {
+    fn default() -> Self {
+        TransmitQueue {
+            queues: Arc::default(),
+            packet_size: Packet::MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl TransmitQueue {
+    pub fn new() -> TransmitQueue {
+        Self::default()
+    }
+
+    /// split what is queued from now on to `packet_size` bytes a packet, see
+    /// `AcousticConfig::packet_size`
+    pub fn with_packet_size(mut self, packet_size: usize) -> TransmitQueue {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// Queue `data` to every receiver, on the default port, see `enqueue_to`.
+    pub fn enqueue(&self, data: &[u8], priority: Priority) {
+        self.enqueue_to(Packet::BROADCAST, DEFAULT_PORT, data, priority)
+    }
+
+    /// Queue the packets of `data` to `port` of `destination`, after those of `priority`
+    /// queued already.
+    pub fn enqueue_to(&self, destination: u8, port: u8, data: &[u8], priority: Priority) {
+        let packets = Packet::new_packets_sized(data, self.packet_size)
+            .into_iter()
+            .map(|packet| packet.addressed(0, destination).on_port(port));
+        self.with_queues(|queues| queues.entry(priority).or_default().extend(packets));
+    }
+
+    /// Queue a packet of any kind, as urgent as its kind, see `Priority::of`.
+    pub fn push(&self, packet: Packet) {
+        let priority = Priority::of(&packet);
+        self.with_queues(|queues| queues.entry(priority).or_default().push_back(packet));
+    }
+
+    /// The most urgent packet, the oldest of those.
+    pub fn pop(&self) -> Option<Packet> {
+        self.with_queues(|queues| {
+            queues
+                .values_mut()
+                .rev()
+                .find_map(|queue| queue.pop_front())
+        })
+    }
+
+    /// packets waiting
+    pub fn len(&self) -> usize {
+        self.with_queues(|queues| queues.values().map(VecDeque::len).sum())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A thread that panicked while queueing left whole packets behind, they are kept.
+    fn with_queues<T>(&self, f: impl FnOnce(&mut BTreeMap<Priority, VecDeque<Packet>>) -> T) -> T {
+        let mut queues = self
+            .queues
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(&mut queues)
+    }
+}
+
+#[test]
+fn test_transmit_queue() {
+    let queue = TransmitQueue::new();
+    queue.enqueue(&[1; 200], Priority::Low);
+    queue.enqueue_to(2, 7, b"ping", Priority::Normal);
+    let sender = queue.clone();
+    // the peer's window closes while ours waits
+    sender.push(Packet::ack(5));
+    assert_eq!(queue.len(), 4);
+
+    let ack = queue.pop().unwrap();
+    assert_eq!((ack.kind, ack.order), (PacketKind::Ack, 5));
+    let ping = queue.pop().unwrap();
+    assert_eq!((ping.destination, ping.port), (2, 7));
+    assert_eq!(ping.data, b"ping");
+    // the bulk message last, in order
+    assert_eq!(queue.pop().unwrap().order, 0);
+    queue.push(Packet::ack(6));
+    assert_eq!(queue.pop().unwrap().kind, PacketKind::Ack);
+    assert_eq!(queue.pop().unwrap().order, 1);
+    assert!(queue.pop().is_none());
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,110 @@
+//! # Rate adaptation
+//!
+//! How short symbols can get depends on the room, and the room changes. The controller
+//! starts at the slowest symbol time and watches how packets fare: after a run of packets
+//! that mostly went through on the first try it steps to shorter symbols, and once
+//! retransmissions pile up it steps back. A new symbol time only takes effect once the peer
+//! agreed to it, see `Initiator::retune`.
+//!
+//! Like the ARQ, this is a plain state machine, the caller reports what happened to its
+//! packets.
+
+/// symbol times to choose from, slowest first, in milliseconds
+pub const SYMBOL_TIMES_MS: [u16; 5] = [100, 70, 50, 35, 25];
+
+pub struct RateController {
+    /// index into `SYMBOL_TIMES_MS`
+    level: usize,
+    /// packets delivered since the last change
+    delivered: usize,
+    /// packets sent again since the last change
+    retransmitted: usize,
+    /// deliveries to judge the pass rate on before speeding up
+    speed_up_after: usize,
+    /// retransmissions to slow down after
+    slow_down_after: usize,
+}
+
+impl Default for RateController {
+    fn default() -> Self {
+        RateController {
+            level: 0,
+            delivered: 0,
+            retransmitted: 0,
+            speed_up_after: 16,
+            slow_down_after: 4,
+        }
+    }
+}
+
+impl RateController {
+    pub fn new() -> RateController {
+        RateController::default()
+    }
+
+    /// Speed up after `speed_up_after` deliveries with at most one retransmission per ten,
+    /// slow down after `slow_down_after` retransmissions.
+    pub fn with_thresholds(speed_up_after: usize, slow_down_after: usize) -> RateController {
+        RateController {
+            speed_up_after: speed_up_after.max(1),
+            slow_down_after: slow_down_after.max(1),
+            ..RateController::default()
+        }
+    }
+
+    /// the symbol time in use, in milliseconds
+    pub fn symbol_time_ms(&self) -> u16 {
+        SYMBOL_TIMES_MS[self.level]
+    }
+
+    /// `packets` were acknowledged, returns the symbol time to change to, if any
+    pub fn on_delivered(&mut self, packets: usize) -> Option<u16> {
+        self.delivered += packets;
+        if self.delivered < self.speed_up_after {
+            return None;
+        }
+        let passing = self.retransmitted * 10 <= self.delivered;
+        self.delivered = 0;
+        self.retransmitted = 0;
+        (passing && self.level + 1 < SYMBOL_TIMES_MS.len()).then(|| self.change(self.level + 1))
+    }
+
+    /// `packets` had to be sent again, returns the symbol time to change to, if any
+    pub fn on_retransmitted(&mut self, packets: usize) -> Option<u16> {
+        self.retransmitted += packets;
+        if self.retransmitted < self.slow_down_after {
+            return None;
+        }
+        self.delivered = 0;
+        self.retransmitted = 0;
+        (self.level > 0).then(|| self.change(self.level - 1))
+    }
+
+    fn change(&mut self, level: usize) -> u16 {
+        self.level = level;
+        self.symbol_time_ms()
+    }
+}
+
+#[test]
+fn test_rate_controller() {
+    let mut rate = RateController::with_thresholds(8, 3);
+    assert_eq!(rate.symbol_time_ms(), 100);
+    // nothing to slow down from
+    assert_eq!(rate.on_retransmitted(5), None);
+
+    assert_eq!(rate.on_delivered(7), None);
+    assert_eq!(rate.on_delivered(1), Some(70));
+    assert_eq!(rate.on_delivered(8), Some(50));
+    // too many retransmissions to speed up, not enough to slow down
+    rate.on_retransmitted(2);
+    assert_eq!(rate.on_delivered(8), None);
+    assert_eq!(rate.symbol_time_ms(), 50);
+
+    rate.on_retransmitted(2);
+    assert_eq!(rate.on_retransmitted(1), Some(70));
+    for _ in 0..10 {
+        rate.on_delivered(8);
+    }
+    assert_eq!(rate.symbol_time_ms(), 25);
+}
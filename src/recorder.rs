@@ -1,20 +1,153 @@
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Sample, SampleRate};
-use dasp::sample::ToSample;
-use tracing::{error, info};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Sample, SampleRate, SupportedStreamConfig};
+use tracing::{error, info, warn};
 
+use crate::config::AcousticConfig;
+use crate::device::input_device;
+use crate::error::{AcousticError, Result};
+use crate::filter::HighPass;
 use crate::output_wav;
+use crate::resampler::InputConverter;
+use crate::ring_buffer::RingBuffer;
+use crate::spsc::SampleQueue;
 use crate::transmission::SampleReader;
 
-type BufferHandle = Arc<Mutex<Vec<f32>>>;
+/// Samples the cpal callback may push before they are drained, about 24 seconds of mono at
+/// 44.1 kHz, 11 of stereo at 48 kHz.
+pub const QUEUE_CAPACITY: usize = 1 << 20;
+
+/// Recorded samples, shared between the cpal callback and the `Recorder`.
+///
+/// The callback only ever copies what the device recorded into a lock-free `SampleQueue`,
+/// so it neither waits for the receiver nor allocates. Readers drain the queue under the
+/// lock of the ring buffer before they look, converting the samples on the way, and a
+/// `Recorder` waiting for samples is unparked by every push. Nothing else drains the queue:
+/// samples not read within `QUEUE_CAPACITY` are lost, as silence in their place.
+#[derive(Debug)]
+pub struct SharedBuffer {
+    /// pushed but not drained yet
+    queue: SampleQueue,
+    recorded: Mutex<Recorded>,
+    /// the thread of the `Recorder` waiting for samples, if any
+    reader: Mutex<Option<Thread>>,
+}
+
+/// drained samples, and what they go through on the way
+#[derive(Debug)]
+struct Recorded {
+    ring_buffer: RingBuffer,
+    /// conversion of what the device records, samples are pushed as they are kept if `None`
+    input: Option<(InputConverter, HighPass)>,
+    /// raw samples being converted, kept from one drain to the next
+    raw: Vec<f32>,
+}
+
+impl SharedBuffer {
+    /// keeping `capacity` samples
+    fn new(capacity: usize) -> Arc<SharedBuffer> {
+        Self::with_queue(capacity, QUEUE_CAPACITY)
+    }
+
+    fn with_queue(capacity: usize, queue: usize) -> Arc<SharedBuffer> {
+        Arc::new(SharedBuffer {
+            queue: SampleQueue::new(queue),
+            recorded: Mutex::new(Recorded {
+                ring_buffer: RingBuffer::new(capacity),
+                input: None,
+                raw: Vec::new(),
+            }),
+            reader: Mutex::new(None),
+        })
+    }
+
+    /// Convert what is pushed from now on with `converter`, then high-pass it, as it is
+    /// drained.
+    fn convert_with(&self, converter: InputConverter, high_pass: HighPass) -> Result<()> {
+        self.recorded
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)?
+            .input = Some((converter, high_pass));
+        Ok(())
+    }
+
+    /// the ring buffer with everything pushed so far
+    fn lock(&self) -> Result<MutexGuard<'_, Recorded>> {
+        let mut recorded = self
+            .recorded
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)?;
+        self.drain(&mut recorded);
+        Ok(recorded)
+    }
+
+    /// Move what was pushed into the ring buffer, with silence where the queue had no room.
+    fn drain(&self, recorded: &mut Recorded) {
+        let Recorded {
+            ring_buffer,
+            input,
+            raw,
+        } = recorded;
+        match input {
+            None => {
+                self.queue.pop_with(|samples| ring_buffer.extend(samples));
+            }
+            Some((converter, high_pass)) => {
+                raw.clear();
+                self.queue.pop_with(|samples| raw.extend(samples));
+                ring_buffer.extend(high_pass.process(&converter.process(raw)));
+            }
+        }
+        let dropped = self.queue.take_dropped();
+        if dropped > 0 {
+            warn!("recording overran, {} samples lost", dropped);
+        }
+    }
+
+    /// index of the next sample pushed, as many as were recorded so far
+    pub fn end(&self) -> Result<usize> {
+        Ok(self.lock()?.ring_buffer.end())
+    }
+
+    /// the last `len` samples recorded, fewer if there are not as many yet
+    pub fn latest(&self, len: usize) -> Result<Vec<f64>> {
+        let recorded = self.lock()?;
+        let buffer = &recorded.ring_buffer;
+        let end = buffer.end();
+        buffer.get(end.saturating_sub(len).max(buffer.start()), end)
+    }
+
+    /// Append samples without waiting for any lock or allocating, dropping what does not fit
+    /// into the queue, and wake the `Recorder` waiting for them. Safe to call from the audio
+    /// callback, but from one thread at a time.
+    pub fn push(&self, input: impl IntoIterator<Item = f32>) -> Result<()> {
+        self.queue.push(input);
+        // a reader taking the lock registers before it looks, and finds what was pushed
+        if let Ok(reader) = self.reader.try_lock() {
+            if let Some(reader) = reader.as_ref() {
+                reader.unpark();
+            }
+        }
+        Ok(())
+    }
+}
+
+type BufferHandle = Arc<SharedBuffer>;
+
+/// Recordings are high-passed above this, in Hz, well below the lowest tone we send.
+pub const RUMBLE_CUTOFF: f64 = 200.0;
+
+/// Samples kept by default, a minute of audio at 44.1 kHz.
+pub const DEFAULT_CAPACITY: usize = 44100 * 60;
 
 #[derive(Debug)]
 pub struct Recorder {
-    ring_buffer: BufferHandle,
+    buffer: BufferHandle,
+    /// how long `take_samples` waits for samples, forever if `None`
+    timeout: Option<Duration>,
 }
 
 impl Default for Recorder {
@@ -25,80 +158,302 @@ impl Default for Recorder {
 
 impl Recorder {
     pub fn new() -> Recorder {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// keep at most `capacity` samples that the receiver has not consumed yet
+    pub fn with_capacity(capacity: usize) -> Recorder {
         Recorder {
-            ring_buffer: Arc::new(Mutex::new(Vec::new())),
+            buffer: SharedBuffer::new(capacity),
+            timeout: None,
         }
     }
 
+    /// give up waiting for samples after `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Recorder {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub fn clone_handle(&mut self) -> BufferHandle {
-        self.ring_buffer.clone()
-    }
-
-    pub fn take_samples(&mut self, start: usize, end: usize) -> Vec<f64> {
-        while self.ring_buffer.lock().unwrap().len() < end {}
-        let ring_buffer = self.ring_buffer.lock().unwrap();
-        ring_buffer[start..end].iter().map(|f| *f as f64).collect()
-    }
-
-    pub fn save_to_wav(&mut self) {
-        output_wav(
-            &self
-                .ring_buffer
-                .lock()
-                .unwrap()
-                .clone()
-                .iter()
-                .map(|f| *f as f64)
-                .collect::<Vec<f64>>(),
-            "recorder.wav",
-        )
+        self.buffer.clone()
+    }
+
+    /// Block until the samples up to `end` are recorded, then return them from `start`.
+    pub fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    /// `take_samples` into `buffer`, in place of what it held. Parks the thread until the
+    /// next push while the samples are not there yet.
+    pub fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        *self
+            .buffer
+            .reader
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)? = Some(thread::current());
+        loop {
+            let recorded = self.buffer.lock()?;
+            if recorded.ring_buffer.end() >= end {
+                return recorded.ring_buffer.read(start, end, buffer);
+            }
+            drop(recorded);
+            match deadline {
+                None => thread::park(),
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(AcousticError::Timeout);
+                    }
+                    thread::park_timeout(remaining);
+                }
+            }
+        }
+    }
+
+    /// forget the samples before `until`
+    pub fn consume(&mut self, until: usize) -> Result<()> {
+        self.buffer.lock()?.ring_buffer.consume(until);
+        Ok(())
+    }
+
+    pub fn save_to_wav(&mut self, config: &AcousticConfig) -> Result<()> {
+        let samples = self
+            .buffer
+            .lock()?
+            .ring_buffer
+            .iter()
+            .map(|f| *f as f64)
+            .collect::<Vec<f64>>();
+        output_wav(config, &samples, "recorder.wav")
     }
 }
 
 #[test]
+#[ignore = "needs a microphone"]
 fn test_recorder() {
+    use std::thread::sleep;
+
     let _ = tracing_subscriber::fmt::try_init();
+    let config = AcousticConfig::default();
     let mut recorder = Recorder::new();
-    let _stream = run_record(recorder.clone_handle()).unwrap();
+    let _stream = run_record(recorder.clone_handle(), &config).unwrap();
     sleep(Duration::from_secs(3));
-    recorder.save_to_wav();
+    recorder.save_to_wav(&config).unwrap();
+}
+
+#[test]
+fn test_take_samples_waits() {
+    let mut recorder = Recorder::new().with_timeout(Duration::from_secs(5));
+    let handle = recorder.clone_handle();
+    let feeder = thread::spawn(move || {
+        for i in 0..10 {
+            thread::sleep(Duration::from_millis(5));
+            handle.push([i as f32; 100]).unwrap();
+        }
+    });
+    let started = Instant::now();
+    let samples = recorder.take_samples(850, 1000).unwrap();
+    assert_eq!(samples.len(), 150);
+    assert_eq!(samples[0], 8.0);
+    // woken by the pushes, not by the timeout
+    assert!(started.elapsed() < Duration::from_secs(2));
+    feeder.join().unwrap();
+
+    let mut recorder = recorder.with_timeout(Duration::from_millis(20));
+    assert!(matches!(
+        recorder.take_samples(0, 1001),
+        Err(AcousticError::Timeout)
+    ));
+}
+
+#[test]
+fn test_push_overrun() {
+    // more than the queue holds before anything drains it, the rest becomes silence in its
+    // place, ahead of what is pushed after
+    let shared = SharedBuffer::with_queue(DEFAULT_CAPACITY, 100);
+    shared.push([1.0; 150]).unwrap();
+    shared.push([2.0; 10]).unwrap();
+    // the last gap waits for room for its marker
+    assert_eq!(shared.end().unwrap(), 150);
+    shared.push([3.0; 10]).unwrap();
+    assert_eq!(shared.end().unwrap(), 170);
+    let latest = shared.latest(72).unwrap();
+    assert_eq!(latest[0], 1.0);
+    assert_eq!(latest[1..62], [0.0; 61]);
+    assert_eq!(latest[62..], [3.0; 10]);
+
+    // only readers drain
+    let mut recorder = Recorder::new();
+    let handle = recorder.clone_handle();
+    handle.push(vec![2.0; QUEUE_CAPACITY / 2]).unwrap();
+    assert_eq!(handle.queue.len(), QUEUE_CAPACITY / 2);
+    assert_eq!(handle.end().unwrap(), QUEUE_CAPACITY / 2);
+    assert!(handle.queue.is_empty());
+}
+
+#[test]
+fn test_drain_converts() {
+    // the callback pushes stereo as recorded, readers mix it down
+    let shared = SharedBuffer::new(DEFAULT_CAPACITY);
+    shared
+        .convert_with(
+            InputConverter::new(2, 44100, 44100).unwrap(),
+            HighPass::new(RUMBLE_CUTOFF, 44100.0),
+        )
+        .unwrap();
+    shared.push([1.0, 3.0].repeat(100)).unwrap();
+    assert_eq!(shared.end().unwrap(), 100);
+    // the step high-passed
+    let latest = shared.latest(100).unwrap();
+    assert!((latest[0] - 2.0).abs() < 1e-6);
+    assert!(latest[99] < latest[0]);
 }
 
 impl SampleReader for Recorder {
-    fn take_samples(&mut self, start: usize, end: usize) -> Vec<f64> {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
         self.take_samples(start, end)
     }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        self.read_samples(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.consume(until)
+    }
 }
 
 /// start record and analysis routines.
 ///
 /// NB: The returned `Stream` is RAII guarded, so the caller should not drop it until
 /// recording finishes.
-pub fn run_record(handle: BufferHandle) -> Result<cpal::Stream, anyhow::Error> {
-    info!("run record.. preparing");
-    let host = cpal::default_host();
+pub fn run_record(handle: BufferHandle, acoustic_config: &AcousticConfig) -> Result<cpal::Stream> {
+    run_record_with_device(handle, acoustic_config, input_device(None)?)
+}
+
+/// like `run_record`, but records from `device`
+pub fn run_record_with_device(
+    handle: BufferHandle,
+    acoustic_config: &AcousticConfig,
+    device: cpal::Device,
+) -> Result<cpal::Stream> {
+    let config = input_config(acoustic_config, &device, 1)?;
+    handle.convert_with(
+        converter(acoustic_config, &config)?,
+        HighPass::new(RUMBLE_CUTOFF, acoustic_config.sample_rate),
+    )?;
+    // as recorded, `handle` converts as it is drained
+    build_input_stream(device, config, move |samples| {
+        if handle.push(samples).is_err() {
+            error!("sample buffer is poisoned, dropping samples");
+        }
+    })
+}
+
+/// Record from `device` and hand every block of converted samples to `sink`, right from the
+/// cpal callback. Converting allocates, see `run_record_with_device` for a callback that
+/// does not.
+pub fn record_with_device(
+    acoustic_config: &AcousticConfig,
+    device: cpal::Device,
+    mut sink: impl FnMut(Vec<f32>) + Send + 'static,
+) -> Result<cpal::Stream> {
+    record_converted(acoustic_config, device, None, move |mut blocks| {
+        sink(blocks.remove(0))
+    })
+}
+
+/// like `record_with_device`, but keeps `channels` channels of the device apart instead of
+/// mixing them down, and hands `sink` a block of each. A device with fewer channels
+/// repeats its last one.
+pub fn record_channels_with_device(
+    acoustic_config: &AcousticConfig,
+    device: cpal::Device,
+    channels: usize,
+    sink: impl FnMut(Vec<Vec<f32>>) + Send + 'static,
+) -> Result<cpal::Stream> {
+    record_converted(acoustic_config, device, Some(channels.max(1)), sink)
+}
 
-    // Set up the input device and stream with the default input config.
-    let device = host
-        .default_input_device()
-        .expect("failed to find input device");
+/// Prefer recording at our sample rate with as few channels as possible, but at least
+/// `wanted`, anything else is converted.
+fn input_config(
+    acoustic_config: &AcousticConfig,
+    device: &cpal::Device,
+    wanted: usize,
+) -> Result<SupportedStreamConfig> {
+    info!("run record.. preparing");
 
     info!("Input device: {}", device.name()?);
 
-    let configs = device
-        .supported_input_configs()
-        .expect("Failed to get default input config");
+    let sample_rate = SampleRate(acoustic_config.sample_rate as u32);
+    let config = match device
+        .supported_input_configs()?
+        .filter(|cfg| cfg.min_sample_rate() <= sample_rate && sample_rate <= cfg.max_sample_rate())
+        .min_by_key(|cfg| ((cfg.channels() as usize) < wanted, cfg.channels()))
+    {
+        Some(cfg) => cfg.with_sample_rate(sample_rate),
+        None => device.default_input_config()?,
+    };
 
-    let mut config = device.default_input_config().unwrap();
+    info!("input config: {:?}", config);
+    Ok(config)
+}
 
-    for cfg in configs {
-        if cfg.channels() == 1 {
-            config = cfg.with_sample_rate(SampleRate(44100));
-        }
+/// from what `config` records to mono at our sample rate
+fn converter(
+    acoustic_config: &AcousticConfig,
+    config: &SupportedStreamConfig,
+) -> Result<InputConverter> {
+    InputConverter::new(
+        config.channels() as usize,
+        config.sample_rate().0,
+        acoustic_config.sample_rate as u32,
+    )
+}
+
+/// Record the device `channels` apart, or mixed down into one if `None`.
+fn record_converted(
+    acoustic_config: &AcousticConfig,
+    device: cpal::Device,
+    channels: Option<usize>,
+    mut sink: impl FnMut(Vec<Vec<f32>>) + Send + 'static,
+) -> Result<cpal::Stream> {
+    let config = input_config(acoustic_config, &device, channels.unwrap_or(1))?;
+    let mut filters = match channels {
+        None => vec![converter(acoustic_config, &config)?],
+        Some(channels) => (0..channels)
+            .map(|channel| Ok(converter(acoustic_config, &config)?.with_channel(channel)))
+            .collect::<Result<Vec<InputConverter>>>()?,
     }
+    .into_iter()
+    .map(|converter| {
+        (
+            converter,
+            HighPass::new(RUMBLE_CUTOFF, acoustic_config.sample_rate),
+        )
+    })
+    .collect::<Vec<(InputConverter, HighPass)>>();
 
-    println!("config: {:?}", config);
+    // the samples of a callback, kept from one to the next
+    let mut input = Vec::new();
+    build_input_stream(device, config, move |samples| {
+        input.clear();
+        input.extend(samples);
+        write_input_data(&input, &mut filters, &mut sink)
+    })
+}
 
+/// Start recording from `device`, handing the samples of every callback to `on_data`.
+fn build_input_stream(
+    device: cpal::Device,
+    config: SupportedStreamConfig,
+    mut on_data: impl FnMut(&mut dyn Iterator<Item = f32>) + Send + 'static,
+) -> Result<cpal::Stream> {
     let err_fn = move |err| {
         error!("an error occurred on stream: {}", err);
     };
@@ -106,33 +461,29 @@ pub fn run_record(handle: BufferHandle) -> Result<cpal::Stream, anyhow::Error> {
     let stream = match config.sample_format() {
         cpal::SampleFormat::I8 => device.build_input_stream(
             &config.into(),
-            move |data, _: &_| write_input_data::<i8>(data, handle.clone()),
+            move |data: &[i8], _: &_| on_data(&mut data.iter().map(|x| x.to_sample::<f32>())),
             err_fn,
             None,
         )?,
         cpal::SampleFormat::I16 => device.build_input_stream(
             &config.into(),
-            move |data, _: &_| write_input_data::<i16>(data, handle.clone()),
+            move |data: &[i16], _: &_| on_data(&mut data.iter().map(|x| x.to_sample::<f32>())),
             err_fn,
             None,
         )?,
         cpal::SampleFormat::I32 => device.build_input_stream(
             &config.into(),
-            move |data, _: &_| write_input_data::<i32>(data, handle.clone()),
+            move |data: &[i32], _: &_| on_data(&mut data.iter().map(|x| x.to_sample::<f32>())),
             err_fn,
             None,
         )?,
         cpal::SampleFormat::F32 => device.build_input_stream(
             &config.into(),
-            move |data, _: &_| write_input_data::<f32>(data, handle.clone()),
+            move |data: &[f32], _: &_| on_data(&mut data.iter().copied()),
             err_fn,
             None,
         )?,
-        sample_format => {
-            return Err(anyhow::Error::msg(format!(
-                "Unsupported sample format '{sample_format}'"
-            )))
-        }
+        sample_format => return Err(AcousticError::UnsupportedSampleFormat(sample_format)),
     };
 
     stream.play()?;
@@ -144,12 +495,17 @@ pub fn run_record(handle: BufferHandle) -> Result<cpal::Stream, anyhow::Error> {
     Ok(stream)
 }
 
-fn write_input_data<T>(input: &[T], handle: BufferHandle)
-where
-    T: Sample + ToSample<f32>,
-{
-    handle
-        .lock()
-        .unwrap()
-        .extend(input.iter().map(|x| x.to_sample::<f32>()))
+/// Convert to mono at our sample rate, remove the DC offset and rumble, and pass it on, once
+/// per converter.
+fn write_input_data(
+    input: &[f32],
+    filters: &mut [(InputConverter, HighPass)],
+    sink: &mut impl FnMut(Vec<Vec<f32>>),
+) {
+    sink(
+        filters
+            .iter_mut()
+            .map(|(converter, high_pass)| high_pass.process(&converter.process(input)))
+            .collect(),
+    );
 }
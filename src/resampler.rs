@@ -0,0 +1,156 @@
+//! # Input conversion
+//!
+//! Many capture devices only record at 48 kHz, often in stereo. `InputConverter` turns
+//! whatever the device hands us into mono samples at the configured sample rate, so the
+//! `Recorder` buffer always holds what the `Receiver` expects. For stereo, see `stereo`, it
+//! keeps a single channel instead of mixing them down. `stretch` takes out what is
+//! left, the couple of hundred parts per million two sound cards rarely agree on.
+
+use std::fmt;
+
+use rubato::{FftFixedIn, Resampler};
+use tracing::error;
+
+use crate::error::Result;
+
+/// input frames the resampler works on at once
+const CHUNK_SIZE: usize = 1024;
+
+pub struct InputConverter {
+    channels: usize,
+    /// the one channel kept, all of them mixed down if `None`
+    channel: Option<usize>,
+    /// `None` if the device already records at the right rate
+    resampler: Option<FftFixedIn<f32>>,
+    /// downmixed samples waiting for a full chunk
+    pending: Vec<f32>,
+}
+
+impl InputConverter {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32) -> Result<InputConverter> {
+        let resampler = match input_rate == output_rate {
+            true => None,
+            false => Some(FftFixedIn::new(
+                input_rate as usize,
+                output_rate as usize,
+                CHUNK_SIZE,
+                1,
+                1,
+            )?),
+        };
+        Ok(InputConverter {
+            channels: channels.max(1),
+            channel: None,
+            resampler,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Keep only `channel` instead of mixing all of them down, the last one if there are
+    /// fewer.
+    pub fn with_channel(mut self, channel: usize) -> InputConverter {
+        self.channel = Some(channel.min(self.channels - 1));
+        self
+    }
+
+    /// Feed interleaved frames, get back the mono samples that are ready.
+    pub fn process(&mut self, interleaved: &[f32]) -> Vec<f32> {
+        let channel = self.channel;
+        let mono = interleaved
+            .chunks(self.channels)
+            .map(|frame| match channel {
+                Some(channel) => frame[channel],
+                None => frame.iter().sum::<f32>() / frame.len() as f32,
+            });
+        let Some(resampler) = &mut self.resampler else {
+            return mono.collect();
+        };
+        self.pending.extend(mono);
+
+        let mut output = Vec::new();
+        while self.pending.len() >= resampler.input_frames_next() {
+            let chunk = self
+                .pending
+                .drain(..resampler.input_frames_next())
+                .collect::<Vec<f32>>();
+            match resampler.process(&[chunk], None) {
+                Ok(mut resampled) => output.append(&mut resampled[0]),
+                Err(err) => error!("failed to resample: {}", err),
+            }
+        }
+        output
+    }
+}
+
+impl fmt::Debug for InputConverter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InputConverter")
+            .field("channels", &self.channels)
+            .field("channel", &self.channel)
+            .field("resampling", &self.resampler.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The samples of `signal` at every `step` samples, up to its last one, interpolated with a
+/// cubic. A `step` a little off 1 moves a signal from one sample clock to another.
+pub fn stretch(signal: &[f64], step: f64) -> Vec<f64> {
+    if signal.is_empty() {
+        return vec![];
+    }
+    let at = |i: isize| match i {
+        i if i < 0 => 0.0,
+        i => signal.get(i as usize).copied().unwrap_or(0.0),
+    };
+    let len = ((signal.len() - 1) as f64 / step).floor() as usize + 1;
+    (0..len)
+        .map(|j| {
+            let position = j as f64 * step;
+            let i = position.floor() as isize;
+            let t = position - i as f64;
+            let (p0, p1, p2, p3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+            // Catmull-Rom through the four samples around the position
+            p1 + 0.5
+                * t
+                * (p2 - p0
+                    + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+        })
+        .collect()
+}
+
+#[test]
+fn test_downmix_and_resample() {
+    use crate::goertzel::GoertzelBank;
+    use dasp::{signal, Signal};
+
+    let freq = 2067.1875;
+    let stereo = signal::rate(48000.0)
+        .const_hz(freq)
+        .sine()
+        .take(48000)
+        .flat_map(|x| [x as f32, x as f32])
+        .collect::<Vec<f32>>();
+    let mut converter = InputConverter::new(2, 48000, 44100).unwrap();
+    let mono = stereo
+        .chunks(1000)
+        .flat_map(|chunk| converter.process(chunk))
+        .map(|x| x as f64)
+        .collect::<Vec<f64>>();
+    // one second in, a bit less than a second out, the rest is still in the resampler
+    assert!((43000..=44100).contains(&mono.len()), "{}", mono.len());
+
+    let bank = GoertzelBank::new(&[freq, freq * 48000.0 / 44100.0], 44100.0);
+    let amplitudes = bank.amplitudes(&mono[mono.len() - 4410..]);
+    assert!(
+        amplitudes[0] > 0.9 && amplitudes[1] < 0.1,
+        "{:?}",
+        amplitudes
+    );
+
+    let mut passthrough = InputConverter::new(2, 44100, 44100).unwrap();
+    assert_eq!(passthrough.process(&[0.5, 0.25, 1.0, 0.0]), [0.375, 0.5]);
+    let mut right = InputConverter::new(2, 44100, 44100)
+        .unwrap()
+        .with_channel(1);
+    assert_eq!(right.process(&[0.5, 0.25, 1.0, 0.0]), [0.25, 0.0]);
+}
@@ -0,0 +1,121 @@
+//! # Ring buffer
+//!
+//! Recorded samples are addressed by their absolute index since recording started, but only
+//! the latest `capacity` of them are kept. Samples the `Receiver` has consumed can be
+//! dropped early.
+
+use std::collections::VecDeque;
+
+use crate::error::{AcousticError, Result};
+
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+    /// absolute index of `samples[0]`
+    offset: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            samples: VecDeque::new(),
+            capacity,
+            offset: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// absolute index of the oldest sample still kept
+    pub fn start(&self) -> usize {
+        self.offset
+    }
+
+    /// absolute index right after the newest sample, i.e. the number of samples ever pushed
+    pub fn end(&self) -> usize {
+        self.offset + self.samples.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// append samples, evicting the oldest ones beyond capacity
+    pub fn extend(&mut self, input: impl IntoIterator<Item = f32>) {
+        for sample in input {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+                self.offset += 1;
+            }
+            self.samples.push_back(sample);
+        }
+    }
+
+    /// drop every sample before the absolute index `until`
+    pub fn consume(&mut self, until: usize) {
+        let n = until.saturating_sub(self.offset).min(self.samples.len());
+        self.samples.drain(..n);
+        self.offset += n;
+    }
+
+    /// Samples from `start` to `end`, both absolute. `end` must have been pushed already.
+    pub fn get(&self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    /// `get` into `buffer`, in place of what it held, without allocating once it is large
+    /// enough.
+    pub fn read(&self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        if start < self.offset {
+            return Err(AcousticError::Evicted {
+                start,
+                oldest: self.offset,
+            });
+        }
+        buffer.clear();
+        buffer.extend(
+            self.samples
+                .range(start - self.offset..end - self.offset)
+                .map(|f| *f as f64),
+        );
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &f32> {
+        self.samples.iter()
+    }
+}
+
+#[test]
+fn test_ring_buffer() {
+    let mut ring = RingBuffer::new(4);
+    ring.extend([0.0, 1.0, 2.0]);
+    assert_eq!(ring.get(1, 3).unwrap(), [1.0, 2.0]);
+    let mut buffer = vec![9.0; 5];
+    ring.read(0, 2, &mut buffer).unwrap();
+    assert_eq!(buffer, [0.0, 1.0]);
+    ring.extend([3.0, 4.0, 5.0]);
+    assert_eq!((ring.start(), ring.end(), ring.len()), (2, 6, 4));
+    assert_eq!(ring.get(2, 6).unwrap(), [2.0, 3.0, 4.0, 5.0]);
+    assert!(matches!(
+        ring.get(1, 3),
+        Err(AcousticError::Evicted {
+            start: 1,
+            oldest: 2
+        })
+    ));
+    ring.consume(5);
+    assert_eq!(ring.get(5, 6).unwrap(), [5.0]);
+    ring.consume(100);
+    assert!(ring.is_empty());
+    assert_eq!(ring.start(), 6);
+}
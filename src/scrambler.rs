@@ -0,0 +1,66 @@
+//! # Scrambler
+//!
+//! A payload of zeros, or any other run of repeated half-bytes, goes out as one constant
+//! tone. That leaves the receiver nothing to synchronise on and drives the speaker hard at a
+//! single frequency. The scrambler XORs the sealed bytes with the sequence of the 802.11
+//! LFSR, x^7 + x^4 + 1, so the symbols look random whatever the payload. Scrambling the
+//! same bytes twice from the same state gives them back.
+
+/// state every packet starts from
+const SEED: u8 = 0x7f;
+
+pub struct Scrambler {
+    /// the last 7 bits out of the LFSR
+    state: u8,
+}
+
+impl Default for Scrambler {
+    fn default() -> Self {
+        Scrambler { state: SEED }
+    }
+}
+
+impl Scrambler {
+    pub fn new() -> Scrambler {
+        Scrambler::default()
+    }
+
+    /// Scramble or descramble `data`, going on from where the last call stopped.
+    pub fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+        data.iter().map(|byte| byte ^ self.next_byte()).collect()
+    }
+
+    /// eight bits of the sequence, LSB first
+    fn next_byte(&mut self) -> u8 {
+        (0..8).fold(0, |byte, i| byte | (self.next_bit() << i))
+    }
+
+    fn next_bit(&mut self) -> u8 {
+        let bit = ((self.state >> 6) ^ (self.state >> 3)) & 1;
+        self.state = ((self.state << 1) | bit) & 0x7f;
+        bit
+    }
+}
+
+#[test]
+fn test_scramble_roundtrip() {
+    let data = (0..300).map(|i| (i * 31) as u8).collect::<Vec<u8>>();
+    let scrambled = Scrambler::new().apply(&data);
+    assert_ne!(scrambled, data);
+    assert_eq!(Scrambler::new().apply(&scrambled), data);
+
+    // in pieces, as the receiver does with the header and the payload
+    let mut descrambler = Scrambler::new();
+    let mut descrambled = descrambler.apply(&scrambled[..9]);
+    descrambled.extend(descrambler.apply(&scrambled[9..]));
+    assert_eq!(descrambled, data);
+}
+
+#[test]
+fn test_scramble_balances_zeros() {
+    let scrambled = Scrambler::new().apply(&[0; 127]);
+    // the sequence repeats every 127 bits, with 64 ones among them
+    let ones = scrambled.iter().map(|b| b.count_ones()).sum::<u32>();
+    assert!((480..540).contains(&ones), "{} ones", ones);
+    assert!(scrambled.windows(4).all(|w| w.iter().any(|b| *b != w[0])));
+}
@@ -0,0 +1,500 @@
+//! # Session handshake
+//!
+//! Before any data flows, the initiator says HELLO with the length of the message and the
+//! profile it wants to send with, the responder answers HELLO-ACK once it agrees, and the
+//! initiator confirms with START. All three travel as `PacketKind::Control` packets on the
+//! default profile, so that both ends understand them whatever they agree on.
+//!
+//! Both ends also agree on the version of the packet header, see `Packet::VERSION`. HELLO
+//! carries the oldest and the newest version the initiator reads, and the responder picks
+//! the newest one it reads too, so that a newer crate falls back to the header of an older
+//! one. Without a version in common it answers REJECT with its own range. Peers from before
+//! versions were negotiated send none, they read `UNVERSIONED` only.
+//!
+//! Once established, the initiator can ask for another symbol time with RETUNE, see
+//! `rate`. The responder switches as soon as it answers RETUNE-ACK, the initiator once it
+//! hears that.
+
+use std::ops::RangeInclusive;
+
+use tracing::info;
+
+use crate::{
+    config::{AcousticConfig, Fec, Modulation},
+    error::{AcousticError, Result},
+    Packet, PacketKind,
+};
+
+/// the header version of peers whose handshake carries none
+pub const UNVERSIONED: u8 = 3;
+
+/// what the initiator is about to send, and how
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offer {
+    /// length of the whole message, in bytes
+    pub message_len: usize,
+    pub modulation: Modulation,
+    pub fec: Fec,
+    pub interleave_depth: usize,
+    pub scramble: bool,
+    pub pilot: bool,
+    /// symbol duration, in milliseconds
+    pub symbol_time_ms: u16,
+    /// the newest header version the initiator reads, the agreed one once answered
+    pub version: u8,
+    /// the oldest header version the initiator reads, the agreed one once answered
+    pub min_version: u8,
+}
+
+impl Offer {
+    pub fn new(config: &AcousticConfig, message_len: usize) -> Offer {
+        Offer {
+            message_len,
+            modulation: config.modulation,
+            fec: config.fec,
+            interleave_depth: config.interleave_depth,
+            scramble: config.scramble,
+            pilot: config.pilot,
+            symbol_time_ms: (config.symbol_time * 1000.0).round() as u16,
+            version: Packet::VERSION,
+            min_version: Packet::MIN_VERSION,
+        }
+    }
+
+    /// header versions both ends read
+    pub fn versions(&self) -> RangeInclusive<u8> {
+        self.min_version..=self.version
+    }
+
+    /// The offer with the newest header version we read too, if any.
+    pub fn negotiate(&self) -> Option<Offer> {
+        let version = self.version.min(Packet::VERSION);
+        (version >= self.min_version.max(Packet::MIN_VERSION)).then_some(Offer {
+            version,
+            min_version: version,
+            ..*self
+        })
+    }
+
+    /// whether `other` is the same profile, whatever the versions
+    fn same_profile(&self, other: &Offer) -> bool {
+        Offer {
+            version: self.version,
+            min_version: self.min_version,
+            ..*other
+        } == *self
+    }
+
+    /// `config` with the agreed profile
+    pub fn apply(&self, config: &AcousticConfig) -> AcousticConfig {
+        AcousticConfig {
+            modulation: self.modulation,
+            fec: self.fec,
+            interleave_depth: self.interleave_depth,
+            scramble: self.scramble,
+            pilot: self.pilot,
+            symbol_time: self.symbol_time_ms as f64 / 1000.0,
+            ..config.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    Hello(Offer),
+    HelloAck(Offer),
+    Start,
+    /// a new symbol time, in milliseconds
+    Retune(u16),
+    RetuneAck(u16),
+    /// no header version in common, with the versions the responder reads
+    Reject {
+        min_version: u8,
+        version: u8,
+    },
+}
+
+impl Handshake {
+    const HELLO: u8 = 0;
+    const HELLO_ACK: u8 = 1;
+    const START: u8 = 2;
+    const RETUNE: u8 = 3;
+    const RETUNE_ACK: u8 = 4;
+    const REJECT: u8 = 5;
+
+    /// A control packet: the message type, then for an offer its length as a little endian
+    /// `u32`, the modulation, the error correction, the interleave depth as a `u16` and
+    /// flags, 1 to scramble and 2 for pilots, the symbol time in milliseconds as a `u16`,
+    /// and the newest and the oldest header version. A retune carries just the symbol time,
+    /// a reject the newest and the oldest version of the responder.
+    pub fn to_packet(&self) -> Packet {
+        let mut data = Vec::new();
+        match self {
+            Handshake::Hello(offer) | Handshake::HelloAck(offer) => {
+                data.push(match self {
+                    Handshake::Hello(_) => Self::HELLO,
+                    _ => Self::HELLO_ACK,
+                });
+                data.extend_from_slice(&(offer.message_len as u32).to_le_bytes());
+                data.push(match offer.modulation {
+                    Modulation::Fsk => 0,
+                    Modulation::Ofdm => 1,
+                    Modulation::Dpsk => 2,
+                    Modulation::Qpsk => 3,
+                    Modulation::Qam16 => 4,
+                    Modulation::Dsss => 5,
+                    Modulation::Css => 6,
+                });
+                data.push(match offer.fec {
+                    Fec::None => 0,
+                    Fec::Hamming74 => 1,
+                });
+                data.extend_from_slice(&(offer.interleave_depth as u16).to_le_bytes());
+                data.push(offer.scramble as u8 | (offer.pilot as u8) << 1);
+                data.extend_from_slice(&offer.symbol_time_ms.to_le_bytes());
+                data.extend_from_slice(&[offer.version, offer.min_version]);
+            }
+            Handshake::Start => data.push(Self::START),
+            Handshake::Retune(symbol_time_ms) | Handshake::RetuneAck(symbol_time_ms) => {
+                data.push(match self {
+                    Handshake::Retune(_) => Self::RETUNE,
+                    _ => Self::RETUNE_ACK,
+                });
+                data.extend_from_slice(&symbol_time_ms.to_le_bytes());
+            }
+            Handshake::Reject {
+                min_version,
+                version,
+            } => data.extend_from_slice(&[Self::REJECT, *version, *min_version]),
+        }
+        Packet::new(PacketKind::Control, 0, &data)
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Handshake> {
+        let malformed =
+            || AcousticError::MalformedPacket(format!("bad handshake {:?}", packet.data));
+        if packet.kind != PacketKind::Control {
+            return Err(malformed());
+        }
+        let offer = || -> Option<Offer> {
+            let field = packet.data.get(1..12)?;
+            if field[8] > 3 {
+                return None;
+            }
+            Some(Offer {
+                message_len: u32::from_le_bytes(field[..4].try_into().ok()?) as usize,
+                modulation: match field[4] {
+                    0 => Modulation::Fsk,
+                    1 => Modulation::Ofdm,
+                    2 => Modulation::Dpsk,
+                    3 => Modulation::Qpsk,
+                    4 => Modulation::Qam16,
+                    5 => Modulation::Dsss,
+                    6 => Modulation::Css,
+                    _ => return None,
+                },
+                fec: match field[5] {
+                    0 => Fec::None,
+                    1 => Fec::Hamming74,
+                    _ => return None,
+                },
+                interleave_depth: u16::from_le_bytes([field[6], field[7]]) as usize,
+                scramble: field[8] & 1 > 0,
+                pilot: field[8] & 2 > 0,
+                symbol_time_ms: u16::from_le_bytes([field[9], field[10]]),
+                // older peers end here
+                version: packet.data.get(12).copied().unwrap_or(UNVERSIONED),
+                min_version: packet.data.get(13).copied().unwrap_or(UNVERSIONED),
+            })
+        };
+        let symbol_time = || -> Option<u16> {
+            let field = packet.data.get(1..3)?;
+            Some(u16::from_le_bytes([field[0], field[1]]))
+        };
+        match packet.data.first() {
+            Some(&Self::HELLO) => offer().map(Handshake::Hello).ok_or_else(malformed),
+            Some(&Self::HELLO_ACK) => offer().map(Handshake::HelloAck).ok_or_else(malformed),
+            Some(&Self::START) => Ok(Handshake::Start),
+            Some(&Self::RETUNE) => symbol_time().map(Handshake::Retune).ok_or_else(malformed),
+            Some(&Self::RETUNE_ACK) => symbol_time()
+                .map(Handshake::RetuneAck)
+                .ok_or_else(malformed),
+            Some(&Self::REJECT) => match packet.data[1..] {
+                [version, min_version, ..] => Ok(Handshake::Reject {
+                    min_version,
+                    version,
+                }),
+                _ => Err(malformed()),
+            },
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// the sending end of a handshake
+pub struct Initiator {
+    offer: Offer,
+    agreed: bool,
+    /// the versions of a responder that rejected ours
+    rejected: Option<RangeInclusive<u8>>,
+}
+
+impl Initiator {
+    pub fn new(offer: Offer) -> Initiator {
+        Initiator {
+            offer,
+            agreed: false,
+            rejected: None,
+        }
+    }
+
+    /// the packet opening the handshake, send it again if no answer comes
+    pub fn hello(&self) -> Packet {
+        Handshake::Hello(self.offer).to_packet()
+    }
+
+    /// ask to switch to another symbol time once established, send it again if no answer
+    /// comes
+    pub fn retune(&self, symbol_time_ms: u16) -> Packet {
+        Handshake::Retune(symbol_time_ms).to_packet()
+    }
+
+    /// Take what the responder sent, and return the START to send once it agreed.
+    pub fn on_packet(&mut self, packet: &Packet) -> Option<Packet> {
+        match Handshake::from_packet(packet) {
+            Ok(Handshake::HelloAck(offer))
+                if offer.same_profile(&self.offer)
+                    && offer.version == offer.min_version
+                    && self.offer.versions().contains(&offer.version) =>
+            {
+                info!("agreed on header version {}", offer.version);
+                self.offer = offer;
+                self.agreed = true;
+                Some(Handshake::Start.to_packet())
+            }
+            Ok(Handshake::Reject {
+                min_version,
+                version,
+            }) if !self.agreed => {
+                info!("rejected, the responder reads versions {min_version} to {version}");
+                self.rejected = Some(min_version..=version);
+                None
+            }
+            Ok(Handshake::RetuneAck(symbol_time_ms)) if self.agreed => {
+                self.offer.symbol_time_ms = symbol_time_ms;
+                None
+            }
+            other => {
+                info!("unexpected during handshake: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// the agreed offer, once the responder acknowledged it
+    pub fn established(&self) -> Option<Offer> {
+        self.agreed.then_some(self.offer)
+    }
+
+    /// The header versions the responder reads, if none of them is one of ours. Sending
+    /// HELLO again is no use then.
+    pub fn rejected(&self) -> Option<RangeInclusive<u8>> {
+        self.rejected.clone()
+    }
+}
+
+/// the receiving end of a handshake
+pub struct Responder {
+    max_message_len: usize,
+    offer: Option<Offer>,
+    started: bool,
+}
+
+impl Responder {
+    /// refuse messages longer than `max_message_len` bytes
+    pub fn new(max_message_len: usize) -> Responder {
+        Responder {
+            max_message_len,
+            offer: None,
+            started: false,
+        }
+    }
+
+    /// Take what the initiator sent, and return the answer to send, if any.
+    pub fn on_packet(&mut self, packet: &Packet) -> Option<Packet> {
+        match Handshake::from_packet(packet) {
+            Ok(Handshake::Hello(offer)) if offer.message_len <= self.max_message_len => {
+                self.started = false;
+                self.offer = offer.negotiate();
+                match self.offer {
+                    Some(offer) => Some(Handshake::HelloAck(offer).to_packet()),
+                    None => {
+                        info!("no header version in common with {:?}", offer.versions());
+                        Some(
+                            Handshake::Reject {
+                                min_version: Packet::MIN_VERSION,
+                                version: Packet::VERSION,
+                            }
+                            .to_packet(),
+                        )
+                    }
+                }
+            }
+            Ok(Handshake::Start) if self.offer.is_some() => {
+                self.started = true;
+                None
+            }
+            Ok(Handshake::Retune(symbol_time_ms)) if self.started => {
+                if let Some(offer) = self.offer.as_mut() {
+                    offer.symbol_time_ms = symbol_time_ms;
+                }
+                Some(Handshake::RetuneAck(symbol_time_ms).to_packet())
+            }
+            other => {
+                info!("unexpected during handshake: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// the agreed offer, once the initiator started
+    pub fn established(&self) -> Option<Offer> {
+        self.offer.filter(|_| self.started)
+    }
+}
+
+#[test]
+fn test_handshake() {
+    let config = AcousticConfig::builder()
+        .modulation(Modulation::Ofdm)
+        .fec(Fec::Hamming74)
+        .interleave_depth(16)
+        .scramble(true)
+        .pilot(true)
+        .symbol_time(0.05)
+        .build()
+        .unwrap();
+    let offer = Offer::new(&config, 3000);
+    let mut initiator = Initiator::new(offer);
+    let mut responder = Responder::new(4096);
+
+    let hello_ack = responder.on_packet(&initiator.hello()).unwrap();
+    assert_eq!(responder.established(), None);
+    let start = initiator.on_packet(&hello_ack).unwrap();
+    assert_eq!(initiator.established(), Some(offer));
+    assert!(responder.on_packet(&start).is_none());
+    assert_eq!(responder.established(), Some(offer));
+    assert_eq!(offer.apply(&AcousticConfig::default()), config);
+
+    let mut picky = Responder::new(100);
+    assert!(picky.on_packet(&initiator.hello()).is_none());
+    assert!(picky.on_packet(&start).is_none());
+    assert_eq!(picky.established(), None);
+}
+
+#[test]
+fn test_retune() {
+    use crate::rate::RateController;
+
+    let config = AcousticConfig::default();
+    let mut initiator = Initiator::new(Offer::new(&config, 3000));
+    let mut responder = Responder::new(4096);
+    // not before the session is established
+    assert!(responder.on_packet(&initiator.retune(50)).is_none());
+    let hello_ack = responder.on_packet(&initiator.hello()).unwrap();
+    let start = initiator.on_packet(&hello_ack).unwrap();
+    responder.on_packet(&start);
+
+    let mut rate = RateController::new();
+    let symbol_time_ms = (0..16).find_map(|_| rate.on_delivered(1)).unwrap();
+    let retune_ack = responder
+        .on_packet(&initiator.retune(symbol_time_ms))
+        .unwrap();
+    assert_eq!(responder.established().unwrap().symbol_time_ms, 70);
+    assert_eq!(initiator.established().unwrap().symbol_time_ms, 100);
+    assert!(initiator.on_packet(&retune_ack).is_none());
+    let offer = initiator.established().unwrap();
+    assert_eq!(Some(offer), responder.established());
+    assert_eq!(offer.apply(&config).symbol_time, 0.07);
+}
+
+#[test]
+fn test_version_negotiation() {
+    let offer = Offer::new(&AcousticConfig::default(), 3000);
+    assert_eq!(offer.negotiate(), Some(offer));
+
+    // a newer initiator falls back to our header
+    let newer = Offer {
+        version: Packet::VERSION + 2,
+        ..offer
+    };
+    let mut initiator = Initiator::new(newer);
+    let mut responder = Responder::new(4096);
+    let hello_ack = responder.on_packet(&initiator.hello()).unwrap();
+    let start = initiator.on_packet(&hello_ack).unwrap();
+    responder.on_packet(&start);
+    let agreed = initiator.established().unwrap();
+    assert_eq!(agreed.versions(), Packet::VERSION..=Packet::VERSION);
+    assert_eq!(responder.established(), Some(agreed));
+
+    // one that no longer reads ours is told what we read
+    let mut initiator = Initiator::new(Offer {
+        min_version: Packet::VERSION + 1,
+        ..newer
+    });
+    let mut responder = Responder::new(4096);
+    let reject = responder.on_packet(&initiator.hello()).unwrap();
+    assert_eq!(responder.established(), None);
+    assert!(initiator.on_packet(&reject).is_none());
+    assert_eq!(initiator.established(), None);
+    assert_eq!(
+        initiator.rejected(),
+        Some(Packet::MIN_VERSION..=Packet::VERSION)
+    );
+
+    // an answer in a version we do not read is no agreement
+    let mut initiator = Initiator::new(offer);
+    let older = Offer {
+        version: Packet::MIN_VERSION - 1,
+        min_version: Packet::MIN_VERSION - 1,
+        ..offer
+    };
+    assert!(initiator
+        .on_packet(&Handshake::HelloAck(older).to_packet())
+        .is_none());
+    assert_eq!(initiator.established(), None);
+
+    // a peer from before versions were negotiated reads no header with a port
+    let mut hello = Initiator::new(offer).hello();
+    hello.data.truncate(12);
+    let mut responder = Responder::new(4096);
+    let reject = responder.on_packet(&hello).unwrap();
+    assert!(matches!(
+        Handshake::from_packet(&reject).unwrap(),
+        Handshake::Reject { .. }
+    ));
+}
+
+#[test]
+fn test_handshake_over_the_air() {
+    use crate::transmission::{Event, MemoryReader, Receiver};
+    use crate::transmitter::modulate_packets;
+
+    let config = AcousticConfig::default();
+    let offer = Offer::new(&config, 11);
+    let hello = Initiator::new(offer).hello();
+    let signal = [
+        vec![0.0; 10000],
+        modulate_packets(&config, &[hello]).unwrap(),
+        vec![0.0; 10000],
+    ]
+    .concat();
+    let mut receiver = Receiver::new(Box::new(MemoryReader(signal)));
+    let Event::Control(packet) = receiver.next_event().unwrap() else {
+        panic!("expected a control packet");
+    };
+    assert_eq!(
+        Handshake::from_packet(&packet).unwrap(),
+        Handshake::Hello(offer)
+    );
+}
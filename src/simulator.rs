@@ -0,0 +1,211 @@
+//! # Channel simulator
+//!
+//! Impairs a modulated signal the way the air between a speaker and a microphone would, so
+//! the receiver can be tested without either: additive white Gaussian noise, the treble
+//! roll-off of a cheap speaker, the echoes of a room, either a few reflections or a
+//! measured impulse response, and sound cards whose clocks do not quite agree.
+
+use std::path::Path;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+
+use crate::{
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    resampler::stretch,
+    transmission::SampleReader,
+    wav_reader::WavSampleReader,
+};
+
+/// mean power of a signal
+pub fn power(signal: &[f64]) -> f64 {
+    if signal.is_empty() {
+        return 0.0;
+    }
+    signal.iter().map(|x| x * x).sum::<f64>() / signal.len() as f64
+}
+
+/// Add white Gaussian noise so that the result has `snr_db` decibels of signal to noise.
+pub fn awgn(signal: &[f64], snr_db: f64) -> Vec<f64> {
+    awgn_with_rng(signal, snr_db, &mut rand::thread_rng())
+}
+
+/// Like `awgn`, but reproducible.
+pub fn awgn_seeded(signal: &[f64], snr_db: f64, seed: u64) -> Vec<f64> {
+    awgn_with_rng(signal, snr_db, &mut StdRng::seed_from_u64(seed))
+}
+
+pub fn awgn_with_rng<R: Rng>(signal: &[f64], snr_db: f64, rng: &mut R) -> Vec<f64> {
+    let noise_power = power(signal) / 10.0_f64.powf(snr_db / 10.0);
+    let Ok(normal) = Normal::new(0.0, noise_power.sqrt()) else {
+        return signal.to_vec();
+    };
+    signal.iter().map(|x| x + normal.sample(rng)).collect()
+}
+
+/// Attenuate high frequencies like a small speaker, with a second order low-pass at `cutoff`.
+/// A tone at twice the cutoff comes out at a fifth of its amplitude.
+pub fn speaker_rolloff(signal: &[f64], cutoff: f64, sample_rate: f64) -> Vec<f64> {
+    let alpha = 1.0 - (-2.0 * std::f64::consts::PI * cutoff / sample_rate).exp();
+    let one_pole = |signal: &[f64]| {
+        signal
+            .iter()
+            .scan(0.0, |y, x| {
+                *y += alpha * (x - *y);
+                Some(*y)
+            })
+            .collect::<Vec<f64>>()
+    };
+    one_pole(&one_pole(signal))
+}
+
+/// A reflection that arrives `delay` seconds after the direct sound, `gain` times as loud.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Echo {
+    pub delay: f64,
+    pub gain: f64,
+}
+
+/// The direct sound followed by `echoes`, like the walls of a room would add them. The
+/// result is longer than `signal` by the last echo.
+pub fn multipath(signal: &[f64], echoes: &[Echo], sample_rate: f64) -> Vec<f64> {
+    let delays = echoes
+        .iter()
+        .map(|echo| ((echo.delay * sample_rate).round() as usize, echo.gain))
+        .collect::<Vec<(usize, f64)>>();
+    let len = delays.iter().map(|(delay, _)| delay + 1).max().unwrap_or(1);
+    let mut response = vec![0.0; len];
+    response[0] = 1.0;
+    for (delay, gain) in delays {
+        response[delay] += gain;
+    }
+    convolve(signal, &response)
+}
+
+/// Convolve `signal` with a room's `impulse_response`, see `impulse_response`. Taps that are
+/// zero cost nothing, a long measured response is slow.
+pub fn convolve(signal: &[f64], impulse_response: &[f64]) -> Vec<f64> {
+    if signal.is_empty() || impulse_response.is_empty() {
+        return vec![];
+    }
+    let mut result = vec![0.0; signal.len() + impulse_response.len() - 1];
+    for (delay, tap) in impulse_response.iter().enumerate() {
+        if *tap == 0.0 {
+            continue;
+        }
+        for (y, x) in result[delay..].iter_mut().zip(signal) {
+            *y += tap * x;
+        }
+    }
+    result
+}
+
+/// Read an impulse response recorded in a room from a wav file, as mono at the sample rate
+/// of `config`.
+pub fn impulse_response(path: impl AsRef<Path>, config: &AcousticConfig) -> Result<Vec<f64>> {
+    let mut reader = WavSampleReader::open(path, config)?;
+    let mut response = Vec::new();
+    // blocks while they last, then what is left sample by sample
+    for block in [4096, 1] {
+        loop {
+            match reader.take_samples(response.len(), response.len() + block) {
+                Ok(samples) => response.extend(samples),
+                Err(AcousticError::EndOfStream) => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(response)
+}
+
+/// Record `signal` with a sound card whose clock runs `ppm` parts per million slower than
+/// the one that played it, so that it comes out that much shorter and higher. Negative
+/// `ppm` stretches it.
+pub fn clock_drift(signal: &[f64], ppm: f64) -> Vec<f64> {
+    stretch(signal, 1.0 + ppm * 1e-6)
+}
+
+#[test]
+fn test_awgn_snr() {
+    use crate::{config::AcousticConfig, physics::modulate_bits};
+
+    let signal = modulate_bits(&AcousticConfig::default(), b"hello world".to_vec());
+    let noisy = awgn_seeded(&signal, 10.0, 42);
+    assert_eq!(noisy.len(), signal.len());
+    let noise = noisy
+        .iter()
+        .zip(&signal)
+        .map(|(n, s)| n - s)
+        .collect::<Vec<f64>>();
+    let snr_db = 10.0 * (power(&signal) / power(&noise)).log10();
+    assert!((snr_db - 10.0).abs() < 0.1, "snr is {snr_db} dB");
+    assert_eq!(noisy, awgn_seeded(&signal, 10.0, 42));
+}
+
+#[test]
+fn test_speaker_rolloff() {
+    use crate::{config::AcousticConfig, physics::pilot_symbol};
+
+    let config = AcousticConfig::default();
+    let muffled = speaker_rolloff(&pilot_symbol(&config), 1500.0, config.sample_rate);
+    let amplitudes = crate::goertzel::GoertzelBank::new(&config.carrier_freqs, config.sample_rate)
+        .amplitudes(&muffled[muffled.len() / 4..]);
+    assert!(amplitudes.windows(2).all(|w| w[0] > w[1]));
+    assert!(amplitudes[0] > 2.5 * amplitudes[3], "{:?}", amplitudes);
+}
+
+#[test]
+fn test_multipath() {
+    let sample_rate = 1000.0;
+    let echoes = [
+        Echo {
+            delay: 0.003,
+            gain: 0.5,
+        },
+        Echo {
+            delay: 0.005,
+            gain: -0.25,
+        },
+    ];
+    let echoed = multipath(&[1.0, 2.0], &echoes, sample_rate);
+    assert_eq!(echoed, [1.0, 2.0, 0.0, 0.5, 1.0, -0.25, -0.5]);
+    assert_eq!(multipath(&[1.0, 2.0], &[], sample_rate), [1.0, 2.0]);
+
+    // a measured response is read back at the configured rate
+    let config = AcousticConfig::default();
+    let response = [1.0, 0.0, 0.0, 0.5, 0.0, -0.25];
+    crate::output_wav(&config, &response, "impulse_response.wav").unwrap();
+    let read = impulse_response("impulse_response.wav", &config).unwrap();
+    assert!(read.len() >= response.len());
+    assert!(read
+        .iter()
+        .zip(response)
+        .all(|(read, tap)| (read - tap).abs() < 0.05));
+    assert!(read[response.len()..].iter().all(|x| x.abs() < 0.05));
+}
+
+#[test]
+fn test_clock_drift() {
+    let sample_rate = 44100.0;
+    let tone = |freq: f64, len: usize| {
+        (0..len)
+            .map(|i| (2.0 * std::f64::consts::PI * freq * i as f64 / sample_rate).sin())
+            .collect::<Vec<f64>>()
+    };
+    let signal = tone(3000.0, 44100);
+    assert_eq!(clock_drift(&signal, 0.0), signal);
+    assert!(clock_drift(&[], 100.0).is_empty());
+
+    // a second at 200 ppm is 9 samples short, and the tone that much higher
+    let drifted = clock_drift(&signal, 200.0);
+    assert_eq!(drifted.len(), 44091);
+    let expected = tone(3000.0 * 1.0002, drifted.len());
+    let error = drifted
+        .iter()
+        .zip(&expected)
+        .map(|(x, y)| (x - y).abs())
+        .fold(0.0, f64::max);
+    assert!(error < 0.01, "{error}");
+    assert_eq!(clock_drift(&signal, -200.0).len(), 44108);
+}
@@ -0,0 +1,190 @@
+//! # Lock-free sample queue
+//!
+//! The cpal callback runs on the audio thread, often at real-time priority, and must never
+//! wait for a lock the receiver holds, or the device overruns and samples go missing. A
+//! `SampleQueue` hands samples from one thread to another through a fixed ring of atomics
+//! instead: the producer only ever stores how far it wrote, the consumer how far it read.
+//! One thread may push at a time and one may pop at a time, the `SharedBuffer` of the
+//! `recorder` pops under its own lock.
+//!
+//! A full queue drops what is pushed and counts it, see `take_dropped`, rather than
+//! overwriting samples the consumer may be reading. The gap is kept in its place among the
+//! samples, so that they keep their index in the stream: a slot is held back for a marker
+//! standing for the dropped samples, which pop as silence.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// a quiet NaN, whose payload is the length of the gap it marks
+const GAP: u32 = 0x7fc0_0000;
+
+/// most samples a single gap marker stands for
+const MAX_GAP: usize = (1 << 22) - 1;
+
+/// Samples in flight between two threads, see the module.
+#[derive(Debug)]
+pub struct SampleQueue {
+    /// the samples, as the bits of `f32`s
+    slots: Box<[AtomicU32]>,
+    /// samples ever pushed, stored by the producer only
+    written: AtomicUsize,
+    /// samples ever popped, stored by the consumer only
+    read: AtomicUsize,
+    /// samples dropped but not marked yet, used by the producer only
+    gap: AtomicUsize,
+    /// samples pushed while the queue was full, since `take_dropped`
+    dropped: AtomicUsize,
+}
+
+impl SampleQueue {
+    /// room for `capacity` samples and gaps, at least two
+    pub fn new(capacity: usize) -> SampleQueue {
+        SampleQueue {
+            slots: (0..capacity.max(2)).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            gap: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// slots pushed but not popped yet, a gap takes one
+    pub fn len(&self) -> usize {
+        // read first, it never passes what was written
+        let read = self.read.load(Ordering::Acquire);
+        self.written.load(Ordering::Acquire) - read
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append `input`, as much of it as fits, without ever blocking. Returns how many
+    /// samples were dropped for want of room. They are marked in their place once there is
+    /// room again, even if that is only on a later push.
+    pub fn push(&self, input: impl IntoIterator<Item = f32>) -> usize {
+        let capacity = self.capacity();
+        let mut written = self.written.load(Ordering::Relaxed);
+        let mut read = self.read.load(Ordering::Acquire);
+        let mut gap = self.gap.load(Ordering::Relaxed);
+        let mut dropped = 0;
+        for sample in input {
+            // the markers of the gap before the sample, and the last slot kept for the one
+            // of a gap after it
+            let needed = gap.div_ceil(MAX_GAP) + 1;
+            if written - read + needed >= capacity {
+                // the consumer may have made room since
+                read = self.read.load(Ordering::Acquire);
+            }
+            if written - read + needed < capacity {
+                self.mark_gap(&mut written, read, &mut gap);
+                // a NaN would be taken for a gap
+                let sample = if sample.is_nan() { 0.0 } else { sample };
+                self.slots[written % capacity].store(sample.to_bits(), Ordering::Relaxed);
+                written += 1;
+            } else {
+                gap += 1;
+                dropped += 1;
+            }
+        }
+        self.mark_gap(&mut written, read, &mut gap);
+        self.gap.store(gap, Ordering::Relaxed);
+        self.written.store(written, Ordering::Release);
+        if dropped > 0 {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
+        }
+        dropped
+    }
+
+    /// Write markers for as much of `gap` as there is room for after `written`.
+    fn mark_gap(&self, written: &mut usize, read: usize, gap: &mut usize) {
+        while *gap > 0 && *written - read < self.capacity() {
+            let len = (*gap).min(MAX_GAP);
+            self.slots[*written % self.capacity()].store(GAP | len as u32, Ordering::Relaxed);
+            *written += 1;
+            *gap -= len;
+        }
+    }
+
+    /// Hand everything pushed so far to `take`, oldest first and with silence for the gaps,
+    /// and free its room. Returns how many samples were taken.
+    pub fn pop_with(&self, take: impl FnOnce(&mut dyn Iterator<Item = f32>)) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        let read = self.read.load(Ordering::Relaxed);
+        let slots = &self.slots;
+        let mut taken = 0;
+        take(
+            &mut (read..written)
+                .flat_map(|i| {
+                    let bits = slots[i % slots.len()].load(Ordering::Relaxed);
+                    match f32::from_bits(bits) {
+                        gap if gap.is_nan() => std::iter::repeat_n(0.0, bits as usize & MAX_GAP),
+                        sample => std::iter::repeat_n(sample, 1),
+                    }
+                })
+                .inspect(|_| taken += 1),
+        );
+        self.read.store(written, Ordering::Release);
+        taken
+    }
+
+    /// samples dropped since last asked, see `push`
+    pub fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_sample_queue() {
+    let queue = SampleQueue::new(4);
+    assert_eq!(queue.push([1.0, 2.0, 3.0]), 0);
+    let mut popped = vec![];
+    assert_eq!(queue.pop_with(|samples| popped.extend(samples)), 3);
+    assert_eq!(popped, [1.0, 2.0, 3.0]);
+    assert!(queue.is_empty());
+
+    // around the end of the ring, and past what fits
+    assert_eq!(queue.push([4.0, 5.0, 6.0, 7.0, 8.0, 9.0]), 3);
+    assert_eq!(queue.len(), 4);
+    assert_eq!(queue.take_dropped(), 3);
+    assert_eq!(queue.take_dropped(), 0);
+    // no room even for a marker, the gap grows until there is
+    assert_eq!(queue.push([10.0, 11.0]), 2);
+    popped.clear();
+    assert_eq!(queue.pop_with(|samples| popped.extend(samples)), 6);
+    assert_eq!(popped, [4.0, 5.0, 6.0, 0.0, 0.0, 0.0]);
+    assert_eq!(queue.push([12.0]), 0);
+    popped.clear();
+    queue.pop_with(|samples| popped.extend(samples));
+    // every sample keeps its index in the stream
+    assert_eq!(popped, [0.0, 0.0, 12.0]);
+}
+
+#[test]
+fn test_sample_queue_threads() {
+    use std::{sync::Arc, thread};
+
+    let queue = Arc::new(SampleQueue::new(64));
+    let producer = {
+        let queue = queue.clone();
+        thread::spawn(move || {
+            for i in 0..10_000 {
+                // wait for room rather than drop, to check that nothing gets mixed up, the
+                // last slot is kept for a gap
+                while queue.len() >= queue.capacity() - 1 {
+                    thread::yield_now();
+                }
+                assert_eq!(queue.push([i as f32]), 0);
+            }
+        })
+    };
+    let mut popped = Vec::new();
+    while popped.len() < 10_000 {
+        queue.pop_with(|samples| popped.extend(samples));
+    }
+    producer.join().unwrap();
+    assert!(popped.iter().enumerate().all(|(i, x)| *x == i as f32));
+}
@@ -0,0 +1,266 @@
+//! # Stereo
+//!
+//! With two speakers and two microphones, two packets can be on the way at once. The left
+//! speaker plays the even packets of a message on our tones, the right one the odd packets
+//! on the tones of `AcousticConfig::right_channel`, each with a preamble of its own. Being
+//! apart in frequency, the streams survive being mixed on the way, so every microphone
+//! hears both, just not equally well. For each stream, a `CombiningReader` listens to
+//! whichever microphone hears its tones loudest.
+
+use std::path::Path;
+
+use tracing::{error, info};
+
+use crate::{
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    goertzel::GoertzelBank,
+    recorder::{record_channels_with_device, Recorder},
+    transmission::{Event, Receiver, SampleReader},
+    transmitter::modulate_packets,
+    wav_reader::WavSampleReader,
+    Packet,
+};
+
+/// The signals for the left and right speaker, with the packets of a message taking turns.
+pub fn modulate_stereo(config: &AcousticConfig, packets: &[Packet]) -> Result<[Vec<f64>; 2]> {
+    let right = config.right_channel()?;
+    let (even, odd): (Vec<Packet>, Vec<Packet>) = packets
+        .iter()
+        .cloned()
+        .partition(|packet| packet.order % 2 == 0);
+    Ok([
+        modulate_packets(config, &even)?,
+        modulate_packets(&right, &odd)?,
+    ])
+}
+
+/// Samples of one stream from whichever microphone hears its tones loudest, decided anew
+/// for every window the `Receiver` asks for.
+pub struct CombiningReader {
+    mics: Vec<Box<dyn SampleReader>>,
+    /// the carriers and preamble tones of the stream
+    tones: GoertzelBank,
+    /// what the mic at hand heard, kept between reads
+    heard: Vec<f64>,
+}
+
+impl CombiningReader {
+    pub fn new(config: &AcousticConfig, mics: Vec<Box<dyn SampleReader>>) -> CombiningReader {
+        let tones = [&config.received_carrier_freqs()[..], &config.preamble_freqs].concat();
+        CombiningReader {
+            mics,
+            tones: GoertzelBank::new(&tones, config.sample_rate),
+            heard: Vec::new(),
+        }
+    }
+}
+
+impl SampleReader for CombiningReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        let mut samples = Vec::new();
+        self.read_samples(start, end, &mut samples)?;
+        Ok(samples)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        buffer.clear();
+        let mut loudest = f64::NEG_INFINITY;
+        for mic in &mut self.mics {
+            mic.read_samples(start, end, &mut self.heard)?;
+            let loudness = self.tones.amplitudes(&self.heard).iter().sum::<f64>();
+            if loudness > loudest {
+                loudest = loudness;
+                std::mem::swap(buffer, &mut self.heard);
+            }
+        }
+        Ok(())
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.mics.iter_mut().try_for_each(|mic| mic.consume(until))
+    }
+}
+
+/// Receives what `modulate_stereo` sent, a `Receiver` per stream.
+pub struct StereoReceiver {
+    /// left stream first
+    receivers: [Receiver; 2],
+}
+
+impl StereoReceiver {
+    /// `mics` has every microphone once per stream, left stream first, as each `Receiver`
+    /// reads at its own pace.
+    pub fn new(
+        config: &AcousticConfig,
+        mics: [Vec<Box<dyn SampleReader>>; 2],
+    ) -> Result<StereoReceiver> {
+        let configs = [config.clone(), config.right_channel()?];
+        let [left, right] = mics;
+        let receiver = |config: &AcousticConfig, mics| {
+            Receiver::with_config(Box::new(CombiningReader::new(config, mics)), config.clone())
+        };
+        Ok(StereoReceiver {
+            receivers: [receiver(&configs[0], left), receiver(&configs[1], right)],
+        })
+    }
+
+    /// take only packets to `address`, and broadcasts
+    pub fn with_address(self, address: u8) -> StereoReceiver {
+        StereoReceiver {
+            receivers: self
+                .receivers
+                .map(|receiver| receiver.with_address(address)),
+        }
+    }
+
+    /// take packets to any address, for debugging
+    pub fn promiscuous(self, promiscuous: bool) -> StereoReceiver {
+        StereoReceiver {
+            receivers: self
+                .receivers
+                .map(|receiver| receiver.promiscuous(promiscuous)),
+        }
+    }
+
+    /// receive from the channels of a wav file, the first two if it has more
+    pub fn open_wav(path: impl AsRef<Path>, config: &AcousticConfig) -> Result<StereoReceiver> {
+        let mics = || {
+            (0..2)
+                .map(|channel| {
+                    let mic = WavSampleReader::open_channel(path.as_ref(), config, channel)?;
+                    Ok(Box::new(mic) as Box<dyn SampleReader>)
+                })
+                .collect::<Result<Vec<Box<dyn SampleReader>>>>()
+        };
+        Self::new(config, [mics()?, mics()?])
+    }
+
+    /// Receive from the first two channels of `device`, or its only one twice.
+    ///
+    /// NB: The returned `Stream` is RAII guarded, so the caller should not drop it until
+    /// recording finishes.
+    pub fn record(
+        config: &AcousticConfig,
+        device: cpal::Device,
+    ) -> Result<(StereoReceiver, cpal::Stream)> {
+        let mut recorders = [
+            [Recorder::new(), Recorder::new()],
+            [Recorder::new(), Recorder::new()],
+        ];
+        let handles = recorders
+            .each_mut()
+            .map(|stream| stream.each_mut().map(|recorder| recorder.clone_handle()));
+        let stream = record_channels_with_device(config, device, 2, move |blocks| {
+            for (mic, block) in blocks.into_iter().enumerate() {
+                for stream in &handles {
+                    if stream[mic].push(block.iter().copied()).is_err() {
+                        error!("sample buffer is poisoned, dropping samples");
+                    }
+                }
+            }
+        })?;
+        let mics = recorders.map(|stream| {
+            stream
+                .into_iter()
+                .map(|recorder| Box::new(recorder) as Box<dyn SampleReader>)
+                .collect()
+        });
+        Ok((Self::new(config, mics)?, stream))
+    }
+
+    /// Receive packets from both streams in turn until a message ends, and return its
+    /// payload. Like `Receiver::run`, drops what it cannot use.
+    pub fn run(&mut self) -> Result<Vec<u8>> {
+        let mut packets = Vec::new();
+        loop {
+            let receiver = &mut self.receivers[packets.len() % 2];
+            let event = match receiver.next_event() {
+                Err(AcousticError::AuthenticationFailed) => {
+                    info!("unauthenticated packet, dropped");
+                    continue;
+                }
+                event => event?,
+            };
+            match event {
+                Event::Data(packet) => {
+                    let last = packet.ends_message(receiver.config().packet_size);
+                    let source = packet.source;
+                    packets.push(packet);
+                    if last {
+                        for receiver in &mut self.receivers {
+                            receiver.forget_seen(source);
+                        }
+                        return Ok(Packet::unpack(&packets));
+                    }
+                }
+                event => info!("skipped {:?}", event),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transmission::MemoryReader, transmitter::modulate_message};
+
+    /// what two microphones hear, each with its share of the left and right speaker
+    fn mics(signals: &[Vec<f64>; 2], shares: [[f64; 2]; 2]) -> [Vec<Box<dyn SampleReader>>; 2] {
+        let len = signals.iter().map(Vec::len).max().unwrap() + 20000;
+        let heard = shares.map(|[left, right]| {
+            (0..len)
+                .map(|i| {
+                    let at = |signal: &Vec<f64>| {
+                        i.checked_sub(10000)
+                            .and_then(|i| signal.get(i).copied())
+                            .unwrap_or(0.0)
+                    };
+                    left * at(&signals[0]) + right * at(&signals[1])
+                })
+                .collect::<Vec<f64>>()
+        });
+        let readers = || {
+            heard
+                .iter()
+                .map(|samples| Box::new(MemoryReader(samples.clone())) as Box<dyn SampleReader>)
+                .collect()
+        };
+        [readers(), readers()]
+    }
+
+    #[test]
+    fn test_read_stereo() {
+        let config = AcousticConfig::default();
+        let data = (0..300).map(|i| i as u8).collect::<Vec<u8>>();
+        let signals = modulate_stereo(&config, &Packet::new_packets(&data)).unwrap();
+        // both packets of a pair are on the way at once
+        let mono = modulate_message(&config, &data).unwrap().len();
+        assert!(signals.iter().all(|signal| signal.len() < mono * 2 / 3));
+
+        let crosstalk = mics(&signals, [[1.0, 0.5], [0.3, 1.0]]);
+        let mut receiver = StereoReceiver::new(&config, crosstalk).unwrap();
+        assert_eq!(receiver.run().unwrap(), data);
+
+        // each microphone hears one speaker only
+        let apart = mics(&signals, [[1.0, 0.0], [0.0, 1.0]]);
+        let mut receiver = StereoReceiver::new(&config, apart).unwrap();
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_combining_reader() {
+        let config = AcousticConfig::default();
+        let data = (0..300).map(|i| i as u8).collect::<Vec<u8>>();
+        let signals = modulate_stereo(&config, &Packet::new_packets(&data)).unwrap();
+        // the first microphone hears mostly the right speaker
+        let [left, mut right] = mics(&signals, [[0.1, 1.0], [1.0, 0.1]]);
+        let start = 10000 + config.sample_number();
+        let end = start + config.sample_number();
+        let second = right[1].take_samples(start, end).unwrap();
+        let mut reader = CombiningReader::new(&config, left);
+        assert_eq!(reader.take_samples(start, end).unwrap(), second);
+        let mut reader = CombiningReader::new(&config.right_channel().unwrap(), right);
+        assert_ne!(reader.take_samples(start, end).unwrap(), second);
+    }
+}
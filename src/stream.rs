@@ -0,0 +1,690 @@
+//! # Byte stream
+//!
+//! `AcousticStream` is `Read` and `Write` over the packet link, so that code written for
+//! sockets or serial ports runs over sound unchanged. Writes are buffered until `flush`,
+//! which sends them as one message with go-back-N retransmission and returns once the peer
+//! acknowledged all of it. Reads hand out messages in order as they complete.
+//!
+//! With `with_selective_repeat`, only the packets the peer's acknowledgment says are missing
+//! go out again, see `arq`. Every stream keeps packets that arrive after a gap and
+//! acknowledges them, whichever way its peer sends.
+//!
+//! When no acknowledgment came and a garbled preamble was heard meanwhile, someone else
+//! sent at the same time. Resending right away would collide again, so the window goes out
+//! again after a random wait instead, see `backoff`.
+//!
+//! An idle stream with `with_keepalive` plays a keepalive now and then while it reads, so
+//! that a receiver with `Receiver::with_liveness` on the other end can tell the link is
+//! still there. When it is not, reads and flushes fail with `AcousticError::LinkDown`.
+//!
+//! A stream with `with_receive_buffer` tells its peer in every acknowledgment how many more
+//! packets fit in what it has not read yet, see `SelectiveAck::window`, so that a slow reader
+//! throttles the writer on the other end. Once a read makes room again it says so right
+//! away. Acknowledgments that say there is no room count as signs of life, a flush waits
+//! for the reader however long it takes.
+//!
+//! How often a flush resends, how long it waits for an acknowledgment and how it backs off
+//! is a `RetransmitPolicy`, see `with_retransmit_policy`. Once the retries are used up the
+//! flush fails with `AcousticError::DeliveryFailed`, saying how much got through.
+//!
+//! With `with_progress`, every flush reports how much of it the peer acknowledged, see
+//! `progress`.
+//!
+//! Packet orders keep counting from one message to the next. They travel as `u16`, so a
+//! stream carries at most 65536 packets.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    arq::{ArqSender, GoBackNSender, SelectiveAck, SelectiveRepeatReceiver, SelectiveRepeatSender},
+    backoff::{Backoff, BackoffStrategy},
+    error::{AcousticError, Result},
+    keepalive::{keepalive_packet, Keepalive},
+    progress::{OnProgress, Progress},
+    transmission::{Event, Receiver},
+    transmitter::Transmitter,
+    Packet,
+};
+
+/// how a stream reaches its peer
+pub trait Link {
+    fn send(&mut self, packets: &[Packet]) -> Result<()>;
+
+    /// The next packet from the peer, `AcousticError::Timeout` if none came in time.
+    fn recv(&mut self) -> Result<Event>;
+
+    /// How long `recv` waits for a packet to start. Links that time out on their own, say
+    /// through a `Recorder` timeout, may ignore it.
+    fn set_timeout(&mut self, _timeout: Duration) {}
+}
+
+/// a `Link` through the speaker and the microphone
+pub struct AcousticLink {
+    transmitter: Transmitter,
+    receiver: Receiver,
+    address: u8,
+    peer: u8,
+    /// how long `recv` waits, if not only as long as the receiver's samples last
+    timeout: Option<Duration>,
+}
+
+impl AcousticLink {
+    /// Talk from `address` to `peer`. The receiver should read from a `Recorder` with a
+    /// timeout, it is what triggers retransmissions.
+    pub fn new(
+        transmitter: Transmitter,
+        receiver: Receiver,
+        address: u8,
+        peer: u8,
+    ) -> AcousticLink {
+        AcousticLink {
+            transmitter,
+            // the microphone hears our own packets too, they are addressed to the peer
+            receiver: receiver.with_address(address),
+            address,
+            peer,
+            timeout: None,
+        }
+    }
+
+    /// whether the peer was heard lately, see `Receiver::with_liveness`
+    pub fn link_alive(&self) -> bool {
+        self.receiver.link_alive()
+    }
+}
+
+impl Link for AcousticLink {
+    fn send(&mut self, packets: &[Packet]) -> Result<()> {
+        let packets = packets
+            .iter()
+            .map(|packet| packet.clone().addressed(self.address, self.peer))
+            .collect::<Vec<Packet>>();
+        self.transmitter.send_packets(&packets)
+    }
+
+    fn recv(&mut self) -> Result<Event> {
+        match self.timeout {
+            Some(timeout) => self.receiver.next_event_within(timeout),
+            None => self.receiver.next_event(),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+}
+
+/// Backoff slot after a collision, a few symbols of the default config.
+pub const COLLISION_SLOT: Duration = Duration::from_millis(400);
+
+/// how a flush resends what the peer did not acknowledge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetransmitPolicy {
+    /// timeouts in a row before a flush fails with `AcousticError::DeliveryFailed`
+    pub max_retries: usize,
+    /// how long to wait for an acknowledgment, if not as long as the link does anyway
+    pub timeout: Option<Duration>,
+    /// how long to wait before resending after a collision
+    pub backoff: BackoffStrategy,
+}
+
+impl Default for RetransmitPolicy {
+    fn default() -> Self {
+        RetransmitPolicy {
+            max_retries: 8,
+            timeout: None,
+            backoff: BackoffStrategy::Exponential(COLLISION_SLOT),
+        }
+    }
+}
+
+pub struct AcousticStream<L: Link> {
+    link: L,
+    window: usize,
+    policy: RetransmitPolicy,
+    /// waits of `policy.backoff`
+    backoff: Backoff,
+    /// when to play a keepalive while idle, if at all
+    keepalive: Option<Keepalive>,
+    /// order of the first packet of the next message we send
+    send_order: usize,
+    /// resend only the packets missing rather than the whole window
+    selective_repeat: bool,
+    incoming: SelectiveRepeatReceiver,
+    /// bytes read but not taken yet we advertise room for, if we advertise
+    receive_buffer: Option<usize>,
+    /// the last acknowledgment we sent said there was no room
+    window_closed: bool,
+    read_buffer: VecDeque<u8>,
+    write_buffer: Vec<u8>,
+    /// told whenever the peer acknowledged more of a flush, if anyone is
+    progress: Option<OnProgress>,
+    /// payload bytes of every packet of a message but the last, both ends agree on it
+    packet_size: usize,
+}
+
+impl<L: Link> AcousticStream<L> {
+    pub fn new(link: L) -> AcousticStream<L> {
+        AcousticStream {
+            link,
+            window: 4,
+            policy: RetransmitPolicy::default(),
+            backoff: Backoff::new(COLLISION_SLOT),
+            keepalive: None,
+            send_order: 0,
+            selective_repeat: false,
+            incoming: SelectiveRepeatReceiver::new(),
+            receive_buffer: None,
+            window_closed: false,
+            read_buffer: VecDeque::new(),
+            write_buffer: Vec::new(),
+            progress: None,
+            packet_size: Packet::MAX_PACKET_SIZE,
+        }
+    }
+
+    /// Split messages to `packet_size` bytes a packet, and expect the peer to, see
+    /// `AcousticConfig::packet_size`.
+    pub fn with_packet_size(mut self, packet_size: usize) -> AcousticStream<L> {
+        self.packet_size = packet_size.clamp(1, Packet::LARGEST_PACKET_SIZE);
+        self.incoming = SelectiveRepeatReceiver::starting_at(self.incoming.end())
+            .with_packet_size(self.packet_size);
+        self
+    }
+
+    /// packets in flight before waiting for an acknowledgment
+    pub fn with_window(mut self, window: usize) -> AcousticStream<L> {
+        self.window = window;
+        self
+    }
+
+    /// Resend, wait and back off as `policy` says, its timeout applies to the link.
+    pub fn with_retransmit_policy(mut self, policy: RetransmitPolicy) -> AcousticStream<L> {
+        if let Some(timeout) = policy.timeout {
+            self.link.set_timeout(timeout);
+        }
+        self.backoff = Backoff::with_strategy(policy.backoff);
+        self.policy = policy;
+        self
+    }
+
+    pub fn retransmit_policy(&self) -> RetransmitPolicy {
+        self.policy
+    }
+
+    pub fn with_max_retries(mut self, max_retries: usize) -> AcousticStream<L> {
+        self.policy.max_retries = max_retries;
+        self
+    }
+
+    /// how to wait before resending after a collision, slots of `COLLISION_SLOT` by default
+    pub fn with_backoff(mut self, backoff: Backoff) -> AcousticStream<L> {
+        self.policy.backoff = backoff.strategy();
+        self.backoff = backoff;
+        self
+    }
+
+    /// resend only what the peer misses, see `SelectiveRepeatSender`
+    pub fn with_selective_repeat(mut self, selective_repeat: bool) -> AcousticStream<L> {
+        self.selective_repeat = selective_repeat;
+        self
+    }
+
+    /// Advertise room for as many packets as fit in `bytes` of data not read yet, see
+    /// `SelectiveAck::window`. Less than a packet lets one through per timeout of the peer.
+    pub fn with_receive_buffer(mut self, bytes: usize) -> AcousticStream<L> {
+        self.receive_buffer = Some(bytes);
+        self
+    }
+
+    /// play a keepalive while reading whenever nothing went out for `interval`
+    pub fn with_keepalive(mut self, interval: Duration) -> AcousticStream<L> {
+        self.keepalive = Some(Keepalive::new(interval));
+        self
+    }
+
+    /// tell `on_progress` how much of every flush the peer acknowledged so far
+    pub fn with_progress(
+        mut self,
+        on_progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> AcousticStream<L> {
+        self.progress = Some(Box::new(on_progress));
+        self
+    }
+
+    fn send(&mut self, packets: &[Packet]) -> Result<()> {
+        self.link.send(packets)?;
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            keepalive.sent();
+        }
+        Ok(())
+    }
+
+    /// Play a keepalive if one is due.
+    fn keep_alive(&mut self) -> Result<()> {
+        if self.keepalive.as_ref().is_some_and(Keepalive::due) {
+            info!("idle, sending a keepalive");
+            self.send(&[keepalive_packet()])?;
+        }
+        Ok(())
+    }
+
+    fn send_message(&mut self, data: &[u8]) -> Result<()> {
+        let packets = Packet::new_packets_sized(data, self.packet_size);
+        let mut sender: Box<dyn ArqSender> = match self.selective_repeat {
+            true => Box::new(SelectiveRepeatSender::from_packets(
+                packets,
+                self.window,
+                self.send_order,
+            )),
+            false => Box::new(GoBackNSender::from_packets(
+                packets,
+                self.window,
+                self.send_order,
+            )),
+        };
+        let mut retries = 0;
+        // a garbled preamble was heard since the last acknowledgment
+        let mut collided = false;
+        let started = Instant::now();
+        let mut acknowledged = 0;
+        while !sender.is_done() {
+            let packets = sender.poll_send();
+            if !packets.is_empty() {
+                self.send(&packets)?;
+            }
+            let event = match self.link.recv() {
+                // a plain acknowledgment is one without a bitmap
+                Ok(Event::Ack(next_expected)) => {
+                    Ok(Event::SelectiveAck(SelectiveAck::cumulative(next_expected)))
+                }
+                event => event,
+            };
+            match event {
+                Ok(Event::SelectiveAck(ack)) => {
+                    let in_flight = sender.in_flight();
+                    sender.on_ack(&ack);
+                    // a busy peer is no lost one
+                    if sender.in_flight() < in_flight || ack.window == Some(0) {
+                        retries = 0;
+                        collided = false;
+                        self.backoff.reset();
+                    }
+                    if let Some(on_progress) = self.progress.as_mut() {
+                        if sender.acknowledged() > acknowledged {
+                            acknowledged = sender.acknowledged();
+                            on_progress(&Progress {
+                                // every packet but the last is full
+                                bytes: (acknowledged * self.packet_size).min(data.len()),
+                                total_bytes: Some(data.len()),
+                                packets: acknowledged,
+                                total_packets: Some(sender.packet_count()),
+                                elapsed: started.elapsed(),
+                            });
+                        }
+                    }
+                }
+                Ok(Event::GarbledPreamble) => collided = true,
+                Ok(Event::LinkDown) => return Err(AcousticError::LinkDown),
+                // the peer may be sending too
+                Ok(Event::Data(packet) | Event::Duplicate(packet)) => self.on_data(packet)?,
+                Ok(event) => info!("skipped {:?}", event),
+                Err(AcousticError::Timeout) if retries < self.policy.max_retries => {
+                    retries += 1;
+                    info!("no acknowledgment, retry {}", retries);
+                    if collided {
+                        let delay = self.backoff.next_delay();
+                        info!("collision, backing off for {:?}", delay);
+                        sleep(delay);
+                        collided = false;
+                    }
+                    sender.timeout();
+                }
+                Err(AcousticError::Timeout) => {
+                    info!("no acknowledgment after {} retries, giving up", retries);
+                    return Err(AcousticError::DeliveryFailed {
+                        acknowledged: sender.acknowledged(),
+                        packets: sender.packet_count(),
+                    });
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.send_order = sender.end();
+        Ok(())
+    }
+
+    fn on_data(&mut self, packet: Packet) -> Result<()> {
+        let ack = self.incoming.receive(packet);
+        self.acknowledge(ack)?;
+        if let Some(message) = self.incoming.message() {
+            self.read_buffer.extend(message);
+            self.incoming = SelectiveRepeatReceiver::starting_at(self.incoming.end())
+                .with_packet_size(self.packet_size);
+        }
+        Ok(())
+    }
+
+    /// packets we have room for, if we advertise it
+    fn window(&self) -> Option<usize> {
+        let unread = self.read_buffer.len() + self.incoming.buffered();
+        self.receive_buffer
+            .map(|bytes| bytes.saturating_sub(unread) / self.packet_size)
+    }
+
+    /// Send `ack`, with the room we have if we advertise it.
+    fn acknowledge(&mut self, ack: SelectiveAck) -> Result<()> {
+        let ack = match self.window() {
+            Some(window) => {
+                self.window_closed = window == 0;
+                ack.with_window(window)
+            }
+            None => ack,
+        };
+        self.send(&[ack.to_packet()])
+    }
+}
+
+impl<L: Link> Read for AcousticStream<L> {
+    /// Block until a message arrived, timeouts only mean the peer is quiet. Fails with
+    /// `AcousticError::LinkDown` when the link is, the next read waits for the peer again.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_buffer.is_empty() {
+            match self.link.recv() {
+                // acknowledged again, the sender missed our acknowledgment
+                Ok(Event::Data(packet) | Event::Duplicate(packet)) => self.on_data(packet)?,
+                Ok(Event::LinkDown) => return Err(AcousticError::LinkDown.into()),
+                Ok(event) => info!("skipped {:?}", event),
+                Err(AcousticError::Timeout) => self.keep_alive()?,
+                Err(AcousticError::EndOfStream) => return Ok(0),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let n = buf.len().min(self.read_buffer.len());
+        for (b, x) in buf.iter_mut().zip(self.read_buffer.drain(..n)) {
+            *b = x;
+        }
+        // the peer waits to hear there is room again
+        if self.window_closed && self.window().is_some_and(|window| window > 0) {
+            self.acknowledge(self.incoming.ack())?;
+        }
+        Ok(n)
+    }
+}
+
+impl<L: Link> Write for AcousticStream<L> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.write_buffer);
+        Ok(self.send_message(&data)?)
+    }
+}
+
+#[test]
+fn test_stream_over_lossy_link() {
+    use std::{
+        sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        thread,
+        time::Duration,
+    };
+
+    /// drops every `drop_every`th packet it sends
+    struct ChannelLink {
+        tx: Sender<Packet>,
+        rx: Receiver<Packet>,
+        sent: usize,
+        drop_every: usize,
+    }
+
+    impl Link for ChannelLink {
+        fn send(&mut self, packets: &[Packet]) -> Result<()> {
+            for packet in packets {
+                self.sent += 1;
+                if !self.sent.is_multiple_of(self.drop_every) {
+                    let _ = self.tx.send(packet.clone());
+                }
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Event> {
+            match self.rx.recv_timeout(Duration::from_millis(20)) {
+                Ok(packet) => Ok(Event::from(packet)),
+                Err(RecvTimeoutError::Timeout) => Err(AcousticError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => Err(AcousticError::EndOfStream),
+            }
+        }
+    }
+
+    // either way of resending
+    for selective_repeat in [false, true] {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+        let a = ChannelLink {
+            tx: a_tx,
+            rx: a_rx,
+            sent: 0,
+            drop_every: 3,
+        };
+        let b = ChannelLink {
+            tx: b_tx,
+            rx: b_rx,
+            sent: 0,
+            drop_every: 4,
+        };
+
+        let data = (0..2000).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let sent = data.clone();
+        let (progress_tx, progress) = channel();
+        let writer = thread::spawn(move || {
+            let mut stream = AcousticStream::new(a)
+                .with_max_retries(100)
+                .with_selective_repeat(selective_repeat)
+                .with_progress(crate::progress::to_channel(progress_tx));
+            stream.write_all(&sent[..500]).unwrap();
+            stream.flush().unwrap();
+            stream.write_all(&sent[500..]).unwrap();
+            stream.flush().unwrap();
+        });
+        let mut stream = AcousticStream::new(b);
+        let mut received = vec![0; data.len()];
+        stream.read_exact(&mut received).unwrap();
+        assert_eq!(received, data);
+        // keep acknowledging until the writer is done and hangs up
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        writer.join().unwrap();
+        // each flush reported up to all of it
+        let done = progress
+            .try_iter()
+            .filter(|progress| progress.fraction() == Some(1.0))
+            .map(|progress| (progress.bytes, progress.total_packets))
+            .collect::<Vec<_>>();
+        assert_eq!(done, [(500, Some(4)), (1500, Some(12))]);
+    }
+}
+
+#[test]
+fn test_stream_backs_off_after_collision() {
+    use std::time::Instant;
+
+    /// answers from a script, then acknowledges everything
+    struct ScriptedLink {
+        script: VecDeque<Result<Event>>,
+        sent: Vec<Instant>,
+    }
+
+    impl Link for ScriptedLink {
+        fn send(&mut self, _packets: &[Packet]) -> Result<()> {
+            self.sent.push(Instant::now());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Event> {
+            self.script.pop_front().unwrap_or(Ok(Event::Ack(1)))
+        }
+    }
+
+    let slot = Duration::from_millis(50);
+    let delay = Backoff::new(slot).with_seed(3).next_delay();
+    assert!(delay > Duration::from_millis(5));
+    let link = |script| ScriptedLink {
+        script: VecDeque::from(script),
+        sent: Vec::new(),
+    };
+
+    // no collision, resent right away
+    let mut stream = AcousticStream::new(link(vec![Err(AcousticError::Timeout)]))
+        .with_backoff(Backoff::new(slot).with_seed(3));
+    stream.send_message(b"hello").unwrap();
+    let sent = &stream.link.sent;
+    assert_eq!(sent.len(), 2);
+    assert!(sent[1] - sent[0] < delay);
+
+    let script = vec![Ok(Event::GarbledPreamble), Err(AcousticError::Timeout)];
+    let mut stream =
+        AcousticStream::new(link(script)).with_backoff(Backoff::new(slot).with_seed(3));
+    stream.send_message(b"hello").unwrap();
+    let sent = &stream.link.sent;
+    assert_eq!(sent.len(), 2);
+    assert!(sent[1] - sent[0] >= delay);
+
+    // the peer is gone, the first retry gives up
+    let policy = RetransmitPolicy {
+        max_retries: 1,
+        timeout: Some(Duration::from_millis(10)),
+        backoff: BackoffStrategy::None,
+    };
+    let script = vec![Err(AcousticError::Timeout), Err(AcousticError::Timeout)];
+    let mut stream = AcousticStream::new(link(script)).with_retransmit_policy(policy);
+    assert_eq!(stream.retransmit_policy(), policy);
+    assert!(matches!(
+        stream.send_message(b"hello"),
+        Err(AcousticError::DeliveryFailed {
+            acknowledged: 0,
+            packets: 1
+        })
+    ));
+    assert_eq!(stream.link.sent.len(), 2);
+}
+
+#[test]
+fn test_stream_flow_control() {
+    use std::{
+        sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        thread,
+    };
+
+    /// remembers the windows it advertised
+    struct WindowLink {
+        tx: Sender<Packet>,
+        rx: Receiver<Packet>,
+        windows: Vec<usize>,
+    }
+
+    impl Link for WindowLink {
+        fn send(&mut self, packets: &[Packet]) -> Result<()> {
+            for packet in packets {
+                if let Some(window) = SelectiveAck::from_packet(packet).and_then(|ack| ack.window) {
+                    self.windows.push(window);
+                }
+                let _ = self.tx.send(packet.clone());
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Event> {
+            match self.rx.recv_timeout(Duration::from_millis(20)) {
+                Ok(packet) => Ok(Event::from(packet)),
+                Err(RecvTimeoutError::Timeout) => Err(AcousticError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => Err(AcousticError::EndOfStream),
+            }
+        }
+    }
+
+    let (a_tx, b_rx) = channel();
+    let (b_tx, a_rx) = channel();
+    let link = |tx, rx| WindowLink {
+        tx,
+        rx,
+        windows: Vec::new(),
+    };
+    let data = (0..1000).map(|i| (i * 3) as u8).collect::<Vec<u8>>();
+    let sent = data.clone();
+    let writer = thread::spawn(move || {
+        let mut stream = AcousticStream::new(link(a_tx, a_rx))
+            .with_window(8)
+            .with_max_retries(100);
+        stream.write_all(&sent[..300]).unwrap();
+        stream.flush().unwrap();
+        stream.write_all(&sent[300..]).unwrap();
+        stream.flush().unwrap();
+    });
+    // room for two packets, read a little at a time
+    let mut stream = AcousticStream::new(link(b_tx, b_rx)).with_receive_buffer(256);
+    let mut received = vec![0; data.len()];
+    for chunk in received.chunks_mut(100) {
+        stream.read_exact(chunk).unwrap();
+        sleep(Duration::from_millis(10));
+    }
+    assert_eq!(received, data);
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    writer.join().unwrap();
+    let windows = &stream.link.windows;
+    assert!(windows.iter().all(|window| *window <= 2), "{windows:?}");
+    // it filled up, and said so once there was room again
+    let closed = windows.iter().position(|window| *window == 0).unwrap();
+    assert!(windows[closed..].iter().any(|window| *window > 0));
+}
+
+#[test]
+fn test_stream_keepalive() {
+    use crate::keepalive::is_keepalive;
+
+    /// quiet for a while, then gone
+    struct QuietLink {
+        sent: Vec<Packet>,
+        timeouts: usize,
+    }
+
+    impl Link for QuietLink {
+        fn send(&mut self, packets: &[Packet]) -> Result<()> {
+            self.sent.extend_from_slice(packets);
+            Ok(())
+        }
+
+        fn recv(&mut self) -> Result<Event> {
+            if self.timeouts == 0 {
+                return Ok(Event::LinkDown);
+            }
+            self.timeouts -= 1;
+            sleep(Duration::from_millis(10));
+            Err(AcousticError::Timeout)
+        }
+    }
+
+    let link = QuietLink {
+        sent: Vec::new(),
+        timeouts: 10,
+    };
+    let mut stream = AcousticStream::new(link).with_keepalive(Duration::from_millis(25));
+    let err = stream.read(&mut [0; 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    // every few timeouts over the 100 ms
+    let sent = &stream.link.sent;
+    assert!((2..=4).contains(&sent.len()), "{}", sent.len());
+    assert!(sent.iter().all(is_keepalive));
+}
@@ -0,0 +1,265 @@
+//! # Slotted mode
+//!
+//! Carrier sense lets devices take turns, but nothing stops a few of them from hearing a
+//! free channel at once. With many senders on one channel, a classroom or a room full of
+//! sensors, a coordinator hands out time instead: its `PacketKind::Beacon` carries a
+//! `Schedule`, a slot length and who owns each slot of a frame, and frames follow each
+//! other from the end of the beacon on. A `SlottedTransmitter` sends only in the slots of
+//! its address, keeping clear of their edges by `GUARD_SYMBOLS`, since every device heard the
+//! beacon end a little differently.
+//!
+//! Clocks drift apart, the coordinator should send the beacon again every few frames, in a
+//! slot of its own.
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    error::{AcousticError, Result},
+    recorder::SharedBuffer,
+    transmitter::{modulate_packets, Transmitter},
+    Packet, PacketKind,
+};
+
+/// first byte of a beacon with a schedule, see `PacketKind::Beacon`
+pub const SCHEDULE: u8 = 0;
+
+/// symbols kept clear at both ends of every slot
+pub const GUARD_SYMBOLS: usize = 2;
+
+/// Who sends when, as a beacon announces it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// how long every slot is, in whole milliseconds on the air
+    pub slot: Duration,
+    /// the address sending in each slot, the frame starts over after the last one
+    pub owners: Vec<u8>,
+}
+
+impl Schedule {
+    pub fn new(slot: Duration, owners: &[u8]) -> Schedule {
+        Schedule {
+            slot,
+            owners: owners.to_vec(),
+        }
+    }
+
+    /// how long until the slots start over
+    pub fn frame(&self) -> Duration {
+        self.slot * self.owners.len() as u32
+    }
+
+    /// A beacon: `SCHEDULE`, the slot length in milliseconds as a little endian `u16`, then
+    /// the owner of every slot.
+    pub fn to_packet(&self) -> Packet {
+        let mut data = vec![SCHEDULE];
+        data.extend_from_slice(&(self.slot.as_millis() as u16).to_le_bytes());
+        data.extend_from_slice(&self.owners);
+        Packet::new(PacketKind::Beacon, 0, &data)
+    }
+
+    pub fn from_packet(packet: &Packet) -> Result<Schedule> {
+        match (packet.kind, packet.data.as_slice()) {
+            (PacketKind::Beacon, [SCHEDULE, low, high, owners @ ..]) if !owners.is_empty() => {
+                let slot = u16::from_le_bytes([*low, *high]);
+                Ok(Schedule::new(Duration::from_millis(slot as u64), owners))
+            }
+            _ => Err(AcousticError::MalformedPacket(format!(
+                "bad beacon {:?}",
+                packet.data
+            ))),
+        }
+    }
+
+    /// The earliest time since the first frame started, not before `elapsed`, at which
+    /// `address` can send for `len`, `guard` clear of both ends of its slot.
+    pub fn next_window(
+        &self,
+        address: u8,
+        elapsed: Duration,
+        len: Duration,
+        guard: Duration,
+    ) -> Result<Duration> {
+        if len + guard * 2 > self.slot {
+            return Err(AcousticError::SlotTooShort(len));
+        }
+        let frame = self.frame();
+        let first = (elapsed.as_nanos() / frame.as_nanos()) as u32;
+        // a slot later in this frame, or one in the next
+        (first..first + 2)
+            .flat_map(|frame_number| {
+                self.owners
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, owner)| **owner == address)
+                    .map(move |(i, _)| frame * frame_number + self.slot * i as u32)
+            })
+            .map(|start| ((start + guard).max(elapsed), start + self.slot))
+            .find(|&(at, end)| at + len + guard <= end)
+            .map(|(at, _)| at)
+            .ok_or(AcousticError::NotScheduled)
+    }
+}
+
+/// When the sample at index `sample` was recorded into `recorded`, up to the latency of
+/// the input.
+pub fn heard_at(recorded: &SharedBuffer, sample: usize, sample_rate: f64) -> Result<Instant> {
+    let ago = recorded.end()?.saturating_sub(sample) as f64 / sample_rate;
+    Ok(Instant::now() - Duration::from_secs_f64(ago))
+}
+
+/// A `Transmitter` that keeps to the slots of its address.
+pub struct SlottedTransmitter {
+    transmitter: Transmitter,
+    address: u8,
+    /// the schedule in force and when its first frame started
+    schedule: Option<(Schedule, Instant)>,
+}
+
+impl SlottedTransmitter {
+    /// Send from `address`, once a schedule came, see `follow` and `announce`.
+    pub fn new(transmitter: Transmitter, address: u8) -> SlottedTransmitter {
+        SlottedTransmitter {
+            transmitter: transmitter.with_address(address),
+            address,
+            schedule: None,
+        }
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref().map(|(schedule, _)| schedule)
+    }
+
+    /// Keep to the schedule of `beacon`, which ended at `ended`, see `heard_at`.
+    pub fn follow(&mut self, beacon: &Packet, ended: Instant) -> Result<()> {
+        let schedule = Schedule::from_packet(beacon)?;
+        info!("following {:?} from {}", schedule, beacon.source);
+        self.schedule = Some((schedule, ended));
+        Ok(())
+    }
+
+    /// As the coordinator, play a beacon with `schedule` to every device, and keep to it
+    /// ourselves.
+    pub fn announce(&mut self, schedule: Schedule) -> Result<()> {
+        let config = self.transmitter.config();
+        let beacon = schedule
+            .to_packet()
+            .addressed(self.address, Packet::BROADCAST);
+        let signal = modulate_packets(config, &[beacon])?;
+        let ends =
+            Instant::now() + Duration::from_secs_f64(signal.len() as f64 / config.sample_rate);
+        self.transmitter.play(&signal)?;
+        self.schedule = Some((schedule, ends));
+        Ok(())
+    }
+
+    /// Send `data` to `destination` in our slots, see `send_packets`.
+    pub fn send_to(&mut self, destination: u8, data: &[u8]) -> Result<()> {
+        let packets = Packet::new_packets_sized(data, self.transmitter.config().packet_size)
+            .into_iter()
+            .map(|packet| packet.addressed(self.address, destination))
+            .collect::<Vec<Packet>>();
+        self.send_packets(&packets)
+    }
+
+    /// Play packets of any kind, as many as fit in each of our slots, blocking until the
+    /// last is out. `AcousticError::NotScheduled` without a slot to send in.
+    pub fn send_packets(&mut self, packets: &[Packet]) -> Result<()> {
+        let (schedule, first_frame) = self.schedule.clone().ok_or(AcousticError::NotScheduled)?;
+        let config = self.transmitter.config().clone();
+        let duration =
+            |signal: &[f64]| Duration::from_secs_f64(signal.len() as f64 / config.sample_rate);
+        let guard = Duration::from_secs_f64(GUARD_SYMBOLS as f64 * config.symbol_time);
+        let signals = packets
+            .iter()
+            .map(|packet| modulate_packets(&config, std::slice::from_ref(packet)).unwrap())
+            .collect::<Vec<Vec<f64>>>();
+        let mut pending = &signals[..];
+        while !pending.is_empty() {
+            let mut burst = pending[0].clone();
+            let mut taken = 1;
+            for signal in &pending[1..] {
+                if duration(&burst) + duration(signal) + guard * 2 > schedule.slot {
+                    break;
+                }
+                burst.extend_from_slice(signal);
+                taken += 1;
+            }
+            let at = schedule.next_window(
+                self.address,
+                first_frame.elapsed(),
+                duration(&burst),
+                guard,
+            )?;
+            sleep(at.saturating_sub(first_frame.elapsed()));
+            info!("sending {} packets in our slot", taken);
+            self.transmitter.play(&burst)?;
+            pending = &pending[taken..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::AcousticConfig,
+        transmission::{Event, MemoryReader, Receiver},
+    };
+
+    #[test]
+    fn test_beacon() {
+        let config = AcousticConfig::default();
+        let schedule = Schedule::new(Duration::from_millis(1500), &[0, 1, 2, 1]);
+        let beacon = schedule.to_packet().addressed(0, Packet::BROADCAST);
+        let signal = modulate_packets(&config, &[beacon]).unwrap();
+        let heard = [vec![0.0; 10000], signal.clone(), vec![0.0; 20000]].concat();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(heard)), config.clone());
+        match receiver.next_event().unwrap() {
+            Event::Beacon(packet) => assert_eq!(Schedule::from_packet(&packet).unwrap(), schedule),
+            event => panic!("{:?}", event),
+        }
+        // the frames start where the beacon ends, to a symbol
+        let end = 10000 + signal.len();
+        assert!(receiver.position().abs_diff(end) <= config.sample_number());
+
+        assert!(Schedule::from_packet(&Packet::ack(1)).is_err());
+        assert!(
+            Schedule::from_packet(&Packet::new(PacketKind::Beacon, 0, &[SCHEDULE, 1, 2])).is_err()
+        );
+    }
+
+    #[test]
+    fn test_next_window() {
+        let ms = Duration::from_millis;
+        let schedule = Schedule::new(ms(1000), &[0, 1, 2, 1]);
+        assert_eq!(schedule.frame(), ms(4000));
+        let window =
+            |address, elapsed, len| schedule.next_window(address, ms(elapsed), ms(len), ms(100));
+        // the next slot of ours, past the guard
+        assert_eq!(window(1, 0, 500).unwrap(), ms(1100));
+        assert_eq!(window(2, 0, 500).unwrap(), ms(2100));
+        // right away, still in our slot
+        assert_eq!(window(1, 1200, 500).unwrap(), ms(1200));
+        // too late in our slot, on to the next
+        assert_eq!(window(1, 1500, 500).unwrap(), ms(3100));
+        // in the next frame
+        assert_eq!(window(0, 500, 500).unwrap(), ms(4100));
+        assert_eq!(window(2, 10000, 500).unwrap(), ms(10100));
+        // our slot is over, the one after is not ours
+        assert_eq!(window(0, 2500, 500).unwrap(), ms(4100));
+        assert!(matches!(
+            window(3, 0, 500),
+            Err(AcousticError::NotScheduled)
+        ));
+        assert!(matches!(
+            window(1, 0, 900),
+            Err(AcousticError::SlotTooShort(_))
+        ));
+    }
+}
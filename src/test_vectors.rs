@@ -0,0 +1,101 @@
+//! # Test vectors
+//!
+//! Implementations in other languages check that they speak the same protocol against
+//! signals this crate made. `test_vectors` modulates one message with every modulation, the
+//! default configs otherwise, and `write_test_vectors` writes each to a wav file next to
+//! the bytes it decodes to. Nothing in modulating plaintext is random, so the signals are
+//! the same every time, sample for sample; encrypted payloads get a random nonce and are
+//! left out.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{AcousticConfig, Modulation},
+    error::Result,
+    output_wav,
+    transmitter::modulate_message,
+};
+
+/// the message of every vector, some text and the bytes at the edges of their range
+pub const TEST_MESSAGE: &[u8] = b"acousticdi\x00\x01\x7f\x80\xfe\xff";
+
+/// silence around the signal, in symbols, for the receiver to settle on the noise floor
+const PADDING_SYMBOLS: usize = 4;
+
+/// A signal and what it decodes to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestVector {
+    /// the modulation in lower case, like `qam16`
+    pub name: String,
+    pub config: AcousticConfig,
+    pub message: Vec<u8>,
+    /// the modulated message between silence, before it is scaled to the output level
+    pub signal: Vec<f64>,
+}
+
+/// One vector for every modulation, in the order of `Modulation::ALL`.
+pub fn test_vectors() -> Vec<TestVector> {
+    Modulation::ALL
+        .iter()
+        .map(|modulation| {
+            let config = AcousticConfig::builder()
+                .modulation(*modulation)
+                .build()
+                .unwrap();
+            let silence = vec![0.0; PADDING_SYMBOLS * config.sample_number()];
+            let signal = [
+                &silence[..],
+                &modulate_message(&config, TEST_MESSAGE).unwrap(),
+                &silence,
+            ]
+            .concat();
+            TestVector {
+                name: format!("{:?}", modulation).to_lowercase(),
+                config,
+                message: TEST_MESSAGE.to_vec(),
+                signal,
+            }
+        })
+        .collect()
+}
+
+/// Write every vector into `dir` as `<name>.wav`, mono 32 bit floats at the output level,
+/// and `<name>.bin`, the message. Returns the paths written.
+pub fn write_test_vectors(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for vector in test_vectors() {
+        let wav = dir.join(format!("{}.wav", vector.name));
+        output_wav(&vector.config, &vector.signal, &wav.to_string_lossy())?;
+        let bin = dir.join(format!("{}.bin", vector.name));
+        fs::write(&bin, &vector.message)?;
+        written.extend([wav, bin]);
+    }
+    Ok(written)
+}
+
+#[test]
+fn test_test_vectors() {
+    use crate::{transmission::Receiver, wav_reader::WavSampleReader};
+
+    let vectors = test_vectors();
+    assert_eq!(vectors.len(), Modulation::ALL.len());
+    assert_eq!(vectors, test_vectors());
+    assert_eq!(vectors[4].name, "qam16");
+
+    let written = write_test_vectors("test_vectors").unwrap();
+    assert_eq!(written.len(), 2 * vectors.len());
+    for vector in &vectors {
+        let wav = Path::new("test_vectors").join(format!("{}.wav", vector.name));
+        let reader = WavSampleReader::open(&wav, &vector.config).unwrap();
+        let mut receiver = Receiver::with_config(Box::new(reader), vector.config.clone());
+        assert_eq!(receiver.run().unwrap(), vector.message, "{}", vector.name);
+        let bin = Path::new("test_vectors").join(format!("{}.bin", vector.name));
+        assert_eq!(fs::read(bin).unwrap(), TEST_MESSAGE);
+    }
+    fs::remove_dir_all("test_vectors").unwrap();
+}
@@ -0,0 +1,267 @@
+//! # Full duplex
+//!
+//! `Transceiver` keeps the microphone recording while it plays, so that acknowledgments can
+//! come in while packets are still going out. With both directions on the air at once, each
+//! has a band of its own: one end sends on the tones of the config and listens on those of
+//! `AcousticConfig::right_channel`, the other end the other way round, see `Band`. The
+//! microphone hears our own packets as well. The band-pass of the receiver keeps most of
+//! them out, and what we played is taken out of the recording before that, see `echo`.
+//!
+//! It is a `Link`, so an `AcousticStream` over it acknowledges without stopping to listen.
+
+use std::{
+    sync::{
+        mpsc::{channel, Receiver as DoneReceiver},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::{
+    config::AcousticConfig,
+    device::{input_device, output_device},
+    echo::{EchoSuppressor, PlayedSignals},
+    error::{AcousticError, Result},
+    recorder::{run_record_with_device, Recorder, SharedBuffer},
+    stream::Link,
+    transmission::{Event, Receiver, SampleReader},
+    transmitter::{modulate_packets, Transmitter},
+    Packet,
+};
+
+/// which tones a transceiver sends on, its peer has to use the other band
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Band {
+    /// send on the tones of the config
+    Primary,
+    /// send on the tones of `AcousticConfig::right_channel`
+    Secondary,
+}
+
+impl Band {
+    /// the configs to send and to listen with, in that order
+    pub fn configs(self, config: &AcousticConfig) -> Result<[AcousticConfig; 2]> {
+        let other = config.right_channel()?;
+        Ok(match self {
+            Band::Primary => [config.clone(), other],
+            Band::Secondary => [other, config.clone()],
+        })
+    }
+}
+
+/// A `Recorder` that times out once `next_event` waited long enough, however many samples
+/// keep coming in.
+struct DeadlineReader {
+    recorder: Recorder,
+    deadline: Arc<Mutex<Instant>>,
+}
+
+impl DeadlineReader {
+    fn check_deadline(&self) -> Result<()> {
+        let deadline = *self
+            .deadline
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)?;
+        match Instant::now() >= deadline {
+            true => Err(AcousticError::Timeout),
+            false => Ok(()),
+        }
+    }
+}
+
+impl SampleReader for DeadlineReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        self.check_deadline()?;
+        self.recorder.take_samples(start, end)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        self.check_deadline()?;
+        self.recorder.read_samples(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.recorder.consume(until)
+    }
+}
+
+pub struct Transceiver {
+    transmitter: Transmitter,
+    /// the config we send with
+    config: AcousticConfig,
+    receiver: Receiver,
+    /// what the receiver hears, to tell where what we play starts
+    recorded: Arc<SharedBuffer>,
+    /// what we played, for the receiver to take out again
+    played: Arc<PlayedSignals>,
+    deadline: Arc<Mutex<Instant>>,
+    /// how long `next_event` waits for a packet once we are done sending
+    timeout: Duration,
+    /// the output stream still playing, and what tells when it is done
+    playing: Option<(cpal::Stream, DoneReceiver<()>)>,
+    /// when the last packet we sent is out
+    sending_until: Instant,
+    /// kept alive for the receiver
+    _input: cpal::Stream,
+    address: u8,
+    peer: u8,
+}
+
+impl Transceiver {
+    /// Open the default input and output device.
+    pub fn new(config: &AcousticConfig, band: Band) -> Result<Transceiver> {
+        Self::with_devices(config, band, input_device(None)?, output_device(None)?)
+    }
+
+    /// Record from `input` and play through `output` at the same time. By default
+    /// `next_event` waits twice as long as an acknowledgment takes on the air.
+    pub fn with_devices(
+        config: &AcousticConfig,
+        band: Band,
+        input: cpal::Device,
+        output: cpal::Device,
+    ) -> Result<Transceiver> {
+        let [send, listen] = band.configs(config)?;
+        let mut recorder = Recorder::new();
+        let recorded = recorder.clone_handle();
+        let input = run_record_with_device(recorded.clone(), &listen, input)?;
+        let deadline = Arc::new(Mutex::new(Instant::now()));
+        let reader = EchoSuppressor::new(
+            Box::new(DeadlineReader {
+                recorder,
+                deadline: deadline.clone(),
+            }),
+            &listen,
+        );
+        let played = reader.played();
+        let ack_time =
+            modulate_packets(&listen, &[Packet::ack(0)])?.len() as f64 / listen.sample_rate;
+        Ok(Transceiver {
+            transmitter: Transmitter::with_device(send.clone(), output)?,
+            config: send,
+            receiver: Receiver::with_config(Box::new(reader), listen),
+            recorded,
+            played,
+            deadline,
+            timeout: Duration::from_secs_f64(2.0 * ack_time),
+            playing: None,
+            sending_until: Instant::now(),
+            _input: input,
+            address: 0,
+            peer: Packet::BROADCAST,
+        })
+    }
+
+    /// send from `address`, and take only packets to it, and broadcasts
+    pub fn with_address(mut self, address: u8) -> Transceiver {
+        self.address = address;
+        self.receiver = self.receiver.with_address(address);
+        self
+    }
+
+    /// send to `peer` instead of every receiver
+    pub fn with_peer(mut self, peer: u8) -> Transceiver {
+        self.peer = peer;
+        self
+    }
+
+    /// how long `next_event` waits for a packet once we are done sending
+    pub fn with_timeout(mut self, timeout: Duration) -> Transceiver {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Start playing packets of any kind and return right away, once the ones sent before
+    /// are out.
+    pub fn send_packets(&mut self, packets: &[Packet]) -> Result<()> {
+        self.wait_sent()?;
+        let signal = modulate_packets(&self.config, packets)?;
+        self.played.push(self.recorded.end()?, signal.clone())?;
+        let (tx, rx) = channel();
+        let stream = self.transmitter.start(&signal, move || {
+            let _ = tx.send(());
+        })?;
+        self.playing = Some((stream, rx));
+        self.sending_until =
+            Instant::now() + Duration::from_secs_f64(signal.len() as f64 / self.config.sample_rate);
+        Ok(())
+    }
+
+    /// Block until everything sent is out.
+    pub fn wait_sent(&mut self) -> Result<()> {
+        if let Some((_stream, done)) = self.playing.take() {
+            done.recv()
+                .map_err(|_| AcousticError::PlaybackInterrupted)?;
+            info!("Playing finished");
+        }
+        Ok(())
+    }
+
+    /// The next well formed packet of any kind from the peer, `AcousticError::Timeout` if
+    /// none came within the timeout after our own packets are out. A packet cut short by the
+    /// timeout is heard from its start next time.
+    pub fn next_event(&mut self) -> Result<Event> {
+        *self
+            .deadline
+            .lock()
+            .map_err(|_| AcousticError::PoisonedBuffer)? =
+            self.sending_until.max(Instant::now()) + self.timeout;
+        match self.receiver.next_event() {
+            Err(AcousticError::Timeout) => {
+                self.receiver.rewind()?;
+                Err(AcousticError::Timeout)
+            }
+            event => event,
+        }
+    }
+}
+
+impl Link for Transceiver {
+    fn send(&mut self, packets: &[Packet]) -> Result<()> {
+        let packets = packets
+            .iter()
+            .map(|packet| packet.clone().addressed(self.address, self.peer))
+            .collect::<Vec<Packet>>();
+        self.send_packets(&packets)
+    }
+
+    fn recv(&mut self) -> Result<Event> {
+        self.next_event()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+#[test]
+fn test_bands_apart() {
+    use crate::{transmission::MemoryReader, transmitter::modulate_message};
+
+    let config = AcousticConfig::default();
+    let [ours, listen] = Band::Primary.configs(&config).unwrap();
+    let [theirs, their_listen] = Band::Secondary.configs(&config).unwrap();
+    assert_eq!((&ours, &listen), (&their_listen, &theirs));
+
+    // our data, loud as it is right next to the microphone, while the peer acknowledges
+    let data = modulate_message(&ours, &[7; 40]).unwrap();
+    let ack = modulate_packets(&theirs, &[Packet::ack(1)]).unwrap();
+    let heard = (0..data.len() + 20000)
+        .map(|i| {
+            let at = |signal: &[f64], delay: usize| {
+                i.checked_sub(delay)
+                    .and_then(|i| signal.get(i).copied())
+                    .unwrap_or(0.0)
+            };
+            at(&data, 10000) + 0.2 * at(&ack, 30000)
+        })
+        .collect::<Vec<f64>>();
+    let mut receiver = Receiver::with_config(Box::new(MemoryReader(heard)), listen);
+    assert!(matches!(receiver.next_event().unwrap(), Event::Ack(1)));
+    assert!(matches!(
+        receiver.next_event(),
+        Err(AcousticError::EndOfStream)
+    ));
+}
@@ -4,183 +4,2219 @@
 //! physics layer. It utilizes the decoded information to continuously cut down input audio
 //! to pieces.
 //!
+//! The `Receiver` is a state machine, see `ReceiverState`: it hunts for a preamble, locks
+//! on to each of its four tones in turn, then decodes the header and the payload, one
+//! `step` at a time.
 
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rayon::prelude::*;
 use tracing::info;
 
 use crate::{
-    physics::{detect_preamble, Preamble, PREAMBLE_FREQS},
-    recorder::Recorder,
+    arq::SelectiveAck,
+    calibration::{measure_freq_correction, REFERENCE_SYMBOLS},
+    config::{AcousticConfig, Modulation},
+    crypto::{self, TAG_SIZE},
+    error::{AcousticError, Result},
+    fec,
+    filter::BandPassReader,
+    interleaver::deinterleave,
+    keepalive::Liveness,
+    message::{MessageAssembler, MessageHeader},
+    physics::{
+        css_demodulate, css_len, demodulate_symbol_with_gains, dpsk_demodulate, dpsk_len,
+        dsss_demodulate, dsss_len, dsss_slack, estimate_clock_drift, estimate_gains, fsk_symbols,
+        ofdm_demodulate_parallel, ofdm_demodulate_with, pilot_bits, preamble_overlap, preamble_snr,
+        qam16_demodulate, qam16_len, qpsk_demodulate, qpsk_len, timing_error, timing_gate,
+        unpack_symbols, Preamble, PreambleDetector, Stft, FFT_STEP, OFDM_SYMBOL_BYTES,
+        OFDM_SYMBOL_SIZE,
+    },
+    progress::Progress,
+    resampler::stretch,
+    scrambler::Scrambler,
+    Packet, PacketKind,
 };
 
+/// default sample rate, see `AcousticConfig`
 pub const SAMPLE_RATE: f64 = 44100.0;
 
+/// default symbol duration, see `AcousticConfig`
 pub const SIGNAL_TIME: f64 = 0.1;
 
-pub const SAMPLE_NUMBER: usize = (SAMPLE_RATE * SIGNAL_TIME) as usize;
 pub const PROBE_SAMPLE_NUMBER: usize = 256;
 
-pub trait SampleReader {
-    fn take_samples(&mut self, start: usize, end: usize) -> Vec<f64>;
+/// fraction of the timing error of an FSK symbol corrected before the next one
+const TIMING_GAIN: f64 = 0.5;
+
+/// data packets remembered per sender to tell repeats, more than any ARQ window
+pub const DUPLICATE_WINDOW: usize = 64;
+
+/// tones in a preamble
+const PREAMBLE_SYMBOLS: usize = 4;
+
+/// probes of the other tone a lock on one preamble tone puts up with
+const MAX_WRONG_PROBES: u32 = 3;
+
+/// probes skipped once a tone gives way to silence, which cannot be part of the preamble
+const SILENCE_SKIP_PROBES: usize = 31;
+
+/// FSK symbols timed at once with `AcousticConfig::parallel`, a wrong guess costs deciding
+/// the rest of them again, see `Receiver::demodulate_timed_symbols_parallel`
+const PARALLEL_SYMBOLS: usize = 128;
+
+pub trait SampleReader: Send {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>>;
+
+    /// `take_samples` into `buffer`, in place of what it held. The receiver probes every
+    /// few hundred samples with the same buffer, so readers that keep their samples around
+    /// copy them straight in instead of allocating, the default goes through `take_samples`.
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        *buffer = self.take_samples(start, end)?;
+        Ok(())
+    }
+
+    /// The receiver will not ask for samples before `until` any more.
+    fn consume(&mut self, _until: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Samples all in memory at once, a signal modulated or impaired in place of a recording.
+/// Asking past its end is `AcousticError::EndOfStream`.
+#[derive(Debug, Clone)]
+pub struct MemoryReader(pub Vec<f64>);
+
+impl SampleReader for MemoryReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        self.0
+            .get(start..end)
+            .map(<[f64]>::to_vec)
+            .ok_or(AcousticError::EndOfStream)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        let samples = self.0.get(start..end).ok_or(AcousticError::EndOfStream)?;
+        buffer.clear();
+        buffer.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Callbacks on the progress of a `Receiver`, see `Receiver::with_events`. Each does nothing
+/// unless implemented.
+pub trait ReceiverEvents: Send {
+    /// A preamble checked out, a packet should start at sample `position`.
+    fn on_preamble_detected(&mut self, _position: usize) {}
+
+    /// A well formed packet for us arrived, `next_event` is about to return it.
+    fn on_packet(&mut self, _packet: &Packet) {}
+
+    /// What came after a preamble was mangled, by noise or by another sender, and dropped.
+    fn on_malformed_packet(&mut self) {}
+
+    /// Data packets `orders` of `source` never arrived, the one after them just did.
+    fn on_packets_skipped(&mut self, _source: u8, _orders: Range<usize>) {}
+
+    /// `run` added a packet to the message it collects, see `progress`.
+    fn on_progress(&mut self, _progress: &Progress) {}
+
+    /// `run` has the whole message.
+    fn on_message_complete(&mut self, _message: &[u8]) {}
+}
+
+/// no callbacks
+impl ReceiverEvents for () {}
+
+/// What a `Receiver` heard so far, see `Receiver::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReceiverStats {
+    /// preambles that checked out
+    pub preambles: usize,
+    /// tones that started like a preamble but did not check out
+    pub false_preambles: usize,
+    /// well formed packets, whoever they were for
+    pub packets: usize,
+    /// packets mangled on the way, which failed their checks and were dropped
+    pub malformed: usize,
+    /// packets that failed authentication, see `crypto`
+    pub forged: usize,
+    /// data packets lost on the way, told by the orders of those after them
+    pub skipped: usize,
+    /// payload bytes of the well formed packets
+    pub bytes: usize,
+    /// seconds of audio processed
+    pub seconds: f64,
+    /// sum of the SNR of the preambles measured, in decibels
+    snr_sum: f64,
+    /// preambles measured, not those at the very start of the recording
+    snr_count: usize,
+}
+
+impl ReceiverStats {
+    fn on_preamble(&mut self, snr: Option<f64>) {
+        self.preambles += 1;
+        if let Some(snr) = snr {
+            self.snr_sum += snr;
+            self.snr_count += 1;
+        }
+    }
+
+    /// average signal to noise ratio of the preambles in decibels, see `preamble_snr`
+    pub fn average_snr(&self) -> Option<f64> {
+        (self.snr_count > 0).then(|| self.snr_sum / self.snr_count as f64)
+    }
+
+    /// payload bits per second of audio, pauses and all
+    pub fn throughput(&self) -> f64 {
+        match self.seconds > 0.0 {
+            true => self.bytes as f64 * 8.0 / self.seconds,
+            false => 0.0,
+        }
+    }
+}
+
+/// Stops a `Receiver` from another thread, see `Receiver::stop_handle`.
+#[derive(Clone, Debug)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// `Receiver::run` and `Receiver::next_event` return `AcousticError::Stopped` once they
+    /// are done with the packet at hand, for good.
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// a packet the receiver heard, by kind
+#[derive(Debug, Clone)]
+pub enum Event {
+    Data(Packet),
+    /// A data packet heard before from the same sender, resent since our acknowledgment got
+    /// lost. Acknowledge it again, but do not keep it.
+    Duplicate(Packet),
+    /// the peer expects this packet next
+    Ack(usize),
+    /// an acknowledgment with a bitmap of the packets after the gap, see `arq`
+    SelectiveAck(SelectiveAck),
+    Control(Packet),
+    Beacon(Packet),
+    /// A preamble overlapping another one, followed by a packet that did not survive it.
+    /// Two senders collided.
+    GarbledPreamble,
+    /// Nothing was heard from the peer for too long, see `keepalive`.
+    LinkDown,
+}
+
+impl From<Packet> for Event {
+    fn from(packet: Packet) -> Self {
+        match packet.kind {
+            PacketKind::Data => Event::Data(packet),
+            PacketKind::Ack => match SelectiveAck::from_packet(&packet) {
+                Some(ack) if ack.received != 0 || ack.window.is_some() => Event::SelectiveAck(ack),
+                _ => Event::Ack(packet.order),
+            },
+            PacketKind::Control => Event::Control(packet),
+            PacketKind::Beacon => Event::Beacon(packet),
+        }
+    }
+}
+
+/// Where a `Receiver` is in hearing a packet, see `Receiver::step`.
+enum ReceiverState {
+    /// probing for any preamble tone, nothing before can be part of a packet
+    Hunting,
+    /// waiting for the tone of preamble symbol `symbol`
+    PreambleSeek { symbol: usize },
+    /// collecting votes for the tone of preamble symbol `symbol`
+    PreambleLock { symbol: usize, lock: ToneLock },
+    /// the whole preamble was heard, the header is next
+    HeaderDecode,
+    /// the header says `len` more bytes follow, then the trailer
+    PayloadDecode {
+        sealed: Vec<u8>,
+        len: usize,
+        scrambler: Option<Scrambler>,
+    },
+}
+
+impl ReceiverState {
+    /// still waiting for a preamble, a timeout here is no packet cut short
+    fn is_hunting(&self) -> bool {
+        !matches!(
+            self,
+            ReceiverState::HeaderDecode | ReceiverState::PayloadDecode { .. }
+        )
+    }
+}
+
+/// the votes for one preamble tone so far
+#[derive(Default)]
+struct ToneLock {
+    /// right after the last probe that heard the tone
+    end: usize,
+    votes: u32,
+    /// probes that heard the other tone
+    wrong: u32,
+}
+
+/// where the receiver is in a run of FSK symbols, to go back to
+#[derive(Clone, Copy)]
+struct SymbolTiming {
+    processed_samples: usize,
+    drift_remainder: f64,
+    previous_symbol: u8,
+}
+
+/// what a `Receiver::step` came to
+enum Step {
+    Continue,
+    /// a whole preamble was heard
+    Preamble,
+    /// a packet ended, `None` if mangled by noise
+    Packet(Option<Packet>),
+}
+
+/// the bit of the tone of preamble symbol `symbol`, tones A B A B
+fn preamble_bit(symbol: usize) -> u8 {
+    (symbol % 2) as u8
+}
+
+fn descramble(scrambler: &mut Option<Scrambler>, bytes: Vec<u8>) -> Vec<u8> {
+    match scrambler.as_mut() {
+        Some(scrambler) => scrambler.apply(&bytes),
+        None => bytes,
+    }
 }
 
 /// This is essentially a Turing machine
 pub struct Receiver {
     reader: Box<dyn SampleReader>,
     processed_samples: usize,
+    /// where in hearing a packet `processed_samples` is
+    state: ReceiverState,
+    config: AcousticConfig,
+    /// packets to other addresses are ignored
+    address: u8,
+    /// take every packet, whoever it is for
+    promiscuous: bool,
+    /// where `next_event` was last waiting for a preamble, see `rewind`
+    checkpoint: usize,
+    /// how loud each carrier arrives, from the pilot of the current packet
+    gains: Vec<f64>,
+    /// how much faster the sender's sample clock runs, from the preamble of the current
+    /// packet, see `estimate_clock_drift`
+    drift: f64,
+    /// the part of a sample `advance` has not stepped over yet, and timing corrections it
+    /// has not made yet
+    drift_remainder: f64,
+    /// the FSK symbol before the one at `processed_samples`, for `timing_error`
+    previous_symbol: u8,
+    /// when the peer was last heard, if we keep track
+    liveness: Option<Liveness>,
+    /// orders of the latest data packets of every sender and port, to tell repeats
+    seen: BTreeMap<(u8, u8), VecDeque<usize>>,
+    events: Box<dyn ReceiverEvents>,
+    stats: ReceiverStats,
+    /// how long `run` waits for a message
+    timeout: Option<Duration>,
+    /// when the current `run` gives up
+    deadline: Option<Instant>,
+    stopped: Arc<AtomicBool>,
+    /// the filters preambles are listened for with, set up once
+    preamble: PreambleDetector,
+    /// the FFT of OFDM symbols, planned once
+    stft: Stft,
+    /// the samples of the latest probe, reused
+    probe: Vec<f64>,
+    /// the samples of the latest FSK symbol and the one before, reused
+    symbols: Vec<f64>,
 }
 
 impl Receiver {
     pub fn new(recorder: Box<dyn SampleReader>) -> Receiver {
+        Self::with_config(recorder, AcousticConfig::default())
+    }
+
+    pub fn with_config(recorder: Box<dyn SampleReader>, config: AcousticConfig) -> Receiver {
+        let reader: Box<dyn SampleReader> = match config.pass_band() {
+            Some((low, high)) => {
+                Box::new(BandPassReader::new(recorder, low, high, config.sample_rate))
+            }
+            None => recorder,
+        };
         Receiver {
-            reader: recorder,
+            reader,
             processed_samples: 0,
+            state: ReceiverState::Hunting,
+            address: 0,
+            promiscuous: false,
+            checkpoint: 0,
+            gains: vec![1.0; config.carrier_freqs.len()],
+            drift: 0.0,
+            drift_remainder: 0.0,
+            previous_symbol: 0,
+            liveness: None,
+            seen: BTreeMap::new(),
+            events: Box::new(()),
+            stats: ReceiverStats::default(),
+            timeout: None,
+            deadline: None,
+            stopped: Arc::new(AtomicBool::new(false)),
+            preamble: PreambleDetector::new(&config),
+            stft: Stft::new(),
+            probe: Vec::with_capacity(PROBE_SAMPLE_NUMBER),
+            symbols: Vec::new(),
+            config,
+        }
+    }
+
+    /// take only packets to `address`, and broadcasts
+    pub fn with_address(mut self, address: u8) -> Receiver {
+        self.address = address;
+        self
+    }
+
+    /// take packets to any address, for debugging
+    pub fn promiscuous(mut self, promiscuous: bool) -> Receiver {
+        self.promiscuous = promiscuous;
+        self
+    }
+
+    /// `next_event`, giving up with `AcousticError::Timeout` when no packet started within
+    /// `timeout`
+    pub fn next_event_within(&mut self, timeout: Duration) -> Result<Event> {
+        self.deadline = Some(Instant::now() + timeout);
+        let event = self.next_event();
+        self.deadline = None;
+        event
+    }
+
+    /// `run` gives up with `AcousticError::Timeout` when no message came within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Receiver {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// A handle to stop receiving from another thread.
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle(self.stopped.clone())
+    }
+
+    pub fn config(&self) -> &AcousticConfig {
+        &self.config
+    }
+
+    /// How receiving went so far.
+    pub fn stats(&self) -> ReceiverStats {
+        ReceiverStats {
+            seconds: self.processed_samples as f64 / self.config.sample_rate,
+            ..self.stats
         }
     }
 
-    pub fn run(&mut self) {
+    /// tell `events` how receiving goes
+    pub fn with_events(mut self, events: Box<dyn ReceiverEvents>) -> Receiver {
+        self.events = events;
+        self
+    }
+
+    /// report `Event::LinkDown` once no packet came for a while
+    pub fn with_liveness(mut self, liveness: Liveness) -> Receiver {
+        self.liveness = Some(liveness);
+        self
+    }
+
+    /// whether a packet came lately, always without `with_liveness`
+    pub fn link_alive(&self) -> bool {
+        self.liveness.as_ref().is_none_or(Liveness::is_alive)
+    }
+
+    /// Forget which data packets came from `source`, on every port, as its next message
+    /// numbers them from 0 again. `run` does so once a message ended.
+    pub fn forget_seen(&mut self, source: u8) {
+        self.seen.retain(|(from, _), _| *from != source);
+    }
+
+    /// Forget which data packets came from `source` to `port` only, see `ports`.
+    pub fn forget_seen_on(&mut self, source: u8, port: u8) {
+        self.seen.remove(&(source, port));
+    }
+
+    /// The data packets of the same sender to the same port that should have come before
+    /// this one but did not, going by the latest order heard.
+    fn skipped_before(&self, packet: &Packet) -> Option<Range<usize>> {
+        if packet.kind != PacketKind::Data {
+            return None;
+        }
+        let next = self
+            .seen
+            .get(&(packet.source, packet.port))
+            .and_then(|seen| seen.iter().max())
+            .map_or(0, |order| order + 1);
+        (packet.order > next).then_some(next..packet.order)
+    }
+
+    /// Whether the data packet was heard before, and remember it if not.
+    fn is_duplicate(&mut self, packet: &Packet) -> bool {
+        let seen = self.seen.entry((packet.source, packet.port)).or_default();
+        if seen.contains(&packet.order) {
+            return true;
+        }
+        if seen.len() == DUPLICATE_WINDOW {
+            seen.pop_front();
+        }
+        seen.push_back(packet.order);
+        false
+    }
+
+    /// the sample after everything processed, right after the last packet `next_event`
+    /// returned
+    pub fn position(&self) -> usize {
+        self.processed_samples
+    }
+
+    /// Receive packets until a message ends, and return its payload.
+    ///
+    /// Malformed and unauthenticated packets are dropped, only failures of the sample source
+    /// are returned.
+    /// Control traffic heard meanwhile is logged and skipped, use `next_event` to see it.
+    ///
+    /// The message ends with its last packet, or once every packet announced by a
+    /// `MessageHeader` arrived, see `message`. Data to every port goes into that one
+    /// message, a `ports::Dispatcher` keeps ports apart.
+    ///
+    /// Every packet starts with a preamble of its own, so after losing one the receiver
+    /// picks up at the next. The message then has a hole, which is reported, see
+    /// `ReceiverEvents::on_packets_skipped` and `ReceiverStats::skipped`.
+    ///
+    /// Gives up with `AcousticError::Timeout` once `with_timeout` is over, and with
+    /// `AcousticError::Stopped` once stopped, see `stop_handle`. Both are checked while
+    /// waiting for a preamble, so the sample source has to keep delivering samples or time
+    /// out by itself, as a `Recorder` of a running input does.
+    pub fn run(&mut self) -> Result<Vec<u8>> {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let result = self.collect_message();
+        self.deadline = None;
+        result
+    }
+
+    fn collect_message(&mut self) -> Result<Vec<u8>> {
+        let mut message = MessageAssembler::with_packet_size(self.config.packet_size);
+        // when the first packet of the message arrived
+        let mut started = None;
         loop {
-            if self.detect_preambles(0) {
-                if self.verify_preamble() {
-                    panic!("get it");
+            let event = match self.next_event() {
+                Err(AcousticError::AuthenticationFailed) => {
+                    info!("unauthenticated packet, dropped");
+                    continue;
                 }
+                event => event?,
+            };
+            match event {
+                Event::Data(packet) => {
+                    let source = packet.source;
+                    let started = *started.get_or_insert_with(Instant::now);
+                    let complete = message.add(packet);
+                    self.events
+                        .on_progress(&message.progress(started.elapsed()));
+                    if complete {
+                        self.forget_seen(source);
+                        let message = message.take_message();
+                        self.events.on_message_complete(&message);
+                        return Ok(message);
+                    }
+                }
+                Event::Control(packet) => match MessageHeader::from_packet(&packet) {
+                    Ok(header) => {
+                        info!("message of {} bytes announced", header.len);
+                        message.start(header);
+                    }
+                    Err(_) => info!("skipped control {:?}", packet.data),
+                },
+                event => info!("skipped {:?}", event),
             }
         }
     }
 
-    fn take_samples(&mut self) -> Vec<f64> {
-        self.reader.take_samples(
-            self.processed_samples,
-            self.processed_samples + SAMPLE_NUMBER,
+    /// Take what the reader has now, without waiting for more, and return the next packet
+    /// once all of it was there. The reader has to give up right away on samples it does not
+    /// have yet, like a `Recorder` with a zero timeout. Until a packet is complete, this
+    /// returns `None` and starts over from the preamble on the next call, see `rewind`.
+    pub fn poll(&mut self) -> Result<Option<Event>> {
+        match self.next_event() {
+            Ok(event) => Ok(Some(event)),
+            Err(AcousticError::Timeout) => {
+                self.rewind()?;
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait for the next well formed packet of any kind.
+    ///
+    /// With `with_liveness`, a timeout of the sample source once the peer was quiet for too
+    /// long is `Event::LinkDown` instead, and the receiver starts over where it waited for a
+    /// preamble, like after `rewind`.
+    pub fn next_event(&mut self) -> Result<Event> {
+        // what the preamble of the packet at hand sounded like
+        let mut garbled = false;
+        let mut snr = None;
+        loop {
+            let hunting = self.state.is_hunting();
+            let packet = match self.step() {
+                Ok(Step::Continue) => continue,
+                Ok(Step::Preamble) => {
+                    self.events.on_preamble_detected(self.processed_samples);
+                    let preamble = self.last_preamble()?;
+                    garbled = preamble
+                        .as_ref()
+                        .is_some_and(|preamble| self.preamble_garbled(preamble));
+                    snr = preamble.and_then(|preamble| preamble_snr(&self.config, &preamble));
+                    continue;
+                }
+                Ok(Step::Packet(packet)) => packet,
+                Err(AcousticError::Timeout)
+                    if hunting && self.liveness.as_mut().is_some_and(Liveness::went_down) =>
+                {
+                    info!("nothing heard for too long, link down");
+                    self.rewind()?;
+                    return Ok(Event::LinkDown);
+                }
+                Err(AcousticError::AuthenticationFailed) => {
+                    // a forged packet is spent too
+                    self.consume_processed()?;
+                    self.stats.on_preamble(snr);
+                    self.stats.forged += 1;
+                    return Err(AcousticError::AuthenticationFailed);
+                }
+                Err(err) => return Err(err),
+            };
+            self.consume_processed()?;
+            self.stats.on_preamble(snr);
+            match &packet {
+                Some(packet) => {
+                    self.stats.packets += 1;
+                    self.stats.bytes += packet.data.len();
+                }
+                None => self.stats.malformed += 1,
+            }
+            match packet {
+                Some(packet)
+                    if !self.promiscuous
+                        && packet.destination != self.address
+                        && packet.destination != Packet::BROADCAST =>
+                {
+                    info!("packet to {} ignored", packet.destination)
+                }
+                Some(packet) => {
+                    info!("received {:?} packet {}", packet.kind, packet.order);
+                    if let Some(liveness) = self.liveness.as_mut() {
+                        liveness.heard();
+                    }
+                    self.events.on_packet(&packet);
+                    if let Some(orders) = self.skipped_before(&packet) {
+                        info!("packets {:?} from {} lost", orders, packet.source);
+                        self.stats.skipped += orders.len();
+                        self.events.on_packets_skipped(packet.source, orders);
+                    }
+                    if packet.kind == PacketKind::Data && self.is_duplicate(&packet) {
+                        info!("packet {} from {} again", packet.order, packet.source);
+                        return Ok(Event::Duplicate(packet));
+                    }
+                    return Ok(Event::from(packet));
+                }
+                None if garbled => {
+                    info!("garbled preamble, collision");
+                    self.events.on_malformed_packet();
+                    return Ok(Event::GarbledPreamble);
+                }
+                None => {
+                    info!("malformed packet, dropped");
+                    self.events.on_malformed_packet();
+                }
+            }
+        }
+    }
+
+    /// Wait for a `calibration_signal` and listen for the carriers where they arrived from
+    /// then on. Returns the new `AcousticConfig::freq_correction`.
+    pub fn calibrate(&mut self) -> Result<f64> {
+        loop {
+            self.wait_for_preamble()?;
+            let end = self.processed_samples + self.config.sample_number() * REFERENCE_SYMBOLS;
+            let reference = self.reader.take_samples(self.processed_samples, end)?;
+            self.processed_samples = end;
+            self.reader.consume(self.processed_samples)?;
+            match measure_freq_correction(&self.config, &reference) {
+                Some(correction) => {
+                    info!("carriers arrive {} times as high", correction);
+                    self.config.freq_correction = correction;
+                    return Ok(correction);
+                }
+                None => info!("no reference tones after the preamble"),
+            }
+        }
+    }
+
+    /// Go back to where `next_event` last waited for a preamble, so that a `next_event` cut
+    /// short by a reader without enough samples yet can start over once they arrived.
+    /// Everything up to a symbol before is consumed, the preamble heard there may have
+    /// started that much earlier, see `estimate_clock_drift`.
+    pub fn rewind(&mut self) -> Result<()> {
+        self.state = ReceiverState::Hunting;
+        self.processed_samples = self.checkpoint;
+        self.consume_checkpoint()
+    }
+
+    /// Forget the samples up to a symbol before `checkpoint`, like `rewind`.
+    fn consume_checkpoint(&mut self) -> Result<()> {
+        self.reader
+            .consume(self.checkpoint.saturating_sub(self.config.sample_number()))
+    }
+
+    /// Move `checkpoint` up to a preamble before `position` while still seeking one, a
+    /// preamble that started earlier would be over by now.
+    fn keep_preamble_before(&mut self, position: usize) -> Result<()> {
+        let preamble = PREAMBLE_SYMBOLS * self.config.sample_number();
+        self.checkpoint = self.checkpoint.max(position.saturating_sub(preamble));
+        self.consume_checkpoint()
+    }
+
+    /// Forget the samples processed so far but for a symbol, a preamble right after them
+    /// may have started that much earlier, see `estimate_clock_drift`.
+    fn consume_processed(&mut self) -> Result<()> {
+        self.reader.consume(
+            self.processed_samples
+                .saturating_sub(self.config.sample_number()),
         )
     }
 
-    fn take_probe_samples(&mut self) -> Vec<f64> {
+    /// Step until a whole preamble was heard, and stop right after it, for whatever else
+    /// than a packet follows, see `calibrate`.
+    fn wait_for_preamble(&mut self) -> Result<()> {
+        self.state = ReceiverState::Hunting;
+        loop {
+            if let Step::Preamble = self.step()? {
+                self.state = ReceiverState::Hunting;
+                return Ok(());
+            }
+        }
+    }
+
+    fn take_samples(&mut self) -> Result<Vec<f64>> {
         self.reader.take_samples(
             self.processed_samples,
-            self.processed_samples + PROBE_SAMPLE_NUMBER,
+            self.processed_samples + self.config.sample_number(),
         )
     }
 
-    fn verify_preamble(&mut self) -> bool {
-        for i in [1, 0, 1] {
-            if !self.detect_preambles(i) {
-                return false;
-            }
-        }
-        info!("verified data pack");
-        true
+    /// Listen for a preamble in the `PROBE_SAMPLE_NUMBER` samples from `processed_samples`.
+    fn probe(&mut self) -> Result<Preamble> {
+        self.reader.read_samples(
+            self.processed_samples,
+            self.processed_samples + PROBE_SAMPLE_NUMBER,
+            &mut self.probe,
+        )?;
+        Ok(self.preamble.detect(&self.probe))
     }
 
-    /// probe and detect *bit*. wait until see bit. consume all bit and calculate vote
-    fn detect_preambles(&mut self, bit: u8) -> bool {
-        loop {
-            let samples = self.take_probe_samples();
-            match detect_preamble(&samples) {
-                crate::physics::Preamble::NoPreamble => {
-                    self.processed_samples += PROBE_SAMPLE_NUMBER;
-                    continue;
+    /// Make a single transition of the state machine, taking the samples it needs. Any error
+    /// leaves the receiver hunting from where it got to.
+    fn step(&mut self) -> Result<Step> {
+        match std::mem::replace(&mut self.state, ReceiverState::Hunting) {
+            ReceiverState::Hunting => {
+                if self.stopped.load(Ordering::Relaxed) {
+                    return Err(AcousticError::Stopped);
                 }
-                crate::physics::Preamble::Detected {
-                    ending_position,
-                    signal_bit,
-                    votes,
-                } => {
-                    self.processed_samples += ending_position;
-                    // 0 -> 0
-                    if signal_bit != bit {
-                        continue;
+                if self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    return Err(AcousticError::Timeout);
+                }
+                // nothing before here can be part of a packet anymore
+                self.checkpoint = self.processed_samples;
+                self.consume_checkpoint()?;
+                match self.probe()? {
+                    Preamble::NoPreamble => self.processed_samples += PROBE_SAMPLE_NUMBER,
+                    // probed again as the first symbol
+                    Preamble::Detected { .. } => {
+                        self.state = ReceiverState::PreambleSeek { symbol: 0 }
                     }
-                    // 0 -> 1
-                    info!("probed preamble {}", signal_bit);
-                    let mut samples = self.take_probe_samples();
-                    let mut cumulated_pos_votes = 0;
-                    let mut cumulated_neg_votes = 0;
-                    let mut cumulated_spaces = 0;
-                    loop {
-                        match detect_preamble(&samples) {
-                            Preamble::Detected {
-                                ending_position,
-                                signal_bit,
-                                votes,
-                            } => {
-                                info!("collecting preamble {}", signal_bit);
-                                if signal_bit != bit {
-                                    cumulated_neg_votes += 1;
-                                    if cumulated_neg_votes > 3 {
-                                        info!("not correct, fallback");
-                                        break;
-                                    }
-                                }
-                                cumulated_pos_votes += votes;
-                                self.processed_samples += ending_position;
-                                samples = self.take_probe_samples();
-                            }
-                            Preamble::NoPreamble => {
-                                info!("gotten some noises");
-                                self.processed_samples += PROBE_SAMPLE_NUMBER;
-                                cumulated_spaces += 1;
-                                if cumulated_spaces > 30 {
-                                    break;
-                                } else {
-                                    continue;
-                                }
-                            }
+                }
+                Ok(Step::Continue)
+            }
+            ReceiverState::PreambleSeek { symbol } => {
+                self.keep_preamble_before(self.processed_samples)?;
+                let preamble = self.probe()?;
+                self.state = ReceiverState::PreambleSeek { symbol };
+                match preamble {
+                    Preamble::NoPreamble => self.processed_samples += PROBE_SAMPLE_NUMBER,
+                    Preamble::Detected {
+                        ending_position,
+                        signal_bit,
+                        votes: _,
+                    } => {
+                        self.processed_samples += ending_position;
+                        if signal_bit == preamble_bit(symbol) {
+                            info!("probed preamble {}", signal_bit);
+                            self.state = ReceiverState::PreambleLock {
+                                symbol,
+                                lock: ToneLock {
+                                    end: self.processed_samples,
+                                    ..ToneLock::default()
+                                },
+                            };
+                        }
+                    }
+                }
+                Ok(Step::Continue)
+            }
+            ReceiverState::PreambleLock { symbol, mut lock } => {
+                self.keep_preamble_before(lock.end)?;
+                let preamble = self.probe()?;
+                let bit = preamble_bit(symbol);
+                match preamble {
+                    Preamble::Detected { signal_bit, .. }
+                        if signal_bit != bit && lock.wrong >= MAX_WRONG_PROBES =>
+                    {
+                        info!("not correct, fallback");
+                        Ok(self.end_lock(symbol, lock))
+                    }
+                    Preamble::Detected {
+                        ending_position,
+                        signal_bit,
+                        votes,
+                    } => {
+                        info!("collecting preamble {}", signal_bit);
+                        if signal_bit != bit {
+                            lock.wrong += 1;
+                        }
+                        lock.votes += votes;
+                        self.processed_samples += ending_position;
+                        if signal_bit == bit {
+                            lock.end = self.processed_samples;
                         }
+                        self.state = ReceiverState::PreambleLock { symbol, lock };
+                        Ok(Step::Continue)
                     }
-                    if cumulated_pos_votes > 20 {
-                        info!(
-                            "because get {} votes, {} is verified",
-                            cumulated_pos_votes, bit
-                        );
-                        return true;
+                    Preamble::NoPreamble => {
+                        info!("gotten some noises");
+                        self.processed_samples += PROBE_SAMPLE_NUMBER * SILENCE_SKIP_PROBES;
+                        Ok(self.end_lock(symbol, lock))
                     }
                 }
             }
+            ReceiverState::HeaderDecode => self.decode_header(),
+            ReceiverState::PayloadDecode {
+                sealed,
+                len,
+                scrambler,
+            } => self.decode_payload(sealed, len, scrambler),
         }
     }
 
-    fn demodulate_data(&mut self) -> Vec<u8> {
-        // self.consume_lagging_preambles();
-        todo!();
+    /// Whether the tone of preamble symbol `symbol` was heard, on to the next one if so, or
+    /// to the header after the last.
+    fn end_lock(&mut self, symbol: usize, lock: ToneLock) -> Step {
+        let bit = preamble_bit(symbol);
+        if lock.votes <= self.min_preamble_votes() {
+            self.stats.false_preambles += 1;
+            self.state = ReceiverState::PreambleSeek { symbol };
+            return Step::Continue;
+        }
+        info!("because get {} votes, {} is verified", lock.votes, bit);
+        // whatever comes after the tone has not been consumed yet
+        self.processed_samples = lock.end;
+        if symbol + 1 < PREAMBLE_SYMBOLS {
+            self.state = ReceiverState::PreambleSeek { symbol: symbol + 1 };
+            return Step::Continue;
+        }
+        info!("verified data pack");
+        self.state = ReceiverState::HeaderDecode;
+        Step::Preamble
+    }
+
+    /// the samples of the preamble just verified, unless the recording started within it
+    fn last_preamble(&mut self) -> Result<Option<Vec<f64>>> {
+        let Some(start) = self
+            .processed_samples
+            .checked_sub(4 * self.config.sample_number())
+        else {
+            return Ok(None);
+        };
+        self.reader
+            .take_samples(start, self.processed_samples)
+            .map(Some)
+    }
+
+    /// Whether another preamble overlaps the one just heard, by more than
+    /// `detection_margin` allows for.
+    fn preamble_garbled(&self, preamble: &[f64]) -> bool {
+        let overlap = preamble_overlap(&self.config, preamble);
+        info!("preamble overlap {}", overlap);
+        overlap * self.config.detection_margin > 1.0
+    }
+
+    /// a tone counts as heard once most of its STFT columns voted for it
+    fn min_preamble_votes(&self) -> u32 {
+        (self.config.sample_number() / FFT_STEP * 3 / 5)
+            .try_into()
+            .unwrap_or(u32::MAX)
+    }
+
+    /// Demodulate the header of a sealed packet right after a verified preamble. Packets
+    /// mangled by noise are `None`.
+    fn decode_header(&mut self) -> Result<Step> {
+        let n = self.config.sample_number();
+        // a calibrated receiver knows how far off the sender's clock is
+        self.drift = match self.processed_samples.checked_sub(4 * n) {
+            _ if self.config.freq_correction != 1.0 => self.config.freq_correction - 1.0,
+            Some(start) => estimate_clock_drift(
+                &self.config,
+                &self.reader.take_samples(start, self.processed_samples)?,
+            ),
+            None => 0.0,
+        };
+        self.drift_remainder = 0.0;
+        self.previous_symbol = 0;
+        if self.config.pilot && self.config.modulation == Modulation::Fsk {
+            let samples = self.take_samples()?;
+            self.advance(n);
+            self.gains = estimate_gains(&self.config, &samples);
+            self.previous_symbol = pilot_bits(&self.config);
+        }
+        let mut scrambler = self.config.scramble.then(Scrambler::new);
+        let header = self.demodulate_bytes(self.config.header_size())?;
+        let sealed = descramble(&mut scrambler, header);
+        let len = match Packet::payload_len(&sealed) {
+            Ok(len) => len,
+            Err(err) => {
+                info!("{}", err);
+                return Ok(Step::Packet(None));
+            }
+        };
+        let max_len = match self.config.key {
+            Some(_) => self.config.packet_size + TAG_SIZE,
+            None => self.config.packet_size,
+        };
+        if len > max_len {
+            info!("packet length {} is too long", len);
+            return Ok(Step::Packet(None));
+        }
+        self.state = ReceiverState::PayloadDecode {
+            sealed,
+            len,
+            scrambler,
+        };
+        Ok(Step::Continue)
+    }
+
+    /// Demodulate the rest of a sealed packet after its header. Packets mangled by noise are
+    /// `None`, forged ones an error.
+    fn decode_payload(
+        &mut self,
+        mut sealed: Vec<u8>,
+        len: usize,
+        mut scrambler: Option<Scrambler>,
+    ) -> Result<Step> {
+        let payload = self.demodulate_bytes(len + self.config.trailer_size())?;
+        sealed.extend(descramble(&mut scrambler, payload));
+        let packet = match &self.config.mac_key {
+            Some(mac_key) => crypto::verify(mac_key, &sealed),
+            None => Ok(&sealed[..]),
+        }
+        .and_then(|sealed| match &self.config.key {
+            Some(key) => crypto::unseal(key, sealed),
+            None => Ok(Packet::unseal_one(sealed)?),
+        });
+        match packet {
+            Ok(packet) => Ok(Step::Packet(Some(packet))),
+            Err(AcousticError::AuthenticationFailed) => Err(AcousticError::AuthenticationFailed),
+            Err(err) => {
+                info!("{}", err);
+                Ok(Step::Packet(None))
+            }
+        }
+    }
+
+    fn demodulate_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let encoded_len = fec::encoded_len(self.config.fec, n);
+        let encoded = match self.config.modulation {
+            Modulation::Fsk => self.demodulate_fsk_bytes(encoded_len)?,
+            Modulation::Ofdm => self.demodulate_ofdm_bytes(encoded_len)?,
+            Modulation::Dpsk => {
+                let size = dpsk_len(&self.config, encoded_len);
+                self.demodulate_carrier_bytes(size, dpsk_demodulate)?
+            }
+            Modulation::Qpsk => {
+                let size = qpsk_len(&self.config, encoded_len);
+                self.demodulate_carrier_bytes(size, qpsk_demodulate)?
+            }
+            Modulation::Qam16 => {
+                let size = qam16_len(&self.config, encoded_len);
+                self.demodulate_carrier_bytes(size, qam16_demodulate)?
+            }
+            Modulation::Dsss => self.demodulate_dsss_bytes(encoded_len)?,
+            Modulation::Css => {
+                let size = css_len(&self.config, encoded_len);
+                self.demodulate_carrier_bytes(size, css_demodulate)?
+            }
+        };
+        let encoded = deinterleave(&encoded, self.config.interleave_depth);
+        Ok(fec::decode(self.config.fec, &encoded, n))
+    }
+
+    fn demodulate_ofdm_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let size = n.div_ceil(OFDM_SYMBOL_BYTES) * OFDM_SYMBOL_SIZE;
+        let samples = self.take_block(size, 0)?;
+        let mut bytes = match self.config.parallel {
+            true => ofdm_demodulate_parallel(&samples),
+            false => ofdm_demodulate_with(&mut self.stft, &samples),
+        };
+        bytes.truncate(n);
+        Ok(bytes)
+    }
+
+    /// `size` samples of a single carrier modulation, demodulated all at once
+    fn demodulate_carrier_bytes(
+        &mut self,
+        size: usize,
+        demodulate: fn(&AcousticConfig, &[f64]) -> Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let samples = self.take_block(size, 0)?;
+        Ok(demodulate(&self.config, &samples))
+    }
+
+    /// like `demodulate_carrier_bytes`, with room around the samples to find the chips in
+    fn demodulate_dsss_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let slack = dsss_slack(&self.config);
+        let size = dsss_len(&self.config, n);
+        let samples = self.take_block(size, slack)?;
+        Ok(dsss_demodulate(&self.config, &samples))
+    }
+
+    fn demodulate_fsk_bytes(&mut self, n: usize) -> Result<Vec<u8>> {
+        let count = fsk_symbols(&self.config, n);
+        let symbols = match (self.config.parallel, self.config.timing_recovery) {
+            (true, false) => self.demodulate_symbols_parallel(count)?,
+            (true, true) => self.demodulate_timed_symbols_parallel(count)?,
+            (false, _) => (0..count)
+                .map(|_| self.demodulate_symbol())
+                .collect::<Result<Vec<u8>>>()?,
+        };
+        Ok(unpack_symbols(&self.config, &symbols, n))
+    }
+
+    /// `count` FSK symbols read at once and demodulated on every core, without timing
+    /// recovery where every window lies is known before any symbol is decided on.
+    fn demodulate_symbols_parallel(&mut self, count: usize) -> Result<Vec<u8>> {
+        let windows = self.untimed_windows(count);
+        let symbols = self.decide_symbols(&windows)?;
+        if let Some(last) = symbols.last() {
+            self.previous_symbol = *last;
+        }
+        Ok(symbols)
+    }
+
+    /// Like `demodulate_symbols_parallel`, with timing recovery, where every window depends
+    /// on the symbols before. The symbols are guessed at windows without any correction
+    /// first, then the windows are timed from the guesses one after the other, which takes
+    /// less than deciding on the symbols, and the symbols decided at them on every core. Up
+    /// to the first wrong guess these are the symbols of `demodulate_symbol`, the rest are
+    /// timed again from there, guessed to be what was just decided.
+    fn demodulate_timed_symbols_parallel(&mut self, count: usize) -> Result<Vec<u8>> {
+        let mut symbols = Vec::with_capacity(count);
+        let mut guesses = Vec::new();
+        while symbols.len() < count {
+            if guesses.is_empty() {
+                let timing = self.symbol_timing();
+                let windows = self.untimed_windows((count - symbols.len()).min(PARALLEL_SYMBOLS));
+                self.set_symbol_timing(timing);
+                guesses = self.decide_symbols(&windows)?;
+            }
+            let mut timings = Vec::with_capacity(guesses.len());
+            let mut windows = Vec::with_capacity(guesses.len());
+            for guess in &guesses {
+                timings.push(self.symbol_timing());
+                self.read_symbol()?;
+                windows.push(self.processed_samples);
+                self.recover_timing(*guess);
+            }
+            let decided = self.decide_symbols(&windows)?;
+            match decided
+                .iter()
+                .zip(&guesses)
+                .position(|(symbol, guess)| symbol != guess)
+            {
+                None => {
+                    symbols.extend(decided);
+                    guesses.clear();
+                }
+                // timed by the symbols before, its window was right
+                Some(wrong) => {
+                    self.set_symbol_timing(timings[wrong]);
+                    symbols.extend(&decided[..wrong]);
+                    guesses = decided[wrong..].to_vec();
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    /// Where the next `count` FSK symbols start without any timing correction, stepping
+    /// over them.
+    fn untimed_windows(&mut self, count: usize) -> Vec<usize> {
+        let n = self.config.sample_number();
+        let gap = self.config.symbol_gap_samples();
+        (0..count)
+            .map(|_| {
+                self.advance(gap);
+                let window = self.processed_samples;
+                self.advance(n);
+                window
+            })
+            .collect()
+    }
+
+    /// The FSK symbols starting at `windows`, read at once and decided on every core.
+    fn decide_symbols(&mut self, windows: &[usize]) -> Result<Vec<u8>> {
+        let (Some(first), Some(last)) = (windows.first(), windows.last()) else {
+            return Ok(vec![]);
+        };
+        let n = self.config.sample_number();
+        // a symbol is `n` samples long even where our clock made fewer of it
+        self.reader
+            .read_samples(*first, last + n, &mut self.symbols)?;
+        Ok(windows
+            .par_iter()
+            .map(|window| {
+                let symbol = &self.symbols[window - first..window - first + n];
+                demodulate_symbol_with_gains(&self.config, symbol, &self.gains)
+            })
+            .collect())
+    }
+
+    fn symbol_timing(&self) -> SymbolTiming {
+        SymbolTiming {
+            processed_samples: self.processed_samples,
+            drift_remainder: self.drift_remainder,
+            previous_symbol: self.previous_symbol,
+        }
+    }
+
+    fn set_symbol_timing(&mut self, timing: SymbolTiming) {
+        self.processed_samples = timing.processed_samples;
+        self.drift_remainder = timing.drift_remainder;
+        self.previous_symbol = timing.previous_symbol;
+    }
+
+    /// Demodulate the FSK symbol after the gap at `processed_samples`, and with
+    /// `config.timing_recovery` move the next window by part of how far off this one was.
+    fn demodulate_symbol(&mut self) -> Result<u8> {
+        self.read_symbol()?;
+        let n = self.config.sample_number();
+        let symbol = demodulate_symbol_with_gains(&self.config, &self.symbols[n..], &self.gains);
+        self.recover_timing(symbol);
+        Ok(symbol)
+    }
+
+    /// Step over the gap before the next FSK symbol, and read it into `symbols` after the
+    /// symbol before, the preamble is always there.
+    fn read_symbol(&mut self) -> Result<()> {
+        let n = self.config.sample_number();
+        let gap = self.config.symbol_gap_samples();
+        self.advance(gap);
+        // after a gap as long as the timing gate, every carrier of the symbol turns on
+        if gap >= timing_gate(&self.config) {
+            self.previous_symbol = 0;
+        }
+        self.reader.read_samples(
+            self.processed_samples - n,
+            self.processed_samples + n,
+            &mut self.symbols,
+        )
+    }
+
+    /// Step over the symbol `read_symbol` read, heard as `symbol`, with
+    /// `config.timing_recovery` moving the next window by part of how far off this one was.
+    fn recover_timing(&mut self, symbol: u8) {
+        if self.config.timing_recovery {
+            let error = timing_error(&self.config, &self.symbols, self.previous_symbol, symbol);
+            // an error as large as the gate may be any larger, all of it is corrected
+            let gain = match error.abs() + 1.0 >= timing_gate(&self.config) as f64 {
+                true => 1.0,
+                false => TIMING_GAIN,
+            };
+            self.drift_remainder += gain * error;
+        }
+        self.previous_symbol = symbol;
+        self.advance(self.config.sample_number());
+    }
+
+    /// Step over `sent` samples of the sender, as many as our clock made of them.
+    fn advance(&mut self, sent: usize) {
+        let received = sent as f64 / (1.0 + self.drift) + self.drift_remainder;
+        self.drift_remainder = received.fract();
+        self.processed_samples += received as usize;
+    }
+
+    /// The `size` samples from `processed_samples` on, with `margin` more on either side,
+    /// put back onto the sender's clock. Steps over the `size`.
+    fn take_block(&mut self, size: usize, margin: usize) -> Result<Vec<f64>> {
+        let received = |sent: usize| (sent as f64 / (1.0 + self.drift)).round() as usize;
+        let samples = self.reader.take_samples(
+            self.processed_samples - received(margin),
+            self.processed_samples + received(size + margin),
+        )?;
+        self.advance(size);
+        if self.drift == 0.0 {
+            return Ok(samples);
+        }
+        let mut samples = stretch(&samples, 1.0 / (1.0 + self.drift));
+        samples.resize(size + 2 * margin, 0.0);
+        Ok(samples)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::iter::repeat;
-
-    use tracing::info;
+    use std::iter::repeat_n;
 
-    use crate::physics::prepend_preamble;
+    use crate::{
+        calibration::calibration_signal,
+        config::{BandPass, Fec},
+        crypto::Key,
+        ring_buffer::RingBuffer,
+        simulator::{awgn_seeded, clock_drift, multipath, speaker_rolloff, Echo},
+        transmitter::{modulate_message, modulate_packets},
+        wav_reader::WavSampleReader,
+    };
 
     use super::*;
 
-    struct MockSampleReader(pub Vec<f64>);
-
-    impl SampleReader for MockSampleReader {
-        fn take_samples(&mut self, start: usize, end: usize) -> Vec<f64> {
-            info!("taking sample from {} to {}", start, end);
-            assert!(start < end && end <= self.0.len());
-            self.0[start..end].to_owned()
-        }
+    /// Plays back a signal held in memory, the end of it being the end of the stream.
+    fn padded(signal: Vec<f64>) -> Vec<f64> {
+        let silence = repeat_n(0.0_f64, 10000).collect::<Vec<f64>>();
+        [silence.clone(), signal, silence].concat()
     }
 
     #[test]
     fn test_read_preamble() {
         let _ = tracing_subscriber::fmt::try_init();
-        let mut v = repeat(0.5_f64).take(10000).collect::<Vec<f64>>();
-        v = prepend_preamble(&mut v);
-        let mut receiver = Receiver::new(Box::new(MockSampleReader(v)));
-        receiver.run();
+        let v = padded(modulate_message(&AcousticConfig::default(), b"hello world").unwrap());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v)));
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_read_packets() {
+        let data = (0..200).map(|i| i as u8).collect::<Vec<u8>>();
+        let v = padded(modulate_message(&AcousticConfig::default(), &data).unwrap());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v.clone())));
+        assert_eq!(receiver.run().unwrap(), data);
+
+        // back to back, through a reader that forgets what was consumed
+        struct Consuming(RingBuffer);
+        impl SampleReader for Consuming {
+            fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+                match end <= self.0.end() {
+                    true => self.0.get(start, end),
+                    false => Err(AcousticError::EndOfStream),
+                }
+            }
+
+            fn consume(&mut self, until: usize) -> Result<()> {
+                self.0.consume(until);
+                Ok(())
+            }
+        }
+        let mut buffer = RingBuffer::new(usize::MAX);
+        buffer.extend(v.iter().map(|x| *x as f32));
+        let mut receiver = Receiver::new(Box::new(Consuming(buffer)));
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_samples_in_place() {
+        use std::sync::atomic::AtomicUsize;
+
+        /// counts the samples read, and those it had to allocate for
+        struct Counting {
+            signal: Vec<f64>,
+            read: Arc<AtomicUsize>,
+            allocated: Arc<AtomicUsize>,
+        }
+        impl SampleReader for Counting {
+            fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+                let mut samples = Vec::new();
+                self.read_samples(start, end, &mut samples)?;
+                Ok(samples)
+            }
+
+            fn read_samples(
+                &mut self,
+                start: usize,
+                end: usize,
+                buffer: &mut Vec<f64>,
+            ) -> Result<()> {
+                let samples = self
+                    .signal
+                    .get(start..end)
+                    .ok_or(AcousticError::EndOfStream)?;
+                if buffer.capacity() < samples.len() {
+                    self.allocated.fetch_add(samples.len(), Ordering::Relaxed);
+                }
+                self.read.fetch_add(samples.len(), Ordering::Relaxed);
+                buffer.clear();
+                buffer.extend_from_slice(samples);
+                Ok(())
+            }
+        }
+
+        let data = (0..100).map(|i| i as u8).collect::<Vec<u8>>();
+        let (read, allocated) = (Arc::default(), Arc::default());
+        let reader = Counting {
+            signal: padded(modulate_message(&AcousticConfig::default(), &data).unwrap()),
+            read: Arc::clone(&read),
+            allocated: Arc::clone(&allocated),
+        };
+        let mut receiver = Receiver::new(Box::new(reader));
+        assert_eq!(receiver.run().unwrap(), data);
+        // probes and symbols go into the same buffers over and over
+        let (read, allocated) = (
+            read.load(Ordering::Relaxed),
+            allocated.load(Ordering::Relaxed),
+        );
+        assert!(allocated * 10 < read, "{} of {}", allocated, read);
+    }
+
+    #[test]
+    fn test_read_packet_size() {
+        let data = (0..200).map(|i| i as u8).collect::<Vec<u8>>();
+        let config = AcousticConfig::builder().packet_size(48).build().unwrap();
+        let v = padded(modulate_message(&config, &data).unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v.clone())), config);
+        assert_eq!(receiver.run().unwrap(), data);
+
+        // packets larger than ours are dropped, the short last one is taken for a message
+        let small = AcousticConfig::builder().packet_size(16).build().unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), small);
+        assert_eq!(receiver.run().unwrap(), &data[192..]);
+    }
+
+    #[test]
+    fn test_read_events() {
+        let config = AcousticConfig::default();
+        let packets = [
+            Packet::ack(3),
+            Packet::new(PacketKind::Beacon, 0, b"here"),
+            Packet::from((0, &b"hi"[..])),
+        ];
+        let v = padded(modulate_packets(&config, &packets).unwrap());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v.clone())));
+        assert!(matches!(receiver.next_event().unwrap(), Event::Ack(3)));
+        assert!(matches!(receiver.next_event().unwrap(), Event::Beacon(p) if p.data == b"here"));
+        assert!(matches!(receiver.next_event().unwrap(), Event::Data(p) if p.data == b"hi"));
+
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v)));
+        assert_eq!(receiver.run().unwrap(), b"hi");
+    }
+
+    #[test]
+    fn test_read_addressed() {
+        let config = AcousticConfig::default();
+        let packets = [
+            Packet::from((0, &b"to 2"[..])).addressed(1, 2),
+            Packet::from((0, &b"to 3"[..])).addressed(1, 3),
+            Packet::from((0, &b"to all"[..])).addressed(1, Packet::BROADCAST),
+        ];
+        let v = padded(modulate_packets(&config, &packets).unwrap());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v.clone()))).with_address(3);
+        assert_eq!(receiver.run().unwrap(), b"to 3");
+        assert_eq!(receiver.run().unwrap(), b"to all");
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v))).promiscuous(true);
+        assert_eq!(receiver.run().unwrap(), b"to 2");
+    }
+
+    #[test]
+    fn test_read_collision() {
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let ours = modulate_packets(&config, &[Packet::from((0, &[7; 20][..]))]).unwrap();
+        assert!(preamble_overlap(&config, &ours) < 0.1);
+
+        // another sender starts a symbol and a half later
+        let theirs = modulate_packets(&config, &[Packet::from((0, &[9; 20][..]))]).unwrap();
+        let offset = n * 3 / 2;
+        let both = (0..theirs.len() + offset)
+            .map(|i| {
+                let at = |signal: &[f64], i: Option<usize>| {
+                    i.and_then(|i| signal.get(i)).copied().unwrap_or(0.0)
+                };
+                at(&ours, Some(i)) + at(&theirs, i.checked_sub(offset))
+            })
+            .collect::<Vec<f64>>();
+        let mut receiver = Receiver::new(Box::new(MemoryReader(padded(both))));
+        let events = std::iter::from_fn(|| receiver.next_event().ok()).collect::<Vec<Event>>();
+        assert!(
+            matches!(events[..], [Event::GarbledPreamble, ..]),
+            "{:?}",
+            events
+        );
+        assert!(!events.iter().any(|event| matches!(event, Event::Data(_))));
+    }
+
+    #[test]
+    fn test_read_duplicates() {
+        let config = AcousticConfig::default();
+        let data = [7; 300];
+        let packets = Packet::new_packets(&data);
+        // the second packet is resent, and the whole message once more later
+        let sent = [&packets[..2], &packets[1..2], &packets[2..], &packets[..]].concat();
+        let signal = padded(modulate_packets(&config, &sent).unwrap());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(signal.clone())));
+        assert_eq!(receiver.run().unwrap(), data);
+        assert_eq!(receiver.run().unwrap(), data);
+
+        let mut receiver = Receiver::new(Box::new(MemoryReader(signal)));
+        let events = std::iter::from_fn(|| receiver.next_event().ok()).collect::<Vec<Event>>();
+        let duplicates = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Duplicate(packet) => Some(packet.order),
+                _ => None,
+            })
+            .collect::<Vec<usize>>();
+        // without `run`, nothing tells the second message is not a repeat
+        assert_eq!(duplicates, [1, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_receiver_events() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::decode;
+
+        #[derive(Clone, Default)]
+        struct Log(Arc<Mutex<Vec<String>>>);
+
+        impl ReceiverEvents for Log {
+            fn on_preamble_detected(&mut self, _position: usize) {
+                self.0.lock().unwrap().push("preamble".into());
+            }
+
+            fn on_packet(&mut self, packet: &Packet) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("packet {}", packet.order));
+            }
+
+            fn on_malformed_packet(&mut self) {
+                self.0.lock().unwrap().push("malformed".into());
+            }
+
+            fn on_message_complete(&mut self, message: &[u8]) {
+                self.0.lock().unwrap().push(decode(message));
+            }
+        }
+
+        let config = AcousticConfig::default();
+        let packets = Packet::new_packets(&[b'a'; 200]);
+        let n = config.sample_number();
+        let mut first = modulate_packets(&config, &packets[..1]).unwrap();
+        // the header drowned out right after the preamble
+        first[4 * n..8 * n].fill(0.0);
+        let signal = padded(
+            [
+                first,
+                vec![0.0; 10000],
+                modulate_packets(&config, &packets).unwrap(),
+            ]
+            .concat(),
+        );
+        let log = Log::default();
+        let mut receiver =
+            Receiver::new(Box::new(MemoryReader(signal))).with_events(Box::new(log.clone()));
+        assert_eq!(receiver.run().unwrap(), [b'a'; 200]);
+        let expected = [
+            "preamble",
+            "malformed",
+            "preamble",
+            "packet 0",
+            "preamble",
+            "packet 1",
+            &decode(&[b'a'; 200]),
+        ];
+        assert_eq!(*log.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_receiver_stats() {
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let data = (0..200).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let packets = Packet::new_packets(&data);
+        let mut drowned = modulate_packets(&config, &packets[..1]).unwrap();
+        drowned[4 * n..8 * n].fill(0.0);
+        let signal = padded([drowned, modulate_packets(&config, &packets).unwrap()].concat());
+        let stats = |signal: Vec<f64>| {
+            let mut receiver = Receiver::new(Box::new(MemoryReader(signal)));
+            while receiver.next_event().is_ok() {}
+            receiver.stats()
+        };
+        let clean = stats(signal);
+        assert_eq!(
+            (clean.preambles, clean.packets, clean.malformed, clean.bytes),
+            (3, 2, 1, 200)
+        );
+        assert_eq!(clean.forged, 0);
+        let expected = 1600.0 / clean.seconds;
+        assert!((clean.throughput() - expected).abs() < 1e-9);
+
+        // spread spectrum gets through a lot of noise
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Dsss)
+            .build()
+            .unwrap();
+        let message = modulate_message(&config, &data[..20]).unwrap();
+        let mut receiver = Receiver::with_config(
+            Box::new(MemoryReader(padded(awgn_seeded(&message, 0.0, 6)))),
+            config,
+        );
+        assert_eq!(receiver.run().unwrap(), data[..20]);
+        let noisy = receiver.stats();
+        assert_eq!(noisy.packets, 1);
+        let (clean_snr, noisy_snr) = (clean.average_snr().unwrap(), noisy.average_snr().unwrap());
+        assert!(clean_snr > noisy_snr + 20.0, "{clean_snr} {noisy_snr}");
+        // the noise is spread over the whole band, little of it is near the tones
+        assert!(noisy_snr > 0.0, "{noisy_snr}");
+    }
+
+    #[test]
+    fn test_read_progress() {
+        let config = AcousticConfig::default();
+        let data = [9; 300];
+        let signal = padded(modulate_packets(&config, &Packet::new_message(&data)).unwrap());
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut receiver = Receiver::new(Box::new(MemoryReader(signal))).with_events(Box::new(tx));
+        assert_eq!(receiver.run().unwrap(), data);
+        let progress = rx
+            .try_iter()
+            .map(|progress| (progress.bytes, progress.fraction()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            progress,
+            [
+                (128, Some(128.0 / 300.0)),
+                (256, Some(256.0 / 300.0)),
+                (300, Some(1.0))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_resynchronised() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Skipped(Arc<Mutex<Vec<String>>>);
+
+        impl ReceiverEvents for Skipped {
+            fn on_packets_skipped(&mut self, source: u8, orders: Range<usize>) {
+                self.0.lock().unwrap().push(format!("{source} {orders:?}"));
+            }
+        }
+
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let data = (0..500).map(|i| (i * 3) as u8).collect::<Vec<u8>>();
+        let packets = Packet::new_packets(&data)
+            .into_iter()
+            .map(|packet| packet.addressed(2, 0))
+            .collect::<Vec<Packet>>();
+        // lock lost on the second and third packet, right after their preambles
+        let signal = packets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, packet)| {
+                let mut signal = modulate_packets(&config, std::slice::from_ref(packet)).unwrap();
+                if i == 1 || i == 2 {
+                    signal[4 * n..].fill(0.0);
+                }
+                signal
+            })
+            .collect::<Vec<f64>>();
+        let skipped = Skipped::default();
+        let mut receiver = Receiver::new(Box::new(MemoryReader(padded(signal))))
+            .with_events(Box::new(skipped.clone()));
+        let message = receiver.run().unwrap();
+        let kept = [&data[..128], &data[384..]].concat();
+        assert_eq!(message, kept);
+        assert_eq!(*skipped.0.lock().unwrap(), ["2 1..3"]);
+        assert_eq!(receiver.stats().skipped, 2);
+    }
+
+    #[test]
+    fn test_read_message_header() {
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let data = (0..300).map(|i| (i * 5) as u8).collect::<Vec<u8>>();
+        let packets = Packet::new_message(&data);
+        // the second data packet is lost the first time, so the message is sent again
+        let first = packets
+            .iter()
+            .enumerate()
+            .flat_map(|(i, packet)| {
+                let mut signal = modulate_packets(&config, std::slice::from_ref(packet)).unwrap();
+                if i == 2 {
+                    signal[4 * n..].fill(0.0);
+                }
+                signal
+            })
+            .collect::<Vec<f64>>();
+        let signal = padded([first, modulate_packets(&config, &packets).unwrap()].concat());
+        let mut receiver = Receiver::new(Box::new(MemoryReader(signal)));
+        assert_eq!(receiver.run().unwrap(), data);
+        assert_eq!(receiver.stats().skipped, 1);
+    }
+
+    /// a quiet room, for as long as anyone listens
+    struct Silence;
+
+    impl SampleReader for Silence {
+        fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+            Ok(vec![0.0; end - start])
+        }
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let timeout = Duration::from_millis(200);
+        let mut receiver = Receiver::new(Box::new(Silence)).with_timeout(timeout);
+        let started = Instant::now();
+        assert!(matches!(receiver.run(), Err(AcousticError::Timeout)));
+        assert!(started.elapsed() >= timeout);
+        assert!(started.elapsed() < timeout * 5);
+        // every run waits as long again
+        let started = Instant::now();
+        assert!(matches!(receiver.run(), Err(AcousticError::Timeout)));
+        assert!(started.elapsed() >= timeout);
+
+        let signal = padded(modulate_message(&AcousticConfig::default(), b"hello world").unwrap());
+        let mut receiver =
+            Receiver::new(Box::new(MemoryReader(signal))).with_timeout(Duration::from_secs(60));
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_stop() {
+        let mut receiver = Receiver::new(Box::new(Silence));
+        let stop = receiver.stop_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            stop.stop();
+        });
+        assert!(matches!(receiver.run(), Err(AcousticError::Stopped)));
+        assert!(matches!(receiver.next_event(), Err(AcousticError::Stopped)));
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn test_poll() {
+        use crate::recorder::Recorder;
+
+        let config = AcousticConfig::default();
+        let signal = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut recorder = Recorder::new().with_timeout(Duration::ZERO);
+        let recorded = recorder.clone_handle();
+        let mut receiver = Receiver::new(Box::new(recorder));
+        assert!(receiver.poll().unwrap().is_none());
+        let mut polls = 0;
+        let mut events = Vec::new();
+        for chunk in signal.chunks(4096) {
+            recorded.push(chunk.iter().map(|x| *x as f32)).unwrap();
+            polls += 1;
+            events.extend(receiver.poll().unwrap());
+        }
+        assert!(polls > 10);
+        assert!(
+            matches!(&events[..], [Event::Data(packet)] if packet.data == b"hello world"),
+            "{:?}",
+            events
+        );
+        // nothing more, and no waiting for it
+        assert!(receiver.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_link_down() {
+        /// a recording that stops growing, like a microphone with the peer gone
+        struct Quiet(Vec<f64>);
+
+        impl SampleReader for Quiet {
+            fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+                self.0
+                    .get(start..end)
+                    .map(<[f64]>::to_vec)
+                    .ok_or(AcousticError::Timeout)
+            }
+        }
+
+        let config = AcousticConfig::default();
+        let signal = padded(modulate_packets(&config, &[Packet::from((0, &b"hi"[..]))]).unwrap());
+        let mut receiver = Receiver::new(Box::new(Quiet(signal)))
+            .with_liveness(Liveness::new(Duration::from_millis(100)));
+        assert!(matches!(receiver.next_event(), Ok(Event::Data(_))));
+        assert!(receiver.link_alive());
+        let mut next = || loop {
+            match receiver.next_event() {
+                Err(AcousticError::Timeout) => std::thread::sleep(Duration::from_millis(10)),
+                event => return event,
+            }
+        };
+        assert!(matches!(next(), Ok(Event::LinkDown)));
+        // reported once
+        assert!(matches!(receiver.next_event(), Err(AcousticError::Timeout)));
+        assert!(!receiver.link_alive());
+    }
+
+    #[test]
+    fn test_read_encrypted() {
+        let key = Key::new([7; 32]);
+        let config = AcousticConfig::builder().key(key).build().unwrap();
+        let v = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v.clone())), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+
+        // someone without the key cannot read it, and can tell it is not noise
+        let config = AcousticConfig::builder()
+            .key(Key::new([8; 32]))
+            .build()
+            .unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert!(matches!(
+            receiver.next_event(),
+            Err(AcousticError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            receiver.next_event(),
+            Err(AcousticError::EndOfStream)
+        ));
+    }
+
+    #[test]
+    fn test_read_signed() {
+        let mac_key = Key::new([9; 32]);
+        let config = AcousticConfig::builder()
+            .mac_key(mac_key.clone())
+            .build()
+            .unwrap();
+        let v = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v.clone())), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+
+        // encryption and signing stack
+        let config = AcousticConfig::builder()
+            .key(Key::new([7; 32]))
+            .mac_key(mac_key)
+            .build()
+            .unwrap();
+        let signed = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(signed)), config.clone());
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+
+        let config = AcousticConfig::builder()
+            .mac_key(Key::new([8; 32]))
+            .build()
+            .unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert!(matches!(
+            receiver.next_event(),
+            Err(AcousticError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn test_read_custom_config() {
+        let config = AcousticConfig::builder()
+            .symbol_time(0.05)
+            .carrier_freqs(&[1000.0, 1500.0, 2000.0, 2500.0])
+            .preamble_freqs([3000.0, 3500.0])
+            .build()
+            .unwrap();
+        let v = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_read_fast_symbols() {
+        // a quiet room, a quarter of the energy per symbol has to do
+        for symbol_time in [0.05, 0.025] {
+            let config = AcousticConfig::builder()
+                .symbol_time(symbol_time)
+                .build()
+                .unwrap();
+            let v = padded(awgn_seeded(
+                &modulate_message(&config, b"hello world").unwrap(),
+                20.0,
+                5,
+            ));
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+            assert_eq!(receiver.run().unwrap(), b"hello world", "{symbol_time} s");
+        }
+    }
+
+    #[test]
+    fn test_read_carrier_count() {
+        for count in [6, 8] {
+            let config = AcousticConfig::builder()
+                .carrier_count(count)
+                .build()
+                .unwrap();
+            let v = padded(modulate_message(&config, b"hello world").unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+            assert_eq!(receiver.run().unwrap(), b"hello world", "{count} carriers");
+        }
+    }
+
+    #[test]
+    fn test_read_reverberant() {
+        // a small room, the speaker a meter from the microphone
+        let echoes = [(0.003, 0.6), (0.007, -0.4), (0.012, 0.3), (0.02, 0.15)]
+            .map(|(delay, gain)| Echo { delay, gain });
+        let echoed = |config: &AcousticConfig| {
+            padded(multipath(
+                &modulate_message(config, b"hello world").unwrap(),
+                &echoes,
+                config.sample_rate,
+            ))
+        };
+        // the echoes fade some carriers much more than others, the pilot evens them out
+        let config = AcousticConfig::builder().pilot(true).build().unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(echoed(&config))), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+
+        let config = AcousticConfig::default();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(echoed(&config))), config);
+        assert!(receiver.run().is_err());
+    }
+
+    #[test]
+    fn test_read_gaps() {
+        // a hall, the echo of every symbol drowns the middle of the next one
+        let echoes = [Echo {
+            delay: 0.05,
+            gain: 0.7,
+        }];
+        let echoed = |config: &AcousticConfig| {
+            padded(multipath(
+                &modulate_message(config, &[0x5a; 150]).unwrap(),
+                &echoes,
+                config.sample_rate,
+            ))
+        };
+        let config = AcousticConfig::default();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(echoed(&config))), config);
+        assert!(receiver.run().is_err());
+
+        let config = AcousticConfig::builder()
+            .symbol_gap(0.05)
+            .packet_gap(0.1)
+            .build()
+            .unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(echoed(&config))), config);
+        assert_eq!(receiver.run().unwrap(), [0x5a; 150]);
+    }
+
+    #[test]
+    fn test_read_drifting() {
+        let config = AcousticConfig::default();
+        let data = (0..100).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        // cheap sound cards, by the end of the packet the symbols are 6% off the grid
+        for ppm in [-300.0, 300.0] {
+            let v = padded(clock_drift(&modulate_message(&config, &data).unwrap(), ppm));
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+            assert_eq!(receiver.run().unwrap(), data, "{ppm} ppm");
+        }
+    }
+
+    #[test]
+    fn test_read_drift_compensated() {
+        // off the grid by the end of a packet without compensation, eight symbols a byte make
+        // DPSK packets the longest
+        let data = (0..120).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        for (modulation, ppm) in [(Modulation::Dpsk, 500.0), (Modulation::Fsk, 1200.0)] {
+            let config = AcousticConfig::builder()
+                .modulation(modulation)
+                .build()
+                .unwrap();
+            for ppm in [-ppm, ppm] {
+                let v = padded(clock_drift(&modulate_message(&config, &data).unwrap(), ppm));
+                let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+                assert_eq!(receiver.run().unwrap(), data, "{modulation:?} at {ppm} ppm");
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_offset_symbols() {
+        let data = (0..100).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        // the preamble detected a third of a symbol off where the data starts
+        let offset = |config: &AcousticConfig, shift: isize| {
+            let n = config.sample_number();
+            let v = modulate_message(config, &data).unwrap();
+            let gap = repeat_n(0.0, shift.max(0) as usize).collect::<Vec<f64>>();
+            padded([&v[..4 * n], &gap, &v[4 * n + (-shift).max(0) as usize..]].concat())
+        };
+        for recovery in [true, false] {
+            let config = AcousticConfig::builder()
+                .timing_recovery(recovery)
+                .build()
+                .unwrap();
+            let shift = config.sample_number() as isize * 7 / 20;
+            for shift in [-shift, shift] {
+                let v = offset(&config, shift);
+                let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+                let received = receiver.run().ok();
+                assert_eq!(
+                    received.as_ref() == Some(&data),
+                    recovery,
+                    "{shift} samples late"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_parallel() {
+        let data = (0..200).map(|i| (i * 13) as u8).collect::<Vec<u8>>();
+        let configs = [
+            AcousticConfig::builder().timing_recovery(false),
+            AcousticConfig::builder().timing_recovery(true),
+            AcousticConfig::builder().modulation(Modulation::Ofdm),
+            AcousticConfig::builder().modulation(Modulation::Qpsk),
+            AcousticConfig::builder().modulation(Modulation::Css),
+        ];
+        for builder in configs {
+            let config = builder.parallel(true).build().unwrap();
+            let v = padded(modulate_message(&config, &data).unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+            assert_eq!(receiver.run().unwrap(), data, "{:?}", config.modulation);
+        }
+    }
+
+    #[test]
+    fn test_read_parallel_timed() {
+        // timing recovery is on by default, every core has to come to the symbols one core
+        // does, through clock drift, noise and symbols late enough for some guesses to be
+        // wrong
+        let data = (0..120).map(|i| (i * 11) as u8).collect::<Vec<u8>>();
+        let config = AcousticConfig::default();
+        let n = config.sample_number();
+        let v = modulate_message(&config, &data).unwrap();
+        let late = [&v[..4 * n], &vec![0.0; n * 7 / 20], &v[4 * n..]].concat();
+        let v = padded(awgn_seeded(&clock_drift(&late, 300.0), 0.0, 8));
+        let [one, every] = [false, true].map(|parallel| {
+            let config = AcousticConfig {
+                parallel,
+                ..config.clone()
+            };
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v.clone())), config);
+            (receiver.run().ok(), receiver.stats())
+        });
+        assert_eq!(every.0.as_ref(), Some(&data));
+        assert_eq!(one.0, every.0);
+        assert_eq!(one.1, every.1);
+    }
+
+    #[test]
+    fn test_read_calibrated() {
+        let config = AcousticConfig::default();
+        let data = (0..60).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        // a sender this fast plays the carriers about 25 Hz too high
+        let fast = |signal: Vec<f64>| padded(clock_drift(&signal, 8000.0));
+        let v = fast(
+            [
+                calibration_signal(&config),
+                modulate_message(&config, &data).unwrap(),
+            ]
+            .concat(),
+        );
+
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+        let correction = receiver.calibrate().unwrap();
+        assert!((correction - 1.008).abs() < 2e-4, "{}", correction);
+        assert_eq!(receiver.run().unwrap(), data);
+
+        let v = fast(modulate_message(&config, &data).unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert!(receiver.run().is_err());
+    }
+
+    #[test]
+    fn test_read_ultrasonic() {
+        let config = AcousticConfig::ultrasonic();
+        let v = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_read_ofdm() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Ofdm)
+            .build()
+            .unwrap();
+        for n in [1, 128, 3000] {
+            let data = (0..n).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+            let v = padded(modulate_message(&config, &data).unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config.clone());
+            assert_eq!(receiver.run().unwrap(), data, "n={n}");
+        }
+    }
+
+    #[test]
+    fn test_read_dpsk() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Dpsk)
+            .build()
+            .unwrap();
+        let data = (0..40).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        // the speaker's polarity and phase do not matter
+        let inverted = modulate_message(&config, &data)
+            .unwrap()
+            .iter()
+            .map(|x| -x)
+            .collect::<Vec<f64>>();
+        let v = padded(awgn_seeded(&inverted, 10.0, 3));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_qpsk() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Qpsk)
+            .build()
+            .unwrap();
+        let data = (0..80).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let v = padded(awgn_seeded(
+            &modulate_message(&config, &data).unwrap(),
+            15.0,
+            4,
+        ));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_qam16() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Qam16)
+            .build()
+            .unwrap();
+        let data = (0..160).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let quiet = modulate_message(&config, &data)
+            .unwrap()
+            .iter()
+            .map(|x| 0.1 * x)
+            .collect::<Vec<f64>>();
+        let v = padded(awgn_seeded(&quiet, 25.0, 5));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_dsss() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Dsss)
+            .build()
+            .unwrap();
+        let data = (0..20).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let v = padded(awgn_seeded(
+            &modulate_message(&config, &data).unwrap(),
+            0.0,
+            6,
+        ));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_css() {
+        let config = AcousticConfig::builder()
+            .modulation(Modulation::Css)
+            .build()
+            .unwrap();
+        let data = (0..100).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let v = padded(awgn_seeded(
+            &modulate_message(&config, &data).unwrap(),
+            0.0,
+            8,
+        ));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+    }
+
+    #[test]
+    fn test_read_fec() {
+        for modulation in [Modulation::Fsk, Modulation::Ofdm] {
+            let config = AcousticConfig::builder()
+                .modulation(modulation)
+                .fec(Fec::Hamming74)
+                .build()
+                .unwrap();
+            let v = padded(modulate_message(&config, b"hello world").unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+            assert_eq!(receiver.run().unwrap(), b"hello world", "{modulation:?}");
+        }
+    }
+
+    #[test]
+    fn test_read_scrambled() {
+        let data = [0; 200];
+        for modulation in [Modulation::Fsk, Modulation::Ofdm] {
+            let config = AcousticConfig::builder()
+                .modulation(modulation)
+                .scramble(true)
+                .build()
+                .unwrap();
+            let v = padded(modulate_message(&config, &data).unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+            assert_eq!(receiver.run().unwrap(), data, "{modulation:?}");
+        }
+    }
+
+    #[test]
+    fn test_read_muffled() {
+        // 0x9 sounds the loudest carrier together with the quietest one
+        let data = [0x99; 20];
+        let muffled = |config: &AcousticConfig| {
+            padded(speaker_rolloff(
+                &modulate_message(config, &data).unwrap(),
+                1500.0,
+                config.sample_rate,
+            ))
+        };
+        let config = AcousticConfig::builder().pilot(true).build().unwrap();
+        let v = muffled(&config);
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), data);
+
+        // without the pilot, the quiet carrier is lost next to the loud one
+        let config = AcousticConfig::default();
+        let v = muffled(&config);
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert!(receiver.run().is_err());
+    }
+
+    #[test]
+    fn test_read_shaped() {
+        for modulation in [Modulation::Fsk, Modulation::Ofdm] {
+            let config = AcousticConfig::builder()
+                .modulation(modulation)
+                .pulse_rolloff(0.5)
+                .build()
+                .unwrap();
+            let v = padded(modulate_message(&config, b"hello world").unwrap());
+            let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+            assert_eq!(receiver.run().unwrap(), b"hello world", "{modulation:?}");
+        }
+
+        let config = AcousticConfig::builder().ramp_time(0.03).build().unwrap();
+        let v = padded(modulate_message(&config, b"hello world").unwrap());
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_read_band_passed() {
+        // mains hum and its harmonics, ten times louder than the signal
+        let hum = |v: Vec<f64>| {
+            v.iter()
+                .enumerate()
+                .map(|(i, x)| {
+                    let phase = 2.0 * std::f64::consts::PI * 100.0 * i as f64 / SAMPLE_RATE;
+                    x + (1..=5)
+                        .map(|k| 10.0 * (phase * k as f64).sin())
+                        .sum::<f64>()
+                })
+                .collect::<Vec<f64>>()
+        };
+        let config = AcousticConfig::default();
+        let v = hum(padded(modulate_message(&config, b"hello world").unwrap()));
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v.clone())), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+
+        let config = AcousticConfig::builder()
+            .band_pass(BandPass::Off)
+            .build()
+            .unwrap();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert!(receiver.run().is_err());
+    }
+
+    #[test]
+    fn test_read_interleaved_burst() {
+        let config = AcousticConfig::builder()
+            .fec(Fec::Hamming74)
+            .interleave_depth(16)
+            .build()
+            .unwrap();
+        let mut v = padded(modulate_message(&config, b"hello world").unwrap());
+        // three symbols of the payload drop out
+        let symbol = config.sample_number();
+        let end = v.len() - 10000;
+        v[end - 30 * symbol..end - 27 * symbol].fill(0.0);
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(v)), config);
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_read_noisy() {
+        let v = awgn_seeded(
+            &padded(modulate_message(&AcousticConfig::default(), b"hello world").unwrap()),
+            20.0,
+            7,
+        );
+        let mut receiver = Receiver::new(Box::new(MemoryReader(v)));
+        assert_eq!(receiver.run().unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_min_preamble_votes() {
+        // a ten second symbol takes thousands of votes, more than a byte holds
+        let config = AcousticConfig::builder().symbol_time(10.0).build().unwrap();
+        let receiver = Receiver::with_config(Box::new(MemoryReader(Vec::new())), config);
+        assert_eq!(
+            receiver.min_preamble_votes(),
+            441000 / FFT_STEP as u32 * 3 / 5
+        );
     }
 
     #[test]
+    #[ignore = "reads recorder.wav left by test_recorder"]
     fn test_read_zeros() {
         let _ = tracing_subscriber::fmt::try_init();
-        let mut reader = hound::WavReader::open("recorder.wav").unwrap();
-        let samples: Vec<f64> = reader.samples::<f32>().map(|f| f.unwrap() as f64).collect();
-        println!("{}", samples.len());
-        let mut receiver = Receiver::new(Box::new(MockSampleReader(samples)));
-        receiver.run();
+        let config = AcousticConfig::default();
+        let reader = WavSampleReader::open("recorder.wav", &config).unwrap();
+        let mut receiver = Receiver::with_config(Box::new(reader), config);
+        receiver.run().unwrap();
     }
 }
@@ -0,0 +1,964 @@
+//! # Transmitter
+//!
+//! Plays modulated audio directly from the speakers. Data is cut into packets, each packet
+//! is sealed, modulated and prefixed with a preamble, exactly the way the `Receiver` expects
+//! to find it in the recorded audio.
+//!
+//! A signal can have a channel per speaker as well, see `Transmitter::play_channels`. With
+//! carrier sense, packets wait until no one else is sending, see `carrier_sense`.
+//!
+//! Packets are modulated one at a time as they are played, on a thread a little ahead of
+//! the device, so long messages never sit in memory as a whole signal, see `Modulated`.
+//!
+//! With a duty cycle, packets go out in bursts that fit its budget, each held back until
+//! it does, see `duty_cycle`.
+//!
+//! Whatever is played, or written to a wav file, is turned down to
+//! `AcousticConfig::amplitude` first, and clipped softly, see `output_level`.
+//!
+//! A signal can go anywhere that takes samples, the speaker, a wav file or memory, through
+//! a `SampleSink`, the way a `SampleReader` feeds the receiver from anywhere, see
+//! `write_stream`. Tests capture what would be played in a `Vec<f64>`.
+
+use std::iter::repeat_n;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, SampleRate, SizedSample};
+use tracing::{error, info, warn};
+
+use crate::carrier_sense::CarrierSense;
+use crate::config::{AcousticConfig, Modulation};
+use crate::crypto;
+use crate::device::output_device;
+use crate::duty_cycle::DutyCycle;
+use crate::error::{AcousticError, Result};
+use crate::fec;
+use crate::interleaver::interleave;
+use crate::physics::{
+    css_modulate, dpsk_modulate, dsss_modulate, modulate_bits_into, modulate_symbol_into,
+    ofdm_modulate, pilot_bits, prepend_preamble, qam16_modulate, qpsk_modulate, LowPass,
+};
+use crate::ports::DEFAULT_PORT;
+use crate::progress::{OnProgress, Progress};
+use crate::queue::TransmitQueue;
+use crate::scrambler::Scrambler;
+use crate::Packet;
+
+/// frames of a signal handed to the output stream at once
+const CHUNK_FRAMES: usize = 4096;
+
+/// chunks modulated ahead of the output stream, two seconds or so
+const CHUNKS_AHEAD: usize = 24;
+
+/// how often playback is checked for packets that went out, see `Transmitter::with_progress`
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Samples being played by the output stream.
+struct Playback {
+    /// frames of every channel of the signal, interleaved, a chunk at a time
+    chunks: Receiver<Vec<f32>>,
+    /// what is left of the chunk being played
+    chunk: std::vec::IntoIter<f32>,
+    channels: usize,
+    /// the frame being played, `channels` samples filled in place by `next_frame`
+    frame: Vec<f32>,
+    /// frames of the signal handed to the device so far
+    played: Arc<AtomicUsize>,
+    /// frames played as silence because the next chunk was not ready
+    late: usize,
+    /// called once every sample has been handed to the device
+    done: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Playback {
+    /// Put the next frame into `frame`, silence if the chunk is late. False once the signal
+    /// is over, with `frame` silent.
+    fn next_frame(&mut self) -> bool {
+        if self.chunk.len() == 0 {
+            match self.chunks.try_recv() {
+                Ok(chunk) => self.chunk = chunk.into_iter(),
+                Err(TryRecvError::Empty) => {
+                    self.late += 1;
+                    self.frame.fill(0.0);
+                    return true;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.frame.fill(0.0);
+                    return false;
+                }
+            }
+        }
+        self.played.fetch_add(1, Ordering::Relaxed);
+        for x in self.frame.iter_mut() {
+            *x = self.chunk.next().unwrap_or(0.0);
+        }
+        true
+    }
+}
+
+type PlaybackHandle = Arc<Mutex<Playback>>;
+
+/// fraction of full scale above which samples are bent towards it, see `output_level`
+const CLIP_KNEE: f64 = 0.9;
+
+pub struct Transmitter {
+    device: cpal::Device,
+    stream_config: cpal::SupportedStreamConfig,
+    config: AcousticConfig,
+    /// source of every packet we send
+    address: u8,
+    /// what tells whether someone else is sending, if we listen before we talk
+    carrier_sense: Option<CarrierSense>,
+    /// how much airtime we may take, if it is limited
+    duty_cycle: Option<DutyCycle>,
+    /// told whenever another packet was played, if anyone is
+    progress: Option<OnProgress>,
+    /// frames of the signal being played handed to the device so far
+    played: Arc<AtomicUsize>,
+}
+
+impl Transmitter {
+    pub fn new() -> Result<Transmitter> {
+        Self::with_config(AcousticConfig::default())
+    }
+
+    /// Open the default output device, preferring a mono config at the configured sample rate.
+    pub fn with_config(acoustic_config: AcousticConfig) -> Result<Transmitter> {
+        Self::with_device(acoustic_config, output_device(None)?)
+    }
+
+    /// Play through `device`, preferring a mono config at the configured sample rate.
+    pub fn with_device(
+        acoustic_config: AcousticConfig,
+        device: cpal::Device,
+    ) -> Result<Transmitter> {
+        Self::with_device_channels(acoustic_config, device, 1)
+    }
+
+    /// Play through `device`, preferring a config with at least `channels` channels, and as
+    /// few as possible beyond that, at the configured sample rate.
+    pub fn with_device_channels(
+        acoustic_config: AcousticConfig,
+        device: cpal::Device,
+        channels: u16,
+    ) -> Result<Transmitter> {
+        let name = device.name()?;
+        info!("Output device: {}", name);
+
+        let sample_rate = SampleRate(acoustic_config.sample_rate as u32);
+        // enough channels first, then as few as possible
+        let rank = |cfg_channels: u16| (cfg_channels < channels, cfg_channels);
+        let mut config = device.default_output_config()?;
+        let mut supported = Vec::new();
+        for cfg in device.supported_output_configs()? {
+            supported.push(cfg.min_sample_rate().0..=cfg.max_sample_rate().0);
+            if cfg.min_sample_rate() > sample_rate || cfg.max_sample_rate() < sample_rate {
+                continue;
+            }
+            if config.sample_rate() != sample_rate || rank(cfg.channels()) < rank(config.channels())
+            {
+                config = cfg.with_sample_rate(sample_rate);
+            }
+        }
+
+        if config.sample_rate() != sample_rate {
+            supported.sort_by_key(|range| (*range.start(), *range.end()));
+            supported.dedup();
+            return Err(AcousticError::UnsupportedSampleRate {
+                device: name,
+                rate: sample_rate.0,
+                supported,
+            });
+        }
+
+        info!("output config: {:?}", config);
+        Ok(Transmitter {
+            device,
+            stream_config: config,
+            config: acoustic_config,
+            address: 0,
+            carrier_sense: None,
+            duty_cycle: None,
+            progress: None,
+            played: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// send from `address` instead of 0
+    pub fn with_address(mut self, address: u8) -> Transmitter {
+        self.address = address;
+        self
+    }
+
+    pub fn config(&self) -> &AcousticConfig {
+        &self.config
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// wait for the channel to be free before sending packets
+    pub fn with_carrier_sense(mut self, carrier_sense: CarrierSense) -> Transmitter {
+        self.carrier_sense = Some(carrier_sense);
+        self
+    }
+
+    /// stay on the air for no more than `duty_cycle` allows
+    pub fn with_duty_cycle(mut self, duty_cycle: DutyCycle) -> Transmitter {
+        self.duty_cycle = Some(duty_cycle);
+        self
+    }
+
+    /// tell `on_progress` how much of what `send_packets` plays is out, see `progress`
+    pub fn with_progress(
+        mut self,
+        on_progress: impl FnMut(&Progress) + Send + 'static,
+    ) -> Transmitter {
+        self.progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Modulate `data` and play it to every receiver, blocking until playback finishes.
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        self.send_to(Packet::BROADCAST, data)
+    }
+
+    /// Modulate `data` and play it to the receiver at `destination`, blocking until playback
+    /// finishes.
+    pub fn send_to(&mut self, destination: u8, data: &[u8]) -> Result<()> {
+        self.send_to_port(destination, DEFAULT_PORT, data)
+    }
+
+    /// Like `send_to`, to `port` at `destination`, see `ports`.
+    pub fn send_to_port(&mut self, destination: u8, port: u8, data: &[u8]) -> Result<()> {
+        let packets = Packet::new_packets_sized(data, self.config.packet_size)
+            .into_iter()
+            .map(|packet| packet.addressed(self.address, destination).on_port(port))
+            .collect::<Vec<Packet>>();
+        self.send_packets(&packets)
+    }
+
+    /// Play what `queue` holds, a packet at a time and the most urgent first, until it is
+    /// empty, see `queue`. Every packet goes out from our address. Returns how many did.
+    pub fn send_queued(&mut self, queue: &TransmitQueue) -> Result<usize> {
+        let mut sent = 0;
+        while let Some(packet) = queue.pop() {
+            let destination = packet.destination;
+            self.send_packets(&[packet.addressed(self.address, destination)])?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
+
+    /// Modulate packets of any kind and play them, blocking until playback finishes. With
+    /// carrier sense, `AcousticError::ChannelBusy` if someone else does not stop sending.
+    /// With a duty cycle, `AcousticError::AirtimeExceeded` if a packet alone takes more
+    /// than its budget.
+    pub fn send_packets(&mut self, packets: &[Packet]) -> Result<()> {
+        let started = Instant::now();
+        let airtimes = match self.duty_cycle {
+            Some(_) => packets
+                .iter()
+                .map(|packet| airtime(&self.config, std::slice::from_ref(packet)))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        let gap = Duration::from_secs_f64(self.config.packet_gap);
+        let mut first = 0;
+        loop {
+            let (end, burst) = match &self.duty_cycle {
+                Some(duty_cycle) if first < packets.len() => {
+                    let (count, burst) = duty_cycle.fitting(&airtimes[first..], gap);
+                    duty_cycle.wait(burst)?;
+                    (first + count, Some(burst))
+                }
+                _ => (packets.len(), None),
+            };
+            if let Some(carrier_sense) = &mut self.carrier_sense {
+                carrier_sense.wait_idle()?;
+            }
+            if let (Some(duty_cycle), Some(burst)) = (&mut self.duty_cycle, burst) {
+                duty_cycle.record(Instant::now(), burst);
+            }
+            self.play_packets(packets, first..end, started)?;
+            if end >= packets.len() {
+                return Ok(());
+            }
+            first = end;
+        }
+    }
+
+    /// Play the packets of `packets` in `burst`, reporting progress on all of them since
+    /// `started`.
+    fn play_packets(
+        &mut self,
+        packets: &[Packet],
+        burst: Range<usize>,
+        started: Instant,
+    ) -> Result<()> {
+        let Some(mut on_progress) = self.progress.take() else {
+            return self.play_stream(Modulated::new(&self.config, &packets[burst])?);
+        };
+        let result = self.play_reporting(packets, burst, started, &mut on_progress);
+        self.progress = Some(on_progress);
+        result
+    }
+
+    /// Play the packets of `packets` in `burst`, telling `on_progress` whenever one more of
+    /// them is out.
+    fn play_reporting(
+        &mut self,
+        packets: &[Packet],
+        burst: Range<usize>,
+        started: Instant,
+        on_progress: &mut OnProgress,
+    ) -> Result<()> {
+        let first = burst.start;
+        let mut modulated = Modulated::new(&self.config, &packets[burst.clone()])?;
+        let (ends_tx, ends) = channel();
+        let mut told = 0;
+        let signal = std::iter::from_fn(move || {
+            let sample = modulated.next();
+            for end in &modulated.packet_ends()[told..] {
+                let _ = ends_tx.send(*end);
+            }
+            told = modulated.packet_ends().len();
+            sample
+        });
+        let (tx, rx) = channel();
+        let _stream = self.start_stream(signal, move || {
+            let _ = tx.send(());
+        })?;
+        let mut known_ends = Vec::new();
+        let mut sent = 0;
+        loop {
+            let finished = match rx.recv_timeout(PROGRESS_INTERVAL) {
+                Ok(()) => true,
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(AcousticError::PlaybackInterrupted)
+                }
+            };
+            known_ends.extend(ends.try_iter());
+            let played = self.played.load(Ordering::Relaxed);
+            let out = match finished {
+                true => burst.len(),
+                false => known_ends.iter().take_while(|end| **end <= played).count(),
+            };
+            if out > sent {
+                sent = out;
+                on_progress(&Progress::of_packets(
+                    packets,
+                    first + sent,
+                    started.elapsed(),
+                ));
+            }
+            if finished {
+                info!("Playing finished");
+                return Ok(());
+            }
+        }
+    }
+
+    /// A sink that plays what is written to it as it comes, see `write_stream`.
+    pub fn sink(&mut self) -> PlaybackSink<'_> {
+        let (done_tx, done) = channel();
+        PlaybackSink {
+            transmitter: self,
+            chunks: None,
+            stream: None,
+            done_tx: Some(done_tx),
+            done,
+        }
+    }
+
+    /// Play a raw signal, blocking until playback finishes.
+    pub fn play(&mut self, signal: &[f64]) -> Result<()> {
+        let (tx, rx) = channel();
+        let _stream = self.start(signal, move || {
+            let _ = tx.send(());
+        })?;
+        rx.recv().map_err(|_| AcousticError::PlaybackInterrupted)?;
+        info!("Playing finished");
+        Ok(())
+    }
+
+    /// Play a signal as it is generated, like `Modulated`, blocking until playback finishes.
+    pub fn play_stream(
+        &mut self,
+        signal: impl Iterator<Item = f64> + Send + 'static,
+    ) -> Result<()> {
+        let (tx, rx) = channel();
+        let _stream = self.start_stream(signal, move || {
+            let _ = tx.send(());
+        })?;
+        rx.recv().map_err(|_| AcousticError::PlaybackInterrupted)?;
+        info!("Playing finished");
+        Ok(())
+    }
+
+    /// Play a signal per channel, the first on the left speaker, blocking until playback
+    /// finishes. A device with fewer speakers plays them mixed down.
+    pub fn play_channels(&mut self, channels: &[&[f64]]) -> Result<()> {
+        let (tx, rx) = channel();
+        let _stream = self.start_channels(channels, move || {
+            let _ = tx.send(());
+        })?;
+        rx.recv().map_err(|_| AcousticError::PlaybackInterrupted)?;
+        info!("Playing finished");
+        Ok(())
+    }
+
+    /// Start playing a raw signal and return right away. `done` is called from the cpal
+    /// callback once the signal is out, the returned stream has to be kept until then.
+    pub fn start(
+        &mut self,
+        signal: &[f64],
+        done: impl FnOnce() + Send + 'static,
+    ) -> Result<cpal::Stream> {
+        self.start_channels(&[signal], done)
+    }
+
+    /// Like `start`, generating the signal on a thread of its own, a little ahead of the
+    /// device, see `play_stream`.
+    pub fn start_stream(
+        &mut self,
+        signal: impl Iterator<Item = f64> + Send + 'static,
+        done: impl FnOnce() + Send + 'static,
+    ) -> Result<cpal::Stream> {
+        let (tx, rx) = sync_channel(CHUNKS_AHEAD);
+        let config = self.config.clone();
+        thread::spawn(move || {
+            // trailing silence, so that the last symbol leaves the device before `done`
+            let mut samples = signal
+                .chain(repeat_n(0.0, config.sample_number()))
+                .map(|x| output_sample(&config, x) as f32);
+            loop {
+                let chunk = samples.by_ref().take(CHUNK_FRAMES).collect::<Vec<f32>>();
+                if chunk.is_empty() || tx.send(chunk).is_err() {
+                    break;
+                }
+            }
+        });
+        // the first chunk before the device asks for it
+        let chunk = rx.recv().unwrap_or_default();
+        self.start_chunks(rx, chunk, 1, done)
+    }
+
+    /// like `start`, with a signal per channel, see `play_channels`
+    pub fn start_channels(
+        &mut self,
+        signals: &[&[f64]],
+        done: impl FnOnce() + Send + 'static,
+    ) -> Result<cpal::Stream> {
+        let signals = signals
+            .iter()
+            .map(|signal| output_level(&self.config, signal))
+            .collect::<Vec<Vec<f64>>>();
+        // trailing silence, so that the last symbol leaves the device before `done`.
+        let frames =
+            signals.iter().map(|s| s.len()).max().unwrap_or(0) + self.config.sample_number();
+        let samples = (0..frames)
+            .flat_map(|i| {
+                signals
+                    .iter()
+                    .map(move |s| s.get(i).copied().unwrap_or(0.0) as f32)
+            })
+            .collect();
+        // all of it is here already
+        let (_, rx) = channel();
+        self.start_chunks(rx, samples, signals.len().max(1), done)
+    }
+
+    /// Play `chunk`, then the chunks from `chunks` as they come, of `signal_channels`
+    /// interleaved.
+    fn start_chunks(
+        &mut self,
+        chunks: Receiver<Vec<f32>>,
+        chunk: Vec<f32>,
+        signal_channels: usize,
+        done: impl FnOnce() + Send + 'static,
+    ) -> Result<cpal::Stream> {
+        self.played = Arc::new(AtomicUsize::new(0));
+        let handle = Arc::new(Mutex::new(Playback {
+            chunks,
+            chunk: chunk.into_iter(),
+            channels: signal_channels,
+            frame: vec![0.0; signal_channels],
+            played: self.played.clone(),
+            late: 0,
+            done: Some(Box::new(done)),
+        }));
+
+        let channels = self.stream_config.channels() as usize;
+        let err_fn = move |err| {
+            error!("an error occurred on stream: {}", err);
+        };
+        let config = self.stream_config.config();
+
+        let stream = match self.stream_config.sample_format() {
+            cpal::SampleFormat::I8 => self.device.build_output_stream(
+                &config,
+                move |data, _: &_| write_output_data::<i8>(data, channels, handle.clone()),
+                err_fn,
+                None,
+            )?,
+            cpal::SampleFormat::I16 => self.device.build_output_stream(
+                &config,
+                move |data, _: &_| write_output_data::<i16>(data, channels, handle.clone()),
+                err_fn,
+                None,
+            )?,
+            cpal::SampleFormat::I32 => self.device.build_output_stream(
+                &config,
+                move |data, _: &_| write_output_data::<i32>(data, channels, handle.clone()),
+                err_fn,
+                None,
+            )?,
+            cpal::SampleFormat::F32 => self.device.build_output_stream(
+                &config,
+                move |data, _: &_| write_output_data::<f32>(data, channels, handle.clone()),
+                err_fn,
+                None,
+            )?,
+            sample_format => return Err(AcousticError::UnsupportedSampleFormat(sample_format)),
+        };
+
+        stream.play()?;
+        info!("Begin playing...");
+        Ok(stream)
+    }
+}
+
+/// Where a signal goes, mirroring `SampleReader`.
+pub trait SampleSink {
+    /// Take the next samples of the signal, at the level they go out at, see `output_level`.
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()>;
+
+    /// No more samples come, block until all of them are out.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// keeps everything in memory
+impl SampleSink for Vec<f64> {
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        self.extend_from_slice(samples);
+        Ok(())
+    }
+}
+
+/// Plays what is written to it through the output device of a `Transmitter`, see
+/// `Transmitter::sink`. The stream starts with the first samples, writes block while the
+/// device is `CHUNKS_AHEAD` chunks behind.
+pub struct PlaybackSink<'a> {
+    transmitter: &'a mut Transmitter,
+    /// where samples go once the stream started
+    chunks: Option<SyncSender<Vec<f32>>>,
+    stream: Option<cpal::Stream>,
+    /// told once everything was played, taken by the stream
+    done_tx: Option<Sender<()>>,
+    done: Receiver<()>,
+}
+
+impl SampleSink for PlaybackSink<'_> {
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let chunk = samples.iter().map(|x| *x as f32).collect::<Vec<f32>>();
+        if let Some(chunks) = &self.chunks {
+            return chunks
+                .send(chunk)
+                .map_err(|_| AcousticError::PlaybackInterrupted);
+        }
+        let (tx, rx) = sync_channel(CHUNKS_AHEAD);
+        let done_tx = self
+            .done_tx
+            .take()
+            .ok_or(AcousticError::PlaybackInterrupted)?;
+        let stream = self.transmitter.start_chunks(rx, chunk, 1, move || {
+            let _ = done_tx.send(());
+        })?;
+        self.stream = Some(stream);
+        self.chunks = Some(tx);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        // trailing silence, so that the last symbol leaves the device before we return
+        let silence = vec![0.0; self.transmitter.config.sample_number()];
+        self.write_samples(&silence)?;
+        self.chunks = None;
+        self.done
+            .recv()
+            .map_err(|_| AcousticError::PlaybackInterrupted)?;
+        self.stream = None;
+        info!("Playing finished");
+        Ok(())
+    }
+}
+
+/// Write `signal` to `sink` as it is generated, a chunk at a time at the level it goes out
+/// at, and finish it.
+pub fn write_stream(
+    config: &AcousticConfig,
+    signal: impl IntoIterator<Item = f64>,
+    sink: &mut dyn SampleSink,
+) -> Result<()> {
+    let mut samples = signal.into_iter().map(|x| output_sample(config, x));
+    loop {
+        let chunk = samples.by_ref().take(CHUNK_FRAMES).collect::<Vec<f64>>();
+        if chunk.is_empty() {
+            return sink.finish();
+        }
+        sink.write_samples(&chunk)?;
+    }
+}
+
+/// Modulate `packets` into `sink` as it takes them, see `Modulated`.
+pub fn send_to_sink(
+    config: &AcousticConfig,
+    packets: &[Packet],
+    sink: &mut dyn SampleSink,
+) -> Result<()> {
+    write_stream(config, Modulated::new(config, packets)?, sink)
+}
+
+/// Turn a message into the signal we play, see `modulate_packets`.
+pub fn modulate_message(config: &AcousticConfig, data: &[u8]) -> Result<Vec<f64>> {
+    modulate_packets(config, &Packet::new_packets_sized(data, config.packet_size))
+}
+
+/// Every sealed packet, encrypted and signed if the config has keys for it, is modulated
+/// and gets its own preamble, `AcousticConfig::packet_gap` apart. With tones near the top
+/// of the band, whatever the symbol edges splatter above the guard frequency is filtered
+/// out, see `AcousticConfig::low_pass_cutoff`. See `Modulated` to modulate a long
+/// message as it is played or written. `AcousticError::InvalidPacketOrder` if a packet
+/// could not be sealed, see `Packet::check_order`.
+pub fn modulate_packets(config: &AcousticConfig, packets: &[Packet]) -> Result<Vec<f64>> {
+    Ok(Modulated::new(config, packets)?.collect())
+}
+
+/// How long `packets` take on the air, `AcousticConfig::packet_gap` apart.
+pub fn airtime(config: &AcousticConfig, packets: &[Packet]) -> Result<Duration> {
+    let mut samples = packets.len().saturating_sub(1) * config.packet_gap_samples();
+    for packet in packets {
+        samples += modulate_packet(config, packet)?.len();
+    }
+    Ok(Duration::from_secs_f64(samples as f64 / config.sample_rate))
+}
+
+/// The signal of `modulate_packets`, modulated a packet at a time as the samples are taken,
+/// so that however long the message, only one packet of it is held in memory.
+pub struct Modulated {
+    config: AcousticConfig,
+    packets: std::vec::IntoIter<Packet>,
+    /// what is left of the packet being modulated, not filtered yet
+    samples: std::vec::IntoIter<f64>,
+    /// at `AcousticConfig::low_pass_cutoff`, if any
+    filter: Option<LowPass>,
+    /// packets taken to modulate so far
+    taken: usize,
+    /// samples pushed through the filter so far
+    pushed: usize,
+    /// where every packet modulated so far ends, see `packet_ends`
+    ends: Vec<usize>,
+}
+
+impl Modulated {
+    /// `AcousticError::InvalidPacketOrder` if a packet could not be sealed, see
+    /// `Packet::check_order`, before anything is modulated.
+    pub fn new(config: &AcousticConfig, packets: &[Packet]) -> Result<Modulated> {
+        packets
+            .iter()
+            .try_for_each(|packet| Packet::check_order(packet.order))?;
+        Ok(Modulated {
+            config: config.clone(),
+            packets: Vec::from(packets).into_iter(),
+            samples: Vec::new().into_iter(),
+            filter: config
+                .low_pass_cutoff()
+                .map(|cutoff| LowPass::new(cutoff, config.sample_rate)),
+            taken: 0,
+            pushed: 0,
+            ends: Vec::new(),
+        })
+    }
+
+    /// The sample each packet modulated so far ends at, counted from the first sample of
+    /// the signal. Known once the signal is taken that far, or before by the delay of the
+    /// filter if there is one.
+    pub fn packet_ends(&self) -> &[usize] {
+        &self.ends
+    }
+
+    /// the packet being modulated is all in the filter
+    fn end_packet(&mut self) {
+        if self.ends.len() < self.taken {
+            self.ends.push(self.pushed);
+        }
+    }
+}
+
+impl Iterator for Modulated {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        loop {
+            for x in self.samples.by_ref() {
+                self.pushed += 1;
+                let Some(filter) = &mut self.filter else {
+                    return Some(x);
+                };
+                if let Some(y) = filter.push(x) {
+                    return Some(y);
+                }
+            }
+            self.end_packet();
+            let Some(packet) = self.packets.next() else {
+                return self.filter.as_mut().and_then(LowPass::flush);
+            };
+            let gap = match self.taken {
+                0 => 0,
+                _ => self.config.packet_gap_samples(),
+            };
+            self.taken += 1;
+            let signal = modulate_packet(&self.config, &packet).expect("checked in `new`");
+            self.samples = [vec![0.0; gap], signal].concat().into_iter();
+        }
+    }
+}
+
+/// One packet after its preamble, not filtered.
+fn modulate_packet(config: &AcousticConfig, packet: &Packet) -> Result<Vec<f64>> {
+    let sealed = match &config.key {
+        Some(key) => crypto::seal(key, packet)?,
+        None => packet.seal_one()?,
+    };
+    let sealed = match &config.mac_key {
+        Some(mac_key) => crypto::sign(mac_key, &sealed),
+        None => sealed,
+    };
+    let sealed = match config.scramble {
+        true => Scrambler::new().apply(&sealed),
+        false => sealed,
+    };
+    // header and payload are decoded one after the other, so each is coded and modulated
+    // on its own
+    let (header, payload) = sealed.split_at(config.header_size());
+    let mut data = Vec::new();
+    if let (true, Modulation::Fsk) = (config.pilot, config.modulation) {
+        modulate_symbol_into(config, pilot_bits(config), &mut data);
+    }
+    for part in [header, payload] {
+        let coded = interleave(&fec::encode(config.fec, part), config.interleave_depth);
+        match config.modulation {
+            // straight into `data`, FSK takes the most samples per byte
+            Modulation::Fsk => modulate_bits_into(config, &coded, &mut data),
+            Modulation::Ofdm => data.extend(ofdm_modulate(&coded)),
+            Modulation::Dpsk => data.extend(dpsk_modulate(config, &coded)),
+            Modulation::Qpsk => data.extend(qpsk_modulate(config, &coded)),
+            Modulation::Qam16 => data.extend(qam16_modulate(config, &coded)),
+            Modulation::Dsss => data.extend(dsss_modulate(config, &coded)),
+            Modulation::Css => data.extend(css_modulate(config, &coded)),
+        }
+    }
+    Ok(prepend_preamble(config, &data))
+}
+
+/// `signal` as it goes out, scaled by `AcousticConfig::amplitude`. Samples past `CLIP_KNEE`,
+/// like where the low pass filter overshoots, are bent smoothly towards full scale rather
+/// than clipped flat by the DAC, which would splatter over the other carriers.
+pub fn output_level(config: &AcousticConfig, signal: &[f64]) -> Vec<f64> {
+    signal.iter().map(|x| output_sample(config, *x)).collect()
+}
+
+/// one sample of `output_level`
+pub fn output_sample(config: &AcousticConfig, x: f64) -> f64 {
+    soft_clip(x * config.amplitude)
+}
+
+fn soft_clip(x: f64) -> f64 {
+    match x.abs() > CLIP_KNEE {
+        true => {
+            let headroom = 1.0 - CLIP_KNEE;
+            x.signum() * (CLIP_KNEE + headroom * ((x.abs() - CLIP_KNEE) / headroom).tanh())
+        }
+        false => x,
+    }
+}
+
+fn write_output_data<T>(output: &mut [T], channels: usize, handle: PlaybackHandle)
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let Ok(mut playback) = handle.lock() else {
+        error!("playback buffer is poisoned");
+        return;
+    };
+    let signal_channels = playback.channels;
+    for frame in output.chunks_mut(channels) {
+        if !playback.next_frame() {
+            if let Some(done) = playback.done.take() {
+                if playback.late > 0 {
+                    warn!("{} frames played late as silence", playback.late);
+                }
+                done();
+            }
+        }
+        let samples = &playback.frame;
+        // a mono signal on every speaker, more channels than speakers mixed down
+        let mixed = samples.iter().sum::<f32>() / signal_channels as f32;
+        let mix_down = channels < signal_channels;
+        for (i, out) in frame.iter_mut().enumerate() {
+            let sample = match (signal_channels, mix_down) {
+                (1, _) | (_, true) => mixed,
+                _ => samples.get(i).copied().unwrap_or(0.0),
+            };
+            *out = T::from_sample(sample);
+        }
+    }
+}
+
+#[test]
+fn test_playback_frames() {
+    let (tx, chunks) = channel();
+    let mut playback = Playback {
+        chunks,
+        chunk: vec![1.0, 2.0, 3.0, 4.0].into_iter(),
+        channels: 2,
+        frame: vec![0.0; 2],
+        played: Arc::new(AtomicUsize::new(0)),
+        late: 0,
+        done: None,
+    };
+    assert!(playback.next_frame());
+    assert_eq!(playback.frame, [1.0, 2.0]);
+    assert!(playback.next_frame());
+    assert_eq!(playback.frame, [3.0, 4.0]);
+    // the next chunk is late
+    assert!(playback.next_frame());
+    assert_eq!((&playback.frame[..], playback.late), (&[0.0, 0.0][..], 1));
+    tx.send(vec![5.0, 6.0]).unwrap();
+    drop(tx);
+    assert!(playback.next_frame());
+    assert_eq!(playback.frame, [5.0, 6.0]);
+    assert!(!playback.next_frame());
+    assert_eq!(playback.frame, [0.0, 0.0]);
+    assert_eq!(playback.played.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn test_modulate_message() {
+    let data = "hello world";
+    let config = AcousticConfig::default();
+    let modulated = modulate_message(&config, &crate::encode(data)).unwrap();
+    // four preamble tones, then two symbols per byte of the sealed packet
+    assert_eq!(
+        modulated.len(),
+        config.sample_number() * (4 + 2 * (Packet::HEADER_SIZE + 11))
+    );
+}
+
+#[test]
+fn test_modulated() {
+    // streamed, packet gaps and all, the signal is the one modulated at once, and filtered
+    // only near the top of the band
+    let audible = AcousticConfig::builder().packet_gap(0.01).build().unwrap();
+    let ultrasonic = AcousticConfig {
+        packet_gap: 0.01,
+        ..AcousticConfig::ultrasonic()
+    };
+    assert_eq!(audible.low_pass_cutoff(), None);
+    assert_eq!(ultrasonic.low_pass_cutoff(), Some(ultrasonic.guard_freq()));
+    let packets = Packet::new_packets(&[3; 300]);
+    for config in [audible, ultrasonic] {
+        let streamed = Modulated::new(&config, &packets)
+            .unwrap()
+            .collect::<Vec<f64>>();
+        let whole = packets
+            .iter()
+            .map(|packet| modulate_packet(&config, packet).unwrap())
+            .collect::<Vec<Vec<f64>>>()
+            .join(&vec![0.0; config.packet_gap_samples()][..]);
+        let whole = match config.low_pass_cutoff() {
+            Some(cutoff) => crate::physics::low_pass(&whole, cutoff, config.sample_rate),
+            None => whole,
+        };
+        assert_eq!(streamed.len(), whole.len());
+        assert!(streamed
+            .iter()
+            .zip(&whole)
+            .all(|(a, b)| (a - b).abs() < 1e-9));
+        assert_eq!(
+            airtime(&config, &packets).unwrap(),
+            Duration::from_secs_f64(streamed.len() as f64 / config.sample_rate)
+        );
+    }
+}
+
+#[test]
+fn test_send_to_sink() {
+    let config = AcousticConfig::builder().amplitude(0.5).build().unwrap();
+    let packets = Packet::new_packets(&[3; 300]);
+    let mut captured = Vec::new();
+    send_to_sink(&config, &packets, &mut captured).unwrap();
+    assert_eq!(
+        captured,
+        output_level(&config, &modulate_packets(&config, &packets).unwrap())
+    );
+}
+
+#[test]
+fn test_packet_ends() {
+    let config = AcousticConfig::builder().packet_gap(0.01).build().unwrap();
+    let packets = Packet::new_packets(&[3; 300]);
+    let lengths = packets
+        .iter()
+        .map(|packet| modulate_packet(&config, packet).unwrap().len())
+        .collect::<Vec<usize>>();
+    let mut modulated = Modulated::new(&config, &packets).unwrap();
+    assert!(modulated.packet_ends().is_empty());
+    let len = modulated.by_ref().count();
+    let gap = config.packet_gap_samples();
+    assert_eq!(
+        modulated.packet_ends(),
+        [
+            lengths[0],
+            lengths[0] + gap + lengths[1],
+            lengths[0] + gap + lengths[1] + gap + lengths[2]
+        ]
+    );
+    assert_eq!(len, modulated.packet_ends()[2]);
+}
+
+#[test]
+fn test_output_level() {
+    let config = AcousticConfig::builder().amplitude(0.5).build().unwrap();
+    assert_eq!(output_level(&config, &[0.5, -1.0]), [0.25, -0.5]);
+
+    // nothing goes past full scale, however loud, and quiet samples are left alone
+    let loud = output_level(&AcousticConfig::default(), &[0.5, 0.95, 1.2, -3.0]);
+    assert_eq!(loud[0], 0.5);
+    assert!(loud[1] > 0.9 && loud[1] < 0.95);
+    assert!(loud[1] < loud[2] && loud[2] < 1.0);
+    assert!(loud[3] >= -1.0 && loud[3] < -0.9);
+    let modulated = modulate_message(&AcousticConfig::default(), b"hello").unwrap();
+    assert!(output_level(&AcousticConfig::default(), &modulated)
+        .iter()
+        .all(|x| x.abs() < 1.0));
+}
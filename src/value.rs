@@ -0,0 +1,89 @@
+//! # Values
+//!
+//! Any Rust value with serde's `Serialize` goes out as a message of its own, serialized
+//! with bincode, a compact format without field names, see `Transmitter::send_value`. The
+//! other end turns the message back into the value with `Receiver::recv_value`. Both ends
+//! have to agree on the type, nothing on the air says what it is.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{error::Result, transmission::Receiver, transmitter::Transmitter, Packet};
+
+/// the message `value` goes out as
+pub fn encode_value<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+/// The value a message of `encode_value` carries, `AcousticError::Value` if it carries no
+/// `T`.
+pub fn decode_value<T: DeserializeOwned>(message: &[u8]) -> Result<T> {
+    Ok(bincode::deserialize(message)?)
+}
+
+impl Transmitter {
+    /// Serialize `value` and play it to every receiver, see `send`.
+    pub fn send_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.send_value_to(Packet::BROADCAST, value)
+    }
+
+    /// Serialize `value` and play it to the receiver at `destination`, see `send_to`.
+    pub fn send_value_to<T: Serialize + ?Sized>(
+        &mut self,
+        destination: u8,
+        value: &T,
+    ) -> Result<()> {
+        let message = encode_value(value)?;
+        self.send_to(destination, &message)
+    }
+}
+
+impl Receiver {
+    /// Wait for the next message like `run` and deserialize it.
+    pub fn recv_value<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let message = self.run()?;
+        decode_value(&message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        config::AcousticConfig, error::AcousticError, transmission::MemoryReader,
+        transmitter::modulate_message,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        celsius: f32,
+        history: Vec<i16>,
+    }
+
+    #[test]
+    fn test_value() {
+        let reading = Reading {
+            sensor: "kitchen".to_string(),
+            celsius: 21.5,
+            history: vec![20, 21, -3],
+        };
+        let message = encode_value(&reading).unwrap();
+        assert_eq!(decode_value::<Reading>(&message).unwrap(), reading);
+        assert!(matches!(
+            decode_value::<Reading>(&message[..4]),
+            Err(AcousticError::Value(_))
+        ));
+
+        let config = AcousticConfig::default();
+        let heard = [
+            vec![0.0; 10000],
+            modulate_message(&config, &message).unwrap(),
+            vec![0.0; 10000],
+        ]
+        .concat();
+        let mut receiver = Receiver::with_config(Box::new(MemoryReader(heard)), config);
+        assert_eq!(receiver.recv_value::<Reading>().unwrap(), reading);
+    }
+}
@@ -0,0 +1,140 @@
+//! # Vector math
+//!
+//! Mixing carriers, windowing blocks and correlating against a reference are loops over
+//! long runs of samples, and they are where a small ARM board spends its time receiving.
+//! With the `simd` feature they run four samples at a time on `wide` vectors, NEON, SSE or
+//! AVX, whatever the target has. Without it they are plain loops, see `scalar`, which the
+//! SIMD versions fall back on for the samples left over as well.
+//!
+//! A SIMD `dot` sums in another order, so its last bits may differ from the scalar one.
+//! `benches/vector.rs` compares the two, run it with and without `--features simd`.
+
+#[cfg(not(feature = "simd"))]
+use scalar as imp;
+#[cfg(feature = "simd")]
+use simd as imp;
+
+/// `a + b`, element by element, as long as the shorter of the two
+pub fn add(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let mut sum = a[..a.len().min(b.len())].to_vec();
+    add_assign(&mut sum, b);
+    sum
+}
+
+/// `acc += x`, element by element, as far as both go
+pub fn add_assign(acc: &mut [f64], x: &[f64]) {
+    let len = acc.len().min(x.len());
+    imp::add_assign(&mut acc[..len], &x[..len])
+}
+
+/// `a * b`, element by element, as long as the shorter of the two, like a block under a
+/// window
+pub fn multiply(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let len = a.len().min(b.len());
+    let mut product = a[..len].to_vec();
+    imp::multiply_assign(&mut product, &b[..len]);
+    product
+}
+
+/// the sum of `a * b`, as far as both go, the correlation of two signals
+pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+    let len = a.len().min(b.len());
+    imp::dot(&a[..len], &b[..len])
+}
+
+/// The plain loops, on slices of the same length.
+pub mod scalar {
+    pub fn add_assign(acc: &mut [f64], x: &[f64]) {
+        for (a, x) in acc.iter_mut().zip(x) {
+            *a += x;
+        }
+    }
+
+    pub fn multiply_assign(acc: &mut [f64], x: &[f64]) {
+        for (a, x) in acc.iter_mut().zip(x) {
+            *a *= x;
+        }
+    }
+
+    pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+}
+
+/// Four lanes at a time, on slices of the same length.
+#[cfg(feature = "simd")]
+mod simd {
+    use wide::f64x4;
+
+    use super::scalar;
+
+    const LANES: usize = 4;
+
+    fn load(chunk: &[f64]) -> f64x4 {
+        f64x4::new([chunk[0], chunk[1], chunk[2], chunk[3]])
+    }
+
+    /// `op` on every whole chunk of `acc` and `x`, `rest` on what is left over
+    fn zip_assign(
+        acc: &mut [f64],
+        x: &[f64],
+        op: impl Fn(f64x4, f64x4) -> f64x4,
+        rest: fn(&mut [f64], &[f64]),
+    ) {
+        let mut acc_chunks = acc.chunks_exact_mut(LANES);
+        let mut x_chunks = x.chunks_exact(LANES);
+        for (a, x) in (&mut acc_chunks).zip(&mut x_chunks) {
+            a.copy_from_slice(&op(load(a), load(x)).to_array());
+        }
+        rest(acc_chunks.into_remainder(), x_chunks.remainder());
+    }
+
+    pub fn add_assign(acc: &mut [f64], x: &[f64]) {
+        zip_assign(acc, x, |a, x| a + x, scalar::add_assign)
+    }
+
+    pub fn multiply_assign(acc: &mut [f64], x: &[f64]) {
+        zip_assign(acc, x, |a, x| a * x, scalar::multiply_assign)
+    }
+
+    pub fn dot(a: &[f64], b: &[f64]) -> f64 {
+        let a_chunks = a.chunks_exact(LANES);
+        let b_chunks = b.chunks_exact(LANES);
+        let rest = scalar::dot(a_chunks.remainder(), b_chunks.remainder());
+        let sum = a_chunks
+            .zip(b_chunks)
+            .fold(f64x4::ZERO, |sum, (a, b)| load(a).mul_add(load(b), sum));
+        sum.reduce_add() + rest
+    }
+}
+
+#[test]
+fn test_vector() {
+    // not a multiple of the lanes, and of different lengths
+    let a = (0..103)
+        .map(|i| (i as f64 * 0.37).sin())
+        .collect::<Vec<f64>>();
+    let b = (0..101)
+        .map(|i| (i as f64 * 0.11).cos())
+        .collect::<Vec<f64>>();
+
+    let sum = add(&a, &b);
+    assert_eq!(sum.len(), 101);
+    assert!(sum.iter().zip(&a).zip(&b).all(|((s, a), b)| *s == a + b));
+
+    let mut acc = a.clone();
+    add_assign(&mut acc, &b);
+    assert_eq!(acc[..101], sum[..]);
+    assert_eq!(acc[101..], a[101..]);
+
+    let product = multiply(&a, &b);
+    assert!(product
+        .iter()
+        .zip(&a)
+        .zip(&b)
+        .all(|((p, a), b)| *p == a * b));
+
+    let expected = scalar::dot(&a[..101], &b);
+    assert!((dot(&a, &b) - expected).abs() < 1e-12);
+    assert_eq!(dot(&[], &b), 0.0);
+}
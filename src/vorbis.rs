@@ -0,0 +1,613 @@
+//! # Vorbis encoder
+//!
+//! A small OGG/Vorbis encoder for `export`, mono only and far from what libvorbis gets out of
+//! a bitrate, but any Vorbis decoder plays what it writes. Every block is 2048 samples long,
+//! half of it overlapping the next, and goes through the MDCT with the Vorbis window.
+//!
+//! There is no psychoacoustic model. The floor is a flat line through the whole spectrum,
+//! which makes it the step every coefficient is quantized with, and each block takes the
+//! finest step that fits into its share of the bitrate, plus what the blocks before left
+//! unused. The quantized coefficients are the residue, in partitions of 16 that are either
+//! silent or coded as up to three balanced base 31 digits, one codebook pass per digit.
+//! Tones, like the ones we send, take a handful of large coefficients and leave most
+//! partitions silent, so they come through well even at low bitrates, with white noise in
+//! place of whatever did not fit.
+//!
+//! The packets go into OGG pages of their own making, like the FLAC frames of `export`.
+
+use std::{
+    f64::consts::PI,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
+
+use crate::{config::AcousticConfig, error::Result, transmitter::SampleSink};
+
+/// bitrate `export` encodes at unless told otherwise, in bits per second
+pub const DEFAULT_BITRATE: u32 = 64_000;
+
+/// log2 of the samples of a block
+const BLOCK_EXPONENT: u32 = 11;
+
+/// samples of a block
+const BLOCK_SIZE: usize = 1 << BLOCK_EXPONENT;
+
+/// samples a block advances by, and coefficients it has
+const HOP: usize = BLOCK_SIZE / 2;
+
+/// coefficients of a residue partition
+const PARTITION_SIZE: usize = 16;
+
+/// largest magnitude of a residue digit
+const MAX_DIGIT: i64 = 15;
+
+/// base of the residue digits
+const DIGIT_BASE: i64 = 2 * MAX_DIGIT + 1;
+
+/// residue digits of a partition at most, a codebook pass each
+const MAX_DIGITS: usize = 3;
+
+/// largest magnitude of a quantized coefficient
+const MAX_QUANTIZED: i64 = MAX_DIGIT * (DIGIT_BASE * DIGIT_BASE + DIGIT_BASE + 1);
+
+/// codeword lengths of the classbook, from silent partitions to ones of three digits
+const CLASS_LENGTHS: [u32; MAX_DIGITS + 1] = [1, 2, 3, 3];
+
+/// bits of the floor values, multiplier 1
+const FLOOR_BITS: u32 = 8;
+
+/// the floor at value 0, relative to the one at 255, see the floor1 inverse dB table
+const FLOOR_MIN: f64 = 1.0649863e-07;
+
+/// stream serial number, there is only ever the one stream
+const SERIAL: u32 = 0x6163_6469;
+
+/// blocks worth of bits the reservoir holds at most
+const RESERVOIR_BLOCKS: usize = 8;
+
+/// page data at which the page is written out
+const PAGE_SIZE: usize = 4096;
+
+/// Encodes the signal to OGG/Vorbis a block at a time.
+pub struct VorbisSampleWriter<W: Write> {
+    output: W,
+    /// coded bits a block may take
+    block_bits: usize,
+    /// bits earlier blocks left unused, for the next ones to take
+    reservoir: usize,
+    fft: Arc<dyn RealToComplex<f64>>,
+    window: Vec<f64>,
+    /// the codewords of each entry of the digit codebooks
+    digit_codes: Vec<(u32, u32)>,
+    class_codes: Vec<(u32, u32)>,
+    /// samples of the block not encoded yet, starting half a block before the next
+    pending: Vec<f64>,
+    input: Vec<f64>,
+    spectrum: Vec<Complex<f64>>,
+    coefficients: Vec<f64>,
+    /// samples written
+    samples: u64,
+    /// blocks encoded
+    blocks: u64,
+    page: OggPage,
+    finished: bool,
+}
+
+impl VorbisSampleWriter<BufWriter<File>> {
+    pub fn create(
+        path: impl AsRef<Path>,
+        config: &AcousticConfig,
+        bitrate: u32,
+    ) -> Result<VorbisSampleWriter<BufWriter<File>>> {
+        VorbisSampleWriter::new(BufWriter::new(File::create(path)?), config, bitrate)
+    }
+}
+
+impl<W: Write> VorbisSampleWriter<W> {
+    /// Write the three headers to `output` right away, encoding at about `bitrate` bits per
+    /// second from then on.
+    pub fn new(
+        mut output: W,
+        config: &AcousticConfig,
+        bitrate: u32,
+    ) -> Result<VorbisSampleWriter<W>> {
+        let sample_rate = config.sample_rate as u32;
+        let digit_lengths = huffman_lengths(
+            &(-MAX_DIGIT..=MAX_DIGIT)
+                .map(|digit| 0.5_f64.powi(digit.abs() as i32))
+                .collect::<Vec<f64>>(),
+        );
+        let mut page = OggPage::default();
+        // the identification header on a page of its own, the other two on the next
+        page.push(&identification_header(sample_rate, bitrate));
+        page.write(&mut output, 0, false)?;
+        page.push(&comment_header());
+        page.push(&setup_header(&digit_lengths));
+        page.write(&mut output, 0, false)?;
+
+        let fft = RealFftPlanner::<f64>::new().plan_fft_forward(2 * BLOCK_SIZE);
+        Ok(VorbisSampleWriter {
+            output,
+            block_bits: (bitrate as f64 * HOP as f64 / config.sample_rate) as usize,
+            reservoir: 0,
+            window: (0..BLOCK_SIZE)
+                .map(|n| {
+                    let x = ((n as f64 + 0.5) / BLOCK_SIZE as f64 * PI).sin();
+                    (PI / 2.0 * x * x).sin()
+                })
+                .collect(),
+            digit_codes: codewords(&digit_lengths),
+            class_codes: codewords(&CLASS_LENGTHS),
+            // the first block starts half a block early, where the decoder has nothing yet
+            pending: vec![0.0; HOP],
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            fft,
+            coefficients: vec![0.0; HOP],
+            samples: 0,
+            blocks: 0,
+            page,
+            finished: false,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+
+    /// Encode the first block of `pending` and move on by half a block.
+    fn write_block(&mut self) -> Result<()> {
+        self.mdct();
+        // the finest step that fits, the coarsest one if none does
+        let budget = self.block_bits + self.reservoir;
+        let (mut low, mut high) = (0, (1 << FLOOR_BITS) - 1);
+        while low < high {
+            let floor = (low + high) / 2;
+            match self.encode(floor) {
+                Some(packet) if packet.len() <= budget => high = floor,
+                _ => low = floor + 1,
+            }
+        }
+        let packet = self
+            .encode(low)
+            .expect("coefficients are below 4, a step of 1 takes them");
+        self.reservoir = budget
+            .saturating_sub(packet.len())
+            .min(RESERVOIR_BLOCKS * self.block_bits);
+        if !self.page.fits(packet.bytes()) {
+            let decoded = self.decoded();
+            self.page.write(&mut self.output, decoded, false)?;
+        }
+        self.page.push(packet.bytes());
+        self.blocks += 1;
+        self.pending.drain(..HOP);
+        if self.page.data.len() >= PAGE_SIZE {
+            let decoded = self.decoded();
+            self.page.write(&mut self.output, decoded, false)?;
+        }
+        Ok(())
+    }
+
+    /// samples the blocks so far decode into, a block into the half before its middle
+    fn decoded(&self) -> u64 {
+        self.blocks.saturating_sub(1) * HOP as u64
+    }
+
+    /// The MDCT of the windowed block, through an FFT of twice its size, at the scale the
+    /// decoder undoes.
+    fn mdct(&mut self) {
+        for (n, x) in self.input.iter_mut().enumerate() {
+            *x = match n < BLOCK_SIZE {
+                true => self.pending[n] * self.window[n],
+                false => 0.0,
+            };
+        }
+        self.fft
+            .process(&mut self.input, &mut self.spectrum)
+            .expect("buffers of the planned size");
+        let shift = 0.5 + HOP as f64 / 2.0;
+        for (k, coefficient) in self.coefficients.iter_mut().enumerate() {
+            let odd = (2 * k + 1) as f64;
+            let twiddle = Complex::from_polar(1.0, -PI * odd * shift / BLOCK_SIZE as f64);
+            *coefficient = 2.0 * (twiddle * self.spectrum[2 * k + 1]).re / HOP as f64;
+        }
+    }
+
+    /// The audio packet of the block at the step of `floor`, `None` if a coefficient is
+    /// too large for it.
+    fn encode(&self, floor: u32) -> Option<BitWriter> {
+        let step = floor_step(floor);
+        let quantized = self
+            .coefficients
+            .iter()
+            .map(|x| (x / step).round() as i64)
+            .collect::<Vec<i64>>();
+        if quantized.iter().any(|q| q.abs() > MAX_QUANTIZED) {
+            return None;
+        }
+        let mut packet = BitWriter::default();
+        // an audio packet of the only mode
+        packet.write(0, 1);
+        if quantized.iter().all(|q| *q == 0) {
+            // an unused floor, and no residue
+            packet.write(0, 1);
+            return Some(packet);
+        }
+        packet.write(1, 1);
+        packet.write(floor, FLOOR_BITS);
+        packet.write(floor, FLOOR_BITS);
+
+        let partitions = quantized
+            .chunks(PARTITION_SIZE)
+            .map(|partition| {
+                let digits = partition
+                    .iter()
+                    .map(|q| balanced_digits(*q))
+                    .collect::<Vec<[i64; MAX_DIGITS]>>();
+                // the number of digits the largest coefficient takes
+                let class = (0..MAX_DIGITS)
+                    .rev()
+                    .find(|i| digits.iter().any(|d| d[*i] != 0))
+                    .map_or(0, |i| i + 1);
+                (class, digits)
+            })
+            .collect::<Vec<(usize, Vec<[i64; MAX_DIGITS]>)>>();
+        // the highest digit goes first, the lowest in the pass after
+        for pass in 0..MAX_DIGITS {
+            for (class, digits) in &partitions {
+                if pass == 0 {
+                    let (code, length) = self.class_codes[*class];
+                    packet.write_code(code, length);
+                }
+                if pass < *class {
+                    let digit = class - 1 - pass;
+                    for d in digits {
+                        let (code, length) = self.digit_codes[(d[digit] + MAX_DIGIT) as usize];
+                        packet.write_code(code, length);
+                    }
+                }
+            }
+        }
+        Some(packet)
+    }
+}
+
+impl<W: Write> SampleSink for VorbisSampleWriter<W> {
+    /// Samples written after `finish` are dropped.
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        for x in samples {
+            self.pending.push(x.clamp(-1.0, 1.0));
+            if self.pending.len() == BLOCK_SIZE {
+                self.write_block()?;
+            }
+        }
+        self.samples += samples.len() as u64;
+        Ok(())
+    }
+
+    /// Encode blocks of silence until the last sample decodes, and end the stream.
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        while self.decoded() < self.samples {
+            self.pending.resize(BLOCK_SIZE, 0.0);
+            self.write_block()?;
+        }
+        // the decoder drops what the silence added
+        self.page.write(&mut self.output, self.samples, true)?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// step of the quantizer at `floor`, the floor1 inverse dB table without the table
+fn floor_step(floor: u32) -> f64 {
+    FLOOR_MIN.powf(1.0 - floor as f64 / ((1 << FLOOR_BITS) - 1) as f64)
+}
+
+/// `q` as balanced base 31 digits, the lowest first
+fn balanced_digits(mut q: i64) -> [i64; MAX_DIGITS] {
+    let mut digits = [0; MAX_DIGITS];
+    for digit in &mut digits {
+        *digit = (q + MAX_DIGIT).rem_euclid(DIGIT_BASE) - MAX_DIGIT;
+        q = (q - *digit) / DIGIT_BASE;
+    }
+    digits
+}
+
+fn identification_header(sample_rate: u32, bitrate: u32) -> Vec<u8> {
+    let mut header = vec![1];
+    header.extend_from_slice(b"vorbis");
+    // version, mono
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    header.push(1);
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    // no upper or lower limit, just a nominal bitrate
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    header.extend_from_slice(&bitrate.to_le_bytes());
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    // both block sizes the same
+    header.push(((BLOCK_EXPONENT << 4) | BLOCK_EXPONENT) as u8);
+    header.push(1);
+    header
+}
+
+fn comment_header() -> Vec<u8> {
+    let vendor = concat!("acousticdi ", env!("CARGO_PKG_VERSION"));
+    let mut header = vec![3];
+    header.extend_from_slice(b"vorbis");
+    header.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    header.extend_from_slice(vendor.as_bytes());
+    // no comments
+    header.extend_from_slice(&0_u32.to_le_bytes());
+    header.push(1);
+    header
+}
+
+/// Codebooks, a flat floor, the residue, and the one mapping and mode using them.
+fn setup_header(digit_lengths: &[u32]) -> Vec<u8> {
+    let mut header = BitWriter::default();
+    header.write_bytes(b"\x05vorbis");
+    // the classbook, then a digit codebook for each power of the base
+    header.write(MAX_DIGITS as u32, 8);
+    write_codebook(&mut header, &CLASS_LENGTHS, None);
+    let mut scale = 1;
+    for _ in 0..MAX_DIGITS {
+        write_codebook(&mut header, digit_lengths, Some(scale));
+        scale *= DIGIT_BASE;
+    }
+    // a single placeholder time domain transform
+    header.write(0, 6);
+    header.write(0, 16);
+    // floor 1 without partitions, a line from the first coefficient past the last, at
+    // multiplier 1
+    header.write(0, 6);
+    header.write(1, 16);
+    header.write(0, 5);
+    header.write(0, 2);
+    header.write(HOP.trailing_zeros(), 4);
+    // residue 1 over every coefficient, a class per number of digits
+    header.write(0, 6);
+    header.write(1, 16);
+    header.write(0, 24);
+    header.write(HOP as u32, 24);
+    header.write(PARTITION_SIZE as u32 - 1, 24);
+    header.write(MAX_DIGITS as u32, 6);
+    header.write(0, 8);
+    for class in 0..=MAX_DIGITS {
+        // a pass per digit, no high bits
+        header.write((1 << class) - 1, 3);
+        header.write(0, 1);
+    }
+    for class in 0..=MAX_DIGITS {
+        // the highest digit first
+        for pass in 0..class {
+            header.write((class - pass) as u32, 8);
+        }
+    }
+    // one mapping, one submap, no coupling
+    header.write(0, 6);
+    header.write(0, 16);
+    header.write(0, 1);
+    header.write(0, 1);
+    header.write(0, 2);
+    header.write(0, 8);
+    header.write(0, 8);
+    header.write(0, 8);
+    // one mode of short blocks, on mapping 0
+    header.write(0, 6);
+    header.write(0, 1);
+    header.write(0, 16);
+    header.write(0, 16);
+    header.write(0, 8);
+    header.write(1, 1);
+    header.bytes().to_vec()
+}
+
+/// A codebook of `lengths`, scalar, or with the entries standing for the balanced digits
+/// times `scale`.
+fn write_codebook(bits: &mut BitWriter, lengths: &[u32], scale: Option<i64>) {
+    bits.write(0x564342, 24);
+    bits.write(1, 16);
+    bits.write(lengths.len() as u32, 24);
+    // not ordered, not sparse
+    bits.write(0, 1);
+    bits.write(0, 1);
+    for length in lengths {
+        bits.write(length - 1, 5);
+    }
+    let Some(scale) = scale else {
+        bits.write(0, 4);
+        return;
+    };
+    // lookup type 1, entry i is i times `scale` past the lowest
+    let value_bits = u32::BITS - (lengths.len() as u32 - 1).leading_zeros();
+    bits.write(1, 4);
+    bits.write(float32(-MAX_DIGIT * scale), 32);
+    bits.write(float32(scale), 32);
+    bits.write(value_bits - 1, 4);
+    bits.write(0, 1);
+    for i in 0..lengths.len() as u32 {
+        bits.write(i, value_bits);
+    }
+}
+
+/// an integer in the float format of Vorbis codebooks
+fn float32(value: i64) -> u32 {
+    let sign = if value < 0 { 0x8000_0000 } else { 0 };
+    // exponent 788 is a factor of 1
+    sign | (788 << 21) | value.unsigned_abs() as u32
+}
+
+/// Codeword lengths of a Huffman code for symbols of `weights`.
+fn huffman_lengths(weights: &[f64]) -> Vec<u32> {
+    let mut lengths = vec![0; weights.len()];
+    // the weight of each subtree and the symbols in it
+    let mut trees = weights
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| (*weight, vec![i]))
+        .collect::<Vec<(f64, Vec<usize>)>>();
+    while trees.len() > 1 {
+        trees.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let (weight_a, a) = trees.pop().expect("two trees");
+        let (weight_b, b) = trees.pop().expect("two trees");
+        for i in a.iter().chain(&b) {
+            lengths[*i] += 1;
+        }
+        trees.push((weight_a + weight_b, [a, b].concat()));
+    }
+    lengths
+}
+
+/// The codeword of each entry for `lengths`, and its length, assigned like the decoder
+/// does: the lowest codeword free at its length, in entry order.
+fn codewords(lengths: &[u32]) -> Vec<(u32, u32)> {
+    // the next free codeword at each length
+    let mut marker = [0_u32; 33];
+    lengths
+        .iter()
+        .map(|&length| {
+            let mut entry = marker[length as usize];
+            let code = entry;
+            for j in (1..=length as usize).rev() {
+                if marker[j] & 1 == 1 {
+                    marker[j] = match j {
+                        1 => marker[1] + 1,
+                        _ => marker[j - 1] << 1,
+                    };
+                    break;
+                }
+                marker[j] += 1;
+            }
+            // longer codewords may not start with this one
+            for j in length as usize + 1..33 {
+                if marker[j] >> 1 != entry {
+                    break;
+                }
+                entry = marker[j];
+                marker[j] = marker[j - 1] << 1;
+            }
+            (code, length)
+        })
+        .collect()
+}
+
+/// Packets on their way into an OGG page.
+#[derive(Default)]
+struct OggPage {
+    data: Vec<u8>,
+    /// lacing values of the packets
+    segments: Vec<u8>,
+    /// pages written
+    sequence: u32,
+}
+
+impl OggPage {
+    fn push(&mut self, packet: &[u8]) {
+        self.segments
+            .extend(std::iter::repeat_n(255, packet.len() / 255));
+        self.segments.push((packet.len() % 255) as u8);
+        self.data.extend_from_slice(packet);
+    }
+
+    /// whether `packet` fits into the lacing values left, packets never span pages
+    fn fits(&self, packet: &[u8]) -> bool {
+        self.segments.len() + packet.len() / 255 < 255
+    }
+
+    /// Write the packets so far as a page, whose last packet ends at sample `granule`, and
+    /// start over with the next one. Never writes an empty page but the last.
+    fn write(&mut self, output: &mut impl Write, granule: u64, last: bool) -> Result<()> {
+        if self.segments.is_empty() && !last {
+            return Ok(());
+        }
+        let mut flags = 0;
+        if self.sequence == 0 {
+            flags |= 0x02;
+        }
+        if last {
+            flags |= 0x04;
+        }
+        let mut page = b"OggS\0".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&SERIAL.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        // the checksum, once the page is complete
+        page.extend_from_slice(&0_u32.to_le_bytes());
+        page.push(self.segments.len() as u8);
+        page.append(&mut self.segments);
+        page.append(&mut self.data);
+        let checksum = crc32(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        output.write_all(&page)?;
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+/// CRC-32 of OGG pages, polynomial 0x04c11db7, not reflected
+fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u32) << 24), |crc, _| {
+            match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            }
+        })
+    })
+}
+
+/// Collects bits least significant first, the order of Vorbis packets.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// bits of the last byte in use, 0 if it is full
+    used: u32,
+}
+
+impl BitWriter {
+    /// the lowest `count` bits of `value`, the lowest first
+    fn write(&mut self, value: u32, count: u32) {
+        for i in 0..count {
+            if self.used == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().expect("a byte was pushed") |= bit << self.used;
+            self.used = (self.used + 1) % 8;
+        }
+    }
+
+    /// a Huffman codeword, read a bit at a time from its first
+    fn write_code(&mut self, code: u32, length: u32) {
+        for i in (0..length).rev() {
+            self.write(code >> i, 1);
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u32, 8);
+        }
+    }
+
+    fn len(&self) -> usize {
+        8 * self.bytes.len()
+    }
+
+    /// whole bytes so far, the last one padded
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
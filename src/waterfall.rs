@@ -0,0 +1,169 @@
+//! # Waterfall
+//!
+//! Whether the carriers reach the microphone at all, and how loud, is the first thing to
+//! know when nothing decodes. A `Waterfall` prints what was recorded last as one line of
+//! text every so often, the band of our tones from left to right, the louder the denser the
+//! character, so the lines scroll by like the waterfall of a radio. `scale` marks where the
+//! data tones (`|`) and the preamble tones (`^`) should show up, and every line ends with
+//! the level of the loudest bin.
+//!
+//! The receiver drops what it consumed from its recording, so the waterfall reads a copy
+//! of its own, see `Waterfall::new`.
+
+use std::{io::Write, sync::Arc, thread::sleep, time::Duration};
+
+use crate::{
+    config::AcousticConfig, diagnostics::spectrogram, error::Result, physics::FFT_SIZE,
+    recorder::SharedBuffer,
+};
+
+/// time between lines of the command line
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(100);
+
+/// characters from silence to full scale
+const SHADES: &[u8] = b" .:-=+*#%@";
+
+/// decibels below full scale still drawn as more than silence
+const DYNAMIC_RANGE: f64 = 80.0;
+
+/// level of a full scale tone in `spectrogram`, the gain of the Hann window
+fn full_scale() -> f64 {
+    20.0 * (FFT_SIZE as f64 / 4.0).log10()
+}
+
+/// Draws the recording as it comes.
+pub struct Waterfall {
+    recorded: Arc<SharedBuffer>,
+    config: AcousticConfig,
+    /// lowest and highest frequency shown
+    band: (f64, f64),
+    /// characters per line
+    width: usize,
+}
+
+impl Waterfall {
+    /// Show the band of `config` in what keeps being recorded into `recorded`, a buffer
+    /// nobody consumes from, like that of a `Recorder::with_capacity` of a second or so.
+    pub fn new(recorded: Arc<SharedBuffer>, config: &AcousticConfig) -> Waterfall {
+        Waterfall {
+            recorded,
+            config: config.clone(),
+            band: config
+                .pass_band()
+                .unwrap_or_else(|| config.auto_pass_band()),
+            width: 64,
+        }
+    }
+
+    /// characters per line, at least 2
+    pub fn with_width(mut self, width: usize) -> Waterfall {
+        self.width = width.max(2);
+        self
+    }
+
+    /// the column of `freq`, if it is in the band
+    fn column(&self, freq: f64) -> Option<usize> {
+        let (low, high) = self.band;
+        let column = ((freq - low) / (high - low) * self.width as f64).floor();
+        (0.0..self.width as f64)
+            .contains(&column)
+            .then_some(column as usize)
+    }
+
+    /// The band from end to end with the tones marked, to print above the lines.
+    pub fn scale(&self) -> String {
+        let mut line = vec![b'-'; self.width];
+        for freq in self.config.data_freqs() {
+            if let Some(column) = self.column(freq) {
+                line[column] = b'|';
+            }
+        }
+        for freq in self.config.preamble_freqs {
+            if let Some(column) = self.column(freq) {
+                line[column] = b'^';
+            }
+        }
+        format!(
+            "{} {:.0} to {:.0} Hz",
+            String::from_utf8_lossy(&line),
+            self.band.0,
+            self.band.1
+        )
+    }
+
+    /// The line for `samples`, the loudest each bin got in them, blank if they are shorter
+    /// than an STFT.
+    pub fn line(&self, samples: &[f64]) -> String {
+        let steps = spectrogram(samples);
+        if steps.is_empty() {
+            return " ".repeat(self.width);
+        }
+        let (low, high) = self.band;
+        let bin_width = self.config.sample_rate / FFT_SIZE as f64;
+        let levels = (0..self.width)
+            .map(|column| {
+                let freq = low + (column as f64 + 0.5) * (high - low) / self.width as f64;
+                let bin = ((freq / bin_width).round() as usize).min(FFT_SIZE / 2 - 1);
+                steps
+                    .iter()
+                    .map(|step| step[bin])
+                    .fold(f64::NEG_INFINITY, f64::max)
+                    - full_scale()
+            })
+            .collect::<Vec<f64>>();
+        let line = levels
+            .iter()
+            .map(|level| {
+                let shade = (level + DYNAMIC_RANGE) / DYNAMIC_RANGE * (SHADES.len() - 1) as f64;
+                SHADES[shade.round().clamp(0.0, (SHADES.len() - 1) as f64) as usize] as char
+            })
+            .collect::<String>();
+        let peak = levels.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        format!("{} {:.0} dBFS", line, peak.max(-DYNAMIC_RANGE))
+    }
+
+    /// Print the scale, then a line for what was recorded every `period`, until writing to
+    /// `out` fails.
+    pub fn run(&self, period: Duration, mut out: impl Write) -> Result<()> {
+        writeln!(out, "{}", self.scale())?;
+        let len = (period.as_secs_f64() * self.config.sample_rate) as usize;
+        loop {
+            sleep(period);
+            let samples = self.recorded.latest(len.max(FFT_SIZE))?;
+            writeln!(out, "{}", self.line(&samples))?;
+        }
+    }
+}
+
+#[test]
+fn test_waterfall() {
+    use crate::{physics::FFT_STEP, recorder::Recorder};
+
+    let config = AcousticConfig::default();
+    let waterfall =
+        Waterfall::new(Recorder::with_capacity(FFT_SIZE).clone_handle(), &config).with_width(40);
+    let scale = waterfall.scale();
+    let carrier = waterfall.column(config.carrier_freqs[0]).unwrap();
+    assert_eq!(scale.as_bytes()[carrier], b'|');
+    let preamble = waterfall.column(config.preamble_freqs[0]).unwrap();
+    assert_eq!(scale.as_bytes()[preamble], b'^');
+
+    assert_eq!(waterfall.line(&[0.0; 10]).trim(), "");
+    let silence = waterfall.line(&[0.0; 4 * FFT_STEP]);
+    assert!(silence.starts_with(&" ".repeat(40)));
+    assert!(silence.ends_with("-80 dBFS"));
+
+    // a tone at half of full scale on the first carrier
+    let tone = (0..4 * FFT_SIZE)
+        .map(|i| {
+            let t = i as f64 / config.sample_rate;
+            0.5 * (2.0 * std::f64::consts::PI * config.carrier_freqs[0] * t).sin()
+        })
+        .collect::<Vec<f64>>();
+    let line = waterfall.line(&tone);
+    let shade = |c: &u8| SHADES.iter().position(|shade| shade == c).unwrap();
+    let loudest = line.as_bytes()[..40].iter().map(shade).max().unwrap();
+    assert_eq!(shade(&line.as_bytes()[carrier]), loudest, "{}", line);
+    assert_eq!(line.as_bytes()[0], b' ', "{}", line);
+    assert!(line.ends_with("-6 dBFS"), "{}", line);
+}
@@ -0,0 +1,198 @@
+//! # WAV sample reader
+//!
+//! Decodes recordings offline through the same `Receiver` code path as live audio. The file
+//! is read a block at a time, converted to mono at the configured sample rate, and only the
+//! samples the receiver has not consumed yet are kept, at most a minute of them like the
+//! `Recorder`, so an hour of recording takes no more memory than a minute, unlike
+//! `input_wav`. A single channel of a stereo recording
+//! can be read as well, see `open_channel`.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use hound::{SampleFormat, WavReader};
+
+use crate::{
+    config::AcousticConfig,
+    error::{AcousticError, Result},
+    recorder::DEFAULT_CAPACITY,
+    resampler::InputConverter,
+    ring_buffer::RingBuffer,
+    transmission::SampleReader,
+};
+
+/// frames read from the file at once
+const BLOCK_FRAMES: usize = 4096;
+
+pub struct WavSampleReader {
+    reader: WavReader<BufReader<File>>,
+    converter: InputConverter,
+    buffer: RingBuffer,
+    /// interleaved samples left in the file
+    remaining: usize,
+}
+
+impl WavSampleReader {
+    pub fn open(path: impl AsRef<Path>, config: &AcousticConfig) -> Result<WavSampleReader> {
+        Self::with_converter(path, config, |converter| converter)
+    }
+
+    /// read only `channel` of the file, the last one if it has fewer
+    pub fn open_channel(
+        path: impl AsRef<Path>,
+        config: &AcousticConfig,
+        channel: usize,
+    ) -> Result<WavSampleReader> {
+        Self::with_converter(path, config, |converter| converter.with_channel(channel))
+    }
+
+    fn with_converter(
+        path: impl AsRef<Path>,
+        config: &AcousticConfig,
+        setup: impl FnOnce(InputConverter) -> InputConverter,
+    ) -> Result<WavSampleReader> {
+        let reader = WavReader::open(path)?;
+        let spec = reader.spec();
+        let converter = setup(InputConverter::new(
+            spec.channels as usize,
+            spec.sample_rate,
+            config.sample_rate as u32,
+        )?);
+        Ok(WavSampleReader {
+            remaining: reader.len() as usize,
+            reader,
+            converter,
+            buffer: RingBuffer::new(DEFAULT_CAPACITY),
+        })
+    }
+
+    /// Read blocks until the samples up to `end` are buffered.
+    fn read_until(&mut self, end: usize) -> Result<()> {
+        while self.buffer.end() < end {
+            if !self.read_block()? {
+                return Err(AcousticError::EndOfStream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Read and convert the next block. Returns `false` once the file is exhausted.
+    fn read_block(&mut self) -> Result<bool> {
+        if self.remaining == 0 {
+            return Ok(false);
+        }
+        let spec = self.reader.spec();
+        let n = self.remaining.min(BLOCK_FRAMES * spec.channels as usize);
+        let block = read_normalized(&mut self.reader, n)?;
+        self.remaining -= n;
+        let converted = self.converter.process(&block);
+        self.buffer.extend(converted);
+        if self.remaining == 0 {
+            // push whatever the resampler still holds back
+            let silence = vec![0.0; 2 * BLOCK_FRAMES * spec.channels as usize];
+            let tail = self.converter.process(&silence);
+            self.buffer.extend(tail);
+        }
+        Ok(true)
+    }
+}
+
+/// The next `n` interleaved samples of `reader` from -1 to 1, whether the file holds floats
+/// or integers of any width.
+pub(crate) fn read_normalized<R: Read>(reader: &mut WavReader<R>, n: usize) -> Result<Vec<f32>> {
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        SampleFormat::Float => reader
+            .samples::<f32>()
+            .take(n)
+            .collect::<std::result::Result<Vec<f32>, _>>()?,
+        SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .take(n)
+                .map(|x| x.map(|x| x as f32 / scale))
+                .collect::<std::result::Result<Vec<f32>, _>>()?
+        }
+    };
+    Ok(samples)
+}
+
+impl SampleReader for WavSampleReader {
+    fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+        self.read_until(end)?;
+        self.buffer.get(start, end)
+    }
+
+    fn read_samples(&mut self, start: usize, end: usize, buffer: &mut Vec<f64>) -> Result<()> {
+        self.read_until(end)?;
+        self.buffer.read(start, end, buffer)
+    }
+
+    fn consume(&mut self, until: usize) -> Result<()> {
+        self.buffer.consume(until);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_wav_sample_reader() {
+    use crate::{output_wav, transmission::Receiver, transmitter::modulate_message};
+
+    let config = AcousticConfig::default();
+    let silence = vec![0.0; 10000];
+    let signal = [
+        &silence[..],
+        &modulate_message(&config, b"hello world").unwrap(),
+        &silence,
+    ]
+    .concat();
+    output_wav(&config, &signal, "wav_reader.wav").unwrap();
+
+    let reader = WavSampleReader::open("wav_reader.wav", &config).unwrap();
+    let mut receiver = Receiver::with_config(Box::new(reader), config.clone());
+    assert_eq!(receiver.run().unwrap(), b"hello world");
+    assert!(matches!(receiver.run(), Err(AcousticError::EndOfStream)));
+}
+
+#[test]
+fn test_wav_sample_reader_memory() {
+    use crate::{output_wav, transmission::Receiver, transmitter::modulate_message};
+
+    /// fails the test once more than `limit` samples are buffered
+    struct Bounded {
+        reader: WavSampleReader,
+        limit: usize,
+    }
+
+    impl SampleReader for Bounded {
+        fn take_samples(&mut self, start: usize, end: usize) -> Result<Vec<f64>> {
+            let samples = self.reader.take_samples(start, end);
+            assert!(self.reader.buffer.len() <= self.limit);
+            samples
+        }
+
+        fn consume(&mut self, until: usize) -> Result<()> {
+            self.reader.consume(until)
+        }
+    }
+
+    let config = AcousticConfig::default();
+    let message = modulate_message(&config, b"hello world").unwrap();
+    // longer than the reader could keep, if the receiver did not consume while hunting
+    let silence = vec![0.0; 2 * DEFAULT_CAPACITY];
+    let signal = [&silence[..], &message, &silence].concat();
+    output_wav(&config, &signal, "wav_reader_memory.wav").unwrap();
+
+    let reader = WavSampleReader::open("wav_reader_memory.wav", &config).unwrap();
+    let limit = message.len() + 4 * BLOCK_FRAMES;
+    let mut receiver = Receiver::with_config(Box::new(Bounded { reader, limit }), config);
+    let received = receiver.run();
+    let end = receiver.run();
+    std::fs::remove_file("wav_reader_memory.wav").unwrap();
+    assert_eq!(received.unwrap(), b"hello world");
+    assert!(matches!(end, Err(AcousticError::EndOfStream)));
+}
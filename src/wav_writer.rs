@@ -0,0 +1,72 @@
+//! # WAV sample writer
+//!
+//! Writes what would be played to a file instead, through the same `SampleSink` as the
+//! speaker, a block at a time as it is modulated. Samples are stored as mono 32 bit floats
+//! at the configured sample rate, at the level they are written with.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::{config::AcousticConfig, error::Result, transmitter::SampleSink};
+
+pub struct WavSampleWriter {
+    /// `None` once finished
+    writer: Option<WavWriter<BufWriter<File>>>,
+}
+
+impl WavSampleWriter {
+    pub fn create(path: impl AsRef<Path>, config: &AcousticConfig) -> Result<WavSampleWriter> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: config.sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        Ok(WavSampleWriter {
+            writer: Some(WavWriter::create(path, spec)?),
+        })
+    }
+}
+
+impl SampleSink for WavSampleWriter {
+    /// Samples written after `finish` are dropped.
+    fn write_samples(&mut self, samples: &[f64]) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            for x in samples {
+                writer.write_sample(*x as f32)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the header with the length of the file.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_wav_sample_writer() {
+    use crate::{input_wav, transmitter::write_stream};
+
+    let config = AcousticConfig::default();
+    let signal = (0..10000)
+        .map(|i| (i as f64 / 10.0).sin())
+        .collect::<Vec<f64>>();
+    let mut writer = WavSampleWriter::create("wav_writer.wav", &config).unwrap();
+    write_stream(&config, signal.iter().copied(), &mut writer).unwrap();
+    let written = input_wav("wav_writer.wav").unwrap();
+    std::fs::remove_file("wav_writer.wav").unwrap();
+
+    let mut captured = Vec::new();
+    write_stream(&config, signal, &mut captured).unwrap();
+    assert_eq!(written.len(), captured.len());
+    assert!(written
+        .iter()
+        .zip(&captured)
+        .all(|(a, b)| (a - b).abs() < 1e-6));
+}